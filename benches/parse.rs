@@ -0,0 +1,43 @@
+//! Times `tangle_document` on a large generated document, through the fence
+//! scan and through the full syntax tree. Run with `cargo bench --bench parse`.
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// A few megabytes of reference-doc prose and tables around a handful of fences
+fn document() -> String {
+    let mut markdown = String::new();
+    for section in 0..20_000 {
+        markdown.push_str(&format!(
+            "## Item {section}\n\nSome *prose* with `code` and a [link](https://example.com/{section}).\n\n| key | value |\n|---|---|\n| a | {section} |\n\n"
+        ));
+        if section % 1000 == 0 {
+            markdown.push_str(&format!(
+                "```rust tangle:///src/lib.rs\npub const ITEM_{section}: u32 = {section};\n```\n\n"
+            ));
+        }
+    }
+    markdown
+}
+
+/// The fastest of a few runs of tangling `markdown`
+fn time(markdown: &str) -> lit::Result<Duration> {
+    let mut fastest = Duration::MAX;
+    for _ in 0..3 {
+        let start = Instant::now();
+        lit::tangle_document("bench.md", markdown)?;
+        fastest = fastest.min(start.elapsed());
+    }
+    Ok(fastest)
+}
+
+fn main() -> lit::Result<()> {
+    let markdown = document();
+    // An HTML line is something the scan gives up on, so this one builds the tree.
+    let with_html = format!("<div></div>\n\n{markdown}");
+
+    println!("document: {} bytes", markdown.len());
+    println!("scan: {:?}", time(&markdown)?);
+    println!("tree: {:?}", time(&with_html)?);
+    Ok(())
+}
@@ -1,738 +1,13487 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use camino::Utf8Component;
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use fs_err as fs;
+use markdown::Constructs;
 use markdown::ParseOptions;
+use markdown::mdast::Code;
 use markdown::mdast::Node;
 use markdown::to_mdast;
 use miette::Diagnostic;
+use miette::LabeledSpan;
+use miette::NamedSource;
+use miette::SourceCode;
+use miette::SourceSpan;
+use percent_encoding::percent_decode_str;
 use petgraph::Direction;
 use petgraph::graph::DiGraph;
 use petgraph::graph::NodeIndex;
 use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::LazyLock;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
 use thiserror::Error;
+use tracing::debug;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 use url::Url;
 use walkdir::WalkDir;
 
-#[derive(Debug)]
-pub struct Lit {
-    pub input: Utf8PathBuf,
-    pub output: Utf8PathBuf,
-}
+/// Tangling from a cargo build script
+pub mod build {
+    use super::*;
 
-impl Lit {
-    pub fn new(input: Utf8PathBuf, output: Utf8PathBuf) -> Self {
-        Lit { input, output }
+    /// Tangle `input` into `output`, telling cargo to rerun when the markdown changes
+    pub fn tangle(
+        input: impl AsRef<Utf8Path>,
+        output: impl AsRef<Utf8Path>,
+    ) -> Result<TangleReport> {
+        let lit = Lit::new(input.as_ref().to_owned(), output.as_ref().to_owned());
+        tangle_with(&lit, &mut std::io::stdout())
     }
 
-    pub fn tangle(&self) -> Result<()> {
-        let files = self.read_blocks()?;
-
-        for file in files {
-            let content = file.render();
-
-            let full_path = self.output.join(&file.path);
-            // Tangle paths always have at least '/' as parent, so this cannot fail.
-            #[allow(clippy::unwrap_used)]
-            let parent = full_path.parent().unwrap();
-            fs::create_dir_all(parent)?;
-            info!("Writing {full_path}");
-            fs::write(&full_path, content)?;
+    /// `tangle` for a configured `Lit`, writing cargo's instructions to `out`
+    pub fn tangle_with(lit: &Lit, out: &mut impl std::io::Write) -> Result<TangleReport> {
+        let report = lit.tangle()?;
+        for path in lit.inputs.iter().chain(&lit.markdown_files()?) {
+            writeln!(out, "cargo:rerun-if-changed={path}")?;
         }
-
-        Ok(())
+        Ok(report)
     }
+}
 
-    /// Parse markdown content and extract code blocks with tangle:// paths
-    pub fn parse_markdown(markdown_text: &str) -> Result<Vec<Block>> {
-        let ast = to_mdast(markdown_text, &ParseOptions::default())
-            .map_err(|e| LitError::Markdown(e.to_string()))?;
-
-        let Node::Root(root) = ast else {
-            return Err(LitError::NotRoot); // cov-excl-line: unreachable — to_mdast always returns Root
-        };
+/// Settings read from `lit.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Directory of markdown sources, used when the CLI is given no INPUT
+    pub input: Option<Utf8PathBuf>,
+    /// Directory to tangle into, used when the CLI is given no OUTPUT
+    pub output: Option<Utf8PathBuf>,
+    /// Content assertions, keyed by destination path
+    #[serde(default, rename = "assert")]
+    pub assertions: BTreeMap<String, Assertions>,
+    /// How blocks from several documents combine, keyed by destination path
+    #[serde(default)]
+    pub merge: BTreeMap<String, MergeStrategy>,
+    /// Blank lines between blocks, keyed by destination extension
+    #[serde(default)]
+    pub separators: BTreeMap<String, usize>,
+    /// Encodings to write destinations in, keyed by extension (see `lit/encoding.md`)
+    #[serde(default)]
+    pub encodings: BTreeMap<String, TextEncoding>,
+    /// Whitespace trimmed from blocks, keyed by extension (see `lit/whitespace.md`)
+    #[serde(default)]
+    pub trim: BTreeMap<String, Trim>,
+    /// Values for `{{name}}` placeholders in tangle paths
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    /// Directories inside the output that tangle path prefixes are written to
+    #[serde(default)]
+    pub roots: BTreeMap<String, Utf8PathBuf>,
+    /// Prepend a generated-file header to each output (see `lit/header.md`)
+    #[serde(default)]
+    pub header: bool,
+    /// Tools to run over tangled `.rs` files (see `lit/rust.md`)
+    #[serde(default)]
+    pub rust: RustHooks,
+    /// Shell commands to run after tangling (see `lit/hooks.md`)
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// External programs that read, transform, or receive blocks, keyed by name (see `lit/plugins.md`)
+    #[serde(default)]
+    pub plugins: BTreeMap<String, Plugin>,
+    /// Severities of `lit lint` rules, keyed by rule ID (see `lit/lint.md`)
+    #[serde(default)]
+    pub lint: BTreeMap<String, Severity>,
+    /// Files listing the targets the tangled tree should hold (see `lit/expect.md`)
+    #[serde(default)]
+    pub expect: Vec<Utf8PathBuf>,
+    /// Templates `lit weave` writes into (see `lit/themes.md`)
+    #[serde(default)]
+    pub weave: WeaveTemplates,
+    /// Files other than documents brought from the inputs into the output (see `lit/assets.md`)
+    #[serde(default)]
+    pub assets: Assets,
+    /// Links from Rust programs to the Rust Playground, when set (see `lit/playground.md`)
+    #[serde(default)]
+    pub playground: Option<Playground>,
+    /// Platforms the documents are rendered on, for `render-compat` (see `lit/render.md`)
+    #[serde(default)]
+    pub render: Vec<Platform>,
+    /// Member projects tangled by `lit --workspace` (see `lit/workspace.md`)
+    #[serde(default)]
+    pub workspace: Workspace,
+    /// Ceilings on block and file sizes (see `lit/limits.md`)
+    #[serde(default)]
+    pub limits: Limits,
+    /// The build `lit verify-build` runs (see `lit/verify.md`)
+    #[serde(default)]
+    pub verify: Verify,
+    /// Read environment variables in tangle paths and content, set by `--env` (see `lit/env.md`)
+    #[serde(skip)]
+    pub env: bool,
+    /// Restrict lit to what is safe for untrusted documents, set by `sandbox` (see `lit/sandbox.md`)
+    #[serde(skip)]
+    pub sandbox: bool,
+}
 
-        // Extract snippets from top-level code blocks only
-        root.children
-            .iter()
-            .map(Block::try_from)
-            .filter_map(|result| match result {
-                Ok(block) => Some(Ok(block)),
-                Err(BlockError::NotTangleBlock) => None,
-                Err(e) => Some(Err(e.into())),
-            })
-            .collect()
+impl Config {
+    /// Read and parse a `lit.toml` file
+    pub fn load(path: &Utf8Path) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        let mut config = Config::parse(path, &text)?;
+
+        let base = path.parent().unwrap_or(Utf8Path::new(""));
+        config.input = config.input.map(|input| base.join(input));
+        config.output = config.output.map(|output| base.join(output));
+        for member in &mut config.workspace.members {
+            *member = base.join(&*member);
+        }
+        for path in config.expect.iter_mut().chain(
+            [&mut config.weave.typst, &mut config.weave.latex]
+                .into_iter()
+                .flatten(),
+        ) {
+            *path = base.join(&*path);
+        }
+        Ok(config)
     }
 
-    /// Read all markdown files from input directory and parse tangle blocks
-    pub fn read_blocks(&self) -> Result<Vec<TangledFile>> {
-        let mut files = HashMap::<Utf8PathBuf, Vec<Block>>::new();
-
-        for entry in WalkDir::new(&self.input)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|entry| entry.file_type().is_file())
-            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "md"))
+    /// Parse `lit.toml` text; `name` is used when reporting errors
+    pub fn parse(name: &Utf8Path, text: &str) -> std::result::Result<Config, ConfigError> {
+        let error = |message: String, span: Option<SourceSpan>| ConfigError {
+            message,
+            src: NamedSource::new(name.as_str(), text.to_string()),
+            span,
+        };
+        let config: Config = toml::from_str(text).map_err(|e: toml::de::Error| {
+            error(e.message().to_string(), e.span().map(SourceSpan::from))
+        })?;
+
+        if let Some((prefix, root)) = config.roots.iter().find(|(_, root)| !inside_output(root)) {
+            return Err(error(
+                format!("root for `{prefix}` must be inside the output directory, got `{root}`"),
+                None,
+            ));
+        }
+        for (prefix, root) in &config.roots {
+            if let Err(e) = check_portable(root.as_str()) {
+                return Err(error(
+                    format!("root for `{prefix}` is not a portable path: {e}"),
+                    None,
+                ));
+            }
+        }
+        if let Some(rule) = config
+            .lint
+            .keys()
+            .find(|rule| !RULES.iter().any(|known| known.id == *rule))
         {
-            let content = fs::read_to_string(entry.path())?;
-            let blocks = Self::parse_markdown(&content)?;
-
-            for block in blocks {
-                files.entry(block.path.clone()).or_default().push(block);
+            return Err(error(format!("unknown lint rule `{rule}`"), None));
+        }
+        if let Some(name) = config.workspace.duplicate_name() {
+            return Err(error(
+                format!("two workspace members are named `{name}`"),
+                None,
+            ));
+        }
+        if let Some(message) = config.invalid_plugins() {
+            return Err(error(message, None));
+        }
+        for (path, assertions) in &config.assertions {
+            if let Some((pattern, e)) = assertions
+                .matches
+                .iter()
+                .find_map(|pattern| Regex::new(pattern).err().map(|e| (pattern, e)))
+            {
+                return Err(error(
+                    format!("`{pattern}` in [assert.\"{path}\"] is not a regular expression: {e}"),
+                    None,
+                ));
             }
         }
-
-        files
-            .into_iter()
-            .map(|(path, blocks)| {
-                let sorted_blocks = solve_block_order(&blocks)?;
-                Ok(TangledFile::new(path, sorted_blocks))
-            })
-            .collect()
+        Ok(config)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    #![allow(
-        clippy::unwrap_used,
-        clippy::expect_used,
-        clippy::panic,
-        clippy::indexing_slicing,
-        clippy::arithmetic_side_effects
-    )]
+/// Whether a relative path stays inside the output directory
+fn inside_output(path: &Utf8Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Utf8Component::Normal(_) | Utf8Component::CurDir))
+}
 
-    use super::*;
+/// A `lit.toml` that could not be parsed
+#[derive(Debug, Error, Diagnostic)]
+#[error("invalid config: {message}")]
+#[diagnostic(code(lit::config))]
+pub struct ConfigError {
+    message: String,
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("here")]
+    span: Option<SourceSpan>,
+}
 
-    #[test]
-    fn test_parse_block_with_id_and_constraints() {
-        let markdown = r#"```tangle:///output.txt?id=main&last
-fn main() {}
-```"#;
+/// Strings a destination file must or must not contain
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Assertions {
+    /// Each string must appear in the file
+    #[serde(default)]
+    pub contains: Vec<String>,
+    /// No string may appear in the file
+    #[serde(default)]
+    pub not_contains: Vec<String>,
+    /// Each regular expression must match somewhere in the file
+    #[serde(default)]
+    pub matches: Vec<String>,
+    /// The file must have at least this many lines
+    #[serde(default)]
+    pub min_lines: Option<usize>,
+    /// The file may have at most this many lines
+    #[serde(default)]
+    pub max_lines: Option<usize>,
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("output.txt"));
-        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "main");
-        assert_eq!(blocks[0].constraints.len(), 1);
-        assert!(matches!(blocks[0].constraints[0], Constraint::Last));
+impl Assertions {
+    /// Each assertion in the table, in the order the keys are documented
+    fn list(&self) -> Vec<Assertion> {
+        let strings = |to: fn(String) -> Assertion, values: &[String]| {
+            values.iter().cloned().map(to).collect::<Vec<_>>()
+        };
+        [
+            strings(Assertion::Contains, &self.contains),
+            strings(Assertion::NotContains, &self.not_contains),
+            strings(Assertion::Matches, &self.matches),
+            self.min_lines
+                .map(Assertion::MinLines)
+                .into_iter()
+                .collect(),
+            self.max_lines
+                .map(Assertion::MaxLines)
+                .into_iter()
+                .collect(),
+        ]
+        .concat()
     }
+}
 
-    #[test]
-    fn test_parse_block_with_after_constraint() {
-        let markdown = r#"```tangle:///output.txt?id=b&after=a
-Second block
-```"#;
-
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "b");
-        match &blocks[0].constraints[0] {
-            Constraint::After(ids) => {
-                assert_eq!(ids.len(), 1);
-                assert_eq!(ids[0].as_str(), "a");
-            }
-            _ => unreachable!(),
+impl Config {
+    /// Check a rendered file against the assertions configured for its path and declared by its fences
+    pub fn check(&self, file: &TangledFile, content: &str) -> Result<()> {
+        let configured = self
+            .assertions
+            .get(file.path.as_str())
+            .map(Assertions::list)
+            .unwrap_or_default();
+        let declared = file.blocks.iter().flat_map(|block| &block.assertions);
+
+        match configured
+            .iter()
+            .chain(declared)
+            .find_map(|assertion| assertion.failure(content))
+        {
+            Some(failure) => Err(LitError::AssertionFailed {
+                path: file.path.clone(),
+                failure,
+                help: contributors_help(file),
+            }),
+            None => Ok(()),
         }
     }
+}
 
-    #[test]
-    fn test_parse_block_with_multiple_after() {
-        let markdown = r#"```tangle:///output.txt?id=c&after=a,b
-Third block
-```"#;
+fn contributors_help(file: &TangledFile) -> String {
+    let origins = file
+        .origins
+        .iter()
+        .map(|origin| origin.to_string())
+        .collect::<Vec<_>>();
+    format!("blocks tangled into this file: {}", origins.join(", "))
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        match &blocks[0].constraints[0] {
-            Constraint::After(ids) => {
-                assert_eq!(ids.len(), 2);
-                assert_eq!(ids[0].as_str(), "a");
-                assert_eq!(ids[1].as_str(), "b");
-            }
-            _ => unreachable!(),
-        }
+/// How blocks from several documents combine into one destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Order every block together, across documents
+    #[default]
+    Interleave,
+    /// Order each document's blocks separately and join them in reading order
+    Concatenate,
+    /// Reject blocks from more than one document
+    Error,
+}
+
+impl Config {
+    /// The merge strategy configured for a destination path
+    pub fn merge_strategy(&self, path: &Utf8Path) -> MergeStrategy {
+        self.merge.get(path.as_str()).copied().unwrap_or_default()
     }
+}
 
-    #[test]
-    fn test_block_id_display() {
-        let id = BlockId::new("my-block".to_string()).unwrap();
-        assert_eq!(format!("{id}"), "my-block");
+/// Blank lines between blocks for extensions the config does not set
+const DEFAULT_SEPARATORS: &[(&str, usize)] = &[("yaml", 0), ("yml", 0)];
+
+impl Config {
+    /// How many blank lines separate blocks in a destination, by its extension
+    pub fn separator(&self, path: &Utf8Path) -> usize {
+        let extension = path.extension().unwrap_or_default();
+        self.separators
+            .get(extension)
+            .copied()
+            .or_else(|| {
+                DEFAULT_SEPARATORS
+                    .iter()
+                    .find(|(known, _)| *known == extension)
+                    .map(|(_, blank_lines)| *blank_lines)
+            })
+            .unwrap_or(1)
     }
+}
 
-    #[test]
-    fn test_parse_block_with_before_constraint() {
-        let markdown = r#"```tangle:///output.txt?id=a&before=b
-First block
-```"#;
+impl Config {
+    /// Replace `{{name}}` placeholders in a tangle path with their values
+    pub fn expand_path(&self, path: &Utf8Path) -> std::result::Result<Utf8PathBuf, BlockError> {
+        let mut expanded = String::new();
+        let mut rest = path.as_str();
+        while let Some((before, after)) = rest.split_once("{{") {
+            let Some((name, tail)) = after.split_once("}}") else {
+                break;
+            };
+            let Some(value) = self.variables.get(name) else {
+                if self.env && name.starts_with("env.") {
+                    expanded.push_str(before);
+                    expanded.push_str(&format!("{{{{{name}}}}}"));
+                    rest = tail;
+                    continue;
+                }
+                return Err(BlockError::UnknownVariable(format!("{{{{{name}}}}}")));
+            };
+            expanded.push_str(before);
+            expanded.push_str(value);
+            rest = tail;
+        }
+        expanded.push_str(rest);
+        let expanded = self.expand_env_path(expanded)?;
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "a");
-        match &blocks[0].constraints[0] {
-            Constraint::Before(ids) => {
-                assert_eq!(ids.len(), 1);
-                assert_eq!(ids[0].as_str(), "b");
+        if expanded != path.as_str() {
+            if expanded.starts_with('/') || !inside_output(Utf8Path::new(&expanded)) {
+                return Err(BlockError::InvalidPath);
             }
-            _ => unreachable!(),
+            check_portable(&expanded)?;
         }
+        Ok(Utf8PathBuf::from(expanded))
     }
+}
 
-    #[test]
-    fn test_parse_block_with_first_constraint() {
-        let markdown = r#"```tangle:///output.txt?id=lead&first
-First block
-```"#;
-
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "lead");
-        assert_eq!(blocks[0].constraints.len(), 1);
-        assert!(matches!(blocks[0].constraints[0], Constraint::First));
+impl Config {
+    /// Move a tangle path under the root configured for its longest matching prefix
+    pub fn route(&self, path: &Utf8Path) -> Utf8PathBuf {
+        let routed = self
+            .roots
+            .iter()
+            .filter_map(|(prefix, root)| Some((prefix, root, path.strip_prefix(prefix).ok()?)))
+            .max_by_key(|(prefix, _, _)| Utf8Path::new(prefix).components().count());
+        let Some((_, root, rest)) = routed else {
+            return path.to_path_buf();
+        };
+        root.components()
+            .chain(rest.components())
+            .filter(|c| *c != Utf8Component::CurDir)
+            .collect()
     }
+}
 
-    #[test]
-    fn test_parse_block_invalid_scheme() {
-        // A code block that looks like a tangle URL but uses a non-tangle scheme
-        let markdown = r#"```https://example.com/file.txt
-code
-```"#;
+/// Settings for one document, from the `lit` key of its YAML front matter
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrontMatter {
+    /// Put in front of every tangle path in the document
+    pub prefix: Option<Utf8PathBuf>,
+    /// Values for `{{name}}` placeholders, over the project's
+    pub variables: BTreeMap<String, String>,
+    /// Directory inside the output to write the document's files to, in place of `[roots]`
+    pub output: Option<Utf8PathBuf>,
+    /// The questions the document asks when it is used as a template
+    pub prompts: Vec<Prompt>,
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 0);
-    }
+impl FrontMatter {
+    /// Read the settings from a markdown document's front matter; `name` is used when reporting errors
+    pub fn parse(name: &Utf8Path, markdown: &str) -> std::result::Result<FrontMatter, ConfigError> {
+        let error = |message: String, span: Option<SourceSpan>| ConfigError {
+            message,
+            src: NamedSource::new(name.as_str(), markdown.to_string()),
+            span,
+        };
+        /// The front matter as a whole, of which lit reads one key
+        #[derive(Deserialize)]
+        struct Document {
+            #[serde(default)]
+            lit: FrontMatter,
+        }
+        let mut front = FrontMatter::default();
+        let mut body = 0;
+        let mut front_span = None;
+        if let Some((start, yaml)) = front_matter(markdown) {
+            let document: Option<Document> = serde_yaml::from_str(yaml).map_err(|e| {
+                let span = e
+                    .location()
+                    .map(|location| start.saturating_add(location.index()).into());
+                error(format!("front matter: {e}"), span)
+            })?;
+            front = document.map(|document| document.lit).unwrap_or_default();
+            body = start.saturating_add(yaml.len());
+            front_span = Some(SourceSpan::new(start.into(), yaml.len()));
+        }
 
-    #[test]
-    fn test_parse_block_host_in_tangle_url() {
-        let markdown = r#"```tangle://example.com/path.txt
-code
-```"#;
+        // Where each path came from, to say so when it is rejected
+        let mut prefix_from = ("front matter", front_span);
+        if let Some((span, settings)) = lit_comment(markdown, body) {
+            let span = Some(span);
+            for setting in settings.split_whitespace() {
+                match setting.split_once('=') {
+                    Some(("prefix", _)) if front.prefix.is_some() => {
+                        return Err(error(
+                            "`prefix` is set in both the front matter and a comment".to_string(),
+                            span,
+                        ));
+                    }
+                    Some(("prefix", prefix)) => {
+                        front.prefix = Some(Utf8PathBuf::from(prefix));
+                        prefix_from = ("`<!-- lit: -->` comment", span);
+                    }
+                    _ => {
+                        return Err(error(
+                            format!("unknown setting `{setting}` in a `<!-- lit: -->` comment"),
+                            span,
+                        ));
+                    }
+                }
+            }
+        }
 
-        let result = Lit::parse_markdown(markdown);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("hostless"));
+        for (key, path, (from, span)) in [
+            ("prefix", &front.prefix, prefix_from),
+            ("output", &front.output, ("front matter", front_span)),
+        ] {
+            let Some(path) = path else {
+                continue;
+            };
+            if !inside_output(path) {
+                return Err(error(
+                    format!("{from} `{key}` must be inside the output directory, got `{path}`"),
+                    span,
+                ));
+            }
+            if let Err(e) = check_portable(path.as_str()) {
+                return Err(error(
+                    format!("{from} `{key}` is not a portable path: {e}"),
+                    span,
+                ));
+            }
+        }
+        Ok(front)
     }
 
-    #[test]
-    fn test_parse_block_missing_path() {
-        let markdown = r#"```tangle:///
-code
-```"#;
+    /// The project's config for `document`: its `stem`, then this front matter's variables
+    pub fn config(&self, project: &Config, document: &Utf8Path) -> Config {
+        let mut config = project.clone();
+        if let Some(stem) = document.file_stem() {
+            config
+                .variables
+                .insert("stem".to_string(), stem.to_string());
+        }
+        config.variables.extend(self.variables.clone());
+        config
+    }
 
-        let result = Lit::parse_markdown(markdown);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("missing path"));
+    /// Where a tangle path in this document is written, given its `config`
+    pub fn place(
+        &self,
+        path: &Utf8Path,
+        config: &Config,
+    ) -> std::result::Result<Utf8PathBuf, BlockError> {
+        let path = match &self.prefix {
+            Some(prefix) => prefix.join(path),
+            None => path.to_path_buf(),
+        };
+        let path = config.expand_path(&path)?;
+        Ok(match &self.output {
+            Some(output) => output
+                .components()
+                .chain(path.components())
+                .filter(|c| *c != Utf8Component::CurDir)
+                .collect(),
+            None => config.route(&path),
+        })
     }
+}
 
-    #[test]
-    fn test_parse_block_invalid_path() {
-        let markdown = r#"```tangle:////etc/passwd
-code
-```"#;
+/// The settings in the first `<!-- lit: ... -->` line after `body`, before any fence, with the comment's span
+fn lit_comment(markdown: &str, body: usize) -> Option<(SourceSpan, &str)> {
+    let mut offset = body;
+    for line in markdown.get(body..)?.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed.trim_start().starts_with("```") || trimmed.trim_start().starts_with("~~~") {
+            return None;
+        }
+        if let Some(settings) = trimmed
+            .strip_prefix("<!--")
+            .and_then(|rest| rest.strip_suffix("-->"))
+            && let Some(settings) = settings.trim().strip_prefix("lit:")
+        {
+            return Some((SourceSpan::new(offset.into(), trimmed.len()), settings));
+        }
+        offset = offset.saturating_add(line.len());
+    }
+    None
+}
 
-        let result = Lit::parse_markdown(markdown);
-        assert!(result.is_err());
-        assert!(
-            result
-                .unwrap_err()
-                .to_string()
-                .contains("Invalid tangle URL path")
-        );
+/// The YAML between a document's opening `---` line and the next, with its offset
+fn front_matter(markdown: &str) -> Option<(usize, &str)> {
+    let mut lines = markdown.split_inclusive('\n');
+    let start = lines.next().filter(|line| line.trim_end() == "---")?.len();
+    let mut end = start;
+    for line in lines {
+        if line.trim_end() == "---" {
+            return markdown.get(start..end).map(|yaml| (start, yaml));
+        }
+        end = end.saturating_add(line.len());
     }
+    None
+}
 
-    #[test]
-    fn test_parse_block_empty_block_id() {
-        let markdown = r#"```tangle:///output.txt?id=
-code
-```"#;
+/// The tangle URL declared by a fence, in its language or its meta string
+fn tangle_url(code: &Code) -> Option<Url> {
+    let lang = code.lang.as_deref()?;
+    let meta = code.meta.as_deref().unwrap_or_default();
+    std::iter::once(lang)
+        .chain(meta.split_whitespace())
+        .filter_map(|word| Url::parse(word).ok())
+        .find(|url| url.scheme() == "tangle")
+}
 
-        let result = Lit::parse_markdown(markdown);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("cannot be empty"));
+/// The language a tangle block's code is written in, if it says
+fn block_language(
+    code: &Code,
+    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+) -> Option<String> {
+    let fence_lang = code
+        .lang
+        .as_deref()
+        .filter(|lang| Url::parse(lang).map_or(true, |url| url.scheme() != "tangle"));
+    params
+        .get("lang")
+        .map(|lang| lang.to_string())
+        .or_else(|| fence_lang.map(str::to_string))
+}
+
+/// A fence word that looks like a misspelled tangle URL, if the fence has no real one
+fn tangle_typo(code: &Code) -> Option<&str> {
+    if tangle_url(code).is_some() {
+        return None;
     }
+    let lang = code.lang.as_deref()?;
+    let meta = code.meta.as_deref().unwrap_or_default();
+    std::iter::once(lang)
+        .chain(meta.split_whitespace())
+        .find(|word| {
+            word.get(..5)
+                .is_some_and(|start| start.eq_ignore_ascii_case("tangl"))
+        })
+}
 
-    #[test]
-    fn test_parse_block_invalid_block_id() {
-        let markdown = r#"```tangle:///output.txt?id=UPPERCASE
-code
-```"#;
+/// The first tangle fence nested somewhere inside `node`
+fn nested_tangle_fence(node: &Node) -> Option<&Node> {
+    node.children()?.iter().find_map(|child| match child {
+        Node::Code(code) if tangle_url(code).is_some() => Some(child),
+        _ => nested_tangle_fence(child),
+    })
+}
 
-        let result = Lit::parse_markdown(markdown);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("invalid"));
+impl TryFrom<&Node> for Block {
+    type Error = BlockError;
+
+    fn try_from(node: &Node) -> std::result::Result<Self, Self::Error> {
+        let Node::Code(code) = node else {
+            return Err(BlockError::NotTangleBlock);
+        };
+        Block::parse_code(code, code.value.clone())
     }
+}
 
-    #[test]
-    fn test_parse_block_unknown_params_ignored() {
-        let markdown = r#"```tangle:///output.txt?unknown=value&also-unknown=123
-code
-```"#;
+impl Block {
+    /// The block a fence declares, with `content` taken from it by the caller
+    fn parse_code(code: &Code, content: String) -> std::result::Result<Self, BlockError> {
+        let parsed = tangle_url(code).ok_or(BlockError::NotTangleBlock)?;
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].content, "code");
-        assert!(blocks[0].id.is_none());
-        assert!(blocks[0].constraints.is_empty());
-    }
+        // Ensure it's hostless (tangle:///path, not tangle://path)
+        if parsed.host_str().is_some() {
+            return Err(BlockError::InvalidTangleUrl);
+        }
+
+        // Get the path from hostless URL (tangle:///path/to/file)
+        let path = parsed.path();
+        if path.is_empty() || path == "/" {
+            return Err(BlockError::MissingPath);
+        }
+        if path.starts_with("//") {
+            return Err(BlockError::InvalidPath);
+        }
+        // Without the slashes (`tangle:a.rs`, `tangle:C:\a.rs`) there is no path to strip.
+        let path = path.strip_prefix('/').ok_or(BlockError::OpaqueTangleUrl)?;
+        // The URL parser percent-encodes spaces, non-ASCII, and the braces of
+        // `{{name}}` variables; the file is named by what was written.
+        let path_str = percent_decode_str(path)
+            .decode_utf8()
+            .map_err(|_| BlockError::InvalidPath)?
+            .into_owned();
+        if !inside_output(Utf8Path::new(&path_str)) {
+            return Err(BlockError::InvalidPath);
+        }
+        check_portable(&path_str)?;
+
+        // Parse constraint parameters
+        check_query(&parsed)?;
+        let params = query_params(&parsed);
+        let assertions = params
+            .iter()
+            .filter(|(key, _)| key == "assert")
+            .map(|(_, value)| Assertion::parse(value))
+            .collect::<std::result::Result<_, _>>()?;
+        let query_params: HashMap<_, _> = params.into_iter().collect();
+        let (id, constraints, inside) = parse_constraints(&query_params)?;
+        // Only weaving reads the mode, but a typo in it is caught with the rest.
+        WeaveMode::of(&query_params)?;
+        let raw = query_params
+            .get("raw")
+            .is_some_and(|value| value != "false");
+        let trim = query_params
+            .get("trim")
+            .map(|value| Trim::named(value))
+            .transpose()?;
+        if raw && trim.is_some() {
+            return Err(BlockError::RawTrimmed);
+        }
+
+        Ok(Block {
+            path: Utf8PathBuf::from(path_str),
+            id,
+            constraints,
+            inside,
+            base64: is_base64(&query_params, &content)?,
+            content,
+            origin: None,
+            skip: is_skipped(code, &query_params),
+            append: match query_params.get("dup").map(AsRef::as_ref) {
+                None => false,
+                Some("append") => true,
+                Some(value) => return Err(BlockError::UnknownDup(value.to_string())),
+            },
+            lang: block_language(code, &query_params),
+            expand: !raw
+                && query_params
+                    .get("expand")
+                    .is_none_or(|value| value != "false"),
+            include: query_params
+                .get("include")
+                .map(|document| format!("{document}#{}", parsed.fragment().unwrap_or_default())),
+            label: url_label(&parsed),
+            from: query_params
+                .get("from")
+                .map(|file| Utf8PathBuf::from(file.as_ref())),
+            lines: parse_lines(&query_params)?,
+            mode: parse_mode(&query_params)?,
+            sep: query_params
+                .get("sep")
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| BlockError::InvalidSeparator(value.to_string()))
+                })
+                .transpose()?,
+            encoding: query_params
+                .get("encoding")
+                .and_then(|name| TextEncoding::named(name)),
+            trim,
+            raw,
+            assertions,
+        })
+    }
+}
+
+/// Whether the fence disables its block with `?skip` or a `no-tangle` meta word
+fn is_skipped(code: &Code, params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>) -> bool {
+    let skip_param = params.get("skip").is_some_and(|value| value != "false");
+    let no_tangle = code
+        .meta
+        .as_deref()
+        .is_some_and(|meta| meta.split_whitespace().any(|word| word == "no-tangle"));
+    skip_param || no_tangle
+}
+
+/// Device names Windows reserves in every directory, with or without an extension
+const RESERVED_WINDOWS_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reject destination paths that could not be checked out on Windows
+fn check_portable(path: &str) -> std::result::Result<(), BlockError> {
+    for component in path.split('/') {
+        let stem = component.split('.').next().unwrap_or_default();
+        let reason = if RESERVED_WINDOWS_NAMES
+            .iter()
+            .any(|name| stem.eq_ignore_ascii_case(name))
+        {
+            "reserved device name"
+        } else if component.contains('\\') {
+            "contains a backslash, which Windows reads as a separator; separate directories with /"
+        } else if is_drive(component) {
+            "names a drive; destinations are relative to the output directory"
+        } else if component.ends_with(['.', ' ']) && component != "." && component != ".." {
+            "ends with a dot or space"
+        } else if component.contains(['<', '>', ':', '"', '|', '?', '*']) {
+            "contains a character Windows forbids (<>:\"|?*)"
+        } else if component.chars().any(|c| c < ' ') {
+            "contains a control character"
+        } else {
+            continue;
+        };
+        return Err(BlockError::NonPortablePath {
+            component: component.to_string(),
+            reason,
+        });
+    }
+    Ok(())
+}
+
+/// Whether a path component is a Windows drive, like `C:`
+fn is_drive(component: &str) -> bool {
+    let mut chars = component.chars();
+    matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), None) if letter.is_ascii_alphabetic()
+    )
+}
+
+type ParsedConstraints = (Option<BlockId>, Vec<Constraint>, Option<BlockId>);
+
+fn parse_constraints(
+    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+) -> std::result::Result<ParsedConstraints, BlockError> {
+    let mut id = None;
+    let mut constraints = Vec::new();
+    let mut inside = None;
+
+    for (key, value) in params {
+        match key.as_ref() {
+            "id" => id = Some(BlockId::new(value.to_string())?),
+            "after" => {
+                let ids = value
+                    .split(',')
+                    .map(|s| BlockId::new(s.trim().to_string()))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                constraints.push(Constraint::After(ids));
+            }
+            "before" => {
+                let ids = value
+                    .split(',')
+                    .map(|s| BlockId::new(s.trim().to_string()))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                constraints.push(Constraint::Before(ids));
+            }
+            "first" => constraints.push(Constraint::First),
+            "last" => constraints.push(Constraint::Last),
+            "inside" => {
+                inside = Some(BlockId::new(value.to_string())?);
+            }
+            _ => {} // Ignore unknown parameters
+        }
+    }
+
+    Ok((id, constraints, inside))
+}
+
+/// A tangle URL's query parameters, split at `&` and then at the first `=`, with only `%XX` escapes decoded
+fn query_params(url: &Url) -> Vec<(std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>)> {
+    let decode = |text| percent_decode_str(text).decode_utf8_lossy();
+    url.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+/// Check that every escape in a tangle URL's query decodes to UTF-8
+fn check_query(url: &Url) -> std::result::Result<(), BlockError> {
+    for param in url.query().unwrap_or_default().split('&') {
+        if percent_decode_str(param).decode_utf8().is_err() {
+            return Err(BlockError::InvalidQueryValue(param.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// The label a tangle URL's fragment gives its block, unless it names an included chunk
+fn url_label(url: &Url) -> Option<String> {
+    if query_params(url)
+        .iter()
+        .any(|(param, _)| param == "include")
+    {
+        return None;
+    }
+    let fragment = url.fragment().filter(|fragment| !fragment.is_empty())?;
+    Some(
+        percent_decode_str(fragment)
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+/// The label of the tangle fence whose info string starts at `offset` in `source`
+fn label_at(source: &str, offset: usize) -> Option<String> {
+    let line = source.get(offset..)?.lines().next()?;
+    line.trim_start()
+        .trim_start_matches(['`', '~'])
+        .split_whitespace()
+        .filter_map(|word| Url::parse(word).ok())
+        .find(|url| url.scheme() == "tangle")
+        .as_ref()
+        .and_then(url_label)
+}
+
+impl Block {
+    /// The block, then a copy of it for each further target its fence lists in `?also=`
+    fn with_also(self, code: &Code) -> std::result::Result<Vec<Block>, BlockError> {
+        let mut blocks = vec![self];
+        let Some(url) = tangle_url(code) else {
+            return Ok(blocks);
+        };
+        for (_, paths) in query_params(&url)
+            .into_iter()
+            .filter(|(key, _)| key == "also")
+        {
+            for path in paths
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+            {
+                if !inside_output(Utf8Path::new(path)) {
+                    return Err(BlockError::InvalidPath);
+                }
+                check_portable(path)?;
+                if blocks.iter().any(|block| block.path == path) {
+                    continue;
+                }
+                let copy = blocks.first().map(|block| Block {
+                    path: Utf8PathBuf::from(path),
+                    ..block.clone()
+                });
+                blocks.extend(copy);
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+/// One check on a destination file once it is assembled (see `lit/assert.md`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assertion {
+    /// The file contains the string
+    Contains(String),
+    /// The file does not contain the string
+    NotContains(String),
+    /// The regular expression matches somewhere in the file
+    Matches(String),
+    /// The file has at least this many lines
+    MinLines(usize),
+    /// The file has at most this many lines
+    MaxLines(usize),
+}
+
+impl Assertion {
+    /// The assertion an `?assert=kind:value` parameter declares
+    fn parse(value: &str) -> std::result::Result<Self, BlockError> {
+        let invalid = || BlockError::InvalidAssertion(value.to_string());
+        let (kind, argument) = value.split_once(':').ok_or_else(invalid)?;
+        let count = || argument.parse().map_err(|_| invalid());
+        Ok(match kind {
+            "contains" => Assertion::Contains(argument.to_string()),
+            "not-contains" => Assertion::NotContains(argument.to_string()),
+            "matches" if Regex::new(argument).is_ok() => Assertion::Matches(argument.to_string()),
+            "min-lines" => Assertion::MinLines(count()?),
+            "max-lines" => Assertion::MaxLines(count()?),
+            _ => return Err(invalid()),
+        })
+    }
+
+    /// How `content` fails the assertion, if it does
+    fn failure(&self, content: &str) -> Option<String> {
+        let lines = content.lines().count();
+        match self {
+            Assertion::Contains(needle) => {
+                (!content.contains(needle.as_str())).then(|| format!("does not contain {needle:?}"))
+            }
+            Assertion::NotContains(needle) => content
+                .contains(needle.as_str())
+                .then(|| format!("contains {needle:?}")),
+            // The config and fences reject a pattern that does not compile, so only a hand-built one can match nothing here.
+            Assertion::Matches(pattern) => (!Regex::new(pattern)
+                .is_ok_and(|regex| regex.is_match(content)))
+            .then(|| format!("does not match /{pattern}/")),
+            Assertion::MinLines(min) => {
+                (lines < *min).then(|| format!("has {lines} lines, fewer than {min}"))
+            }
+            Assertion::MaxLines(max) => {
+                (lines > *max).then(|| format!("has {lines} lines, more than {max}"))
+            }
+        }
+    }
+}
+
+/// `$NAME` and `${NAME}` in a tangle path, expanded with `--env`
+static ENV_PATH_VARIABLE: LazyLock<Regex> = LazyLock::new(|| {
+    // The pattern is a compile-time literal, so compilation cannot fail.
+    #[allow(clippy::unwrap_used)]
+    Regex::new(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))").unwrap()
+});
+
+/// `{{env.NAME}}` in a path or a block's content, expanded with `--env`
+static ENV_CONTENT_VARIABLE: LazyLock<Regex> = LazyLock::new(|| {
+    // The pattern is a compile-time literal, so compilation cannot fail.
+    #[allow(clippy::unwrap_used)]
+    Regex::new(r"\{\{env\.([A-Za-z_][A-Za-z0-9_]*)\}\}").unwrap()
+});
+
+/// `text` with each match of `pattern` replaced by the environment variable it names
+fn substitute_env(text: &str, pattern: &Regex) -> std::result::Result<String, BlockError> {
+    let mut substituted = String::new();
+    let mut rest = 0;
+    for captures in pattern.captures_iter(text) {
+        let (Some(whole), Some(name)) =
+            (captures.get(0), captures.get(1).or_else(|| captures.get(2)))
+        else {
+            continue;
+        };
+        let value = std::env::var(name.as_str())
+            .map_err(|_| BlockError::UnsetEnv(whole.as_str().to_string()))?;
+        substituted.push_str(text.get(rest..whole.start()).unwrap_or_default());
+        substituted.push_str(&value);
+        rest = whole.end();
+    }
+    substituted.push_str(text.get(rest..).unwrap_or_default());
+    Ok(substituted)
+}
+
+impl Config {
+    /// A tangle path with its environment variables expanded, with `--env`
+    fn expand_env_path(&self, path: String) -> std::result::Result<String, BlockError> {
+        if !self.env {
+            return Ok(path);
+        }
+        substitute_env(
+            &substitute_env(&path, &ENV_CONTENT_VARIABLE)?,
+            &ENV_PATH_VARIABLE,
+        )
+    }
+
+    /// Fill in a block's `{{env.NAME}}` placeholders, with `--env`, unless it is written verbatim
+    fn expand_env(&self, block: &mut Block) -> std::result::Result<(), BlockError> {
+        if self.env && block.expand && !block.raw && !block.base64 {
+            block.content = substitute_env(&block.content, &ENV_CONTENT_VARIABLE)?;
+        }
+        Ok(())
+    }
+}
+
+/// What `expect` files say the tangled tree holds
+#[derive(Debug, Default)]
+struct ExpectedTargets {
+    /// Targets that must be tangled
+    required: BTreeSet<Utf8PathBuf>,
+    /// Globs for targets that may be tangled
+    allowed: Vec<TargetGlob>,
+    /// Whether targets not required or allowed are errors, as they are once there is a list
+    closed: bool,
+}
+
+impl ExpectedTargets {
+    /// Add what the `expect` file at `path` says, reading it by its name
+    fn read(&mut self, path: &Utf8Path) -> Result<()> {
+        let text = fs::read_to_string(path)?;
+        let invalid = |message: String| LitError::InvalidExpect {
+            path: path.to_owned(),
+            message,
+        };
+        let mut entries: Vec<&str> = Vec::new();
+        let (cargo, npm);
+        match path.file_name().unwrap_or_default() {
+            "Cargo.toml" => {
+                cargo = toml::from_str::<toml::Table>(&text)
+                    .map_err(|e| invalid(e.message().to_string()))?;
+                let manifest = &cargo;
+                entries.extend(
+                    manifest
+                        .get("lib")
+                        .and_then(|lib| lib.get("path"))
+                        .and_then(toml::Value::as_str),
+                );
+                for kind in ["bin", "test", "bench", "example"] {
+                    let targets = manifest
+                        .get(kind)
+                        .and_then(toml::Value::as_array)
+                        .into_iter()
+                        .flatten();
+                    entries.extend(targets.filter_map(|target| target.get("path")?.as_str()));
+                }
+                let build = manifest
+                    .get("package")
+                    .and_then(|package| package.get("build"));
+                entries.extend(build.and_then(toml::Value::as_str));
+            }
+            "package.json" => {
+                npm = serde_json::from_str::<serde_json::Value>(&text)
+                    .map_err(|e| invalid(e.to_string()))?;
+                let manifest = &npm;
+                for key in ["main", "module", "types"] {
+                    entries.extend(manifest.get(key).and_then(serde_json::Value::as_str));
+                }
+                match manifest.get("bin") {
+                    Some(serde_json::Value::String(bin)) => entries.push(bin.as_str()),
+                    Some(serde_json::Value::Object(bins)) => {
+                        entries.extend(bins.values().filter_map(serde_json::Value::as_str))
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                self.closed = true;
+                for line in text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                {
+                    if line.contains(['*', '?', '[']) {
+                        self.allowed.push(TargetGlob::new(line)?);
+                    } else {
+                        entries.push(line);
+                    }
+                }
+            }
+        }
+        self.required.extend(
+            entries
+                .into_iter()
+                .map(|entry| normalize(Utf8Path::new(entry.trim_start_matches("./")))),
+        );
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Check the tangled destinations against the `expect` files
+    pub fn check_expected<'a>(
+        &self,
+        tangled: impl IntoIterator<Item = &'a Utf8Path>,
+    ) -> Result<()> {
+        if self.expect.is_empty() {
+            return Ok(());
+        }
+        let mut expected = ExpectedTargets::default();
+        for path in &self.expect {
+            expected.read(path)?;
+        }
+        let tangled: BTreeSet<&Utf8Path> = tangled.into_iter().collect();
+        let missing: Vec<Utf8PathBuf> = expected
+            .required
+            .iter()
+            .filter(|path| !tangled.contains(path.as_path()))
+            .cloned()
+            .collect();
+        let unexpected: Vec<Utf8PathBuf> = tangled
+            .into_iter()
+            .filter(|path| {
+                expected.closed
+                    && !expected.required.contains(*path)
+                    && !expected.allowed.iter().any(|glob| glob.matches(path))
+            })
+            .map(Utf8Path::to_path_buf)
+            .collect();
+        if missing.is_empty() && unexpected.is_empty() {
+            return Ok(());
+        }
+        let list = |paths: &[Utf8PathBuf]| {
+            paths
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut differences = Vec::new();
+        if !missing.is_empty() {
+            differences.push(format!("missing {}", list(&missing)));
+        }
+        if !unexpected.is_empty() {
+            differences.push(format!("not expected {}", list(&unexpected)));
+        }
+        Err(LitError::UnexpectedTargets {
+            differences: differences.join("; "),
+            missing,
+            unexpected,
+        })
+    }
+}
+
+/// Query parameters in the order `lit fmt` writes them; others follow
+const PARAM_ORDER: &[&str] = &[
+    "id", "first", "last", "after", "before", "inside", "allow", "also", "assert", "chmod", "dup",
+    "encoding", "expand", "from", "include", "lang", "lines", "raw", "sep", "skip", "trim",
+    "weave",
+];
+
+/// A tangle URL word with its query parameters in canonical order
+fn canonical_url(word: &str) -> String {
+    let Some((path, query)) = word.split_once('?') else {
+        return word.to_string();
+    };
+    // A fragment belongs at the end, whichever parameter it was written after.
+    let (query, fragment) = match query.split_once('#') {
+        Some((query, fragment)) => (query, format!("#{fragment}")),
+        None => (query, String::new()),
+    };
+    let rank = |param: &str| {
+        let key = param.split('=').next().unwrap_or_default();
+        PARAM_ORDER
+            .iter()
+            .position(|known| *known == key)
+            .unwrap_or(PARAM_ORDER.len())
+    };
+    let mut params: Vec<_> = query.split('&').filter(|param| !param.is_empty()).collect();
+    params.sort_by_key(|param| rank(param));
+    if params.is_empty() {
+        format!("{path}{fragment}")
+    } else {
+        format!("{path}?{}{fragment}", params.join("&"))
+    }
+}
+
+/// Normalize the tangle fences in a markdown document
+pub fn format_markdown(markdown: &str) -> Result<String> {
+    let ast = to_mdast(markdown, &ParseOptions::default())
+        .map_err(|e| LitError::Markdown(e.to_string()))?;
+    let Node::Root(root) = ast else {
+        return Err(LitError::NotRoot); // cov-excl-line: unreachable — to_mdast always returns Root
+    };
+
+    let mut formatted = String::new();
+    let mut copied = 0;
+    for node in &root.children {
+        let (Node::Code(code), Some(position)) = (node, node.position()) else {
+            continue;
+        };
+        if tangle_url(code).is_none() {
+            continue;
+        }
+        let (start, end) = (position.start.offset, position.end.offset);
+        formatted.push_str(markdown.get(copied..start).unwrap_or_default());
+        formatted.push_str(&format_fence(markdown.get(start..end).unwrap_or_default()));
+        copied = end;
+    }
+    formatted.push_str(markdown.get(copied..).unwrap_or_default());
+
+    Ok(formatted)
+}
+
+/// Normalize one fenced tangle block, from its opening fence to its closing one
+fn format_fence(fence: &str) -> String {
+    let mut lines: Vec<&str> = fence.split('\n').collect();
+    let opening = lines.first().copied().unwrap_or_default();
+    let indent = opening.len().saturating_sub(opening.trim_start().len());
+    let (indentation, rest) = opening.split_at(indent);
+    let marker_char = rest.chars().next().unwrap_or('`');
+    let marker_len = rest
+        .len()
+        .saturating_sub(rest.trim_start_matches(marker_char).len());
+    let (marker, info) = rest.split_at(marker_len);
+
+    // The last line closes the fence when it is a long-enough run of the marker.
+    let closing = match lines.as_slice() {
+        [_, .., last] if is_closing_fence(last, marker_char, marker_len) => lines.pop(),
+        _ => None,
+    };
+
+    let mut raw = false;
+    let info = info
+        .split(' ')
+        .map(|word| match Url::parse(word) {
+            Ok(url) if url.scheme() == "tangle" => {
+                raw |= url
+                    .query_pairs()
+                    .any(|(key, value)| key == "raw" && value != "false");
+                canonical_url(word)
+            }
+            _ => word.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut formatted = format!("{indentation}{marker}{info}");
+    for line in lines.iter().skip(1) {
+        formatted.push('\n');
+        formatted.push_str(if raw {
+            line
+        } else {
+            line.trim_end_matches([' ', '\t'])
+        });
+    }
+    if let Some(closing) = closing {
+        let closing_indent = closing.len().saturating_sub(closing.trim_start().len());
+        formatted.push('\n');
+        formatted.push_str(closing.get(..closing_indent).unwrap_or_default());
+        formatted.push_str(marker);
+    }
+    formatted
+}
+
+/// Whether `line` closes a fence opened with `len` × `marker`
+fn is_closing_fence(line: &str, marker: char, len: usize) -> bool {
+    let run = line.trim();
+    run.len() >= len && run.chars().all(|c| c == marker)
+}
+
+/// Line comment delimiters (open, close) for common destination extensions
+const COMMENT_SYNTAX: &[(&str, &str, &str)] = &[
+    ("c", "// ", ""),
+    ("cpp", "// ", ""),
+    ("css", "/* ", " */"),
+    ("go", "// ", ""),
+    ("h", "// ", ""),
+    ("html", "<!-- ", " -->"),
+    ("java", "// ", ""),
+    ("js", "// ", ""),
+    ("lua", "-- ", ""),
+    ("md", "<!-- ", " -->"),
+    ("py", "# ", ""),
+    ("rb", "# ", ""),
+    ("rs", "// ", ""),
+    ("sh", "# ", ""),
+    ("sql", "-- ", ""),
+    ("toml", "# ", ""),
+    ("ts", "// ", ""),
+    ("yaml", "# ", ""),
+    ("yml", "# ", ""),
+];
+
+/// Prepend a generated-file header to `content`, if `file`'s extension can carry one
+pub fn with_header(file: &TangledFile, content: &str) -> String {
+    let extension = file.path.extension().unwrap_or_default();
+    let Some((_, open, close)) = COMMENT_SYNTAX.iter().find(|(ext, _, _)| *ext == extension) else {
+        return content.to_string();
+    };
+
+    let mut sources = Vec::new();
+    for origin in &file.origins {
+        if !sources.contains(&origin.file.as_str()) {
+            sources.push(origin.file.as_str());
+        }
+    }
+    let header = format!(
+        "{open}Generated by lit {} from {}. Edit those instead.{close}\n{open}sha256:{}{close}\n",
+        env!("CARGO_PKG_VERSION"),
+        sources.join(", "),
+        content_hash(content),
+    );
+
+    match content
+        .strip_prefix("#!")
+        .and_then(|_| content.split_once('\n'))
+    {
+        Some((shebang, rest)) => format!("{shebang}\n{header}{rest}"),
+        None => format!("{header}{content}"),
+    }
+}
+
+/// The hex-encoded SHA-256 of `content`
+fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Commands to run around a tangle
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Shell commands run, in order, after every successful tangle
+    pub post_tangle: Vec<String>,
+}
+
+const SCAFFOLD_CONFIG: &str = r#"# Settings for lit, the literate-programming tool.
+input = "docs"
+output = "out"
+"#;
+
+const SCAFFOLD_EXAMPLE: &str = r#"# Hello, lit
+
+This document is a literate program. Lit reads the code blocks whose info
+string holds a `tangle:///` URL and writes them to the file that URL names,
+under the output directory.
+
+The program prints a greeting. `main` is declared first in this document, but
+`?last` places it at the end of the file:
+
+```rust tangle:///src/main.rs?id=main&last
+fn main() {
+    println!("{}", greeting());
+}
+```
+
+The greeting itself comes before `main`:
+
+```rust tangle:///src/main.rs?id=greeting&before=main
+fn greeting() -> &'static str {
+    "Hello, lit!"
+}
+```
+
+Run `lit` in the project directory, then look at `out/src/main.rs`.
+"#;
+
+const SCAFFOLD_GITIGNORE: &str = "/out/\n";
+
+/// Create a starter literate project in `root`, returning the files created
+pub fn init(root: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let templates = [
+        ("lit.toml", SCAFFOLD_CONFIG),
+        ("docs/hello.md", SCAFFOLD_EXAMPLE),
+        (".gitignore", SCAFFOLD_GITIGNORE),
+    ];
+
+    let mut created = Vec::new();
+    for (name, content) in templates {
+        let path = root.join(name);
+        if path.exists() {
+            debug!("Keeping existing {path}");
+            continue;
+        }
+        // Every template name has a parent (at least `root`), so this cannot fail.
+        #[allow(clippy::unwrap_used)]
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, content)?;
+        created.push(path);
+    }
+
+    Ok(created)
+}
+
+/// Ceilings on the sizes a tangle may produce, from `[limits]` in `lit.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest code a block may have, in bytes
+    pub max_block_bytes: Option<usize>,
+    /// Largest a tangled file may be, in bytes
+    pub max_file_bytes: Option<usize>,
+    /// Largest the tangled files may be together, in bytes
+    pub max_output_bytes: Option<usize>,
+}
+
+impl Limits {
+    /// Check a block's code against `max_block_bytes`
+    pub fn check_block(&self, block: &Block) -> std::result::Result<(), BlockError> {
+        match self.max_block_bytes {
+            Some(limit) if block.content.len() > limit => Err(BlockError::BlockTooLarge {
+                size: block.content.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a rendered file against `max_file_bytes`
+    pub fn check_file(&self, path: &Utf8Path, content: &str) -> Result<()> {
+        match self.max_file_bytes {
+            Some(limit) if content.len() > limit => Err(LitError::FileTooLarge {
+                path: path.to_owned(),
+                size: content.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the rendered files' total size against `max_output_bytes`
+    pub fn check_output(&self, size: usize) -> Result<()> {
+        match self.max_output_bytes {
+            Some(limit) if size > limit => Err(LitError::OutputTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lit {
+    pub inputs: Vec<Utf8PathBuf>,
+    pub output: Utf8PathBuf,
+    pub config: Config,
+    /// Leave the run's scratch directory in place, for debugging
+    pub keep_temp: bool,
+    /// Overwrite existing files lit did not generate (see `lit/manifest.md`)
+    pub force: bool,
+    /// Fail on fences that look like tangle blocks but are not tangled
+    pub strict: bool,
+    /// Warn about documents that cannot be read, and go on without them, instead of failing
+    pub skip_invalid: bool,
+    /// Write only destinations matching one of these, or all when empty (see `lit/targets.md`)
+    pub targets: Vec<TargetGlob>,
+    /// Documents parsed by earlier runs, reused while their text is unchanged (see `lit/daemon.md`)
+    pub cache: Option<ParseCache>,
+    /// The warnings and notes reported while reading and tangling (see `lit/diagnostics.md`)
+    pub diagnostics: Diagnostics,
+}
+
+impl Lit {
+    pub fn new(input: Utf8PathBuf, output: Utf8PathBuf) -> Self {
+        Lit {
+            inputs: vec![input],
+            output,
+            config: Config::default(),
+            keep_temp: false,
+            force: false,
+            strict: false,
+            skip_invalid: false,
+            targets: Vec::new(),
+            cache: None,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Tangle every destination into the archive at `path` instead of the output directory
+    pub fn tangle_to_archive(&self, path: &Utf8Path) -> Result<TangleReport> {
+        let format = ArchiveFormat::of(path)?;
+        let mut report = TangleReport::default();
+        let entries = self.archive_entries(&mut report)?;
+        let parsed = Instant::now();
+
+        debug!("Writing {} files to {path}", entries.len());
+        let archive = fs::File::create(path)?;
+        match format {
+            ArchiveFormat::Tar => {
+                write_tar(archive, &entries)?;
+            }
+            ArchiveFormat::TarGz => {
+                let encoder =
+                    flate2::write::GzEncoder::new(archive, flate2::Compression::default());
+                write_tar(encoder, &entries)?.finish()?;
+            }
+            ArchiveFormat::Zip => write_zip(archive, &entries).map_err(|e| LitError::Archive {
+                path: path.to_owned(),
+                message: e.to_string(),
+            })?,
+        }
+
+        report.timings.push(("write", parsed.elapsed()));
+        report.peak_memory = peak_memory();
+        Ok(report)
+    }
+
+    /// Every destination `--target` selects, as `(path, mode, bytes)` archive entries, each reported as written
+    fn archive_entries(&self, report: &mut TangleReport) -> Result<ArchiveEntries> {
+        let rendered = self.render_all_timed(report)?;
+        let mut entries = Vec::new();
+        let mut tangled = Vec::new();
+        for (file, content) in rendered {
+            if !self.is_target(&file.path) {
+                continue;
+            }
+            let content = file.bytes(content);
+            report.files.push(FileReport {
+                path: file.path.clone(),
+                bytes: content.len(),
+                blocks: file.block_count,
+                status: WriteStatus::Written,
+            });
+            entries.push((file.path.clone(), file.mode.unwrap_or(0o644), content));
+            tangled.push(file);
+        }
+        for asset in self.assets(&tangled)? {
+            let content = fs::read(&asset.source)?;
+            report.files.push(FileReport {
+                path: asset.path.clone(),
+                bytes: content.len(),
+                blocks: 0,
+                status: WriteStatus::Written,
+            });
+            entries.push((asset.path, file_mode(&asset.source)?, content));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        self.warn_unmatched_targets(&tangled);
+        self.diagnostics.check()?;
+        Ok(entries)
+    }
+
+    /// The assets `[assets]` brings into the output beside the `tangled` files, as `--target` selects them
+    fn assets(&self, tangled: &[TangledFile]) -> Result<Vec<Asset>> {
+        if self.config.assets.include.is_empty() {
+            return Ok(Vec::new());
+        }
+        let globs = self
+            .config
+            .assets
+            .include
+            .iter()
+            .map(|glob| TargetGlob::new(glob))
+            .collect::<Result<Vec<_>>>()?;
+        let mut assets: Vec<Asset> = Vec::new();
+        let mut ignore = IgnoreRules::default();
+        for input in &self.inputs {
+            for entry in WalkDir::new(input)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| {
+                    entry.path() != self.output.as_std_path() && !ignore.skip(entry)
+                })
+                .filter_map(|e| e.ok())
+                .filter(|entry| entry.file_type().is_file())
+            {
+                let source = Utf8PathBuf::try_from(entry.into_path())
+                    .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+                // An input that is a file is a document, not a directory of assets.
+                let Some(path) = source
+                    .strip_prefix(input)
+                    .ok()
+                    .filter(|path| !path.as_str().is_empty())
+                else {
+                    continue;
+                };
+                let document = is_markdown(&source)
+                    || is_rst(&source)
+                    || self.config.frontend(&source).is_some();
+                if document || source.file_name() == Some(IGNORE_NAME) {
+                    continue;
+                }
+                let path = path.to_owned();
+                if !globs.iter().any(|glob| glob.matches(&path))
+                    || !self.is_target(&path)
+                    || assets.iter().any(|asset| asset.path == path)
+                {
+                    continue;
+                }
+                if tangled.iter().any(|file| file.path == path) {
+                    return Err(LitError::AssetConflict { path });
+                }
+                assets.push(Asset { source, path });
+            }
+        }
+        Ok(assets)
+    }
+
+    /// Each asset with what writing it would do, checked against the tangled files and the recorded manifest
+    fn check_assets(
+        &self,
+        tangled: &[TangledFile],
+        recorded: &Manifest,
+    ) -> Result<Vec<(Asset, WriteStatus)>> {
+        let mut checked = Vec::new();
+        for asset in self.assets(tangled)? {
+            if let Some(link) =
+                symlink_under(&self.output, &asset.path).filter(|_| self.config.sandbox)
+            {
+                return Err(LitError::Sandboxed {
+                    what: format!("writing {} through the symlink {link}", asset.path),
+                });
+            }
+            let status = match fs::read(self.output.join(&asset.path)) {
+                Ok(existing) if existing == fs::read(&asset.source)? => WriteStatus::Unchanged,
+                Ok(_) if !self.force && !recorded.files.contains(&asset.path) => {
+                    return Err(LitError::WouldOverwrite { path: asset.path });
+                }
+                _ => WriteStatus::Written,
+            };
+            checked.push((asset, status));
+        }
+        Ok(checked)
+    }
+
+    /// Copy or hard-link each asset that changed into the output
+    fn place_assets(&self, assets: &[(Asset, WriteStatus)]) -> Result<()> {
+        for (asset, _) in assets
+            .iter()
+            .filter(|(_, status)| *status == WriteStatus::Written)
+        {
+            let destination = self.output.join(&asset.path);
+            // `destination` is the output directory joined with a relative path, so it has a parent.
+            #[allow(clippy::unwrap_used)]
+            fs::create_dir_all(destination.parent().unwrap())?;
+            if destination.is_file() {
+                fs::remove_file(&destination)?;
+            }
+            if self.config.assets.link {
+                match fs::hard_link(&asset.source, &destination) {
+                    Ok(()) => continue,
+                    Err(e) => self.diagnostics.note(
+                        "asset-copied",
+                        format!("Copying {}, since it cannot be linked: {e}", asset.source),
+                    ),
+                }
+            }
+            fs::copy(&asset.source, &destination)?;
+        }
+        Ok(())
+    }
+
+    /// Where line `line` (1-based) of `target`, as tangled, came from
+    pub fn blame(&self, target: &Utf8Path, line: usize) -> Result<Blame> {
+        let file = self.target_file(target)?;
+        line.checked_sub(self.header_lines(&file))
+            .and_then(|line| file.blame(line))
+            .ok_or_else(|| LitError::NotFromBlock {
+                path: target.to_owned(),
+                line,
+            })
+    }
+
+    /// How many lines the generated-file header adds to the top of `file`
+    fn header_lines(&self, file: &TangledFile) -> usize {
+        self.render(file)
+            .lines()
+            .count()
+            .saturating_sub(file.render().lines().count())
+    }
+
+    /// Write every destination to `out` as a `lit-bundle`, instead of to the output directory
+    pub fn tangle_to_bundle(&self, out: &mut impl std::io::Write) -> Result<TangleReport> {
+        let mut report = TangleReport::default();
+        let entries = self.archive_entries(&mut report)?;
+        let rendered = Instant::now();
+        out.write_all(b"lit-bundle 1\n")?;
+        for (path, mode, content) in &entries {
+            out.write_all(format!("lit-file {mode:o} {} {path}\n", content.len()).as_bytes())?;
+            out.write_all(content)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        report.timings.push(("write", rendered.elapsed()));
+        report.peak_memory = peak_memory();
+        Ok(report)
+    }
+
+    /// Report what tangling would write, without writing anything
+    pub fn tangle_dry_run(&self) -> Result<TangleReport> {
+        let mut report = TangleReport::default();
+        let rendered = self.render_all_timed(&mut report)?;
+        let recorded = Manifest::load(&self.output)?;
+        let mut tangled = Vec::new();
+        for (file, content) in rendered {
+            if !self.is_target(&file.path) {
+                continue;
+            }
+            let content = file.bytes(content);
+            let status = match fs::read(self.output.join(&file.path)) {
+                Ok(existing) if existing == content => WriteStatus::Unchanged,
+                Ok(existing)
+                    if !self.force
+                        && !recorded.files.contains(&file.path)
+                        && !has_lit_header(&existing) =>
+                {
+                    return Err(LitError::WouldOverwrite { path: file.path });
+                }
+                _ => WriteStatus::Written,
+            };
+            report.files.push(FileReport {
+                path: file.path.clone(),
+                bytes: content.len(),
+                blocks: file.block_count,
+                status,
+            });
+            tangled.push(file);
+        }
+        for (asset, status) in self.check_assets(&tangled, &recorded)? {
+            report.files.push(asset.report(status)?);
+        }
+        self.warn_unmatched_targets(&tangled);
+        self.diagnostics.check()?;
+        report.peak_memory = peak_memory();
+        Ok(report)
+    }
+
+    /// A document's blocks, from the cache if its text has not changed since it was parsed
+    fn parse_document(&self, path: &Utf8Path, content: &str) -> Result<Vec<Block>> {
+        if let Some(blocks) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(path, content))
+        {
+            return Ok(blocks);
+        }
+        let blocks = match self.config.frontend(path) {
+            Some((name, plugin)) => Self::parse_nodes(
+                path,
+                content,
+                plugin_fences(name, plugin, path, content)?,
+                self.strict,
+                &self.diagnostics,
+            )?,
+            None => Self::parse_source(path, content, self.strict, &self.diagnostics)?,
+        };
+        if let Some(cache) = &self.cache {
+            cache.insert(path.to_owned(), content.to_string(), blocks.clone());
+        }
+        Ok(blocks)
+    }
+
+    /// Groups of blocks with at least `min_lines` non-blank lines that are identical, or `similarity` alike
+    pub fn duplicates(&self, min_lines: usize, similarity: f32) -> Result<Vec<Duplicates>> {
+        let blocks = self.blocks()?;
+        let mut groups: Vec<(String, Vec<&Block>, bool)> = Vec::new();
+        let mut exact = HashMap::<String, usize>::new();
+        // A fence with `?also=` is one block, however many targets it is copied to.
+        let mut fences = HashSet::new();
+        for block in blocks
+            .iter()
+            .filter(|block| !block.skip && block.include.is_none())
+        {
+            let Some(origin) = &block.origin else {
+                continue;
+            };
+            if !fences.insert((&origin.file, origin.offset)) {
+                continue;
+            }
+            let normalized = normalized_content(&block.content);
+            if normalized.lines().count() < min_lines.max(1) {
+                continue;
+            }
+            match exact.get(&normalized) {
+                Some(&index) => {
+                    if let Some((_, members, _)) = groups.get_mut(index) {
+                        members.push(block);
+                    }
+                }
+                None => {
+                    exact.insert(normalized.clone(), groups.len());
+                    groups.push((normalized, vec![block], true));
+                }
+            }
+        }
+
+        // Fold each group into the first earlier one it is alike enough to.
+        let mut clusters: Vec<(String, Vec<&Block>, bool)> = Vec::new();
+        for (normalized, members, _) in groups {
+            let lines = normalized.lines().count();
+            let alike = clusters.iter_mut().find(|(first, _, _)| {
+                // The ratio cannot beat twice the shorter text's share of both, so most pairs need no diff.
+                let theirs = first.lines().count();
+                let bound =
+                    2.0 * lines.min(theirs) as f32 / lines.saturating_add(theirs).max(1) as f32;
+                bound >= similarity
+                    && similar::TextDiff::from_lines(first.as_str(), normalized.as_str()).ratio()
+                        >= similarity
+            });
+            match alike {
+                Some((_, cluster, identical)) => {
+                    cluster.extend(members);
+                    *identical = false;
+                }
+                None => clusters.push((normalized, members, true)),
+            }
+        }
+
+        let mut duplicates: Vec<Duplicates> = clusters
+            .into_iter()
+            .filter(|(_, members, _)| members.len() > 1)
+            .map(|(normalized, mut members, identical)| {
+                members.sort_by(|a, b| {
+                    let place = |block: &Block| {
+                        block
+                            .origin
+                            .as_ref()
+                            .map(|origin| (origin.file.clone(), origin.offset))
+                    };
+                    place(a).cmp(&place(b))
+                });
+                let count = |block: &&Block| normalized_content(&block.content).lines().count();
+                Duplicates {
+                    blocks: members
+                        .iter()
+                        .filter_map(|block| {
+                            Some((
+                                block.origin.clone()?,
+                                block.path.clone(),
+                                block.id.as_ref().map(ToString::to_string),
+                            ))
+                        })
+                        .collect(),
+                    lines: normalized.lines().count(),
+                    identical,
+                    savings: members.iter().skip(1).map(count).sum(),
+                }
+            })
+            .collect();
+        duplicates.sort_by(|a, b| {
+            let first = |group: &Duplicates| {
+                group
+                    .blocks
+                    .first()
+                    .map(|(origin, _, _)| (origin.file.clone(), origin.offset))
+            };
+            b.savings
+                .cmp(&a.savings)
+                .then_with(|| first(a).cmp(&first(b)))
+        });
+        Ok(duplicates)
+    }
+
+    /// Format the markdown inputs in place, or only report them with `check`
+    pub fn format(&self, check: bool) -> Result<Vec<Utf8PathBuf>> {
+        let mut changed = Vec::new();
+        for path in self
+            .markdown_files()?
+            .into_iter()
+            .filter(|path| is_markdown(path))
+        {
+            let content = fs::read_to_string(&path)?;
+            let formatted = format_markdown(&content)?;
+            if formatted != content {
+                if !check {
+                    fs::write(&path, &formatted)?;
+                }
+                changed.push(path);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Run the configured post-tangle commands, telling them what changed
+    fn run_post_tangle(&self, report: &TangleReport) -> Result<()> {
+        let changed = report
+            .files
+            .iter()
+            .filter(|file| file.status == WriteStatus::Written)
+            .map(|file| file.path.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        for command in &self.config.hooks.post_tangle {
+            debug!("Running post-tangle hook: {command}");
+            let status = std::process::Command::new("sh")
+                .args(["-c", command])
+                .env("LIT_OUTPUT", &self.output)
+                .env("LIT_CHANGED_FILES", &changed)
+                .status()?;
+            if !status.success() {
+                return Err(LitError::PostTangleFailed {
+                    command: command.clone(),
+                    status: status.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Every block in the inputs, in reading order, with where it is and where it goes
+    pub fn inspect(&self) -> Result<Vec<BlockInfo>> {
+        let (blocks, sources) = self.read_sources()?;
+        let placement = Placement::new(&sources, &self.config)?;
+
+        let mut output_lines = HashMap::new();
+        for file in assemble(blocks.clone(), &sources, &self.config).unwrap_or_default() {
+            let header = self.header_lines(&file);
+            for (block, (start, end)) in file.blocks.iter().zip(file.line_ranges()) {
+                if let Some(origin) = &block.origin {
+                    let range = (start.saturating_add(header), end.saturating_add(header));
+                    output_lines.insert((origin.file.clone(), origin.offset), range);
+                }
+            }
+        }
+
+        Ok(blocks
+            .into_iter()
+            .filter_map(|block| {
+                let origin = block.origin.clone()?;
+                Some(BlockInfo {
+                    target: placement
+                        .place(&block)
+                        .unwrap_or_else(|_| block.path.clone()),
+                    id: block.id.as_ref().map(ToString::to_string),
+                    constraints: block
+                        .constraints
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<BTreeSet<_>>()
+                        .into_iter()
+                        .collect(),
+                    inside: block.inside.as_ref().map(ToString::to_string),
+                    skip: block.skip,
+                    span: (origin.offset, origin.end_offset),
+                    lines: (origin.line, origin.end_line),
+                    output_lines: output_lines
+                        .get(&(origin.file.clone(), origin.offset))
+                        .copied()
+                        .filter(|_| block.inside.is_none()),
+                    file: origin.file,
+                })
+            })
+            .collect())
+    }
+
+    /// Check the inputs against the lint rules
+    pub fn lint(&self) -> Result<Vec<Finding>> {
+        let (blocks, sources) = self.read_sources()?;
+        let mut findings = Vec::new();
+        let mut find = |rule: &'static str, origin: &Origin, message: String| {
+            let severity = self.config.severity(rule);
+            if severity != Severity::Off {
+                findings.push(Finding {
+                    rule,
+                    severity,
+                    file: origin.file.clone(),
+                    line: origin.line,
+                    message,
+                });
+            }
+        };
+
+        let options = ParseOptions {
+            constructs: Constructs {
+                frontmatter: true,
+                ..Constructs::default()
+            },
+            ..ParseOptions::default()
+        };
+        let platforms = match self.config.severity("render-compat") {
+            Severity::Off => Vec::new(),
+            _ => self.platforms(),
+        };
+        let mut repeated = HashSet::new();
+        let mut documents: Vec<_> = sources.iter().collect();
+        documents.sort();
+        for (path, text) in documents.into_iter().filter(|(path, _)| is_markdown(path)) {
+            let Ok(Node::Root(root)) = to_mdast(text, &options) else {
+                continue; // cov-excl-line: unreachable — the source was parsed when it was read
+            };
+            let tangles = blocks
+                .iter()
+                .any(|block| block.origin.as_ref().is_some_and(|o| o.file == *path));
+            for node in &root.children {
+                let Some(origin) = Origin::of(path, node) else {
+                    continue; // cov-excl-line: parsed nodes always carry a position
+                };
+                if !platforms.is_empty() {
+                    for message in render_problems(node, &platforms) {
+                        find("render-compat", &origin, message);
+                    }
+                }
+                let Node::Code(code) = node else {
+                    continue;
+                };
+                match (tangle_url(code), &code.lang) {
+                    (Some(url), _) => {
+                        for (param, value) in query_params(&url) {
+                            if !PARAM_ORDER.contains(&param.as_ref()) {
+                                find(
+                                    "unknown-param",
+                                    &origin,
+                                    format!("unknown query parameter `{param}`"),
+                                );
+                            }
+                            if param == "allow" && value == "duplicate-content" {
+                                repeated.insert((path.clone(), origin.line));
+                            }
+                        }
+                    }
+                    (None, None) => find(
+                        "missing-language",
+                        &origin,
+                        "code fence has no language".to_string(),
+                    ),
+                    (None, Some(lang)) if tangles => {
+                        find(
+                            "untangled-code",
+                            &origin,
+                            format!("`{lang}` fence is not tangled"),
+                        );
+                    }
+                    (None, Some(_)) => {}
+                }
+            }
+        }
+
+        for file in assemble(blocks, &sources, &self.config)? {
+            if let ([block], 0..=1) = (file.blocks.as_slice(), file.render().lines().count())
+                && let Some(origin) = &block.origin
+            {
+                find(
+                    "tiny-target",
+                    origin,
+                    format!("{} is a single line from one block", file.path),
+                );
+            }
+
+            let mut read = file
+                .blocks
+                .iter()
+                .filter_map(|block| Some((block.origin.as_ref()?, block.content.as_str())))
+                .filter(|(_, content)| !content.trim().is_empty())
+                .collect::<Vec<_>>();
+            read.sort_by(|(a, _), (b, _)| (&a.file, a.offset).cmp(&(&b.file, b.offset)));
+            let mut seen = HashMap::<&str, &Origin>::new();
+            for (origin, content) in read {
+                match seen.get(content) {
+                    Some(first) if !repeated.contains(&(origin.file.clone(), origin.line)) => {
+                        let message = format!(
+                            "same content as the block at {}:{}, also for {}",
+                            first.file, first.line, file.path
+                        );
+                        find("duplicate-content", origin, message);
+                    }
+                    Some(_) => {}
+                    None => {
+                        seen.insert(content, origin);
+                    }
+                }
+            }
+        }
+
+        findings.sort_by(|a, b| {
+            (&a.file, a.line, a.rule, &a.message).cmp(&(&b.file, b.line, b.rule, &b.message))
+        });
+        Ok(findings)
+    }
+
+    pub fn tangle(&self) -> Result<TangleReport> {
+        let mut report = TangleReport::default();
+        let rendered = self.render_all_timed(&mut report)?;
+        let parsed = Instant::now();
+        let _lock = OutputLock::acquire(&self.output)?;
+
+        let mut staged = StagedWrites::new(Scratch::new(self.scratch_dir(), self.keep_temp));
+        let mut manifest = Manifest::load(&self.output)?;
+        let recorded = manifest.clone();
+        manifest.format_version = MANIFEST_FORMAT_VERSION;
+        let mut tangled = Vec::new();
+        let mut writes = Vec::new();
+        let mut outputs = Vec::new();
+        let backends = self.config.plugins.values().any(|plugin| plugin.output);
+        for (file, content) in rendered {
+            if !self.is_target(&file.path) {
+                continue;
+            }
+            let text = backends.then(|| content.clone());
+            let content = file.bytes(content);
+            let full_path = self.output.join(&file.path);
+            if let Some(link) =
+                symlink_under(&self.output, &file.path).filter(|_| self.config.sandbox)
+            {
+                return Err(LitError::Sandboxed {
+                    what: format!("writing {} through the symlink {link}", file.path),
+                });
+            }
+            let status = match fs::read(&full_path) {
+                Ok(existing) if existing == content => WriteStatus::Unchanged,
+                Ok(existing)
+                    if !self.force
+                        && !recorded.files.contains(&file.path)
+                        && !has_lit_header(&existing) =>
+                {
+                    return Err(LitError::WouldOverwrite { path: file.path });
+                }
+                _ => WriteStatus::Written,
+            };
+
+            if let Some(text) = text {
+                outputs.push(serde_json::json!({"path": file.path, "content": text, "changed": status == WriteStatus::Written}));
+            }
+            let bytes = content.len();
+            let blocks = file.block_count;
+            debug!("{status} {full_path} ({bytes} bytes, {blocks} blocks)");
+            if status == WriteStatus::Written {
+                writes.push((full_path, content));
+            }
+            manifest.files.insert(file.path.clone());
+            report.files.push(FileReport {
+                path: file.path.clone(),
+                bytes,
+                blocks,
+                status,
+            });
+            tangled.push(file);
+        }
+        let assets = self.check_assets(&tangled, &recorded)?;
+        for (asset, status) in &assets {
+            manifest.files.insert(asset.path.clone());
+            report.files.push(asset.report(*status)?);
+        }
+        if manifest != recorded || !self.output.join(MANIFEST_NAME).is_file() {
+            writes.push((
+                self.output.join(MANIFEST_NAME),
+                manifest.render().into_bytes(),
+            ));
+        }
+        self.warn_unmatched_targets(&tangled);
+        self.diagnostics.check()?;
+        staged.stage(writes)?;
+        staged.commit()?;
+        self.place_assets(&assets)?;
+        self.apply_modes(&tangled)?;
+        let written = Instant::now();
+        self.run_rust_hooks(&tangled)?;
+        self.run_output_plugins(outputs)?;
+        self.run_post_tangle(&report)?;
+
+        report
+            .timings
+            .push(("write", written.duration_since(parsed)));
+        report.timings.push(("hooks", written.elapsed()));
+        report.peak_memory = peak_memory();
+        Ok(report)
+    }
+
+    /// The scratch directory this process uses for temp files under `output`
+    pub fn scratch_dir(&self) -> Utf8PathBuf {
+        self.output.join(format!(".lit-tmp-{}", std::process::id()))
+    }
+
+    /// The contents `tangle` writes for a file
+    pub fn render(&self, file: &TangledFile) -> String {
+        let content = file.render();
+        if self.config.header && !file.is_binary() {
+            with_header(file, &content)
+        } else {
+            content
+        }
+    }
+
+    /// Tangle the inputs in memory, returning each destination's contents
+    pub fn tangle_to_map(&self) -> Result<BTreeMap<Utf8PathBuf, String>> {
+        Ok(self
+            .render_all()?
+            .into_iter()
+            .map(|(file, content)| (file.path, content))
+            .collect())
+    }
+
+    /// Every destination rendered and checked against the assertions, in path order
+    fn render_all(&self) -> Result<Vec<(TangledFile, String)>> {
+        self.render_all_timed(&mut TangleReport::default())
+    }
+
+    /// `render_all`, timing each phase into `report`
+    fn render_all_timed(&self, report: &mut TangleReport) -> Result<Vec<(TangledFile, String)>> {
+        let (blocks, sources) = self.read_sources_timed(report)?;
+        let start = Instant::now();
+        let mut files = assemble(blocks, &sources, &self.config)?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        report.timings.push(("merge", start.elapsed()));
+
+        let start = Instant::now();
+        let mut rendered = Vec::new();
+        for file in files {
+            if file.is_binary() && !file.blocks.iter().all(|block| block.base64) {
+                return Err(LitError::MixedEncoding { path: file.path });
+            }
+            let content = self.render(&file);
+            if let Some(character) = file.encoding.unencodable(&content) {
+                return Err(LitError::Unencodable {
+                    path: file.path,
+                    encoding: file.encoding,
+                    character,
+                });
+            }
+            self.config.check(&file, &content)?;
+            self.config.limits.check_file(&file.path, &content)?;
+            rendered.push((file, content));
+        }
+        let total = rendered.iter().map(|(_, content)| content.len()).sum();
+        self.config.limits.check_output(total)?;
+        self.config
+            .check_expected(rendered.iter().map(|(file, _)| file.path.as_path()))?;
+        report.timings.push(("render", start.elapsed()));
+        Ok(rendered)
+    }
+
+    /// Tangle the single destination `target` in memory and return its contents
+    pub fn extract(&self, target: &Utf8Path) -> Result<String> {
+        Ok(self.render(&self.target_file(target)?))
+    }
+
+    /// Tangle the single destination `target` in memory and write its contents to `out`
+    pub fn extract_to(&self, target: &Utf8Path, out: &mut impl std::io::Write) -> Result<()> {
+        let file = self.target_file(target)?;
+        if self.config.header && !file.is_binary() {
+            out.write_all(self.render(&file).as_bytes())?;
+        } else {
+            file.write_to(out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Assemble the one file `target`, without rendering it
+    fn target_file(&self, target: &Utf8Path) -> Result<TangledFile> {
+        let (blocks, sources) = self.read_sources()?;
+        let placement = Placement::new(&sources, &self.config)?;
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| placement.place(block).is_ok_and(|path| path == target))
+            .collect();
+
+        assemble(blocks, &sources, &self.config)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| LitError::UnknownTarget {
+                path: target.to_owned(),
+            })
+    }
+
+    /// A unified diff from `target` in the output directory to what `tangle` would write
+    pub fn diff(&self, target: &Utf8Path) -> Result<String> {
+        let tangled = self.extract(target)?;
+        let current = match fs::read_to_string(self.output.join(target)) {
+            Ok(current) => current,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(similar::TextDiff::from_lines(&current, &tangled)
+            .unified_diff()
+            .header(&format!("a/{target}"), &format!("b/{target}"))
+            .to_string())
+    }
+
+    /// Parse markdown content and extract code blocks with tangle:// paths
+    pub fn parse_markdown(markdown_text: &str) -> Result<Vec<Block>> {
+        Self::parse_file(Utf8Path::new("<markdown>"), markdown_text)
+    }
+
+    /// Parse the contents of a markdown file, recording where each block came from
+    pub fn parse_file(file: &Utf8Path, markdown_text: &str) -> Result<Vec<Block>> {
+        Self::parse_source(file, markdown_text, false, &Diagnostics::default())
+    }
+
+    /// `parse_file`, failing on fences that would be skipped when `strict`
+    fn parse_source(
+        file: &Utf8Path,
+        markdown_text: &str,
+        strict: bool,
+        diagnostics: &Diagnostics,
+    ) -> Result<Vec<Block>> {
+        // Front matter is lit's settings (see `lit/config.md`), not markdown.
+        let options = ParseOptions {
+            constructs: Constructs {
+                frontmatter: true,
+                ..Constructs::default()
+            },
+            ..ParseOptions::default()
+        };
+        // Strict mode looks inside quotes and lists, which the scan skips.
+        let children = if is_rst(file) {
+            rst_code_blocks(markdown_text)
+        } else if !strict && let Some(fences) = scan_fences(markdown_text) {
+            fences
+        } else {
+            match to_mdast(markdown_text, &options)
+                .map_err(|e| LitError::Markdown(e.to_string()))?
+            {
+                Node::Root(root) => root.children,
+                _ => return Err(LitError::NotRoot), // cov-excl-line: unreachable — to_mdast always returns Root
+            }
+        };
+        Self::parse_nodes(file, markdown_text, children, strict, diagnostics)
+    }
+
+    /// The blocks of a document's top-level nodes, however they were found
+    fn parse_nodes(
+        file: &Utf8Path,
+        markdown_text: &str,
+        children: Vec<Node>,
+        strict: bool,
+        diagnostics: &Diagnostics,
+    ) -> Result<Vec<Block>> {
+        // Extract snippets from top-level code blocks only
+        let mut blocks = Vec::new();
+        let mut errors = Vec::new();
+        for mut node in children {
+            let origin = Origin::of(file, &node);
+            let parsed = match &mut node {
+                Node::Code(code) => {
+                    let content = std::mem::take(&mut code.value);
+                    Block::parse_code(code, content).and_then(|block| block.with_also(code))
+                }
+                _ => Err(BlockError::NotTangleBlock),
+            };
+            match (parsed, origin) {
+                (Ok(parsed), origin) => {
+                    blocks.extend(parsed.into_iter().map(|block| Block {
+                        origin: origin.clone(),
+                        ..block
+                    }));
+                }
+                (Err(BlockError::NotTangleBlock), origin) => {
+                    if let (Node::Code(code), Some(origin)) = (&node, origin.clone())
+                        && let Some(word) = tangle_typo(code)
+                    {
+                        if strict {
+                            let error = BlockError::NotTangleUrl(word.to_string());
+                            errors.push(LocatedError::new(error, origin, markdown_text).into());
+                            continue;
+                        }
+                        diagnostics.warn(
+                            "not-tangle-url",
+                            Some(&origin),
+                            format!("`{word}` is not a tangle URL, so this block is not tangled"),
+                        );
+                    }
+                    if strict
+                        && let Some(nested) = nested_tangle_fence(&node)
+                        && let Some(origin) = Origin::of(file, nested)
+                    {
+                        let error = BlockError::NestedTangleBlock;
+                        errors.push(LocatedError::new(error, origin, markdown_text).into());
+                    }
+                }
+                (Err(e), Some(origin)) => {
+                    errors.push(LocatedError::new(e, origin, markdown_text).into())
+                }
+                (Err(e), None) => errors.push(e.into()), // cov-excl-line: parsed nodes always carry a position
+            }
+        }
+
+        LitError::all(errors)?;
+        Ok(blocks)
+    }
+
+    /// Read all markdown files from the inputs and parse tangle blocks
+    pub fn read_blocks(&self) -> Result<Vec<TangledFile>> {
+        let (blocks, sources) = self.read_sources()?;
+        assemble(blocks, &sources, &self.config)
+    }
+
+    /// Every block in the inputs, in reading order, including skipped ones
+    pub fn blocks(&self) -> Result<Vec<Block>> {
+        Ok(self.read_sources()?.0)
+    }
+
+    fn read_sources(&self) -> Result<(Vec<Block>, HashMap<Utf8PathBuf, String>)> {
+        self.read_sources_timed(&mut TangleReport::default())
+    }
+
+    /// `read_sources`, timing the walk, the parse, and each document's parse into `report`
+    fn read_sources_timed(
+        &self,
+        report: &mut TangleReport,
+    ) -> Result<(Vec<Block>, HashMap<Utf8PathBuf, String>)> {
+        let mut blocks = Vec::new();
+        let mut sources = HashMap::<Utf8PathBuf, String>::new();
+
+        let start = Instant::now();
+        let (files, mut errors) = self.walk_inputs()?;
+        let mut readable = Vec::new();
+        for path in files {
+            match read_source(&path) {
+                Ok(content) => readable.push((path, content)),
+                Err(reason) => errors.extend(self.unreadable(path, reason)),
+            }
+        }
+        LitError::all(std::mem::take(&mut errors))?;
+        if let Some(cache) = &self.cache {
+            cache.retain(&readable.iter().map(|(path, _)| path.as_path()).collect());
+        }
+        report.timings.push(("walk", start.elapsed()));
+
+        let start = Instant::now();
+        for (path, content) in readable {
+            let parsing = Instant::now();
+            match self.parse_document(&path, &content) {
+                Ok(parsed) => blocks.extend(parsed),
+                Err(e) => errors.extend(e.into_errors()),
+            }
+            debug!("Parsed {path} in {:?}", parsing.elapsed());
+            report.documents.push((path.clone(), parsing.elapsed()));
+            sources.insert(path, content);
+        }
+        LitError::all(errors)?;
+
+        let blocks = resolve_files(blocks, &sources, |file| {
+            if self.config.sandbox && !self.within_inputs(file) {
+                let message = "outside the inputs, which the sandbox does not read";
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    message,
+                ));
+            }
+            fs::read_to_string(file)
+        })?;
+        let blocks = resolve_includes(blocks, &sources)?;
+        let blocks = transform_blocks(blocks, &self.config)?;
+        report.timings.push(("parse", start.elapsed()));
+        Ok((blocks, sources))
+    }
+
+    /// Every `.md` and `.rst` file under the inputs, and every plugin's document, in walk order, each listed once
+    fn markdown_files(&self) -> Result<Vec<Utf8PathBuf>> {
+        let (files, errors) = self.walk_inputs()?;
+        LitError::all(errors)?;
+        Ok(files)
+    }
+
+    /// `markdown_files`, with an error for each part of the inputs that could not be walked
+    fn walk_inputs(&self) -> Result<(Vec<Utf8PathBuf>, Vec<LitError>)> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        let mut errors = Vec::new();
+        let mut ignore = IgnoreRules::default();
+        for input in &self.inputs {
+            for entry in WalkDir::new(input)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| !ignore.skip(entry))
+            {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        let path = e
+                            .path()
+                            .and_then(Utf8Path::from_path)
+                            .map_or_else(|| input.clone(), Utf8Path::to_owned);
+                        let reason = e
+                            .io_error()
+                            .map_or_else(|| e.to_string(), ToString::to_string);
+                        errors.extend(self.unreadable(path, reason));
+                        continue;
+                    }
+                };
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let path = Utf8PathBuf::try_from(entry.into_path())
+                    .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+                if !(is_markdown(&path) || is_rst(&path) || self.config.frontend(&path).is_some()) {
+                    continue;
+                }
+                if seen.insert(path.clone()) {
+                    files.push(path);
+                }
+            }
+        }
+        Ok((files, errors))
+    }
+
+    /// An `UnreadableSource` error, or with `skip_invalid` a warning and nothing
+    fn unreadable(&self, path: Utf8PathBuf, reason: String) -> Option<LitError> {
+        let error = LitError::UnreadableSource { path, reason };
+        if self.skip_invalid {
+            if let LitError::UnreadableSource { path, reason } = error {
+                self.diagnostics.report(Message {
+                    file: Some(path),
+                    ..Message::new(
+                        Level::Warning,
+                        "skipped-source",
+                        format!("cannot be read, so it is skipped: {reason}"),
+                    )
+                });
+            }
+            return None;
+        }
+        Some(error)
+    }
+
+    /// Give each tangled file the mode its blocks declare
+    fn apply_modes(&self, files: &[TangledFile]) -> Result<()> {
+        for file in files {
+            if let Some(mode) = file.mode {
+                set_mode(&self.output.join(&file.path), mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite every fence that tangles to `old` to tangle to `new`, returning the documents changed
+    pub fn move_target(
+        &self,
+        old: &Utf8Path,
+        new: &Utf8Path,
+        output: MovedOutput,
+    ) -> Result<Vec<Utf8PathBuf>> {
+        let cannot = |reason: String| LitError::CannotMove {
+            path: old.to_owned(),
+            reason,
+        };
+        if !inside_output(new) {
+            return Err(cannot(format!("{new} is not inside the output directory")));
+        }
+        check_portable(new.as_str()).map_err(|e| cannot(e.to_string()))?;
+        let (blocks, sources) = self.read_sources()?;
+        let placement = Placement::new(&sources, &self.config)?;
+
+        let mut fences: BTreeMap<Utf8PathBuf, Vec<usize>> = BTreeMap::new();
+        let mut declared = HashSet::new();
+        for block in &blocks {
+            let (Ok(placed), Some(origin)) = (placement.place(block), &block.origin) else {
+                continue;
+            };
+            // Only the first of a fence's blocks has the URL's own path; the rest are its `?also=` copies.
+            let copy = !declared.insert((&origin.file, origin.offset));
+            if placed == new && old != new {
+                return Err(cannot(format!("{new} is already tangled, from {origin}")));
+            }
+            if placed != old {
+                continue;
+            }
+            if copy {
+                return Err(cannot(format!(
+                    "{origin} lists it in ?also=; change that fence by hand"
+                )));
+            }
+            let moved = Block {
+                path: new.to_owned(),
+                ..block.clone()
+            };
+            if block.path != old || !placement.place(&moved).is_ok_and(|placed| placed == new) {
+                return Err(cannot(format!(
+                    "{origin} writes it as {}; change that fence by hand",
+                    block.path
+                )));
+            }
+            fences
+                .entry(origin.file.clone())
+                .or_default()
+                .push(origin.offset);
+        }
+        if fences.is_empty() {
+            return Err(LitError::UnknownTarget {
+                path: old.to_owned(),
+            });
+        }
+
+        for (file, offsets) in &fences {
+            let Some(text) = sources.get(file) else {
+                continue; // cov-excl-line: every block's document is among the sources
+            };
+            let mut text = text.clone();
+            for offset in offsets.iter().rev() {
+                let end = text
+                    .get(*offset..)
+                    .and_then(|rest| rest.find('\n'))
+                    .map_or(text.len(), |at| offset.saturating_add(at));
+                let line = retarget_fence(text.get(*offset..end).unwrap_or_default(), new);
+                text.replace_range(*offset..end, &line);
+            }
+            fs::write(file, text)?;
+        }
+        self.move_output(old, new, output)?;
+        Ok(fences.into_keys().collect())
+    }
+
+    /// Move or delete the old target's file in the output directory, if lit wrote it
+    fn move_output(&self, old: &Utf8Path, new: &Utf8Path, output: MovedOutput) -> Result<()> {
+        if output == MovedOutput::Keep || !self.output.join(old).is_file() {
+            return Ok(());
+        }
+        let _lock = OutputLock::acquire(&self.output)?;
+        let mut manifest = Manifest::load(&self.output)?;
+        if !manifest.files.contains(old) {
+            self.diagnostics.warn(
+                "not-lit-output",
+                None,
+                format!("Leaving {old} in the output: lit did not write it"),
+            );
+            return Ok(());
+        }
+        if output == MovedOutput::Delete {
+            return Ok(fs::remove_file(self.output.join(old))?);
+        }
+        let destination = self.output.join(new);
+        if destination.exists() && !self.force && !manifest.files.contains(new) {
+            return Err(LitError::WouldOverwrite {
+                path: new.to_owned(),
+            });
+        }
+        // `destination` is the output directory joined with a relative path, so it has a parent.
+        #[allow(clippy::unwrap_used)]
+        fs::create_dir_all(destination.parent().unwrap())?;
+        fs::rename(self.output.join(old), &destination)?;
+        manifest.files.insert(new.to_owned());
+        fs::write(self.output.join(MANIFEST_NAME), manifest.render())?;
+        Ok(())
+    }
+
+    /// Playground URLs for each tangled Rust program, by destination, when `[playground]` is set
+    fn playground_links(&self) -> Result<HashMap<Utf8PathBuf, String>> {
+        let Some(playground) = &self.config.playground else {
+            return Ok(HashMap::new());
+        };
+        let mut links = HashMap::new();
+        for file in self.read_blocks()? {
+            let content = self.render(&file);
+            if file.path.extension() == Some("rs") && content.contains("fn main") {
+                links.insert(file.path, playground.url(&content));
+            }
+        }
+        Ok(links)
+    }
+
+    /// Send the tangled files to each output backend plugin
+    fn run_output_plugins(&self, files: Vec<serde_json::Value>) -> Result<()> {
+        let request = serde_json::json!({"kind": "output", "output": self.output, "files": files});
+        for (name, plugin) in self
+            .config
+            .plugins
+            .iter()
+            .filter(|(_, plugin)| plugin.output)
+        {
+            run_plugin(name, plugin, &request, false)?;
+        }
+        Ok(())
+    }
+
+    /// The platforms the documents are rendered on, from `render` or else detected around the inputs
+    pub fn platforms(&self) -> Vec<Platform> {
+        let mut platforms = BTreeSet::from_iter(self.config.render.iter().copied());
+        if !platforms.is_empty() {
+            return platforms.into_iter().collect();
+        }
+        for input in &self.inputs {
+            let Ok(input) = fs::canonicalize(input) else {
+                continue;
+            };
+            let start = if input.is_file() {
+                input.parent().unwrap_or(&input)
+            } else {
+                &input
+            };
+            for directory in start.ancestors() {
+                for platform in Platform::ALL {
+                    if platform
+                        .markers()
+                        .iter()
+                        .any(|marker| directory.join(marker).exists())
+                    {
+                        platforms.insert(*platform);
+                    }
+                }
+                if directory.join(".git").exists() {
+                    break;
+                }
+            }
+        }
+        platforms.into_iter().collect()
+    }
+
+    /// Run the configured Rust hooks over the tangled `.rs` files
+    fn run_rust_hooks(&self, files: &[TangledFile]) -> Result<()> {
+        let hooks = &self.config.rust;
+        let rust_files: Vec<_> = files
+            .iter()
+            .filter(|file| file.path.extension() == Some("rs"))
+            .collect();
+        if rust_files.is_empty() {
+            return Ok(());
+        }
+
+        if hooks.rustfmt {
+            let output = std::process::Command::new("rustfmt")
+                .args(["--check", "--edition", &hooks.edition])
+                .args(rust_files.iter().map(|file| self.output.join(&file.path)))
+                .output()?;
+            self.hook_result("rustfmt", Utf8Path::new(""), &output, &rust_files)?;
+        }
+        if hooks.check {
+            let output = std::process::Command::new("cargo")
+                .args(["check", "--quiet", "--message-format", "short"])
+                .current_dir(&self.output)
+                .output()?;
+            self.hook_result("cargo check", Utf8Path::new(""), &output, &rust_files)?;
+        }
+        if hooks.cargo {
+            let crates: Vec<_> = files
+                .iter()
+                .filter(|file| file.path.file_name() == Some("Cargo.toml"))
+                .filter_map(|file| file.path.parent())
+                .collect();
+            if crates.is_empty() {
+                debug!("No Cargo.toml was tangled, so there is no crate to check");
+            }
+            for directory in crates {
+                let commands: [(&'static str, &[&str]); 2] = [
+                    ("cargo fmt", &["fmt", "--check"]),
+                    (
+                        "cargo check",
+                        &["check", "--quiet", "--message-format", "short"],
+                    ),
+                ];
+                for (tool, args) in commands {
+                    debug!("Running {tool} in {}", self.output.join(directory));
+                    let output = std::process::Command::new("cargo")
+                        .args(args)
+                        .current_dir(self.output.join(directory))
+                        .output()?;
+                    self.hook_result(tool, directory, &output, &rust_files)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn a failed hook's output into an error, annotated with markdown origins
+    ///
+    /// `directory` is where the tool ran, relative to the output directory.
+    fn hook_result(
+        &self,
+        tool: &'static str,
+        directory: &Utf8Path,
+        output: &std::process::Output,
+        files: &[&TangledFile],
+    ) -> Result<()> {
+        if output.status.success() {
+            return Ok(());
+        }
+
+        // Compiler output is human-readable text, so lossy decoding is fine.
+        #[allow(clippy::disallowed_methods)]
+        let text = format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let annotated = text
+            .lines()
+            .map(
+                |line| match self.origin_of_position(line, directory, files) {
+                    Some(origin) => format!("{line}  (from {origin})"),
+                    None => line.to_string(),
+                },
+            )
+            .collect::<Vec<_>>()
+            .join("\n");
+        Err(LitError::HookFailed {
+            tool,
+            output: annotated,
+        })
+    }
+
+    /// The markdown origin of the first `path.rs:LINE` in a line of output from a tool run in `directory`
+    fn origin_of_position(
+        &self,
+        line: &str,
+        directory: &Utf8Path,
+        files: &[&TangledFile],
+    ) -> Option<Origin> {
+        let captures = RUST_POSITION.captures(line)?;
+        let path = directory.join(captures.get(1)?.as_str());
+        let number: usize = captures.get(2)?.as_str().parse().ok()?;
+        let file = files.iter().find(|file| path.ends_with(&file.path))?;
+
+        // A generated-file header pushes the blocks down.
+        file.origin_of_line(number.checked_sub(self.header_lines(file))?)
+    }
+
+    /// Whether `file` is inside one of the inputs, once symlinks are followed
+    fn within_inputs(&self, file: &Utf8Path) -> bool {
+        let Ok(file) = fs::canonicalize(file) else {
+            return false;
+        };
+        self.inputs.iter().any(|input| {
+            let directory = if input.is_file() {
+                input.parent().unwrap_or(input)
+            } else {
+                input
+            };
+            fs::canonicalize(directory).is_ok_and(|directory| file.starts_with(directory))
+        })
+    }
+
+    /// Every section of the markdown inputs, in order
+    pub fn sections(&self) -> Result<Vec<Section>> {
+        let options = ParseOptions {
+            constructs: Constructs {
+                frontmatter: true,
+                ..Constructs::default()
+            },
+            ..ParseOptions::default()
+        };
+        let push = |text: &mut String, value: &str| {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(value);
+        };
+        let mut sections = Vec::new();
+        for path in self
+            .markdown_files()?
+            .into_iter()
+            .filter(|path| is_markdown(path))
+        {
+            let content = fs::read_to_string(&path)?;
+            let Node::Root(root) =
+                to_mdast(&content, &options).map_err(|e| LitError::Markdown(e.to_string()))?
+            else {
+                return Err(LitError::NotRoot); // cov-excl-line: unreachable — to_mdast always returns Root
+            };
+            let mut section = Section::new(&path, None, 1);
+            for node in &root.children {
+                match node {
+                    Node::Heading(_) => {
+                        let line = node.position().map_or(1, |position| position.start.line);
+                        let next = Section::new(&path, Some(node.to_string()), line);
+                        let previous = std::mem::replace(&mut section, next);
+                        if previous.title.is_some()
+                            || !previous.prose.is_empty()
+                            || !previous.code.is_empty()
+                        {
+                            sections.push(previous);
+                        }
+                    }
+                    Node::Code(code) => {
+                        push(&mut section.code, &code.value);
+                        section
+                            .fences
+                            .extend(node.position().map(|position| position.start.offset));
+                    }
+                    _ => {
+                        let text = plain_text(node);
+                        if !text.is_empty() {
+                            push(&mut section.prose, &text);
+                        }
+                    }
+                }
+            }
+            if section.title.is_some() || !section.prose.is_empty() || !section.code.is_empty() {
+                sections.push(section);
+            }
+        }
+        Ok(sections)
+    }
+
+    /// Copy the inputs into `site`, with tangle fences rewritten for rendering
+    pub fn copy_site(&self, site: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+        let indexes = self.target_indexes()?;
+        let mut placed = HashMap::new();
+        for index in &indexes {
+            for (origin, lines) in &index.blocks {
+                placed.insert(
+                    (origin.file.as_path(), origin.offset),
+                    (&index.path, *lines),
+                );
+            }
+        }
+        let playground = self.playground_links()?;
+
+        let mut pages = HashMap::new();
+        let mut copied = Vec::new();
+        for input in &self.inputs {
+            for entry in WalkDir::new(input)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| {
+                    entry.path() != self.output.as_std_path() && entry.path() != site.as_std_path()
+                })
+                .filter_map(|e| e.ok())
+                .filter(|entry| entry.file_type().is_file())
+            {
+                let path = Utf8PathBuf::try_from(entry.into_path())
+                    .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+                let relative = match path.strip_prefix(input) {
+                    Ok(relative) if !relative.as_str().is_empty() => relative,
+                    _ => Utf8Path::new(path.file_name().unwrap_or_default()),
+                };
+                let destination = site.join(relative);
+
+                // `destination` is `site` joined with a file name, so it has a parent.
+                #[allow(clippy::unwrap_used)]
+                fs::create_dir_all(destination.parent().unwrap())?;
+                if path.extension() == Some("md") {
+                    let content = fs::read_to_string(&path)?;
+                    let caption = |offset| {
+                        let (target, lines) = placed.get(&(path.as_path(), offset))?;
+                        let caption = target_caption(relative, target, *lines);
+                        Some(match playground.get(*target) {
+                            Some(url) => format!("{caption} · [Run on playground]({url})"),
+                            None => caption,
+                        })
+                    };
+                    fs::write(&destination, rewrite_with_captions(&content, caption)?)?;
+                    pages.insert(path.clone(), relative.to_owned());
+                } else {
+                    fs::copy(&path, &destination)?;
+                }
+                copied.push(destination);
+            }
+        }
+
+        for index in &indexes {
+            let destination = site.join(TARGETS_DIR).join(format!("{}.md", index.path));
+            // `destination` is `site` joined with a file name, so it has a parent.
+            #[allow(clippy::unwrap_used)]
+            fs::create_dir_all(destination.parent().unwrap())?;
+            fs::write(&destination, index.page(&pages))?;
+            copied.push(destination);
+        }
+
+        let destination = site.join(SEARCH_INDEX);
+        fs::write(&destination, search_index(self.sections()?, &pages)?)?;
+        copied.push(destination);
+        Ok(copied)
+    }
+
+    /// Where every tangle block's code ends up, one index per tangled file
+    pub fn target_indexes(&self) -> Result<Vec<TargetIndex>> {
+        let mut indexes = Vec::new();
+        for file in self.read_blocks()? {
+            let header = self
+                .render(&file)
+                .lines()
+                .count()
+                .saturating_sub(file.render().lines().count());
+            let mut blocks = Vec::new();
+            for (block, (start, end)) in file.blocks.iter().zip(file.line_ranges()) {
+                if let Some(origin) = &block.origin {
+                    let lines = (start.saturating_add(header), end.saturating_add(header));
+                    blocks.push((origin.clone(), Some(lines)));
+                }
+            }
+            for origin in &file.origins {
+                if !blocks.iter().any(|(placed, _)| placed == origin) {
+                    blocks.push((origin.clone(), None));
+                }
+            }
+            indexes.push(TargetIndex {
+                path: file.path,
+                blocks,
+            });
+        }
+        Ok(indexes)
+    }
+
+    /// Count documents, blocks, prose, and generated code in the inputs
+    pub fn stats(&self) -> Result<Stats> {
+        let (blocks, sources) = self.read_sources()?;
+        let non_blank = |text: &str| text.lines().filter(|line| !line.trim().is_empty()).count();
+
+        let code_lines: usize = blocks.iter().map(|block| non_blank(&block.content)).sum();
+        let fence_lines = blocks.len().saturating_mul(2);
+        let markdown_lines: usize = sources.values().map(|text| non_blank(text)).sum();
+        let mut stats = Stats {
+            documents: sources.len(),
+            blocks: blocks.len(),
+            skipped: blocks.iter().filter(|block| block.skip).count(),
+            prose_lines: markdown_lines.saturating_sub(code_lines.saturating_add(fence_lines)),
+            code_lines,
+            ..Stats::default()
+        };
+
+        for file in assemble(blocks, &sources, &self.config)? {
+            let lines = self.render(&file).lines().count();
+            let language = match language_of(&file.path) {
+                Some(language) => language.to_string(),
+                None => file.path.extension().unwrap_or("(none)").to_string(),
+            };
+            let total = stats.languages.entry(language).or_default();
+            *total = total.saturating_add(lines);
+            stats.targets.push((file.path, lines));
+        }
+        stats
+            .targets
+            .sort_by(|(a_path, a_lines), (b_path, b_lines)| {
+                b_lines.cmp(a_lines).then(a_path.cmp(b_path))
+            });
+        Ok(stats)
+    }
+
+    /// Whether `tangle` writes the destination `path`
+    fn is_target(&self, path: &Utf8Path) -> bool {
+        self.targets.is_empty() || self.targets.iter().any(|target| target.matches(path))
+    }
+
+    /// Warn about each target glob that matched none of the tangled files
+    fn warn_unmatched_targets(&self, tangled: &[TangledFile]) {
+        for target in &self.targets {
+            if !tangled.iter().any(|file| target.matches(&file.path)) {
+                self.diagnostics.warn(
+                    "unmatched-target",
+                    None,
+                    format!("No destination matches --target {}", target.glob),
+                );
+            }
+        }
+    }
+
+    /// The template `format` is woven into: the one `[weave]` names, or the built-in one
+    fn weave_template(&self, format: WeaveFormat) -> Result<String> {
+        let path = match format {
+            WeaveFormat::Typst => &self.config.weave.typst,
+            WeaveFormat::Latex => &self.config.weave.latex,
+        };
+        let Some(path) = path else {
+            return Ok(format.default_template().to_string());
+        };
+        let template = fs::read_to_string(path)?;
+        if !template.contains("{{body}}") {
+            return Err(LitError::TemplateWithoutBody { path: path.clone() });
+        }
+        Ok(template)
+    }
+
+    /// The sections, targets, and blocks of a weave, for rendering with an outside template engine
+    pub fn weave_context(&self) -> Result<serde_json::Value> {
+        let indexes = self.target_indexes()?;
+        let sections = self.sections()?;
+        let targets: Vec<_> = sections_of_files(&indexes, &sections)
+            .into_iter()
+            .map(|(path, defined)| {
+                let labels: Vec<_> = defined
+                    .iter()
+                    .map(|(number, _)| section_label(*number))
+                    .collect();
+                serde_json::json!({"path": path, "sections": labels})
+            })
+            .collect();
+        let mut labelled = Vec::new();
+        for (number, section) in sections.iter().enumerate() {
+            let mut value = serde_json::json!(section);
+            if let Some(object) = value.as_object_mut() {
+                object.insert("label".to_string(), section_label(number).into());
+            }
+            labelled.push(value);
+        }
+        Ok(serde_json::json!({"sections": labelled, "targets": targets, "blocks": self.inspect()?}))
+    }
+
+    /// Tangle into a temp directory, copy in `root`'s hand-written files, and run the build there
+    pub fn verify_build(&self, root: &Utf8Path) -> Result<TangleReport> {
+        if self.config.sandbox {
+            return Err(LitError::Sandboxed {
+                what: "verify-build, which runs a shell command,".to_string(),
+            });
+        }
+        let verify = &self.config.verify;
+        let command = verify.command.as_ref().ok_or(LitError::NoVerifyCommand)?;
+        let copy = verify
+            .copy
+            .iter()
+            .map(|glob| TargetGlob::new(glob))
+            .collect::<Result<Vec<_>>>()?;
+
+        let temp = format!("lit-verify-{}", std::process::id());
+        let temp = Utf8PathBuf::try_from(std::env::temp_dir().join(temp))
+            .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+        let mut scratch = Scratch::new(temp, self.keep_temp);
+        let project = scratch.path("project")?;
+        let mut lit = self.clone();
+        lit.output = project.clone();
+        lit.config.hooks = Hooks::default();
+        for plugin in lit.config.plugins.values_mut() {
+            plugin.output = false;
+        }
+        let report = lit.tangle()?;
+
+        let output = normalize(&root.join(&self.output));
+        for entry in WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.file_name() != ".git"
+                    && Utf8Path::from_path(entry.path()).map(normalize) != Some(output.clone())
+            })
+            .filter_map(|e| e.ok())
+            .filter(|entry| entry.file_type().is_file())
+        {
+            let path = Utf8PathBuf::try_from(entry.into_path())
+                .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let target = project.join(relative);
+            if !copy.iter().any(|glob| glob.matches(relative)) || target.exists() {
+                continue;
+            }
+            debug!("Copying {relative} into {project}");
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&path, &target)?;
+        }
+
+        debug!("Running {command} in {project}");
+        let status = std::process::Command::new("sh")
+            .args(["-c", command])
+            .current_dir(&project)
+            .env("LIT_OUTPUT", &project)
+            .status()?;
+        if !status.success() {
+            return Err(LitError::VerifyFailed {
+                command: command.clone(),
+                status: status.to_string(),
+            });
+        }
+        Ok(report)
+    }
+
+    /// Typeset the markdown inputs as one document in `format`
+    pub fn weave(&self, format: WeaveFormat) -> Result<String> {
+        let indexes = self.target_indexes()?;
+        let mut captions = HashMap::new();
+        for index in &indexes {
+            for (origin, lines) in &index.blocks {
+                captions.insert((origin.file.clone(), origin.offset), (&index.path, *lines));
+            }
+        }
+
+        let options = ParseOptions {
+            constructs: Constructs {
+                frontmatter: true,
+                ..Constructs::default()
+            },
+            ..ParseOptions::default()
+        };
+        let sections = self.sections()?;
+        let mut headings = HashMap::new();
+        for (number, section) in sections.iter().enumerate() {
+            if section.title.is_some() {
+                headings.insert((section.file.clone(), section.line), number);
+            }
+        }
+
+        let mut weaver = Weaver::new(format);
+        weaver.playground = self.playground_links()?;
+        for path in self
+            .markdown_files()?
+            .into_iter()
+            .filter(|path| is_markdown(path))
+        {
+            let content = fs::read_to_string(&path)?;
+            let Node::Root(root) =
+                to_mdast(&content, &options).map_err(|e| LitError::Markdown(e.to_string()))?
+            else {
+                return Err(LitError::NotRoot); // cov-excl-line: unreachable — to_mdast always returns Root
+            };
+            for node in &root.children {
+                let offset = node.position().map(|position| position.start.offset);
+                let caption = offset.and_then(|offset| captions.get(&(path.clone(), offset)));
+                if let (Node::Heading(_), Some(position)) = (node, node.position()) {
+                    weaver.label = headings
+                        .get(&(path.clone(), position.start.line))
+                        .map(|number| section_label(*number));
+                }
+                weaver.block(
+                    node,
+                    caption.map(|(target, lines)| (target.as_path(), *lines)),
+                );
+            }
+        }
+
+        let template = self.weave_template(format)?;
+        let body = std::mem::take(&mut weaver.out);
+        weaver.index_of_files(&sections_of_files(&indexes, &sections));
+        Ok(format.fill(&template, &body, &weaver.out))
+    }
+}
+
+/// The kinds of archive `tangle_to_archive` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// An uncompressed tarball
+    Tar,
+    /// A gzipped tarball
+    TarGz,
+    /// A deflated zip file
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The kind of archive a file name asks for
+    pub fn of(path: &Utf8Path) -> Result<ArchiveFormat> {
+        let name = path.file_name().unwrap_or_default();
+        if name.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(ArchiveFormat::TarGz)
+        } else if name.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(LitError::UnknownArchive {
+                path: path.to_owned(),
+            })
+        }
+    }
+}
+
+/// Files bound for an archive, as `(path, mode, bytes)`
+type ArchiveEntries = Vec<(Utf8PathBuf, u32, Vec<u8>)>;
+
+/// Write `(path, mode, bytes)` entries as a tarball, returning the writer underneath
+fn write_tar<W: std::io::Write>(out: W, entries: &[(Utf8PathBuf, u32, Vec<u8>)]) -> Result<W> {
+    let mut builder = tar::Builder::new(out);
+    for (path, mode, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(*mode);
+        header.set_mtime(0);
+        builder.append_data(&mut header, path.as_std_path(), content.as_slice())?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Write `(path, mode, bytes)` entries as a zip file
+fn write_zip(
+    out: fs::File,
+    entries: &[(Utf8PathBuf, u32, Vec<u8>)],
+) -> std::result::Result<(), zip::result::ZipError> {
+    use std::io::Write as _;
+
+    let mut writer = zip::ZipWriter::new(out);
+    for (path, mode, content) in entries {
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(*mode);
+        writer.start_file(path.as_str(), options)?;
+        writer.write_all(content)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Where a line of a tangled file came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blame {
+    /// The markdown line it was written on
+    pub line: Origin,
+    /// The opening fence of the block holding it
+    pub fence: Origin,
+    /// The block's ID, if it has one
+    pub id: Option<BlockId>,
+}
+
+impl std::fmt::Display for Blame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in the block at {}", self.line, self.fence)?;
+        match &self.id {
+            Some(id) => write!(f, " (?id={id})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TangledFile {
+    /// Where a line of the rendered file came from (1-based), if a block wrote it
+    pub fn blame(&self, line: usize) -> Option<Blame> {
+        let (block, (start, _)) = self
+            .blocks
+            .iter()
+            .zip(self.line_ranges())
+            .find(|(_, (start, end))| (*start..=*end).contains(&line))?;
+        let fence = block.origin.clone()?;
+        let within = line.saturating_sub(start);
+        Some(Blame {
+            line: Origin {
+                line: fence.line.saturating_add(1).saturating_add(within),
+                ..fence.clone()
+            },
+            fence,
+            id: block.id.clone(),
+        })
+    }
+}
+
+/// Documents' blocks by path, with the text each was parsed from
+type ParsedDocuments = HashMap<Utf8PathBuf, (String, Vec<Block>)>;
+
+/// Parsed documents kept between runs, shared by a `Lit`'s clones
+#[derive(Debug, Clone, Default)]
+pub struct ParseCache(std::sync::Arc<std::sync::Mutex<ParsedDocuments>>);
+
+impl ParseCache {
+    fn documents(&self) -> std::sync::MutexGuard<'_, ParsedDocuments> {
+        // A panic while holding the lock cannot leave an entry half-written, so the map is still good.
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The blocks of `path`, if it was parsed from exactly `text`
+    fn get(&self, path: &Utf8Path, text: &str) -> Option<Vec<Block>> {
+        let documents = self.documents();
+        let (parsed, blocks) = documents.get(path)?;
+        (parsed == text).then(|| blocks.clone())
+    }
+
+    fn insert(&self, path: Utf8PathBuf, text: String, blocks: Vec<Block>) {
+        self.documents().insert(path, (text, blocks));
+    }
+
+    /// Drop every document but those in `paths`
+    fn retain(&self, paths: &HashSet<&Utf8Path>) {
+        self.documents()
+            .retain(|path, _| paths.contains(path.as_path()));
+    }
+
+    /// The documents cached so far
+    pub fn paths(&self) -> Vec<Utf8PathBuf> {
+        let mut paths: Vec<_> = self.documents().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// A `lit daemon` session: one project, with its parsed documents cached
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    /// The project, with a parse cache
+    pub lit: Lit,
+}
+
+impl Daemon {
+    pub fn new(mut lit: Lit) -> Self {
+        lit.cache.get_or_insert_with(ParseCache::default);
+        Daemon { lit }
+    }
+
+    /// Answer requests from `reader` on `writer` until the stream ends, or until `shutdown`, returning whether it was asked to
+    pub fn serve(
+        &self,
+        reader: &mut impl std::io::BufRead,
+        writer: &mut impl std::io::Write,
+    ) -> Result<bool> {
+        while let Some(message) = read_message(reader)? {
+            if let Some(reply) = self.handle(&message) {
+                write_message(writer, &reply)?;
+            }
+            if message
+                .get("method")
+                .is_some_and(|method| method == "shutdown")
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The response to one message, or `None` for a notification
+    pub fn handle(&self, message: &serde_json::Value) -> Option<serde_json::Value> {
+        let id = message.get("id")?;
+        let method = message["method"].as_str().unwrap_or_default();
+        let error =
+            |code: i64, message: String| serde_json::json!({"code": code, "message": message});
+        let result = match method {
+            "tangle" => self.lit.tangle().map(|report| {
+                let files: Vec<_> = report
+                    .files
+                    .iter()
+                    .map(|file| {
+                        serde_json::json!({
+                            "path": file.path,
+                            "status": file.status.to_string(),
+                            "bytes": file.bytes,
+                            "blocks": file.blocks,
+                        })
+                    })
+                    .collect();
+                serde_json::json!({"files": files})
+            }),
+            "check" => self
+                .stale()
+                .map(|stale| serde_json::json!({"stale": stale})),
+            "inspect" => self.lit.inspect().map(|blocks| serde_json::json!(blocks)),
+            "extract" => match message
+                .pointer("/params/target")
+                .and_then(serde_json::Value::as_str)
+            {
+                Some(target) => self
+                    .lit
+                    .extract(Utf8Path::new(target))
+                    .map(|content| serde_json::json!({"content": content})),
+                None => {
+                    let error = error(-32602, "extract needs a target".to_string());
+                    return Some(serde_json::json!({"jsonrpc": "2.0", "id": id, "error": error}));
+                }
+            },
+            "shutdown" => Ok(serde_json::Value::Null),
+            _ => {
+                let error = error(-32601, format!("unsupported method {method}"));
+                return Some(serde_json::json!({"jsonrpc": "2.0", "id": id, "error": error}));
+            }
+        };
+        Some(match result {
+            Ok(result) => serde_json::json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => {
+                let message = e.to_string();
+                let data: Vec<_> = e.into_errors().iter().map(problem).collect();
+                let error = serde_json::json!({"code": -32000, "message": message, "data": data});
+                serde_json::json!({"jsonrpc": "2.0", "id": id, "error": error})
+            }
+        })
+    }
+
+    /// The destinations whose files in the output directory differ from what tangling would write
+    fn stale(&self) -> Result<Vec<Utf8PathBuf>> {
+        let mut stale = Vec::new();
+        for (file, content) in self.lit.render_all()? {
+            if fs::read(self.lit.output.join(&file.path)).ok() != Some(file.bytes(content)) {
+                stale.push(file.path);
+            }
+        }
+        Ok(stale)
+    }
+}
+
+/// One error, as a daemon client sees it
+fn problem(error: &LitError) -> serde_json::Value {
+    match error {
+        LitError::Located(located) => serde_json::json!({
+            "message": located.error.to_string(),
+            "file": located.origin.file,
+            "line": located.origin.line,
+        }),
+        _ => serde_json::json!({"message": error.to_string()}),
+    }
+}
+
+/// Whether a fence declares base64 content with `?encoding=`, checking that it decodes
+fn is_base64(
+    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+    content: &str,
+) -> std::result::Result<bool, BlockError> {
+    match params.get("encoding").map(|encoding| encoding.as_ref()) {
+        None => Ok(false),
+        Some("base64") => {
+            decode_base64(content).map_err(|e| BlockError::InvalidBase64(e.to_string()))?;
+            Ok(true)
+        }
+        Some(encoding) if TextEncoding::named(encoding).is_some() => Ok(false),
+        Some(encoding) => Err(BlockError::UnknownEncoding(encoding.to_string())),
+    }
+}
+
+/// Decode base64 text, ignoring the whitespace it is wrapped with
+fn decode_base64(text: &str) -> std::result::Result<Vec<u8>, base64::DecodeError> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    BASE64.decode(text)
+}
+
+impl TangledFile {
+    /// Whether the file is made of base64 blocks, decoded when it is written
+    pub fn is_binary(&self) -> bool {
+        self.blocks.iter().any(|block| block.base64)
+    }
+
+    /// The bytes to write for the file's rendered `content`
+    pub fn bytes(&self, content: String) -> Vec<u8> {
+        if !self.is_binary() {
+            return self.encoding.encode(content);
+        }
+        // Every base64 block was checked to decode when it was parsed.
+        self.blocks
+            .iter()
+            .flat_map(|block| decode_base64(&block.content).unwrap_or_default())
+            .collect()
+    }
+}
+
+/// An encoding to write a text file in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TextEncoding {
+    /// UTF-8
+    #[default]
+    Utf8,
+    /// UTF-8 after a byte order mark
+    Utf8Bom,
+    /// UTF-16 little-endian after a byte order mark
+    Utf16,
+    /// UTF-16 little-endian
+    Utf16Le,
+    /// UTF-16 big-endian
+    Utf16Be,
+    /// ISO-8859-1
+    Latin1,
+}
+
+/// Each text encoding under the name `?encoding=` and `[encodings]` give it
+const TEXT_ENCODINGS: &[(&str, TextEncoding)] = &[
+    ("utf-8", TextEncoding::Utf8),
+    ("utf-8-bom", TextEncoding::Utf8Bom),
+    ("utf-16", TextEncoding::Utf16),
+    ("utf-16le", TextEncoding::Utf16Le),
+    ("utf-16be", TextEncoding::Utf16Be),
+    ("latin-1", TextEncoding::Latin1),
+];
+
+impl TextEncoding {
+    /// The encoding with this name, if there is one
+    pub fn named(name: &str) -> Option<TextEncoding> {
+        TEXT_ENCODINGS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, encoding)| *encoding)
+    }
+
+    /// The first character of `text` this encoding cannot write
+    pub fn unencodable(self, text: &str) -> Option<char> {
+        match self {
+            TextEncoding::Latin1 => text.chars().find(|c| u32::from(*c) > 0xFF),
+            _ => None,
+        }
+    }
+
+    /// `text` in this encoding; characters it cannot write become `?`
+    pub fn encode(self, text: String) -> Vec<u8> {
+        let utf16 = |to_bytes: fn(u16) -> [u8; 2]| {
+            text.encode_utf16().flat_map(to_bytes).collect::<Vec<_>>()
+        };
+        match self {
+            TextEncoding::Utf8 => text.into_bytes(),
+            TextEncoding::Utf8Bom => [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat(),
+            TextEncoding::Utf16 => [vec![0xFF, 0xFE], utf16(u16::to_le_bytes)].concat(),
+            TextEncoding::Utf16Le => utf16(u16::to_le_bytes),
+            TextEncoding::Utf16Be => utf16(u16::to_be_bytes),
+            TextEncoding::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(c).unwrap_or(b'?'))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<String> for TextEncoding {
+    type Error = String;
+
+    fn try_from(name: String) -> std::result::Result<Self, String> {
+        TextEncoding::named(&name).ok_or_else(|| {
+            let names: Vec<_> = TEXT_ENCODINGS.iter().map(|(name, _)| *name).collect();
+            format!(
+                "unknown encoding `{name}`, expected one of {}",
+                names.join(", ")
+            )
+        })
+    }
+}
+
+impl std::fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = TEXT_ENCODINGS
+            .iter()
+            .find(|(_, encoding)| encoding == self)
+            .map_or("", |(name, _)| *name);
+        f.write_str(name)
+    }
+}
+
+impl Config {
+    /// The encoding a destination is written in, by its extension
+    pub fn encoding(&self, path: &Utf8Path) -> TextEncoding {
+        self.encodings
+            .get(path.extension().unwrap_or_default())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Name of the files listing input paths for lit to skip
+pub const IGNORE_NAME: &str = ".litignore";
+
+/// One pattern of a `.litignore`
+#[derive(Debug, Clone)]
+struct IgnorePattern {
+    /// The directory of the `.litignore` it came from
+    base: Utf8PathBuf,
+    /// The glob, as a regex over paths relative to `base`
+    regex: Regex,
+    /// Whether it re-includes, with `!`
+    negated: bool,
+    /// Whether it matches only directories, with a trailing `/`
+    directory_only: bool,
+}
+
+/// The `.litignore` patterns read so far in a walk of the inputs
+#[derive(Debug, Default)]
+struct IgnoreRules {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    /// Whether the walk should leave `entry` out; a directory it keeps has its
+    /// `.litignore` read, for the entries below it
+    fn skip(&mut self, entry: &walkdir::DirEntry) -> bool {
+        let Some(path) = Utf8Path::from_path(entry.path()) else {
+            return false;
+        };
+        let is_dir = entry.file_type().is_dir();
+        if self.is_ignored(path, is_dir) {
+            debug!("Ignoring {path}");
+            return true;
+        }
+        if is_dir {
+            self.read(path);
+        }
+        false
+    }
+
+    /// Whether the last pattern that matches `path` excludes it
+    fn is_ignored(&self, path: &Utf8Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            let Ok(relative) = path.strip_prefix(&pattern.base) else {
+                continue;
+            };
+            if relative.as_str().is_empty() || (pattern.directory_only && !is_dir) {
+                continue;
+            }
+            if pattern.regex.is_match(relative.as_str()) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+
+    /// Add the patterns of `directory`'s `.litignore`, if it has one
+    fn read(&mut self, directory: &Utf8Path) {
+        let path = directory.join(IGNORE_NAME);
+        let Ok(text) = fs::read_to_string(&path) else {
+            return;
+        };
+        for (index, line) in text.lines().enumerate() {
+            match IgnorePattern::parse(directory, line) {
+                Some(Ok(pattern)) => self.patterns.push(pattern),
+                Some(Err(e)) => warn!(
+                    "{path}:{}: skipping invalid pattern: {e}",
+                    index.saturating_add(1)
+                ),
+                None => {}
+            }
+        }
+    }
+}
+
+impl IgnorePattern {
+    /// The pattern on one line of a `.litignore` in `base`, or `None` for a blank line or comment
+    fn parse(
+        base: &Utf8Path,
+        line: &str,
+    ) -> Option<std::result::Result<IgnorePattern, regex::Error>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, glob) = match line.strip_prefix('!') {
+            Some(glob) => (true, glob),
+            None => (false, line),
+        };
+        let (directory_only, glob) = match glob.strip_suffix('/') {
+            Some(glob) => (true, glob),
+            None => (false, glob),
+        };
+        let anchored = glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+        let prefix = if anchored { "" } else { "(?:.*/)?" };
+        let regex = Regex::new(&format!("^{prefix}{}$", glob_regex(glob)));
+        Some(regex.map(|regex| IgnorePattern {
+            base: base.to_owned(),
+            regex,
+            negated,
+            directory_only,
+        }))
+    }
+}
+
+/// The regex for a `.gitignore`-style glob
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => {
+                if chars.next_if_eq(&'/').is_some() {
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if(|c| *c == '!' || *c == '^').is_some() {
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if matches!(c, '[' | '\\' | '&' | '~') {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            '\\' => regex.push_str(&regex::escape(
+                &chars.next().map(String::from).unwrap_or_default(),
+            )),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+/// A tangle block as editor tooling sees it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockInfo {
+    /// The markdown file declaring it
+    pub file: Utf8PathBuf,
+    /// The destination it is written to, relative to the output directory
+    pub target: Utf8PathBuf,
+    /// Its ID, if it has one
+    pub id: Option<String>,
+    /// Its ordering constraints: `first`, `last`, `after=a`, `before=b`
+    pub constraints: Vec<String>,
+    /// The ID of the block it is nested inside
+    pub inside: Option<String>,
+    /// Whether it is skipped
+    pub skip: bool,
+    /// Byte range of the fence in the markdown
+    pub span: (usize, usize),
+    /// First and last line of the fence, 1-based
+    pub lines: (usize, usize),
+    /// First and last line of its content in the target, 1-based
+    pub output_lines: Option<(usize, usize)>,
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |ids: &[BlockId]| {
+            ids.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            Constraint::First => write!(f, "first"),
+            Constraint::Last => write!(f, "last"),
+            Constraint::After(ids) => write!(f, "after={}", join(ids)),
+            Constraint::Before(ids) => write!(f, "before={}", join(ids)),
+        }
+    }
+}
+
+/// How a lint rule's findings are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The rule is not checked
+    Off,
+    /// Findings are reported
+    Warning,
+    /// Findings are reported, and `lit lint` fails
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Off => "off",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A check `lit lint` makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    /// The name findings and `[lint]` refer to it by
+    pub id: &'static str,
+    /// Its severity unless `[lint]` says otherwise
+    pub default: Severity,
+}
+
+/// Every lint rule
+pub const RULES: &[Rule] = &[
+    Rule {
+        id: "missing-language",
+        default: Severity::Warning,
+    },
+    Rule {
+        id: "unknown-param",
+        default: Severity::Error,
+    },
+    Rule {
+        id: "untangled-code",
+        default: Severity::Off,
+    },
+    Rule {
+        id: "tiny-target",
+        default: Severity::Off,
+    },
+    Rule {
+        id: "duplicate-content",
+        default: Severity::Warning,
+    },
+    Rule {
+        id: "render-compat",
+        default: Severity::Warning,
+    },
+];
+
+impl Config {
+    /// The severity a rule is checked with
+    pub fn severity(&self, rule: &str) -> Severity {
+        self.lint.get(rule).copied().unwrap_or_else(|| {
+            RULES
+                .iter()
+                .find(|known| known.id == rule)
+                .map_or(Severity::Off, |known| known.default)
+        })
+    }
+}
+
+/// One problem `lit lint` found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    /// The rule's ID
+    pub rule: &'static str,
+    /// The rule's configured severity
+    pub severity: Severity,
+    /// The markdown file
+    pub file: Utf8PathBuf,
+    /// 1-based line in the file
+    pub line: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}[{}] {}",
+            self.file, self.line, self.severity, self.rule, self.message
+        )
+    }
+}
+
+/// How serious a message is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// The run failed
+    Error,
+    /// Something is probably wrong, but the run went on
+    Warning,
+    /// Worth knowing, and not a problem
+    Note,
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Note => "note",
+        })
+    }
+}
+
+/// One error, warning, or note from a run, as `Diagnostics` collects it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Message {
+    pub level: Level,
+    /// What kind of problem it is, like `unmatched-target`
+    pub code: String,
+    /// What happened
+    pub message: String,
+    /// The document it is about, if it is about one
+    pub file: Option<Utf8PathBuf>,
+    /// 1-based line in the document
+    pub line: Option<usize>,
+}
+
+impl Message {
+    pub fn new(level: Level, code: &str, message: impl Into<String>) -> Self {
+        Message {
+            level,
+            code: code.to_string(),
+            message: message.into(),
+            file: None,
+            line: None,
+        }
+    }
+
+    /// The message, placed at the fence `origin`
+    fn at(self, origin: Option<&Origin>) -> Self {
+        Message {
+            file: origin.map(|origin| origin.file.clone()),
+            line: origin.map(|origin| origin.line),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{file}:{line}: ")?,
+            (Some(file), None) => write!(f, "{file}: ")?,
+            _ => {}
+        }
+        write!(f, "{}[{}] {}", self.level, self.code, self.message)
+    }
+}
+
+/// What a `Diagnostics` has collected, and how it treats warnings
+#[derive(Debug, Default)]
+struct Collected {
+    messages: Vec<Message>,
+    /// `warnings`, or the codes of the warnings that fail a run
+    denied: Vec<String>,
+    /// Collect without logging, for output as JSON
+    quiet: bool,
+}
+
+/// The warnings and notes a run reports, shared by every clone (see `lit/diagnostics.md`)
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(std::sync::Arc<std::sync::Mutex<Collected>>);
+
+impl Diagnostics {
+    fn collected(&self) -> std::sync::MutexGuard<'_, Collected> {
+        // A panic while holding the lock cannot leave a message half-pushed, so the list is still good.
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Make warnings with `code` fatal, or every warning for `warnings`
+    pub fn deny(&self, code: &str) {
+        self.collected().denied.push(code.to_string());
+    }
+
+    /// Stop logging messages as they are reported
+    pub fn quiet(&self) {
+        self.collected().quiet = true;
+    }
+
+    /// Report a message, logging it unless quiet
+    pub fn report(&self, message: Message) {
+        let mut collected = self.collected();
+        if !collected.quiet {
+            match message.level {
+                Level::Error => error!("{message}"),
+                Level::Warning => warn!("{message}"),
+                Level::Note => info!("{message}"),
+            }
+        }
+        collected.messages.push(message);
+    }
+
+    /// Report a warning, at the fence `origin` if there is one
+    fn warn(&self, code: &str, origin: Option<&Origin>, message: impl Into<String>) {
+        self.report(Message::new(Level::Warning, code, message).at(origin));
+    }
+
+    /// Report a note
+    fn note(&self, code: &str, message: impl Into<String>) {
+        self.report(Message::new(Level::Note, code, message));
+    }
+
+    /// Everything reported so far, in order
+    pub fn messages(&self) -> Vec<Message> {
+        self.collected().messages.clone()
+    }
+
+    /// A `DeniedWarnings` error if any warning reported so far is denied
+    pub fn check(&self) -> Result<()> {
+        let collected = self.collected();
+        let denied: Vec<&Message> = collected
+            .messages
+            .iter()
+            .filter(|message| {
+                message.level == Level::Warning
+                    && collected
+                        .denied
+                        .iter()
+                        .any(|code| code == "warnings" || *code == message.code)
+            })
+            .collect();
+        match denied.first() {
+            None => Ok(()),
+            Some(first) => Err(LitError::DeniedWarnings {
+                count: denied.len(),
+                first: first.to_string(),
+            }),
+        }
+    }
+}
+
+/// A per-run directory for temp files, removed when the run ends
+#[derive(Debug)]
+struct Scratch {
+    dir: Utf8PathBuf,
+    keep: bool,
+    created: bool,
+}
+
+impl Scratch {
+    fn new(dir: Utf8PathBuf, keep: bool) -> Self {
+        Scratch {
+            dir,
+            keep,
+            created: false,
+        }
+    }
+
+    /// A path for `name` in the scratch directory, creating the directory on first use
+    fn path(&mut self, name: &str) -> Result<Utf8PathBuf> {
+        if !self.created {
+            fs::create_dir_all(&self.dir)?;
+            self.created = true;
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if self.created && !self.keep {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Temp files written to the scratch directory, not yet renamed into place
+#[derive(Debug)]
+struct StagedWrites {
+    scratch: Scratch,
+    /// (temp file, destination) pairs
+    files: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+}
+
+impl StagedWrites {
+    fn new(scratch: Scratch) -> Self {
+        StagedWrites {
+            scratch,
+            files: Vec::new(),
+        }
+    }
+
+    /// Write each `(destination, content)` to a temp file to be renamed over it
+    fn stage(&mut self, writes: Vec<(Utf8PathBuf, Vec<u8>)>) -> Result<()> {
+        let directories: BTreeSet<_> = writes
+            .iter()
+            .filter_map(|(destination, _)| destination.parent())
+            .collect();
+        for directory in directories {
+            fs::create_dir_all(directory)?;
+        }
+
+        let mut temps = Vec::new();
+        for (destination, content) in writes {
+            let name = destination.file_name().unwrap_or("out");
+            let number = self.files.len().saturating_add(temps.len());
+            temps.push((
+                self.scratch.path(&format!("{number}-{name}"))?,
+                destination,
+                content,
+            ));
+        }
+        for written in parallel_map(&temps, |(temp, _, content)| fs::write(temp, content)) {
+            written?;
+        }
+        self.files.extend(
+            temps
+                .into_iter()
+                .map(|(temp, destination, _)| (temp, destination)),
+        );
+        Ok(())
+    }
+
+    /// Rename every staged file over its destination
+    fn commit(mut self) -> Result<()> {
+        while let Some((temp, destination)) = self.files.last() {
+            fs::rename(temp, destination)?;
+            self.files.pop();
+        }
+        Ok(())
+    }
+}
+
+/// What a tangle run did, file by file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TangleReport {
+    /// One entry per destination file, sorted by path
+    pub files: Vec<FileReport>,
+    /// Time spent in each phase of the run, in order
+    pub timings: Vec<(&'static str, Duration)>,
+    /// Time spent parsing each document, in reading order
+    pub documents: Vec<(Utf8PathBuf, Duration)>,
+    /// The process's peak resident memory by the end of the run, in bytes, where the platform reports it
+    pub peak_memory: Option<u64>,
+}
+
+/// What happened to a single destination file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    /// Destination path, relative to the output directory
+    pub path: Utf8PathBuf,
+    /// Size of the rendered file
+    pub bytes: usize,
+    /// Number of tangle blocks that contributed to the file
+    pub blocks: usize,
+    /// Whether the file was written
+    pub status: WriteStatus,
+}
+
+/// Whether a destination file was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStatus {
+    /// The file was missing or differed and has been (re)written
+    Written,
+    /// The file already had the rendered contents and was left alone
+    Unchanged,
+}
+
+impl std::fmt::Display for WriteStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteStatus::Written => write!(f, "written"),
+            WriteStatus::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+impl TangleReport {
+    /// Number of files with the given status
+    pub fn count(&self, status: WriteStatus) -> usize {
+        self.files.iter().filter(|f| f.status == status).count()
+    }
+
+    /// One-line summary, e.g. "3 files: 1 written, 2 unchanged"
+    pub fn summary(&self) -> String {
+        format!(
+            "{} files: {} written, {} unchanged",
+            self.files.len(),
+            self.count(WriteStatus::Written),
+            self.count(WriteStatus::Unchanged)
+        )
+    }
+
+    /// How long each phase took, e.g. "parse: 120ms, write: 40ms, hooks: 0ms, peak memory: 12 MiB"
+    pub fn timing(&self) -> String {
+        let mut timing: Vec<_> = self
+            .timings
+            .iter()
+            .map(|(phase, duration)| format!("{phase}: {}ms", duration.as_millis()))
+            .collect();
+        timing.extend(
+            self.peak_memory
+                .map(|bytes| format!("peak memory: {} MiB", bytes >> 20)),
+        );
+        timing.join(", ")
+    }
+}
+
+impl std::fmt::Display for TangleReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<9}  {:>8}  {:>6}  path", "status", "bytes", "blocks")?;
+        for file in &self.files {
+            writeln!(
+                f,
+                "{:<9}  {:>8}  {:>6}  {}",
+                file.status.to_string(),
+                file.bytes,
+                file.blocks,
+                file.path
+            )?;
+        }
+        writeln!(f, "{}", self.summary())?;
+        if !self.timings.is_empty() {
+            writeln!(f, "{}", self.timing())?;
+        }
+        Ok(())
+    }
+}
+
+/// A document's text, or why it cannot be read
+fn read_source(path: &Utf8Path) -> std::result::Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| {
+        std::error::Error::source(&e).map_or_else(|| e.to_string(), ToString::to_string)
+    })?;
+    String::from_utf8(bytes).map_err(|e| {
+        format!(
+            "not valid UTF-8 after byte {}",
+            e.utf8_error().valid_up_to()
+        )
+    })
+}
+
+/// Group blocks by destination and order each group
+fn assemble(
+    blocks: Vec<Block>,
+    sources: &HashMap<Utf8PathBuf, String>,
+    config: &Config,
+) -> Result<Vec<TangledFile>> {
+    let placement = Placement::new(sources, config)?;
+    let mut files = BTreeMap::<Utf8PathBuf, Vec<Block>>::new();
+    let mut errors = Vec::new();
+    for mut block in blocks {
+        if block.skip {
+            debug!("Skipping a block for {}", block.path);
+            continue;
+        }
+        if let Err(e) = config.limits.check_block(&block) {
+            errors.push(locate_at(e, &block, sources));
+            continue;
+        }
+        match placement
+            .place(&block)
+            .and_then(|path| config.expand_env(&mut block).map(|()| path))
+        {
+            Ok(path) => block.path = path,
+            Err(e) => {
+                errors.push(locate_at(e, &block, sources));
+                continue;
+            }
+        }
+        files.entry(block.path.clone()).or_default().push(block);
+    }
+    LitError::all(errors)?;
+    check_case_collisions(&files, sources)?;
+
+    let mut assembled = Vec::new();
+    let mut errors = Vec::new();
+    for (path, blocks) in files {
+        match assemble_file(path, blocks, sources, config) {
+            Ok(file) => assembled.push(file),
+            Err(e) => errors.push(e),
+        }
+    }
+    LitError::all(errors)?;
+    Ok(assembled)
+}
+
+/// Reject destinations that differ only in case, which one file would hold on some filesystems
+fn check_case_collisions(
+    files: &BTreeMap<Utf8PathBuf, Vec<Block>>,
+    sources: &HashMap<Utf8PathBuf, String>,
+) -> Result<()> {
+    let mut folded = HashMap::<String, &[Block]>::new();
+    let mut errors = Vec::new();
+    for (path, blocks) in files {
+        let Some(first) = folded.insert(path.as_str().to_lowercase(), blocks) else {
+            continue;
+        };
+        let (Some(block), Some(other)) = (blocks.first(), first.first()) else {
+            continue; // cov-excl-line: every destination has a block
+        };
+        let error = BlockError::CaseCollision {
+            path: path.clone(),
+            other: other.path.clone(),
+        };
+        let mut error = locate_at(error, block, sources);
+        if let (LitError::Located(located), Some(origin)) = (&mut error, &other.origin)
+            && let Some(source) = sources.get(&origin.file)
+        {
+            located.relate("other destination declared here", origin.clone(), source);
+        }
+        errors.push(error);
+    }
+    LitError::all(errors)
+}
+
+/// Order one destination's blocks into its file
+fn assemble_file(
+    path: Utf8PathBuf,
+    blocks: Vec<Block>,
+    sources: &HashMap<Utf8PathBuf, String>,
+    config: &Config,
+) -> Result<TangledFile> {
+    let solve = |blocks: Vec<Block>| -> Result<Vec<Block>> {
+        let order = block_order(&blocks).map_err(|e| locate(e, &blocks, sources))?;
+        Ok(apply_surrounds(arrange(blocks, &order)))
+    };
+    let blocks = trim_blocks(blocks, config.trim(&path));
+    let block_count = blocks.len();
+    let origins = blocks.iter().filter_map(|b| b.origin.clone()).collect();
+    let mode = blocks.iter().find_map(|b| b.mode);
+    let encoding = blocks.iter().find_map(|b| b.encoding);
+    let sorted_blocks = match config.merge_strategy(&path) {
+        MergeStrategy::Interleave => solve(blocks)?,
+        MergeStrategy::Concatenate => by_document(blocks)
+            .into_iter()
+            .map(solve)
+            .collect::<Result<Vec<_>>>()?
+            .concat(),
+        MergeStrategy::Error => {
+            let names = documents(&blocks)
+                .into_iter()
+                .map(|file| file.map(Utf8Path::as_str).unwrap_or_default())
+                .collect::<Vec<_>>();
+            if names.len() > 1 {
+                return Err(LitError::SharedTarget {
+                    help: format!("declared in {}", names.join(", ")),
+                    path,
+                });
+            }
+            solve(blocks)?
+        }
+    };
+    Ok(TangledFile {
+        block_count,
+        origins,
+        mode,
+        separator: config.separator(&path),
+        encoding: encoding.unwrap_or_else(|| config.encoding(&path)),
+        ..TangledFile::new(path, sorted_blocks)
+    })
+}
+
+/// Put each included chunk at the start of the block that includes it
+fn resolve_includes(
+    mut blocks: Vec<Block>,
+    sources: &HashMap<Utf8PathBuf, String>,
+) -> Result<Vec<Block>> {
+    let mut chunks = HashMap::<(Utf8PathBuf, BlockId), String>::new();
+    for block in &blocks {
+        if let (Some(id), Some(origin)) = (&block.id, &block.origin) {
+            chunks
+                .entry((normalize(&origin.file), id.clone()))
+                .or_insert_with(|| block.content.clone());
+        }
+    }
+
+    let mut errors = Vec::new();
+    for block in &mut blocks {
+        let Some(include) = &block.include else {
+            continue;
+        };
+        let here = block
+            .origin
+            .as_ref()
+            .map_or(Utf8Path::new(""), |origin| &origin.file);
+        let chunk = include.split_once('#').and_then(|(document, id)| {
+            let document = match document {
+                "" => normalize(here),
+                document => normalize(&here.parent().unwrap_or(here).join(document)),
+            };
+            chunks.get(&(document, BlockId::new(id.to_string()).ok()?))
+        });
+        let Some(chunk) = chunk else {
+            errors.push(locate_at(
+                BlockError::UnknownInclude(include.clone()),
+                block,
+                sources,
+            ));
+            continue;
+        };
+        block.content = if block.content.is_empty() {
+            chunk.clone()
+        } else {
+            format!("{chunk}\n{}", block.content)
+        };
+    }
+    LitError::all(errors)?;
+    Ok(blocks)
+}
+
+/// A path with `.` and `..` components resolved lexically, keeping the `..`s that lead out of it
+fn normalize(path: &Utf8Path) -> Utf8PathBuf {
+    let mut normalized = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => match normalized.components().next_back() {
+                Some(Utf8Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // The parent of the root is the root.
+                Some(Utf8Component::RootDir | Utf8Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The 1-based, inclusive line range a fence takes with `?lines=`
+fn parse_lines(
+    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+) -> std::result::Result<Option<(usize, usize)>, BlockError> {
+    let Some(value) = params.get("lines") else {
+        return Ok(None);
+    };
+    let invalid = || BlockError::InvalidLines(value.to_string());
+    if !params.contains_key("from") {
+        return Err(invalid());
+    }
+    let number = |text: &str| text.parse::<usize>().ok().filter(|line| *line > 0);
+    let range = match value.split_once('-') {
+        Some((start, "")) => number(start).map(|start| (start, usize::MAX)),
+        Some((start, end)) => number(start).zip(number(end)),
+        None => number(value).map(|line| (line, line)),
+    };
+    range
+        .filter(|(start, end)| start <= end)
+        .map(Some)
+        .ok_or_else(invalid)
+}
+
+/// Put each `?from=` file's text, or its `?lines=`, at the start of the block that names it
+fn resolve_files(
+    mut blocks: Vec<Block>,
+    sources: &HashMap<Utf8PathBuf, String>,
+    read: impl Fn(&Utf8Path) -> std::io::Result<String>,
+) -> Result<Vec<Block>> {
+    let mut errors = Vec::new();
+    for block in &mut blocks {
+        let Some(from) = &block.from else {
+            continue;
+        };
+        let here = block
+            .origin
+            .as_ref()
+            .map_or(Utf8Path::new(""), |origin| &origin.file);
+        let file = normalize(&here.parent().unwrap_or(here).join(from));
+        let text = match read(&file) {
+            Ok(text) => text,
+            Err(e) => {
+                errors.push(locate_at(
+                    BlockError::UnreadableFile(format!("{file}: {e}")),
+                    block,
+                    sources,
+                ));
+                continue;
+            }
+        };
+        let excerpt = match block.lines {
+            None => text.strip_suffix('\n').unwrap_or(&text).to_string(),
+            Some((start, end)) => {
+                let count = text.lines().count();
+                if start > count || (end != usize::MAX && end > count) {
+                    let lines = if end == usize::MAX {
+                        format!("{start}-")
+                    } else {
+                        format!("{start}-{end}")
+                    };
+                    let error = BlockError::LinesOutOfRange { lines, file, count };
+                    errors.push(locate_at(error, block, sources));
+                    continue;
+                }
+                let taken = end.saturating_sub(start).saturating_add(1);
+                text.lines()
+                    .skip(start.saturating_sub(1))
+                    .take(taken)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        };
+        block.content = if block.content.is_empty() {
+            excerpt
+        } else {
+            format!("{excerpt}\n{}", block.content)
+        };
+    }
+    LitError::all(errors)?;
+    Ok(blocks)
+}
+
+/// Every document's front matter, and the config its blocks are placed with
+struct Placement<'a> {
+    project: &'a Config,
+    documents: HashMap<&'a Utf8Path, (FrontMatter, Config)>,
+}
+
+impl<'a> Placement<'a> {
+    fn new(sources: &'a HashMap<Utf8PathBuf, String>, project: &'a Config) -> Result<Self> {
+        let mut sorted: Vec<_> = sources.iter().collect();
+        sorted.sort();
+        let documents = sorted
+            .into_iter()
+            .map(|(file, text)| {
+                let front = FrontMatter::parse(file, text)?;
+                let config = front.config(project, file);
+                Ok((file.as_path(), (front, config)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Placement { project, documents })
+    }
+
+    /// The destination a block is written to, relative to the output directory
+    fn place(&self, block: &Block) -> std::result::Result<Utf8PathBuf, BlockError> {
+        match block
+            .origin
+            .as_ref()
+            .and_then(|origin| self.documents.get(origin.file.as_path()))
+        {
+            Some((front, config)) => front.place(&block.path, config),
+            None => FrontMatter::default().place(&block.path, self.project), // cov-excl-line: parsed blocks come from a read document
+        }
+    }
+}
+
+/// The documents blocks were declared in, in reading order
+fn documents(blocks: &[Block]) -> Vec<Option<&Utf8Path>> {
+    let mut documents = Vec::new();
+    for file in blocks
+        .iter()
+        .map(|block| block.origin.as_ref().map(|origin| origin.file.as_path()))
+    {
+        if !documents.contains(&file) {
+            documents.push(file);
+        }
+    }
+    documents
+}
+
+/// Split blocks by the document they were declared in, in reading order
+fn by_document(blocks: Vec<Block>) -> Vec<Vec<Block>> {
+    let mut documents: Vec<(Option<Utf8PathBuf>, Vec<Block>)> = Vec::new();
+    for block in blocks {
+        let file = block.origin.as_ref().map(|origin| origin.file.clone());
+        match documents.iter_mut().find(|(f, _)| *f == file) {
+            Some((_, group)) => group.push(block),
+            None => documents.push((file, vec![block])),
+        }
+    }
+    documents.into_iter().map(|(_, blocks)| blocks).collect()
+}
+
+/// Tangle a single markdown document in memory
+pub fn tangle_document(name: &str, markdown: &str) -> Result<Vec<TangledFile>> {
+    let name = Utf8PathBuf::from(name);
+    let blocks = Lit::parse_file(&name, markdown)?;
+    let sources = HashMap::from([(name, markdown.to_string())]);
+    let blocks = resolve_files(blocks, &sources, |_| {
+        Err(std::io::ErrorKind::NotFound.into())
+    })?;
+    let blocks = resolve_includes(blocks, &sources)?;
+
+    let mut files = assemble(blocks, &sources, &Config::default())?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// The outcome of tangling one document of a batch
+pub type DocumentResult = (String, Result<Vec<TangledFile>>);
+
+/// Tangle many independent `(name, markdown)` documents in parallel
+pub fn tangle_documents(
+    documents: impl IntoIterator<Item = (String, String)>,
+) -> Vec<DocumentResult> {
+    let documents: Vec<_> = documents.into_iter().collect();
+    parallel_map(&documents, |(name, markdown)| {
+        (name.clone(), tangle_document(name, markdown))
+    })
+}
+
+/// `f` applied to every item, over one thread per available core, in input order
+fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let workers = std::thread::available_parallelism()
+        .map_or(1, std::num::NonZeroUsize::get)
+        .min(items.len());
+    let next = AtomicUsize::new(0);
+
+    let mut results = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Attach the responsible fence to a solver error, when one can be identified
+fn locate(error: LitError, blocks: &[Block], sources: &HashMap<Utf8PathBuf, String>) -> LitError {
+    let LitError::Block(block_error) = error else {
+        return error; // cov-excl-line: the solver only returns block errors
+    };
+
+    let (culprit, first) = match &block_error {
+        BlockError::UnknownBlockId(id) => (blocks.iter().find(|b| b.references(id)), None),
+        BlockError::NotExpanded(id) => {
+            (blocks.iter().find(|b| b.inside.as_ref() == Some(id)), None)
+        }
+        BlockError::DuplicateId { id, .. } => {
+            let mut declaring = blocks.iter().filter(|b| b.id.as_ref() == Some(id));
+            let first = declaring.next();
+            (declaring.find(|b| !b.append), first)
+        }
+        _ => (None, None),
+    };
+
+    let with_source = |block: &Block| {
+        let origin = block.origin.as_ref()?;
+        Some((origin.clone(), sources.get(&origin.file)?))
+    };
+    let Some((origin, source)) = culprit.and_then(with_source) else {
+        return block_error.into();
+    };
+
+    let mut located = LocatedError::new(block_error, origin, source);
+    if let Some((first, source)) = first.and_then(with_source) {
+        located.relate("first declared here", first, source);
+    }
+    located.into()
+}
+
+/// Attach `block`'s fence to an error about it, when the fence is known
+fn locate_at(error: BlockError, block: &Block, sources: &HashMap<Utf8PathBuf, String>) -> LitError {
+    let origin = block.origin.as_ref();
+    match origin.and_then(|origin| Some((origin, sources.get(&origin.file)?))) {
+        Some((origin, source)) => LocatedError::new(error, origin.clone(), source).into(),
+        None => error.into(), // cov-excl-line: blocks read from files always have an origin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::indexing_slicing,
+        clippy::arithmetic_side_effects
+    )]
+
+    use super::*;
+
+    /// A scratch directory for a test's input and output, removed when the
+    /// returned guard is dropped
+    fn temp_root() -> (tempfile::TempDir, Utf8PathBuf) {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = Utf8PathBuf::from_path_buf(temp_dir.path().to_path_buf()).unwrap();
+        (temp_dir, root)
+    }
+
+    /// An input whose `max` block, with the given ordering, also goes to a test and a bench file
+    fn also_input(ordering: &str) -> (tempfile::TempDir, Utf8PathBuf) {
+        let (temp_dir, root) = temp_root();
+        let markdown = format!(
+            "```tangle:///src/lib.rs?id=use&first\nuse std::fmt;\n```\n\n```tangle:///src/lib.rs?id=max{ordering}&also=tests/common.rs,src/lib.rs&also=%20benches/common.rs\npub const MAX: usize = 64;\n```\n\n```tangle:///tests/common.rs\nuse super::*;\n```\n"
+        );
+        fs::write(root.join("doc.md"), markdown).unwrap();
+        fs::write(
+            root.join("benches.md"),
+            "```tangle:///benches/common.rs?id=use\n// benches\n```\n",
+        )
+        .unwrap();
+        (temp_dir, root)
+    }
+
+    #[test]
+    fn test_also_writes_the_block_to_every_target_once() {
+        let (_temp_dir, root) = also_input("");
+        Lit::new(root.clone(), root.join("out")).tangle().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("out/src/lib.rs")).unwrap(),
+            "use std::fmt;\n\npub const MAX: usize = 64;\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("out/tests/common.rs")).unwrap(),
+            "pub const MAX: usize = 64;\n\nuse super::*;\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("out/benches/common.rs")).unwrap(),
+            "// benches\n\npub const MAX: usize = 64;\n"
+        );
+    }
+
+    #[test]
+    fn test_also_needs_the_anchor_in_every_target() {
+        let (_temp_dir, root) = also_input("&after=use");
+        let error = Lit::new(root.clone(), root.join("out"))
+            .tangle()
+            .unwrap_err();
+        assert!(error.to_string().contains("use"), "{error}");
+    }
+
+    #[test]
+    fn test_also_is_not_a_duplicate_of_itself() {
+        let (_temp_dir, root) = also_input("");
+        assert!(
+            Lit::new(root.clone(), root.join("out"))
+                .duplicates(1, 1.0)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_also_targets_cannot_be_moved() {
+        let (_temp_dir, root) = also_input("");
+        let lit = Lit::new(root.clone(), root.join("out"));
+        let error = lit
+            .move_target(
+                Utf8Path::new("tests/common.rs"),
+                Utf8Path::new("tests/shared.rs"),
+                MovedOutput::Keep,
+            )
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("doc.md:5 lists it in ?also="),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_also_rejects_paths_outside_the_output() {
+        let markdown = "```tangle:///a.rs?also=../b.rs\n```\n";
+        assert!(Lit::parse_file(Utf8Path::new("doc.md"), markdown).is_err());
+    }
+
+    /// A source file, an executable script, and a binary file, and a `Lit` for them
+    fn mixed_files_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///src/a.rs\nfn a() {}\n```\n\n```tangle:///run.sh?chmod=755\n#!/bin/sh\n```\n\n```tangle:///icon.bin?encoding=base64\nAAE=\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_tangle_to_tarball_keeps_contents_and_modes() {
+        use std::io::Read as _;
+
+        let (_temp_dir, root, lit) = mixed_files_input();
+        let report = lit.tangle_to_archive(&root.join("gen.tar.gz")).unwrap();
+        assert_eq!(report.files.len(), 3);
+        assert!(!root.join("out").exists());
+        let tarball =
+            flate2::read::GzDecoder::new(fs::File::open(root.join("gen.tar.gz")).unwrap());
+        let mut archive = tar::Archive::new(tarball);
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            let path = entry.path().unwrap().display().to_string();
+            entries.push((path, entry.header().mode().unwrap(), content));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("icon.bin".to_string(), 0o644, vec![0, 1]),
+                ("run.sh".to_string(), 0o755, b"#!/bin/sh\n".to_vec()),
+                ("src/a.rs".to_string(), 0o644, b"fn a() {}\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tangle_to_archive_is_reproducible() {
+        let (_temp_dir, root, lit) = mixed_files_input();
+        lit.tangle_to_archive(&root.join("gen.tar")).unwrap();
+        let first = fs::read(root.join("gen.tar")).unwrap();
+        lit.tangle_to_archive(&root.join("gen.tar")).unwrap();
+        assert_eq!(fs::read(root.join("gen.tar")).unwrap(), first);
+        lit.tangle_to_archive(&root.join("gen.tgz")).unwrap();
+        lit.tangle_to_archive(&root.join("gen.tar.gz")).unwrap();
+        assert_eq!(
+            fs::read(root.join("gen.tgz")).unwrap(),
+            fs::read(root.join("gen.tar.gz")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tangle_to_zip_keeps_contents_and_modes() {
+        use std::io::Read as _;
+
+        let (_temp_dir, root, lit) = mixed_files_input();
+        lit.tangle_to_archive(&root.join("gen.zip")).unwrap();
+        let mut zip = zip::ZipArchive::new(fs::File::open(root.join("gen.zip")).unwrap()).unwrap();
+        assert_eq!(zip.file_names().count(), 3);
+        let mut script = zip.by_name("run.sh").unwrap();
+        assert_eq!(script.unix_mode().map(|mode| mode & 0o7777), Some(0o755));
+        let mut content = String::new();
+        script.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "#!/bin/sh\n");
+    }
+
+    #[test]
+    fn test_tangle_to_archive_selects_targets() {
+        let (_temp_dir, root, mut lit) = mixed_files_input();
+        lit.targets = vec![TargetGlob::new("src/**").unwrap()];
+        assert_eq!(
+            lit.tangle_to_archive(&root.join("src.zip"))
+                .unwrap()
+                .files
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_tangle_to_archive_rejects_unknown_extensions() {
+        let (_temp_dir, root, lit) = mixed_files_input();
+        let error = lit.tangle_to_archive(&root.join("gen.rar")).unwrap_err();
+        assert!(
+            matches!(&error, LitError::UnknownArchive { path } if path.ends_with("gen.rar")),
+            "{error}"
+        );
+        assert!(!root.join("gen.rar").exists());
+    }
+
+    /// A `src/main.rs` whose first fence has the parameters `url`, and a `Lit` for it
+    fn assert_input(url: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let markdown = format!(
+            "# Doc\n\n```tangle:///src/main.rs?{url}\nfn main() {{}}\n```\n\n```tangle:///src/main.rs\nuse a+b;\n```\n"
+        );
+        fs::write(root.join("doc.md"), markdown).unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_assert_passes_when_the_file_holds() {
+        let (_temp_dir, _root, lit) = assert_input(
+            "assert=contains:fn%20main&assert=matches:use%20a%5C%2Bb;&assert=min-lines:2&assert=max-lines:3",
+        );
+        assert!(lit.tangle_dry_run().is_ok());
+    }
+
+    #[test]
+    fn test_assert_fails_with_the_fence_location() {
+        for (url, failure) in [
+            ("assert=not-contains:use%20a+b", "contains \"use a+b\""),
+            ("assert=matches:%5Euse", "does not match /^use/"),
+            ("assert=max-lines:2", "has 3 lines, more than 2"),
+            ("assert=min-lines:4", "has 3 lines, fewer than 4"),
+        ] {
+            let (_temp_dir, _root, lit) = assert_input(url);
+            let error = lit.tangle_dry_run().unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("assertion failed: src/main.rs {failure}")
+            );
+            assert!(error.help().unwrap().to_string().contains("doc.md:3"));
+        }
+    }
+
+    #[test]
+    fn test_assert_checks_configured_assertions_first() {
+        let (_temp_dir, root, mut lit) = assert_input("assert=max-lines:2");
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[assert.\"src/main.rs\"]\nmatches = ['(?m)^fn main\\(\\)']\nmin_lines = 4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lit.tangle().unwrap_err().to_string(),
+            "assertion failed: src/main.rs has 3 lines, fewer than 4"
+        );
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn test_assert_rejects_a_configured_pattern_that_does_not_compile() {
+        let error = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[assert.\"a.rs\"]\nmatches = ['(']\n",
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("`(` in [assert.\"a.rs\"] is not a regular expression"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_assert_rejects_invalid_fence_assertions() {
+        for value in ["contains", "ends-with:x", "max-lines:many", "matches:("] {
+            let markdown = format!("```tangle:///a.rs?assert={value}\n```\n");
+            let error = Lit::parse_file(Utf8Path::new("doc.md"), &markdown).unwrap_err();
+            assert!(error.to_string().contains("Invalid assertion"), "{error}");
+        }
+    }
+
+    /// A `docs` input with images and fixtures, and a `Lit` bringing those matching `include`
+    fn assets_input(include: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs/images")).unwrap();
+        fs::create_dir_all(root.join("docs/fixtures")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "```tangle:///src/main.rs\nfn main() {}\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("docs/images/logo.svg"), "<svg/>\n").unwrap();
+        fs::write(root.join("docs/fixtures/one.json"), "{}\n").unwrap();
+        fs::write(root.join("docs/fixtures/notes.md"), "# Notes\n").unwrap();
+        fs::write(root.join("docs/.litignore"), "secret.json\n").unwrap();
+        fs::write(root.join("docs/fixtures/secret.json"), "{}\n").unwrap();
+        let mut lit = Lit::new(root.join("docs"), root.join("docs/out"));
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            &format!("[assets]\ninclude = {include}\n"),
+        )
+        .unwrap();
+        (temp_dir, root, lit)
+    }
+
+    const ASSET_GLOBS: &str = "[\"images/*\", \"fixtures/**\", \"out/**\"]";
+
+    #[test]
+    fn test_assets_skip_documents_ignored_files_and_the_output() {
+        let (_temp_dir, root, lit) = assets_input(ASSET_GLOBS);
+        let dry_run = lit.tangle_dry_run().unwrap();
+        let paths: Vec<_> = dry_run
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            ["src/main.rs", "fixtures/one.json", "images/logo.svg"]
+        );
+        assert!(!root.join("docs/out").exists());
+    }
+
+    #[test]
+    fn test_assets_are_bundled() {
+        let (_temp_dir, _root, lit) = assets_input(ASSET_GLOBS);
+        let mut bundle = Vec::new();
+        lit.tangle_to_bundle(&mut bundle).unwrap();
+        assert!(
+            String::from_utf8(bundle)
+                .unwrap()
+                .contains("lit-file 644 7 images/logo.svg\n<svg/>\n")
+        );
+    }
+
+    #[test]
+    fn test_assets_are_copied_and_recorded() {
+        let (_temp_dir, root, lit) = assets_input(ASSET_GLOBS);
+        assert_eq!(lit.tangle().unwrap().count(WriteStatus::Written), 3);
+        assert_eq!(
+            fs::read_to_string(root.join("docs/out/images/logo.svg")).unwrap(),
+            "<svg/>\n"
+        );
+        assert!(!root.join("docs/out/fixtures/notes.md").exists());
+        assert!(!root.join("docs/out/fixtures/secret.json").exists());
+        assert!(
+            Manifest::load(&root.join("docs/out"))
+                .unwrap()
+                .files
+                .contains(Utf8Path::new("images/logo.svg"))
+        );
+        assert_eq!(lit.tangle().unwrap().count(WriteStatus::Unchanged), 3);
+    }
+
+    #[test]
+    fn test_assets_replace_a_copy_with_a_link() {
+        let (_temp_dir, root, mut lit) = assets_input(ASSET_GLOBS);
+        lit.tangle().unwrap();
+        lit.config.assets.link = true;
+        fs::write(root.join("docs/images/logo.svg"), "<svg></svg>\n").unwrap();
+        lit.tangle().unwrap();
+        fs::write(root.join("docs/out/images/logo.svg"), "<svg>edited</svg>\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("docs/images/logo.svg")).unwrap(),
+            "<svg>edited</svg>\n"
+        );
+    }
+
+    #[test]
+    fn test_assets_do_not_overwrite_hand_written_files() {
+        let (_temp_dir, root, mut lit) = assets_input(ASSET_GLOBS);
+        lit.config.assets.link = true;
+        fs::create_dir_all(root.join("docs/out/fixtures")).unwrap();
+        fs::write(root.join("docs/out/fixtures/one.json"), "[]\n").unwrap();
+        assert!(
+            matches!(lit.tangle(), Err(LitError::WouldOverwrite { path }) if path == "fixtures/one.json")
+        );
+    }
+
+    #[test]
+    fn test_assets_conflict_with_tangled_files() {
+        let (_temp_dir, root, lit) = assets_input("[\"src/*\"]");
+        fs::create_dir_all(root.join("docs/src")).unwrap();
+        fs::write(root.join("docs/src/main.rs"), "fn main() {}\n").unwrap();
+        assert!(
+            matches!(lit.tangle(), Err(LitError::AssetConflict { path }) if path == "src/main.rs")
+        );
+    }
+
+    /// `a.rs` from a two-line block with an ID and a one-line block, and a `Lit` for it
+    fn blame_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "# Doc\n\n```tangle:///a.rs?id=a\nfn a() {}\nfn b() {}\n```\n\n```tangle:///a.rs\nfn c() {}\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_blame_finds_the_line_and_its_block() {
+        let (_temp_dir, root, lit) = blame_input();
+        assert_eq!(
+            lit.blame(Utf8Path::new("a.rs"), 2).unwrap().to_string(),
+            format!("{root}/doc.md:5 in the block at {root}/doc.md:3 (?id=a)")
+        );
+        assert_eq!(
+            lit.blame(Utf8Path::new("a.rs"), 4).unwrap().to_string(),
+            format!("{root}/doc.md:9 in the block at {root}/doc.md:8")
+        );
+    }
+
+    #[test]
+    fn test_blame_refuses_a_separator_line() {
+        let (_temp_dir, _root, lit) = blame_input();
+        let error = lit.blame(Utf8Path::new("a.rs"), 3).unwrap_err();
+        assert!(
+            matches!(error, LitError::NotFromBlock { line: 3, .. }),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_blame_counts_the_header() {
+        let (_temp_dir, _root, mut lit) = blame_input();
+        let blame = lit.blame(Utf8Path::new("a.rs"), 2).unwrap();
+        lit.config.header = true;
+        assert_eq!(lit.blame(Utf8Path::new("a.rs"), 4).unwrap(), blame);
+        assert!(lit.blame(Utf8Path::new("a.rs"), 1).is_err());
+    }
+
+    #[test]
+    fn test_blame_refuses_an_unknown_target() {
+        let (_temp_dir, _root, lit) = blame_input();
+        assert!(matches!(
+            lit.blame(Utf8Path::new("b.rs"), 1),
+            Err(LitError::UnknownTarget { .. })
+        ));
+    }
+
+    #[test]
+    fn test_build_script_tangle() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(
+            root.join("docs/lib.md"),
+            "```tangle:///lib.rs\npub fn f() {}\n```\n",
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        let lit = Lit::new(root.join("docs"), root.join("out"));
+        build::tangle_with(&lit, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "cargo:rerun-if-changed={root}/docs\ncargo:rerun-if-changed={root}/docs/lib.md\n"
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("out/lib.rs")).unwrap(),
+            "pub fn f() {}\n"
+        );
+
+        let report = build::tangle(root.join("docs"), root.join("out")).unwrap();
+        assert_eq!(report.files[0].status, WriteStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_tangle_to_bundle_writes_each_file_with_its_mode() {
+        let (_temp_dir, root, lit) = mixed_files_input();
+        let mut bundle = Vec::new();
+        assert_eq!(lit.tangle_to_bundle(&mut bundle).unwrap().files.len(), 3);
+        let mut expected = b"lit-bundle 1\nlit-file 644 2 icon.bin\n".to_vec();
+        expected.extend([0, 1]);
+        expected.extend(
+            b"\nlit-file 755 10 run.sh\n#!/bin/sh\n\nlit-file 644 10 src/a.rs\nfn a() {}\n\n",
+        );
+        assert_eq!(bundle, expected);
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let (_temp_dir, root, lit) = mixed_files_input();
+        assert_eq!(lit.tangle_dry_run().unwrap().count(WriteStatus::Written), 3);
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn test_dry_run_reports_only_changed_files() {
+        let (_temp_dir, root, lit) = mixed_files_input();
+        lit.tangle().unwrap();
+        fs::write(root.join("doc.md"), "```tangle:///src/a.rs\nfn a() {}\n```\n\n```tangle:///run.sh?chmod=755\n#!/bin/bash\n```\n\n```tangle:///icon.bin?encoding=base64\nAAE=\n```\n").unwrap();
+        let report = lit.tangle_dry_run().unwrap();
+        let written: Vec<_> = report
+            .files
+            .iter()
+            .filter(|file| file.status == WriteStatus::Written)
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(written, ["run.sh"]);
+        assert_eq!(
+            fs::read_to_string(root.join("out/run.sh")).unwrap(),
+            "#!/bin/sh\n"
+        );
+    }
+
+    #[test]
+    fn test_dry_run_refuses_to_overwrite_unless_forced() {
+        let (_temp_dir, root, mut lit) = mixed_files_input();
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join("out/extra.rs"), "// mine\n").unwrap();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///extra.rs\nfn b() {}\n```\n",
+        )
+        .unwrap();
+        assert!(
+            matches!(lit.tangle_dry_run(), Err(LitError::WouldOverwrite { path }) if path == "extra.rs")
+        );
+        lit.force = true;
+        assert_eq!(lit.tangle_dry_run().unwrap().count(WriteStatus::Written), 1);
+    }
+
+    #[test]
+    fn test_route() {
+        let config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[roots]\n\"crate\" = \".\"\n\"assets\" = \"static\"\n\"assets/fonts\" = \"static/type\"\n",
+        )
+        .unwrap();
+        let route = |path: &str| config.route(Utf8Path::new(path));
+
+        assert_eq!(route("crate/src/lib.rs"), "src/lib.rs");
+        assert_eq!(route("assets/logo.svg"), "static/logo.svg");
+        assert_eq!(route("assets/fonts/a.woff"), "static/type/a.woff");
+        assert_eq!(route("assetsx/a.txt"), "assetsx/a.txt");
+        assert_eq!(route("README.md"), "README.md");
+
+        for root in ["../up", "/abs"] {
+            let text = format!("[roots]\nx = \"{root}\"\n");
+            let error = Config::parse(Utf8Path::new("lit.toml"), &text).unwrap_err();
+            assert!(error.to_string().contains("root for `x`"), "{error}");
+        }
+        let error =
+            Config::parse(Utf8Path::new("lit.toml"), "[roots]\nx = \"static/nul\"\n").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("root for `x` is not a portable path"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_tangle_writes_under_roots() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///crate/src/main.rs\nfn main() {}\n```\n\n```tangle:///assets/a.css\np {}\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(input, root.join("out"));
+        lit.config
+            .roots
+            .insert("crate".to_string(), Utf8PathBuf::from("."));
+        lit.config
+            .roots
+            .insert("assets".to_string(), Utf8PathBuf::from("static"));
+        lit.tangle().unwrap();
+
+        assert!(root.join("out/src/main.rs").is_file());
+        assert!(root.join("out/static/a.css").is_file());
+        assert_eq!(
+            lit.extract(Utf8Path::new("static/a.css")).unwrap(),
+            "p {}\n"
+        );
+    }
+
+    #[test]
+    fn test_front_matter_prefixes_paths_with_variables() {
+        let markdown = r#"---
+title: Storage
+lit:
+  prefix: crates/{{name}}
+  variables:
+    name: storage
+---
+
+```tangle:///src/lib.rs
+pub mod db;
+```
+
+```tangle:///assets/{{name}}.css
+p {}
+```
+"#;
+        let files = tangle_document("doc.md", markdown).unwrap();
+        let paths: Vec<_> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "crates/storage/assets/storage.css",
+                "crates/storage/src/lib.rs"
+            ]
+        );
+        assert_eq!(files[1].blocks[0].origin.as_ref().unwrap().line, 9);
+    }
+
+    /// The front matter of `doc.md` when it is `text`
+    fn parse_front_matter(text: &str) -> std::result::Result<FrontMatter, ConfigError> {
+        FrontMatter::parse(Utf8Path::new("doc.md"), text)
+    }
+
+    /// A config that routes the top-level directory `a` to `routed`
+    fn routed_config() -> Config {
+        let mut config = Config::default();
+        config
+            .roots
+            .insert("a".to_string(), Utf8PathBuf::from("routed"));
+        config
+    }
+
+    #[test]
+    fn test_front_matter_output_replaces_the_route() {
+        let front = parse_front_matter("---\nlit:\n  output: vendor/./x\n---\n").unwrap();
+        assert_eq!(
+            front
+                .place(Utf8Path::new("a/b.rs"), &routed_config())
+                .unwrap(),
+            "vendor/x/a/b.rs"
+        );
+    }
+
+    #[test]
+    fn test_front_matter_without_lit_keys_changes_nothing() {
+        let plain = parse_front_matter("---\ntitle: x\n---\n").unwrap();
+        assert_eq!(plain, FrontMatter::default());
+        assert_eq!(
+            plain
+                .place(Utf8Path::new("a/b.rs"), &routed_config())
+                .unwrap(),
+            "routed/b.rs"
+        );
+        for text in [
+            "# No front matter\n",
+            "---\n---\n",
+            "---\nunclosed: true\n",
+            "\n---\nx: [\n---\n",
+        ] {
+            assert_eq!(
+                parse_front_matter(text).unwrap(),
+                FrontMatter::default(),
+                "{text}"
+            );
+        }
+    }
+
+    /// Where a front matter error's label points in the document, as an offset and a length
+    fn label_span(error: &ConfigError) -> Option<(usize, usize)> {
+        let label = error.labels()?.next()?;
+        Some((label.offset(), label.len()))
+    }
+
+    #[test]
+    fn test_front_matter_paths_must_be_inside_the_output_and_portable() {
+        let error = parse_front_matter("---\nlit:\n  prefix: ../up\n---\n").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("`prefix` must be inside the output"),
+            "{error}"
+        );
+        assert_eq!(label_span(&error), Some((4, 21)));
+        for text in [
+            "---\nlit:\n  prefix: CON\n---\n",
+            "---\nlit:\n  output: vendor/x.\n---\n",
+        ] {
+            let error = parse_front_matter(text).unwrap_err();
+            assert!(
+                error.to_string().contains("is not a portable path"),
+                "{error}"
+            );
+            assert_eq!(label_span(&error).map(|(offset, _)| offset), Some(4));
+        }
+    }
+
+    #[test]
+    fn test_front_matter_rejects_unknown_keys_and_invalid_yaml() {
+        let error = parse_front_matter("---\nlit:\n  prefx: a\n---\n").unwrap_err();
+        assert!(
+            error.to_string().contains("unknown field `prefx`"),
+            "{error}"
+        );
+        assert!(parse_front_matter("---\nx: [\n---\n").is_err());
+    }
+
+    #[test]
+    fn test_prefix_comment() {
+        let markdown = "# Parser\n\n<!-- lit: prefix=src/parser -->\n\n```tangle:///mod.rs\npub fn parse() {}\n```\n\n<!-- lit: prefix=ignored -->\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        assert_eq!(files[0].path, "src/parser/mod.rs");
+
+        let parse = |text: &str| FrontMatter::parse(Utf8Path::new("doc.md"), text);
+        let front = parse("---\ntitle: x\n---\n<!--lit: prefix=a -->\n").unwrap();
+        assert_eq!(front.prefix, Some(Utf8PathBuf::from("a")));
+        for text in [
+            "```sh\n<!-- lit: prefix=a -->\n```\n",
+            "    <!-- lit: prefix=a -->\n",
+            "<!-- other -->\n",
+        ] {
+            assert_eq!(parse(text).unwrap(), FrontMatter::default(), "{text}");
+        }
+
+        let error = parse("---\nlit:\n  prefix: a\n---\n<!-- lit: prefix=b -->\n").unwrap_err();
+        assert!(error.to_string().contains("set in both"), "{error}");
+        let error = parse("<!-- lit: output=a -->\n").unwrap_err();
+        assert!(
+            error.to_string().contains("unknown setting `output=a`"),
+            "{error}"
+        );
+        let error = parse("<!-- lit: prefix=../up -->\n").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("`<!-- lit: -->` comment `prefix` must be inside the output"),
+            "{error}"
+        );
+        let error = parse("---\ntitle: x\n---\n<!-- lit: prefix=src/aux -->\n").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("`<!-- lit: -->` comment `prefix` is not a portable path"),
+            "{error}"
+        );
+        assert!(!error.to_string().contains("front matter"), "{error}");
+        assert_eq!(label_span(&error), Some((17, 28)));
+    }
+
+    #[test]
+    fn test_stem_variable() {
+        let (_temp_dir, root) = temp_root();
+        let block = "```tangle:///src/{{stem}}.rs\npub fn f() {}\n```\n";
+        fs::write(root.join("parser.md"), block).unwrap();
+        fs::write(root.join("lexer.v2.md"), block).unwrap();
+        fs::write(
+            root.join("named.md"),
+            format!("---\nlit:\n  variables:\n    stem: main\n---\n{block}"),
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config
+            .variables
+            .insert("stem".to_string(), "shadowed".to_string());
+        let paths: BTreeSet<_> = lit
+            .read_blocks()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(
+            paths,
+            ["src/lexer.v2.rs", "src/main.rs", "src/parser.rs"]
+                .map(Utf8PathBuf::from)
+                .into()
+        );
+        assert_eq!(
+            lit.extract(Utf8Path::new("src/parser.rs")).unwrap(),
+            "pub fn f() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_config_parse_assertions() {
+        let config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            r#"
+[assert."src/main.rs"]
+contains = ["fn main"]
+not_contains = ["todo!()"]
+"#,
+        )
+        .unwrap();
+
+        let assertions = &config.assertions["src/main.rs"];
+        assert_eq!(assertions.contains, vec!["fn main".to_string()]);
+        assert_eq!(assertions.not_contains, vec!["todo!()".to_string()]);
+        assert_eq!(
+            Config::parse(Utf8Path::new("lit.toml"), "").unwrap(),
+            Config::default()
+        );
+    }
+
+    /// Two blocks each of `a.rs`, `a.yml`, and `list.md`, and a third `list.md` block with no separator
+    fn separators_input() -> (tempfile::TempDir, Lit) {
+        let (temp_dir, root) = temp_root();
+        let blocks =
+            |path: &str| format!("```tangle:///{path}\na\n```\n\n```tangle:///{path}\nb\n```\n\n");
+        let markdown = format!(
+            "{}{}{}```tangle:///list.md?sep=0\nc\n```\n",
+            blocks("a.rs"),
+            blocks("a.yml"),
+            blocks("list.md")
+        );
+        fs::write(root.join("doc.md"), markdown).unwrap();
+
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), "[separators]\nmd = 2\n").unwrap();
+        (temp_dir, lit)
+    }
+
+    #[test]
+    fn test_block_separators_by_extension_and_fence() {
+        let (_temp_dir, lit) = separators_input();
+        let files = lit.tangle_to_map().unwrap();
+        let file = |path: &str| files.get(Utf8Path::new(path)).map(String::as_str);
+        assert_eq!(file("a.rs"), Some("a\n\nb\n"));
+        assert_eq!(file("a.yml"), Some("a\nb\n"));
+        assert_eq!(file("list.md"), Some("a\n\n\nb\nc\n"));
+    }
+
+    #[test]
+    fn test_block_separators_are_not_any_block_lines() {
+        let (_temp_dir, lit) = separators_input();
+        let list = lit
+            .read_blocks()
+            .unwrap()
+            .into_iter()
+            .find(|file| file.path == "list.md")
+            .unwrap();
+        assert_eq!(list.line_ranges(), vec![(1, 1), (4, 4), (5, 5)]);
+        assert_eq!(list.origin_of_line(4).unwrap().line, 22);
+        assert_eq!(list.origin_of_line(2), None);
+    }
+
+    #[test]
+    fn test_block_separators_must_be_counts() {
+        let error = Lit::parse_markdown("```tangle:///a.txt?sep=-1\n```\n").unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid block separator: -1"),
+            "{error}"
+        );
+    }
+
+    /// Two documents that both declare `one` in `out.txt`, and a `Lit` merging it with `strategy`
+    fn merge_input(strategy: Option<MergeStrategy>) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///out.txt?id=one\na1\n```\n\n```tangle:///out.txt?id=two\na2\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            "```tangle:///out.txt?id=one&first\nb1\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        if let Some(strategy) = strategy {
+            lit.config.merge.insert("out.txt".to_string(), strategy);
+        }
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_merge_interleaves_by_default() {
+        let (_temp_dir, _root, lit) = merge_input(None);
+        // Interleaving sees `one` declared twice.
+        assert!(matches!(lit.read_blocks(), Err(LitError::Located(_))));
+    }
+
+    #[test]
+    fn test_merge_concatenates_documents_in_order() {
+        let (_temp_dir, _root, lit) = merge_input(None);
+        let lit = Lit {
+            config: Config::parse(
+                Utf8Path::new("lit.toml"),
+                "[merge]\n\"out.txt\" = \"concatenate\"\n",
+            )
+            .unwrap(),
+            ..lit
+        };
+        assert_eq!(lit.read_blocks().unwrap()[0].render(), "a1\n\na2\n\nb1\n");
+    }
+
+    #[test]
+    fn test_merge_error_names_every_document() {
+        let (_temp_dir, _root, lit) = merge_input(Some(MergeStrategy::Error));
+        let error = lit.read_blocks().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "out.txt is written by more than one document"
+        );
+        let help = error.help().unwrap().to_string();
+        assert!(help.contains("a.md, "), "{help}");
+        assert!(help.ends_with("b.md"), "{help}");
+    }
+
+    #[test]
+    fn test_merge_error_allows_a_single_document() {
+        let (_temp_dir, root, lit) = merge_input(Some(MergeStrategy::Error));
+        fs::remove_file(root.join("b.md")).unwrap();
+        assert_eq!(lit.read_blocks().unwrap()[0].render(), "a1\n\na2\n");
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let mut config = Config::default();
+        config
+            .variables
+            .insert("src_dir".to_string(), "lib/src".to_string());
+        config
+            .variables
+            .insert("abs".to_string(), "/etc".to_string());
+        let expand = |path: &str| config.expand_path(Utf8Path::new(path));
+
+        assert_eq!(expand("{{src_dir}}/main.rs").unwrap(), "lib/src/main.rs");
+        assert_eq!(
+            expand("{{src_dir}}/{{src_dir}}").unwrap(),
+            "lib/src/lib/src"
+        );
+        assert_eq!(expand("plain/{{unclosed").unwrap(), "plain/{{unclosed");
+        assert!(matches!(
+            expand("{{nope}}/main.rs"),
+            Err(BlockError::UnknownVariable(placeholder)) if placeholder == "{{nope}}"
+        ));
+        assert!(matches!(
+            expand("{{abs}}/passwd"),
+            Err(BlockError::InvalidPath)
+        ));
+    }
+
+    #[test]
+    fn test_tangle_expands_path_variables() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        let markdown = "# Doc\n\n```rust tangle:///{{src_dir}}/main.rs\nfn main() {}\n```\n";
+        fs::write(input.join("doc.md"), markdown).unwrap();
+
+        let mut lit = Lit::new(input.clone(), root.join("out"));
+        let error = lit.tangle().unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.line, 3);
+        let label = error.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &markdown[label.offset()..label.offset() + label.len()],
+            "{{src_dir}}"
+        );
+
+        lit.config
+            .variables
+            .insert("src_dir".to_string(), "src".to_string());
+        lit.tangle().unwrap();
+        assert!(root.join("out/src/main.rs").exists());
+    }
+
+    #[test]
+    fn test_config_errors_point_at_the_problem() {
+        let text = "[assert.\"a.rs\"]\ncontians = [\"x\"]\n";
+        let error = Config::parse(Utf8Path::new("lit.toml"), text).unwrap_err();
+        assert!(error.to_string().starts_with("invalid config:"), "{error}");
+
+        let label = error.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &text[label.offset()..label.offset() + label.len()],
+            "contians"
+        );
+    }
+
+    #[test]
+    fn test_config_load() {
+        let (_temp_dir, root) = temp_root();
+
+        fs::write(
+            root.join("lit.toml"),
+            "input = \"docs\"\noutput = \"/abs/out\"\n[assert.\"a.txt\"]\ncontains = [\"a\"]\n",
+        )
+        .unwrap();
+        let config = Config::load(&root.join("lit.toml")).unwrap();
+        assert!(config.assertions.contains_key("a.txt"));
+        assert_eq!(config.input, Some(root.join("docs")));
+        assert_eq!(config.output, Some(Utf8PathBuf::from("/abs/out")));
+
+        fs::write(root.join("bad.toml"), "assert = 1\n").unwrap();
+        assert!(matches!(
+            Config::load(&root.join("bad.toml")),
+            Err(LitError::Config(_))
+        ));
+        assert!(matches!(
+            Config::load(&root.join("missing.toml")),
+            Err(LitError::Io(_))
+        ));
+    }
+
+    /// A `main.rs` with a `todo!()`, and a `Lit` checking it against `assertions`
+    fn assertions_input(assertions: Assertions) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "# Doc\n\n```tangle:///main.rs\nfn main() {}\n```\n\n```tangle:///main.rs\ntodo!()\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(input, root.join("out"));
+        lit.config
+            .assertions
+            .insert("main.rs".to_string(), assertions);
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_assertions_are_read_from_the_config() {
+        let config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[assert.\"main.rs\"]\ncontains = [\"fn main\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.assertions["main.rs"].contains, ["fn main"]);
+    }
+
+    #[test]
+    fn test_assertions_pass_when_the_file_holds() {
+        let (_temp_dir, root, lit) = assertions_input(Assertions {
+            contains: vec!["fn main".to_string()],
+            ..Assertions::default()
+        });
+        lit.tangle().unwrap();
+        assert!(root.join("out/main.rs").exists());
+    }
+
+    #[test]
+    fn test_assertions_fail_before_writing() {
+        let (_temp_dir, root, lit) = assertions_input(Assertions {
+            contains: vec!["fn main".to_string(), "fn helper".to_string()],
+            ..Assertions::default()
+        });
+        let error = lit.tangle().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "assertion failed: main.rs does not contain \"fn helper\""
+        );
+        let help = error.help().unwrap().to_string();
+        assert!(help.contains("in/doc.md:3"), "{help}");
+        assert!(help.contains("in/doc.md:7"), "{help}");
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn test_assertions_can_forbid_text() {
+        let (_temp_dir, _root, lit) = assertions_input(Assertions {
+            not_contains: vec!["todo!()".to_string()],
+            ..Assertions::default()
+        });
+        let error = lit.tangle().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "assertion failed: main.rs contains \"todo!()\""
+        );
+    }
+
+    #[test]
+    fn test_reading_order_is_the_fallback() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs/a")).unwrap();
+        fs::write(
+            root.join("docs/a/z.md"),
+            "```tangle:///out.txt\nz1\n```\n\n```tangle:///out.txt?id=z\nz2\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("docs/b.md"), "```tangle:///out.txt\nb1\n```\n\n```tangle:///out.txt?first\nb2\n```\n\n```tangle:///out.txt?id=b\nb3\n```\n").unwrap();
+        fs::write(root.join("extra.md"), "```tangle:///out.txt\ne1\n```\n").unwrap();
+
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.inputs
+            .extend([root.join("extra.md"), root.join("docs/b.md")]);
+        assert_eq!(
+            lit.tangle_to_map().unwrap()[Utf8Path::new("out.txt")],
+            "b2\n\nz2\n\nb3\n\nz1\n\nb1\n\ne1\n"
+        );
+
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[merge]\n\"out.txt\" = \"concatenate\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lit.tangle_to_map().unwrap()[Utf8Path::new("out.txt")],
+            "z2\n\nz1\n\nb2\n\nb3\n\nb1\n\ne1\n"
+        );
+
+        lit.inputs.reverse();
+        assert_eq!(
+            lit.tangle_to_map().unwrap()[Utf8Path::new("out.txt")],
+            "b2\n\nb3\n\nb1\n\ne1\n\nz2\n\nz1\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_block_with_id_and_constraints() {
+        let markdown = r#"```tangle:///output.txt?id=main&last
+fn main() {}
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("output.txt"));
+        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "main");
+        assert_eq!(blocks[0].constraints.len(), 1);
+        assert!(matches!(blocks[0].constraints[0], Constraint::Last));
+    }
+
+    #[test]
+    fn test_query_values() {
+        let parse = |params: &str| {
+            let markdown = format!("```tangle:///a.rs?{params}\n```\n");
+            Lit::parse_file(Utf8Path::new("doc.md"), &markdown)
+                .map(|blocks| blocks.into_iter().next().unwrap())
+        };
+        assert_eq!(parse("lang=c++").unwrap().lang.as_deref(), Some("c++"));
+        assert_eq!(parse("lang=c%2B%2B").unwrap().lang.as_deref(), Some("c++"));
+        assert_eq!(parse("lang=a=b&&").unwrap().lang.as_deref(), Some("a=b"));
+        assert_eq!(
+            parse("lang=50%&from=a+b%20c.rs").unwrap().lang.as_deref(),
+            Some("50%")
+        );
+        assert_eq!(
+            parse("from=a+b%20c.rs").unwrap().from,
+            Some(Utf8PathBuf::from("a+b c.rs"))
+        );
+        assert_eq!(
+            parse("from=caf%C3%A9.rs").unwrap().from,
+            Some(Utf8PathBuf::from("café.rs"))
+        );
+        let block = parse("id=main&after=a%2Cb").unwrap();
+        assert_eq!(
+            block.constraints,
+            vec![Constraint::After(vec![
+                BlockId::new("a".to_string()).unwrap(),
+                BlockId::new("b".to_string()).unwrap()
+            ])]
+        );
+        let blocks = Lit::parse_file(
+            Utf8Path::new("doc.md"),
+            "```tangle:///a.rs?also=x+y.rs,a%26b.rs\n```\n",
+        )
+        .unwrap();
+        let paths: Vec<_> = blocks.iter().map(|block| block.path.as_str()).collect();
+        assert_eq!(paths, ["a.rs", "x+y.rs", "a&b.rs"]);
+
+        let error = parse("id=main&lang=%FF").unwrap_err();
+        assert!(error.to_string().contains("lang=%FF"), "{error}");
+        let url = Url::parse("tangle:///a.rs?lang&=x&%3D=%3D").unwrap();
+        assert_eq!(
+            query_params(&url),
+            [
+                ("lang".into(), "".into()),
+                ("".into(), "x".into()),
+                ("=".into(), "=".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_block_with_after_constraint() {
+        let markdown = r#"```tangle:///output.txt?id=b&after=a
+Second block
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "b");
+        match &blocks[0].constraints[0] {
+            Constraint::After(ids) => {
+                assert_eq!(ids.len(), 1);
+                assert_eq!(ids[0].as_str(), "a");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_block_with_multiple_after() {
+        let markdown = r#"```tangle:///output.txt?id=c&after=a,b
+Third block
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        match &blocks[0].constraints[0] {
+            Constraint::After(ids) => {
+                assert_eq!(ids.len(), 2);
+                assert_eq!(ids[0].as_str(), "a");
+                assert_eq!(ids[1].as_str(), "b");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_url_labels() {
+        let markdown = "```tangle:///a.rs?id=a#error%20handling\nfn a() {}\n```\n\n```tangle:///b.rs?include=#a\n```\n\n```rust tangle:///c.rs#\n```\n";
+        let blocks = Lit::parse_file(Utf8Path::new("doc.md"), markdown).unwrap();
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| block.label.as_deref())
+                .collect::<Vec<_>>(),
+            [Some("error handling"), None, None]
+        );
+        let files = tangle_document("doc.md", markdown).unwrap();
+        assert_eq!(files.first().unwrap().render(), "fn a() {}\n");
+
+        let error = tangle_document(
+            "doc.md",
+            "# Doc\n\n```tangle:///a.rs?after=nope#setup\n```\n",
+        )
+        .unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert_eq!(located.block_label.as_deref(), Some("setup"));
+        let labels: Vec<_> = located
+            .labels()
+            .unwrap()
+            .filter_map(|label| label.label().map(str::to_string))
+            .collect();
+        assert_eq!(labels, ["no block has this ID (#setup)"]);
+        assert_eq!(label_at("```rust\n", 0), None);
+    }
+
+    #[test]
+    fn test_block_id_display() {
+        let id = BlockId::new("my-block".to_string()).unwrap();
+        assert_eq!(format!("{id}"), "my-block");
+    }
+
+    #[test]
+    fn test_parse_block_with_before_constraint() {
+        let markdown = r#"```tangle:///output.txt?id=a&before=b
+First block
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "a");
+        match &blocks[0].constraints[0] {
+            Constraint::Before(ids) => {
+                assert_eq!(ids.len(), 1);
+                assert_eq!(ids[0].as_str(), "b");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_block_with_first_constraint() {
+        let markdown = r#"```tangle:///output.txt?id=lead&first
+First block
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "lead");
+        assert_eq!(blocks[0].constraints.len(), 1);
+        assert!(matches!(blocks[0].constraints[0], Constraint::First));
+    }
+
+    #[test]
+    fn test_parse_tangle_url_in_meta_string() {
+        let markdown = r#"```rust tangle:///src/main.rs?id=main&first
+fn main() {}
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("src/main.rs"));
+        assert_eq!(blocks[0].id.as_ref().unwrap().as_str(), "main");
+        assert!(matches!(blocks[0].constraints[0], Constraint::First));
+        assert_eq!(blocks[0].content, "fn main() {}");
+        assert_eq!(blocks[0].lang.as_deref(), Some("rust"));
+    }
+
+    #[test]
+    fn test_parse_block_language() {
+        let markdown = "```tangle:///a.rs?lang=rust\n```\n\n```tangle:///b.rs\n```\n\n```rust tangle:///c.rs?lang=ron\n```\n";
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        let langs: Vec<_> = blocks.iter().map(|block| block.lang.as_deref()).collect();
+        assert_eq!(langs, vec![Some("rust"), None, Some("ron")]);
+    }
+
+    #[test]
+    fn test_parse_meta_string_skips_other_words() {
+        let markdown = r#"```rust ignore title=main.rs tangle:///main.rs
+fn main() {}
+```
+
+```rust ignore https://example.com/main.rs
+fn main() {}
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("main.rs"));
+    }
+
+    #[test]
+    fn test_tangle_typo() {
+        let typo = |markdown: &str| {
+            let Node::Root(root) = to_mdast(markdown, &ParseOptions::default()).unwrap() else {
+                unreachable!()
+            };
+            let Some(Node::Code(code)) = root.children.first() else {
+                unreachable!()
+            };
+            tangle_typo(code).map(str::to_string)
+        };
+
+        assert_eq!(
+            typo("```tangl:///a.rs\n```"),
+            Some("tangl:///a.rs".to_string())
+        );
+        assert_eq!(
+            typo("```rust Tangle//a.rs\n```"),
+            Some("Tangle//a.rs".to_string())
+        );
+        assert_eq!(typo("```rust tangle:///a.rs\n```"), None);
+        assert_eq!(typo("```rust\n```"), None);
+        assert_eq!(typo("```\n```"), None);
+
+        // A typo is only a warning: the block is skipped as before.
+        assert!(
+            Lit::parse_markdown("```tangl:///a.rs\nx\n```\n")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_meta_string_error_is_located() {
+        let markdown = "```rust tangle:///main.rs?after=Main\nfn main() {}\n```\n";
+
+        let error = Lit::parse_markdown(markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        let label = located.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &markdown[label.offset()..label.offset() + label.len()],
+            "Main"
+        );
+    }
+
+    #[test]
+    fn test_parse_skip() {
+        let markdown = r#"```tangle:///a.txt?skip
+a
+```
+
+```tangle:///a.txt?skip=true
+b
+```
+
+```rust tangle:///a.txt no-tangle
+c
+```
+
+```tangle:///a.txt?skip=false
+d
+```
+
+```tangle:///a.txt
+e
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        let skipped: Vec<_> = blocks.iter().map(|b| b.skip).collect();
+        assert_eq!(skipped, vec![true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_parse_rejects_paths_invalid_on_windows() {
+        for (path, component) in [
+            ("CON", "CON"),
+            ("src/nul.txt", "nul.txt"),
+            ("Aux/lib.rs", "Aux"),
+            ("lpt9.log", "lpt9.log"),
+            ("notes.", "notes."),
+            ("a/b:c.rs", "b:c.rs"),
+            ("a|b", "a|b"),
+            ("src\\win\\path.rs", "src\\win\\path.rs"),
+            ("src/win\\path.rs", "win\\path.rs"),
+            ("C:/src/main.rs", "C:"),
+            ("out/d:", "d:"),
+            ("a%0Ab.rs", "a\nb.rs"),
+            ("src/%00.rs", "\0.rs"),
+            ("tab%09/x.rs", "tab\t"),
+        ] {
+            let block = Block::try_from(&Node::Code(Code {
+                value: String::new(),
+                position: None,
+                lang: Some(format!("tangle:///{path}")),
+                meta: None,
+            }));
+            match block {
+                Err(BlockError::NonPortablePath { component: c, .. }) => assert_eq!(c, component),
+                other => panic!("{path}: expected a non-portable path error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_accepts_portable_lookalikes() {
+        let markdown = r#"```tangle:///console.rs
+a
+```
+
+```tangle:///con-fig/comma.rs
+b
+```
+
+```tangle:///src/./lib.rs
+c
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_non_portable_path_error_is_located() {
+        let markdown = "```tangle:///src/aux.rs?id=aux\nx\n```\n";
+
+        let error = Lit::parse_markdown(markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        let label = located.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), 17);
+        assert_eq!(
+            &markdown[label.offset()..label.offset() + label.len()],
+            "aux.rs"
+        );
+        assert!(error.to_string().contains("reserved device name"));
+    }
+
+    #[test]
+    fn test_parse_block_invalid_scheme() {
+        // A code block that looks like a tangle URL but uses a non-tangle scheme
+        let markdown = r#"```https://example.com/file.txt
+code
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_block_host_in_tangle_url() {
+        let markdown = r#"```tangle://example.com/path.txt
+code
+```"#;
+
+        let result = Lit::parse_markdown(markdown);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("hostless"));
+    }
+
+    #[test]
+    fn test_parse_block_missing_path() {
+        let markdown = r#"```tangle:///
+code
+```"#;
+
+        let result = Lit::parse_markdown(markdown);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("missing path"));
+    }
+
+    #[test]
+    fn test_parse_block_invalid_path() {
+        let markdown = r#"```tangle:////etc/passwd
+code
+```"#;
+
+        let result = Lit::parse_markdown(markdown);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid tangle URL path")
+        );
+    }
+
+    #[test]
+    fn test_parse_block_percent_decodes_path() {
+        let markdown =
+            "```tangle:///My%20Notes.txt\na\n```\n\n```tangle:///docs/café/日本語.md\nb\n```\n";
+        let paths: Vec<_> = Lit::parse_markdown(markdown)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.path)
+            .collect();
+        assert_eq!(paths, vec!["My Notes.txt", "docs/café/日本語.md"]);
+
+        for path in ["%2E%2E%2Fa.rs", "%2Fetc/passwd", "a/%2e%2E%2Fb", "%FF.rs"] {
+            let markdown = format!("```tangle:///{path}\ncode\n```");
+            let error = Lit::parse_markdown(&markdown).unwrap_err();
+            assert!(
+                error.to_string().contains("Invalid tangle URL path"),
+                "{path}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_block_without_slashes() {
+        for markdown in [
+            "```tangle:a.rs\ncode\n```",
+            "```tangle:C:\\src\\a.rs\ncode\n```",
+        ] {
+            let result = Lit::parse_markdown(markdown);
+            assert!(
+                result
+                    .unwrap_err()
+                    .to_string()
+                    .contains("must start with tangle:///"),
+                "{markdown}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_block_empty_block_id() {
+        let markdown = r#"```tangle:///output.txt?id=
+code
+```"#;
+
+        let result = Lit::parse_markdown(markdown);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("cannot be empty"));
+    }
+
+    #[test]
+    fn test_parse_block_invalid_block_id() {
+        let markdown = r#"```tangle:///output.txt?id=UPPERCASE
+code
+```"#;
+
+        let result = Lit::parse_markdown(markdown);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("invalid"));
+    }
+
+    #[test]
+    fn test_parse_block_unknown_params_ignored() {
+        let markdown = r#"```tangle:///output.txt?unknown=value&also-unknown=123
+code
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].content, "code");
+        assert!(blocks[0].id.is_none());
+        assert!(blocks[0].constraints.is_empty());
+    }
+
+    #[test]
+    fn test_parse_error_points_at_bad_value() {
+        let markdown = "# Doc\n\n```tangle:///out.txt?id=Bad-Id&last\ncode\n```\n";
+
+        let error = Lit::parse_file(Utf8Path::new("doc.md"), markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.file, Utf8PathBuf::from("doc.md"));
+        assert_eq!(located.origin.line, 3);
+
+        let label = located.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &markdown[label.offset()..label.offset() + label.len()],
+            "Bad-Id"
+        );
+        assert_eq!(
+            error.code().unwrap().to_string(),
+            "lit::block_id::invalid_characters"
+        );
+        assert!(error.help().is_some());
+    }
+
+    #[test]
+    fn test_parse_error_without_value_underlines_info_string() {
+        let markdown = "~~~ tangle://host/out.txt\ncode\n~~~\n";
+
+        let error = Lit::parse_file(Utf8Path::new("doc.md"), markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        let label = located.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &markdown[label.offset()..label.offset() + label.len()],
+            "tangle://host/out.txt"
+        );
+        assert_eq!(label.label(), Some("tangle URL has a host"));
+    }
+
+    #[test]
+    fn test_located_error_renders_snippet() {
+        let markdown = "# Doc\n\n```tangle:///out.txt?id=\ncode\n```\n";
+        let error = Lit::parse_file(Utf8Path::new("docs/doc.md"), markdown).unwrap_err();
+
+        let mut rendered = String::new();
+        miette::GraphicalReportHandler::new_themed(miette::GraphicalTheme::unicode_nocolor())
+            .render_report(&mut rendered, &error)
+            .unwrap();
+
+        assert!(rendered.contains("docs/doc.md:3:"), "{rendered}");
+        assert!(rendered.contains("```tangle:///out.txt?id="), "{rendered}");
+        assert!(rendered.contains("cannot be empty"), "{rendered}");
+    }
+
+    #[test]
+    fn test_solver_errors_point_at_responsible_block() {
+        let (_temp_dir, input) = temp_root();
+        fs::write(
+            input.join("a.md"),
+            "```tangle:///out.txt?id=one\n1\n```\n\n```tangle:///out.txt?id=two&after=one,missing\n2\n```\n",
+        )
+        .unwrap();
+
+        let error = Lit::new(input.clone(), input.join("out"))
+            .read_blocks()
+            .unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.line, 5);
+        let label = located.labels().unwrap().next().unwrap();
+        let source = fs::read_to_string(input.join("a.md")).unwrap();
+        assert_eq!(
+            &source[label.offset()..label.offset() + label.len()],
+            "missing"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_id_points_at_second_declaration() {
+        let (_temp_dir, input) = temp_root();
+        fs::write(
+            input.join("b.md"),
+            "```tangle:///dup.txt?id=dup\n1\n```\n\n```tangle:///dup.txt?id=dup\n2\n```\n",
+        )
+        .unwrap();
+
+        let error = Lit::new(input.clone(), input.join("out"))
+            .read_blocks()
+            .unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert!(matches!(located.error, BlockError::DuplicateId { .. }));
+        assert_eq!(located.origin.line, 5);
+    }
+
+    #[test]
+    fn test_duplicate_id_across_files_names_both() {
+        let (_temp_dir, input) = temp_root();
+        fs::write(
+            input.join("a.md"),
+            "# A\n\n```tangle:///dup.txt?id=dup\n1\n```\n",
+        )
+        .unwrap();
+        fs::write(input.join("b.md"), "```tangle:///dup.txt?id=dup\n2\n```\n").unwrap();
+
+        let error = Lit::new(input.clone(), input.join("out"))
+            .read_blocks()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Duplicate block ID within file: dup (declared at {input}/a.md:3 and again at {input}/b.md:1)"
+            )
+        );
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.file, input.join("b.md"));
+        assert_eq!(located.related[0].origin.file, input.join("a.md"));
+    }
+
+    #[test]
+    fn test_cycle_error_is_not_located() {
+        let (_temp_dir, input) = temp_root();
+        fs::write(
+            input.join("a.md"),
+            "```tangle:///out.txt?id=a&after=b\n1\n```\n\n```tangle:///out.txt?id=b&after=a\n2\n```\n",
+        )
+        .unwrap();
+
+        let error = Lit::new(input.clone(), input.join("out"))
+            .read_blocks()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            LitError::Block(BlockError::UnsatisfiableConstraints)
+        ));
+    }
+
+    #[test]
+    fn test_solve_simple_constraint_ordering() {
+        let blocks = vec![
+            create_constrained_block(
+                "c",
+                vec![Constraint::After(vec![
+                    BlockId::new("b".to_string()).unwrap(),
+                ])],
+                "Third",
+            ),
+            create_constrained_block("a", vec![Constraint::First], "First"),
+            create_constrained_block(
+                "b",
+                vec![Constraint::After(vec![
+                    BlockId::new("a".to_string()).unwrap(),
+                ])],
+                "Second",
+            ),
+        ];
+
+        let sorted = solve_block_order(blocks).unwrap();
+        assert_eq!(sorted.len(), 3);
+        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "a");
+        assert_eq!(sorted[1].id.as_ref().unwrap().as_str(), "b");
+        assert_eq!(sorted[2].id.as_ref().unwrap().as_str(), "c");
+    }
+
+    #[test]
+    fn test_solve_circular_dependency() {
+        let blocks = vec![
+            create_constrained_block(
+                "a",
+                vec![Constraint::After(vec![
+                    BlockId::new("b".to_string()).unwrap(),
+                ])],
+                "A",
+            ),
+            create_constrained_block(
+                "b",
+                vec![Constraint::After(vec![
+                    BlockId::new("a".to_string()).unwrap(),
+                ])],
+                "B",
+            ),
+        ];
+
+        let result = solve_block_order(blocks);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Constraints are unsatisfiable")
+        );
+    }
+
+    #[test]
+    fn test_solve_unknown_block_id() {
+        let blocks = vec![create_constrained_block(
+            "a",
+            vec![Constraint::After(vec![
+                BlockId::new("unknown".to_string()).unwrap(),
+            ])],
+            "A",
+        )];
+
+        let result = solve_block_order(blocks);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Unknown block ID"));
+    }
+
+    #[test]
+    fn test_solve_orders_anonymous_blocks_with_constraints() {
+        let markdown = "```tangle:///a.rs?id=main\nmain\n```\n\n```tangle:///a.rs?id=imports&first\nimports\n```\n\n```tangle:///a.rs?before=main\nhelper\n```\n\n```tangle:///a.rs\nloose\n```\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        assert_eq!(files[0].render(), "imports\n\nhelper\n\nmain\n\nloose\n");
+
+        let markdown = "```tangle:///a.rs?after=missing\nx\n```\n";
+        assert!(tangle_document("doc.md", markdown).is_err());
+    }
+
+    #[test]
+    fn test_solve_appends_repeated_ids() {
+        let markdown = "```tangle:///a.rs?id=main\nmain\n```\n\n```tangle:///a.rs?id=deps&dup=append\nuse a;\n```\n\n```tangle:///a.rs?id=helper\nhelper\n```\n\n```tangle:///a.rs?id=deps&dup=append&first\nuse b;\n```\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        assert_eq!(files[0].render(), "use a;\n\nuse b;\n\nmain\n\nhelper\n");
+
+        // A repeat without `dup=append` is still an error, reported at that block.
+        let markdown = "```tangle:///a.rs?id=main\na\n```\n\n```tangle:///a.rs?id=main&dup=append\nb\n```\n\n```tangle:///a.rs?id=main\nc\n```\n";
+        let error = tangle_document("doc.md", markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert!(matches!(located.error, BlockError::DuplicateId { .. }));
+        assert_eq!(located.origin.line, 9);
+    }
+
+    #[test]
+    fn test_dup_rejects_unknown_values() {
+        let error = Lit::parse_markdown("```tangle:///a.rs?id=a&dup=content\n```\n").unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert!(matches!(&located.error, BlockError::UnknownDup(value) if value == "content"));
+    }
+
+    #[test]
+    fn test_solve_first_and_last() {
+        let blocks = vec![
+            create_constrained_block("middle", vec![], "Middle"),
+            create_constrained_block("first", vec![Constraint::First], "First"),
+            create_constrained_block("last", vec![Constraint::Last], "Last"),
+        ];
+
+        let sorted = solve_block_order(blocks).unwrap();
+        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "first");
+        assert_eq!(sorted[2].id.as_ref().unwrap().as_str(), "last");
+    }
+
+    #[test]
+    fn test_solve_duplicate_id() {
+        let blocks = vec![
+            create_constrained_block("dup", vec![], "First"),
+            create_constrained_block("dup", vec![], "Second"),
+        ];
+
+        let result = solve_block_order(blocks);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Duplicate"));
+    }
+
+    #[test]
+    fn test_solve_unknown_inside_block_id() {
+        let blocks = vec![Block {
+            path: Utf8PathBuf::from("test.txt"),
+            id: Some(BlockId::new("child".to_string()).unwrap()),
+            constraints: vec![],
+            inside: Some(BlockId::new("nonexistent".to_string()).unwrap()),
+            content: "content".to_string(),
+            origin: None,
+            skip: false,
+            append: false,
+            lang: None,
+            expand: true,
+            include: None,
+            label: None,
+            from: None,
+            lines: None,
+            base64: false,
+            mode: None,
+            sep: None,
+            encoding: None,
+            trim: None,
+            raw: false,
+            assertions: Vec::new(),
+        }];
+
+        let result = solve_block_order(blocks);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Unknown"));
+    }
+
+    #[test]
+    fn test_solve_empty_input() {
+        let blocks: Vec<Block> = vec![];
+        let sorted = solve_block_order(blocks).unwrap();
+        assert!(sorted.is_empty());
+    }
+
+    fn create_constrained_block(id: &str, constraints: Vec<Constraint>, content: &str) -> Block {
+        Block {
+            path: Utf8PathBuf::from("test.txt"),
+            id: Some(BlockId::new(id.to_string()).unwrap()),
+            constraints,
+            inside: None,
+            content: content.to_string(),
+            origin: None,
+            skip: false,
+            append: false,
+            lang: None,
+            expand: true,
+            include: None,
+            label: None,
+            from: None,
+            lines: None,
+            base64: false,
+            mode: None,
+            sep: None,
+            encoding: None,
+            trim: None,
+            raw: false,
+            assertions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_escaped_placeholder_and_expand_false() {
+        let markdown = "```tangle:///a.txt?id=p\n[{{}}] \\{{}}\n```\n\n```tangle:///a.txt?inside=p\nx\n```\n\n```tangle:///b.txt?id=v&expand=false\n{{}}\n```\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        assert_eq!(files[0].render(), "[x] {{}}\n");
+        assert_eq!(files[1].render(), "{{}}\n");
+
+        let markdown = format!("{markdown}\n```tangle:///b.txt?inside=v\ny\n```\n");
+        let error = tangle_document("doc.md", &markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert!(matches!(&located.error, BlockError::NotExpanded(id) if id.as_str() == "v"));
+        assert_eq!(located.origin.line, 13);
+    }
+
+    #[test]
+    fn test_surround_constraint() {
+        let markdown = r##"
+```tangle:///output.txt?id=wrapper
+struct Foo;
+
+{{}}
+```
+
+```tangle:///output.txt?id=impl1&inside=wrapper
+impl Foo {
+    fn bar(&self) {}
+}
+```
+
+```tangle:///output.txt?id=impl2&inside=wrapper
+impl Foo {
+    fn baz(&self) {}
+}
+```
+"##;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 3);
+
+        let sorted = solve_block_order(blocks).unwrap();
+        assert_eq!(sorted.len(), 1); // Surrounded blocks merged into wrapper
+
+        let content = &sorted[0].content;
+        assert!(content.contains("struct Foo;"));
+        assert!(content.contains("fn bar(&self) {}"));
+        assert!(content.contains("fn baz(&self) {}"));
+        assert!(!content.contains("{{}}")); // Placeholder replaced
+    }
+
+    #[test]
+    fn test_surround_preserves_order() {
+        let markdown = r##"
+```tangle:///output.txt?id=wrapper&first
+fn main() {
+    {{}}
+}
+```
+
+```tangle:///output.txt?id=body1&inside=wrapper
+println!("Hello");
+```
+
+```tangle:///output.txt?id=body2&inside=wrapper&after=body1
+println!("World");
+```
+
+```tangle:///output.txt?id=after&after=wrapper&last
+// End
+```
+"##;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        let sorted = solve_block_order(blocks).unwrap();
+
+        assert_eq!(sorted.len(), 2); // wrapper (with surrounded) and after
+        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "wrapper");
+        assert_eq!(sorted[1].id.as_ref().unwrap().as_str(), "after");
+
+        let wrapper_content = &sorted[0].content;
+        assert!(wrapper_content.contains("println!(\"Hello\")"));
+        assert!(wrapper_content.contains("println!(\"World\")"));
+
+        // Check order of surrounded blocks
+        let hello_pos = wrapper_content.find("Hello").unwrap();
+        let world_pos = wrapper_content.find("World").unwrap();
+        assert!(hello_pos < world_pos);
+    }
+
+    #[test]
+    fn test_surround_block_without_children() {
+        // A block with an id but no blocks inside=it should pass through unchanged;
+        // exercises the else branch in apply_surrounds (id present, no children)
+        let blocks = vec![Block {
+            path: Utf8PathBuf::from("test.txt"),
+            id: Some(BlockId::new("only".to_string()).unwrap()),
+            constraints: vec![],
+            inside: None,
+            content: "only block".to_string(),
+            origin: None,
+            skip: false,
+            append: false,
+            lang: None,
+            expand: true,
+            include: None,
+            label: None,
+            from: None,
+            lines: None,
+            base64: false,
+            mode: None,
+            sep: None,
+            encoding: None,
+            trim: None,
+            raw: false,
+            assertions: Vec::new(),
+        }];
+
+        let result = apply_surrounds(blocks);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].id.as_ref().unwrap().as_str(), "only");
+        assert_eq!(result[0].content, "only block");
+    }
+
+    /// A daemon for two documents, each tangling one file
+    fn daemon_input() -> (tempfile::TempDir, Utf8PathBuf, Daemon) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.rs?id=a\nfn a() {}\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("b.md"), "```tangle:///b.rs\nfn b() {}\n```\n").unwrap();
+        let daemon = Daemon::new(Lit::new(root.clone(), root.join("out")));
+        (temp_dir, root, daemon)
+    }
+
+    /// The daemon's reply to a request
+    fn daemon_request(
+        daemon: &Daemon,
+        id: usize,
+        method: &str,
+        params: serde_json::Value,
+    ) -> serde_json::Value {
+        daemon.handle(&serde_json::json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})).unwrap()
+    }
+
+    #[test]
+    fn test_daemon_tangles_and_checks() {
+        let (_temp_dir, _root, daemon) = daemon_input();
+        assert_eq!(
+            daemon_request(&daemon, 1, "check", serde_json::Value::Null)["result"]["stale"],
+            serde_json::json!(["a.rs", "b.rs"])
+        );
+        let reply = daemon_request(&daemon, 2, "tangle", serde_json::Value::Null);
+        assert_eq!(
+            reply["result"]["files"][0],
+            serde_json::json!({"path": "a.rs", "status": "written", "bytes": 10, "blocks": 1})
+        );
+        assert_eq!(
+            daemon_request(&daemon, 3, "check", serde_json::Value::Null)["result"]["stale"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn test_daemon_caches_documents() {
+        let (_temp_dir, root, daemon) = daemon_input();
+        daemon_request(&daemon, 1, "tangle", serde_json::Value::Null);
+        assert_eq!(
+            daemon.lit.cache.as_ref().unwrap().paths(),
+            [root.join("a.md"), root.join("b.md")]
+        );
+    }
+
+    #[test]
+    fn test_daemon_rereads_edited_and_deleted_documents() {
+        let (_temp_dir, root, daemon) = daemon_input();
+        daemon_request(&daemon, 1, "tangle", serde_json::Value::Null);
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.rs?id=a\nfn a2() {}\n```\n",
+        )
+        .unwrap();
+        fs::remove_file(root.join("b.md")).unwrap();
+        let reply = daemon_request(&daemon, 2, "extract", serde_json::json!({"target": "a.rs"}));
+        assert_eq!(reply["result"]["content"], "fn a2() {}\n");
+        assert_eq!(
+            daemon.lit.cache.as_ref().unwrap().paths(),
+            [root.join("a.md")]
+        );
+        assert_eq!(
+            daemon_request(&daemon, 3, "inspect", serde_json::Value::Null)["result"][0]["target"],
+            "a.rs"
+        );
+    }
+
+    #[test]
+    fn test_daemon_reports_errors_with_their_locations() {
+        let (_temp_dir, root, daemon) = daemon_input();
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.rs?id=a\n```\n\n```tangle:///a.rs?id=a\n```\n",
+        )
+        .unwrap();
+        let reply = daemon_request(&daemon, 1, "tangle", serde_json::Value::Null);
+        assert_eq!(reply["error"]["code"], -32000);
+        assert_eq!(
+            reply["error"]["data"][0]["file"],
+            root.join("a.md").as_str()
+        );
+        assert_eq!(reply["error"]["data"][0]["line"], 4);
+    }
+
+    #[test]
+    fn test_daemon_rejects_bad_requests_and_ignores_notifications() {
+        let (_temp_dir, _root, daemon) = daemon_input();
+        assert_eq!(
+            daemon_request(&daemon, 1, "extract", serde_json::Value::Null)["error"]["code"],
+            -32602
+        );
+        assert_eq!(
+            daemon_request(&daemon, 2, "weave", serde_json::Value::Null)["error"]["code"],
+            -32601
+        );
+        assert_eq!(
+            daemon.handle(&serde_json::json!({"jsonrpc": "2.0", "method": "tangle"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_daemon_serves_until_shutdown() {
+        let (_temp_dir, _root, daemon) = daemon_input();
+        let mut input = Vec::new();
+        for (id, method) in [(1, "inspect"), (2, "shutdown"), (3, "tangle")] {
+            write_message(
+                &mut input,
+                &serde_json::json!({"jsonrpc": "2.0", "id": id, "method": method}),
+            )
+            .unwrap();
+        }
+        let mut output = Vec::new();
+        assert!(
+            daemon
+                .serve(&mut std::io::Cursor::new(input), &mut output)
+                .unwrap()
+        );
+        let mut output = std::io::Cursor::new(output);
+        assert_eq!(read_message(&mut output).unwrap().unwrap()["id"], 1);
+        assert_eq!(
+            read_message(&mut output).unwrap().unwrap()["result"],
+            serde_json::Value::Null
+        );
+        assert_eq!(read_message(&mut output).unwrap(), None);
+    }
+
+    #[test]
+    fn test_daemon_stops_at_the_end_of_input() {
+        let (_temp_dir, _root, daemon) = daemon_input();
+        assert!(
+            !daemon
+                .serve(&mut std::io::Cursor::new(Vec::new()), &mut Vec::new())
+                .unwrap()
+        );
+    }
+
+    /// A document with a misspelled tangle URL, and a quiet `Lit` for it that selects no target
+    fn diagnostics_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "# Doc\n\n```tangl:///a.rs\n```\n\n```tangle:///b.rs\nfn b() {}\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.targets = vec![TargetGlob::new("*.py").unwrap()];
+        lit.diagnostics.quiet();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_diagnostics_are_collected_in_order() {
+        let (_temp_dir, root, lit) = diagnostics_input();
+        lit.tangle_dry_run().unwrap();
+        let messages = lit.diagnostics.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].to_string(),
+            format!(
+                "{root}/doc.md:3: warning[not-tangle-url] `tangl:///a.rs` is not a tangle URL, so this block is not tangled"
+            )
+        );
+        assert_eq!(
+            serde_json::json!(messages[1]),
+            serde_json::json!({"level": "warning", "code": "unmatched-target", "message": "No destination matches --target *.py", "file": null, "line": null})
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_denied_code_fails_before_writing() {
+        let (_temp_dir, root, lit) = diagnostics_input();
+        lit.diagnostics.deny("unmatched-target");
+        let error = lit.tangle().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1 denied warnings; the first is warning[unmatched-target] No destination matches --target *.py"
+        );
+        assert!(!root.join("out/b.rs").exists());
+    }
+
+    #[test]
+    fn test_diagnostics_other_codes_are_not_fatal() {
+        let (_temp_dir, _root, lit) = diagnostics_input();
+        lit.diagnostics.deny("skipped-source");
+        assert!(lit.tangle_dry_run().is_ok());
+    }
+
+    #[test]
+    fn test_diagnostics_deny_warnings_denies_every_warning_but_not_notes() {
+        let (_temp_dir, _root, lit) = diagnostics_input();
+        lit.diagnostics.note("asset-copied", "a note");
+        lit.tangle_dry_run().unwrap();
+        lit.diagnostics.deny("warnings");
+        assert!(matches!(
+            lit.diagnostics.check(),
+            Err(LitError::DeniedWarnings { count: 2, .. })
+        ));
+    }
+
+    /// Documents that repeat a three-line header and nearly repeat an eleven-line function
+    fn duplicates_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("tests")).unwrap();
+        let header = "// one\n// two\n\n// three\n";
+        fs::write(
+            root.join("docs/a.md"),
+            format!("```tangle:///src/a.rs?id=header\n{header}```\n\n```tangle:///src/a.rs\n}}\n```\n\n```tangle:///src/a.rs\nfn f() {{\n    1\n    2\n    3\n    4\n    5\n    6\n    7\n    8\n    9\n}}\n```\n"),
+        )
+        .unwrap();
+        fs::write(
+            root.join("docs/b.md"),
+            "```tangle:///src/b.rs\n  // one\n  // two\n  // three  \n```\n\n```tangle:///src/b.rs\n}\n```\n\n```tangle:///src/b.rs?include=a.md#header\n// one\n// two\n// three\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("tests/c.md"),
+            format!("```tangle:///tests/c.rs\n{header}```\n\n```tangle:///tests/c.rs\nfn f() {{\n    1\n    2\n    3\n    4\n    5\n    6\n    7\n    8\n    10\n}}\n```\n\n```tangle:///tests/c.rs?skip\n{header}```\n"),
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_duplicates_groups_identical_blocks_ignoring_blank_lines_and_indentation() {
+        let (_temp_dir, root, lit) = duplicates_input();
+        let groups = lit.duplicates(3, 1.0).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].to_string(),
+            format!(
+                "3 identical blocks, 3 lines each, 6 lines to save\n  {root}/docs/a.md:1  src/a.rs  ?id=header\n  {root}/docs/b.md:1  src/b.rs  ?include=a.md#header\n  {root}/tests/c.md:1  tests/c.rs  ?include=../docs/a.md#header\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_duplicates_groups_similar_blocks_largest_first() {
+        let (_temp_dir, root, lit) = duplicates_input();
+        let groups = lit.duplicates(3, 0.9).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[0].to_string(),
+            format!(
+                "2 similar blocks, 11 lines each, 11 lines to save\n  {root}/docs/a.md:12  src/a.rs  ?id=ID\n  {root}/tests/c.md:8  tests/c.rs  ?include=../docs/a.md#ID\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_duplicates_skips_short_blocks() {
+        let (_temp_dir, _root, lit) = duplicates_input();
+        assert!(lit.duplicates(12, 0.9).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_relative_to_strips_the_directory() {
+        assert_eq!(
+            relative_to(Utf8Path::new("/p/docs/a.md"), Utf8Path::new("/p/docs")),
+            "a.md"
+        );
+    }
+
+    #[test]
+    fn test_base64_blocks_are_written_as_bytes() {
+        let (_temp_dir, root) = temp_root();
+        let markdown = "```tangle:///icon.bin?encoding=base64&id=a\nAAEC\n  /w==\n```\n\n```tangle:///icon.bin?encoding=base64\nKg==\n```\n";
+        fs::write(root.join("doc.md"), markdown).unwrap();
+
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config.header = true;
+        let report = lit.tangle().unwrap();
+        assert_eq!(
+            fs::read(root.join("out/icon.bin")).unwrap(),
+            [0, 1, 2, 255, 42]
+        );
+        assert_eq!(report.files[0].bytes, 5);
+        assert_eq!(
+            lit.tangle().unwrap().files[0].status,
+            WriteStatus::Unchanged
+        );
+        assert_eq!(
+            lit.tangle_to_map().unwrap()[Utf8Path::new("icon.bin")],
+            "AAEC\n  /w==\n\nKg==\n"
+        );
+
+        fs::write(
+            root.join("doc.md"),
+            format!("{markdown}\n```tangle:///icon.bin\ntext\n```\n"),
+        )
+        .unwrap();
+        let error = lit.tangle().unwrap_err();
+        assert!(
+            matches!(&error, LitError::MixedEncoding { path } if path == "icon.bin"),
+            "{error}"
+        );
+    }
+
+    /// A `Lit` that writes `.bat` files with a byte-order mark, for a document tangling `markdown`
+    fn encodings_input(markdown: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), markdown).unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[encodings]\nbat = \"utf-8-bom\"\n",
+        )
+        .unwrap();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_text_encodings_are_written_by_fence_and_extension() {
+        let (_temp_dir, root, lit) = encodings_input(
+            "```tangle:///a.txt?encoding=utf-16\nhé\n```\n\n```tangle:///b.bat\n@echo\n```\n\n```tangle:///c.txt?encoding=latin-1\né\n```\n\n```tangle:///d.txt\nd\n```\n\n```tangle:///d.txt?encoding=utf-16be\nx\n```\n\n```tangle:///e.txt?encoding=utf-16le\ne\n```\n",
+        );
+        lit.tangle().unwrap();
+        let read = |path: &str| fs::read(root.join("out").join(path)).unwrap();
+        assert_eq!(read("a.txt"), [0xFF, 0xFE, b'h', 0, 0xE9, 0, b'\n', 0]);
+        assert_eq!(read("b.bat"), b"\xEF\xBB\xBF@echo\n");
+        assert_eq!(read("c.txt"), [0xE9, b'\n']);
+        assert_eq!(
+            read("d.txt"),
+            [0, b'd', 0, b'\n', 0, b'\n', 0, b'x', 0, b'\n']
+        );
+        assert_eq!(read("e.txt"), [b'e', 0, b'\n', 0]);
+        assert!(
+            lit.tangle()
+                .unwrap()
+                .files
+                .iter()
+                .all(|file| file.status == WriteStatus::Unchanged)
+        );
+    }
+
+    #[test]
+    fn test_text_encodings_are_decoded_by_extract() {
+        let (_temp_dir, _root, lit) =
+            encodings_input("```tangle:///a.txt?encoding=utf-16\nhé\n```\n");
+        lit.tangle().unwrap();
+        assert_eq!(lit.extract(Utf8Path::new("a.txt")).unwrap(), "hé\n");
+    }
+
+    #[test]
+    fn test_text_encodings_refuse_characters_they_cannot_encode() {
+        let (_temp_dir, _root, lit) =
+            encodings_input("```tangle:///c.txt?encoding=latin-1\n→\n```\n");
+        let error = lit.tangle().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "c.txt contains '→', which latin-1 cannot encode"
+        );
+        assert_eq!(TextEncoding::Latin1.encode("→".to_string()), b"?");
+    }
+
+    #[test]
+    fn test_text_encodings_config_rejects_unknown_encodings() {
+        let error = Config::parse(Utf8Path::new("lit.toml"), "[encodings]\ntxt = \"ebcdic\"\n")
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("unknown encoding `ebcdic`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_base64_errors_point_at_the_fence() {
+        let error = tangle_document(
+            "doc.md",
+            "# Doc\n\n```tangle:///a.bin?encoding=base64\nnot base64!\n```\n",
+        )
+        .unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(matches!(&located.error, BlockError::InvalidBase64(_)));
+        assert_eq!(located.origin.line, 3);
+
+        let error =
+            tangle_document("doc.md", "```tangle:///a.bin?encoding=hex\n00\n```\n").unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(
+            matches!(&located.error, BlockError::UnknownEncoding(encoding) if encoding == "hex")
+        );
+    }
+
+    #[test]
+    fn test_env_is_off_by_default() {
+        let path = "$LIT_TEST_UNSET/{{env.LIT_TEST_UNSET}}".to_string();
+        assert_eq!(
+            Config::default().expand_env_path(path.clone()).unwrap(),
+            path
+        );
+    }
+
+    #[test]
+    fn test_env_paths_fail_on_unset_variables() {
+        let config = Config {
+            env: true,
+            ..Config::default()
+        };
+        let path = "$LIT_TEST_UNSET/{{env.LIT_TEST_UNSET}}".to_string();
+        assert!(
+            matches!(config.expand_env_path(path), Err(BlockError::UnsetEnv(name)) if name == "{{env.LIT_TEST_UNSET}}")
+        );
+        assert!(matches!(
+            config.expand_env_path("a/${LIT_TEST_UNSET}".to_string()),
+            Err(BlockError::UnsetEnv(name)) if name == "${LIT_TEST_UNSET}"
+        ));
+    }
+
+    #[test]
+    fn test_env_paths_keep_a_bare_dollar() {
+        let config = Config {
+            env: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.expand_env_path("a/$/b$".to_string()).unwrap(),
+            "a/$/b$"
+        );
+    }
+
+    #[test]
+    fn test_env_skips_raw_blocks() {
+        let config = Config {
+            env: true,
+            ..Config::default()
+        };
+        let markdown = "```tangle:///a.txt\n{{env.LIT_TEST_UNSET}}\n```\n\n```tangle:///b.txt?raw=true\n{{env.LIT_TEST_UNSET}}\n```\n";
+        let mut blocks = Lit::parse_file(Utf8Path::new("doc.md"), markdown).unwrap();
+        assert!(config.expand_env(&mut blocks[1]).is_ok());
+        assert!(matches!(
+            config.expand_env(&mut blocks[0]),
+            Err(BlockError::UnsetEnv(_))
+        ));
+    }
+
+    #[test]
+    fn test_env_is_off_in_a_sandbox() {
+        let mut config = Config {
+            env: true,
+            ..Config::default()
+        };
+        config.sandbox();
+        let mut blocks = Lit::parse_file(
+            Utf8Path::new("doc.md"),
+            "```tangle:///a.txt\n{{env.LIT_TEST_UNSET}}\n```\n",
+        )
+        .unwrap();
+        assert!(config.expand_env(&mut blocks[0]).is_ok());
+    }
+
+    /// A document, a target list and two package manifests beside it, and a `Lit` expecting `expect`
+    fn expect_input(expect: &[&str]) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "```tangle:///src/lib.rs\npub mod parse;\n```\n\n```tangle:///tests/fixtures/one.txt\n1\n```\n\n```tangle:///src/extra.rs\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("targets.txt"),
+            "# Modules\nsrc/lib.rs\n./src/parse.rs\n\ntests/fixtures/**\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"a\"\nbuild = \"build.rs\"\n\n[lib]\npath = \"src/lib.rs\"\n\n[[bin]]\nname = \"a\"\npath = \"./src/main.rs\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("package.json"),
+            r#"{"main": "./index.js", "bin": {"a": "bin/a.js"}}"#,
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.config.expect = expect.iter().map(|path| root.join(path)).collect();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_expected_targets_are_relative_to_the_config() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("lit.toml"), "expect = [\"targets.txt\"]\n").unwrap();
+        assert_eq!(
+            Config::load(&root.join("lit.toml")).unwrap().expect,
+            [root.join("targets.txt")]
+        );
+    }
+
+    #[test]
+    fn test_expected_targets_report_missing_and_unexpected_files() {
+        let (_temp_dir, root, lit) = expect_input(&["targets.txt"]);
+        let error = lit.tangle().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the tangled files are not the expected ones: missing src/parse.rs; not expected src/extra.rs"
+        );
+        assert!(!root.join("out").exists());
+        let LitError::UnexpectedTargets {
+            missing,
+            unexpected,
+            ..
+        } = error
+        else {
+            panic!("expected UnexpectedTargets");
+        };
+        assert_eq!(
+            (missing, unexpected),
+            (
+                vec![Utf8PathBuf::from("src/parse.rs")],
+                vec![Utf8PathBuf::from("src/extra.rs")]
+            )
+        );
+    }
+
+    #[test]
+    fn test_expected_targets_from_manifests_are_only_entry_points() {
+        let (_temp_dir, _root, lit) = expect_input(&["Cargo.toml", "package.json"]);
+        let Err(LitError::UnexpectedTargets {
+            missing,
+            unexpected,
+            ..
+        }) = lit.tangle_dry_run()
+        else {
+            panic!("expected UnexpectedTargets");
+        };
+        assert_eq!(
+            missing,
+            ["bin/a.js", "build.rs", "index.js", "src/main.rs"].map(Utf8PathBuf::from)
+        );
+        assert!(unexpected.is_empty());
+    }
+
+    #[test]
+    fn test_expected_targets_refuse_an_invalid_manifest() {
+        let (_temp_dir, root, lit) = expect_input(&["package.json"]);
+        fs::write(root.join("package.json"), "{").unwrap();
+        assert!(
+            matches!(lit.tangle_dry_run(), Err(LitError::InvalidExpect { path, .. }) if path == root.join("package.json"))
+        );
+    }
+
+    #[test]
+    fn test_format_markdown() {
+        let markdown = r#"Prose with trailing spaces stays.␠␠
+
+```rust tangle:///src/main.rs?last&&id=main linenos
+fn main() {␠␠
+⇥println!("hi");⇥
+}
+`````
+
+```python
+x = 1␠␠␠
+```
+
+  ~~~tangle:///a.txt?skip&x=1&after=b&id=a
+text␠
+  ~~~~
+
+> ```tangle:///quoted.rs?last&id=q
+> ```
+
+```tangle:///open.txt?first&id=o
+unclosed␠␠␠"#
+            .replace('␠', " ")
+            .replace('⇥', "\t");
+
+        let formatted = format_markdown(&markdown).unwrap();
+        assert_eq!(
+            formatted,
+            r#"Prose with trailing spaces stays.␠␠
+
+```rust tangle:///src/main.rs?id=main&last linenos
+fn main() {
+⇥println!("hi");
+}
+```
+
+```python
+x = 1␠␠␠
+```
+
+  ~~~tangle:///a.txt?id=a&after=b&skip&x=1
+text
+  ~~~
+
+> ```tangle:///quoted.rs?last&id=q
+> ```
+
+```tangle:///open.txt?id=o&first
+unclosed"#
+                .replace('␠', " ")
+                .replace('⇥', "\t")
+        );
+        assert_eq!(format_markdown(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_markdown_keeps_raw_blocks_byte_for_byte() {
+        let markdown =
+            "```diff tangle:///fix.patch?raw=true&id=fix\n@@ -1,2 +1,2 @@\n \n-a\t\n+b\n```\n";
+        assert_eq!(
+            format_markdown(markdown).unwrap(),
+            "```diff tangle:///fix.patch?id=fix&raw=true\n@@ -1,2 +1,2 @@\n \n-a\t\n+b\n```\n"
+        );
+        let markdown = "```tangle:///a.txt?raw=false\na \n```\n";
+        assert_eq!(
+            format_markdown(markdown).unwrap(),
+            "```tangle:///a.txt?raw=false\na\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_canonical_url_orders_and_drops_empty_parameters() {
+        assert_eq!(canonical_url("tangle:///a.rs?&"), "tangle:///a.rs");
+        assert_eq!(canonical_url("tangle:///a.rs"), "tangle:///a.rs");
+        assert_eq!(
+            canonical_url("tangle:///a.rs?skip&include=b.md#c"),
+            "tangle:///a.rs?include=b.md&skip#c"
+        );
+        assert_eq!(canonical_url("tangle:///a.rs?#c"), "tangle:///a.rs#c");
+    }
+
+    /// One unformatted document and one formatted, and a `Lit` for them
+    fn format_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(root.join("a.md"), "```tangle:///a.txt?last&id=a\na\n```\n").unwrap();
+        fs::write(root.join("b.md"), "```tangle:///b.txt?id=b\nb\n```\n").unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_format_check_lists_files_without_changing_them() {
+        let (_temp_dir, root, lit) = format_input();
+        assert_eq!(lit.format(true).unwrap(), vec![root.join("a.md")]);
+        assert!(
+            fs::read_to_string(root.join("a.md"))
+                .unwrap()
+                .contains("?last&id=a")
+        );
+    }
+
+    #[test]
+    fn test_format_rewrites_only_changed_files() {
+        let (_temp_dir, root, lit) = format_input();
+        assert_eq!(lit.format(false).unwrap(), vec![root.join("a.md")]);
+        assert!(
+            fs::read_to_string(root.join("a.md"))
+                .unwrap()
+                .contains("?id=a&last")
+        );
+        assert!(lit.format(true).unwrap().is_empty());
+    }
+
+    /// `path` tangled from `a.md`, `b.md`, then `a.md` again
+    fn header_file(path: &str) -> TangledFile {
+        let origin = |file: &str, line| Origin {
+            file: Utf8PathBuf::from(file),
+            line,
+            offset: 0,
+            end_line: line,
+            end_offset: 0,
+        };
+        let mut file = TangledFile::new(Utf8PathBuf::from(path), vec![]);
+        file.origins = vec![origin("a.md", 1), origin("b.md", 3), origin("a.md", 9)];
+        file
+    }
+
+    #[test]
+    fn test_with_header_names_each_source_once_and_the_hash() {
+        let header = with_header(&header_file("src/main.rs"), "fn main() {}\n");
+        let mut lines = header.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            format!(
+                "// Generated by lit {} from a.md, b.md. Edit those instead.",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            lines.next().unwrap(),
+            format!("// sha256:{}", content_hash("fn main() {}\n"))
+        );
+        assert_eq!(lines.next().unwrap(), "fn main() {}");
+        assert_eq!(content_hash("").len(), 64);
+    }
+
+    #[test]
+    fn test_with_header_goes_after_a_shebang() {
+        let script = with_header(&header_file("run.sh"), "#!/bin/sh\necho hi\n");
+        assert!(
+            script.starts_with("#!/bin/sh\n# Generated by lit"),
+            "{script}"
+        );
+        assert!(script.ends_with("\necho hi\n"), "{script}");
+    }
+
+    #[test]
+    fn test_with_header_uses_block_comments() {
+        let page = with_header(&header_file("page.html"), "<p>\n");
+        assert!(page.starts_with("<!-- Generated by lit"), "{page}");
+        assert!(page.contains(" -->\n<!-- sha256:"), "{page}");
+    }
+
+    #[test]
+    fn test_with_header_skips_formats_without_comments() {
+        assert_eq!(with_header(&header_file("data.json"), "{}\n"), "{}\n");
+    }
+
+    #[test]
+    fn test_tangle_writes_headers_when_configured() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), "```tangle:///a.py\nprint(1)\n```\n").unwrap();
+
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), "header = true\n").unwrap();
+        lit.tangle().unwrap();
+
+        let written = fs::read_to_string(root.join("out/a.py")).unwrap();
+        assert!(written.starts_with("# Generated by lit"), "{written}");
+        assert!(written.contains(root.join("doc.md").as_str()), "{written}");
+        assert!(written.ends_with("\nprint(1)\n"), "{written}");
+
+        // The header is deterministic, so a second run changes nothing.
+        let report = lit.tangle().unwrap();
+        assert_eq!(report.files[0].status, WriteStatus::Unchanged);
+    }
+
+    /// Two files to tangle, and a `Lit` whose hooks record the changed files in `changed`
+    fn hooks_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.txt\na\n```\n\n```tangle:///b/c.txt\nc\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            r#"[hooks]
+post_tangle = ["printf '%s' \"$LIT_CHANGED_FILES\" > \"$LIT_OUTPUT/../changed\"", "true"]
+"#,
+        )
+        .unwrap();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_post_tangle_hooks_see_changed_files() {
+        let (_temp_dir, root, lit) = hooks_input();
+        lit.tangle().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("changed")).unwrap(),
+            "a.txt\nb/c.txt"
+        );
+    }
+
+    #[test]
+    fn test_post_tangle_hooks_run_when_nothing_changed() {
+        let (_temp_dir, root, lit) = hooks_input();
+        lit.tangle().unwrap();
+        lit.tangle().unwrap();
+        assert_eq!(fs::read_to_string(root.join("changed")).unwrap(), "");
+    }
+
+    #[test]
+    fn test_post_tangle_hooks_stop_at_the_first_failure() {
+        let (_temp_dir, root, mut lit) = hooks_input();
+        lit.config.hooks.post_tangle = vec!["exit 3".to_string(), "touch never".to_string()];
+        let error = lit.tangle().unwrap_err();
+        assert!(
+            matches!(&error, LitError::PostTangleFailed { command, .. } if command == "exit 3"),
+            "{error}"
+        );
+        assert!(error.to_string().contains("exit status: 3"), "{error}");
+        assert!(!root.join("never").exists());
+    }
+
+    #[test]
+    fn test_litignore_skips_matching_paths() {
+        let (_temp_dir, root) = temp_root();
+        for dir in ["drafts", "guide/deep", "guide/notes.md"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "a.md",
+            "a.wip.md",
+            "keep.wip.md",
+            "notes.md",
+            "drafts/b.md",
+            "guide/notes.md/c.md",
+            "guide/deep/notes.md",
+            "guide/deep/d.md",
+            "guide/[x].md",
+            "guide/#e.md",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::write(
+            root.join(IGNORE_NAME),
+            "# drafts\ndrafts/\n*.wip.md\n!keep.wip.md\n/notes.md/\n/guide/**/notes.md\n[z-a]\n\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("guide").join(IGNORE_NAME),
+            "deep/d.md\n\\[x].md\n\\#e.md\n",
+        )
+        .unwrap();
+
+        let lit = Lit::new(root.clone(), root.join("out"));
+        let files: Vec<_> = lit
+            .markdown_files()
+            .unwrap()
+            .iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_string())
+            .collect();
+        assert_eq!(files, ["a.md", "keep.wip.md", "notes.md"]);
+    }
+
+    #[test]
+    fn test_litignore_does_not_skip_a_named_input() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("drafts")).unwrap();
+        fs::write(root.join("drafts/b.md"), "").unwrap();
+        fs::write(root.join(IGNORE_NAME), "drafts/\n").unwrap();
+        let lit = Lit::new(root.join("drafts/b.md"), root.join("out"));
+        assert_eq!(lit.markdown_files().unwrap(), [root.join("drafts/b.md")]);
+    }
+
+    #[test]
+    fn test_glob_regex_translates_classes_and_escapes() {
+        assert_eq!(glob_regex("[!a-c]?"), "[^a-c][^/]");
+        assert_eq!(glob_regex("[[\\]"), "[\\[\\\\]");
+        assert_eq!(glob_regex("a\\"), "a");
+    }
+
+    /// A `docs` input whose `sub/main.md` includes chunks from `license.md` and from itself
+    fn include_input() -> (tempfile::TempDir, Utf8PathBuf) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs/sub")).unwrap();
+        fs::write(
+            root.join("docs/license.md"),
+            "```tangle:///LICENSE?id=license\nMIT\n```\n\n```tangle:///x?id=lints&skip\n#![deny(unsafe_code)]\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("docs/sub/main.md"),
+            r#"```tangle:///NOTICE?include=../license.md#license
+```
+
+```tangle:///src/main.rs?include=./../license.md#lints
+fn main() {}
+```
+
+```tangle:///src/lib.rs?include=#header
+```
+
+```tangle:///header?id=header&skip
+// Shared header
+```
+"#,
+        )
+        .unwrap();
+        (temp_dir, root)
+    }
+
+    #[test]
+    fn test_include_chunks_across_documents() {
+        let (_temp_dir, root) = include_input();
+        let files = Lit::new(root.join("docs"), root.join("out"))
+            .tangle_to_map()
+            .unwrap();
+        let file = |path: &str| files.get(Utf8Path::new(path)).map(String::as_str);
+        assert_eq!(file("LICENSE"), Some("MIT\n"));
+        assert_eq!(file("NOTICE"), Some("MIT\n"));
+        assert_eq!(
+            file("src/main.rs"),
+            Some("#![deny(unsafe_code)]\nfn main() {}\n")
+        );
+        assert_eq!(file("src/lib.rs"), Some("// Shared header\n"));
+        assert_eq!(file("x"), None);
+    }
+
+    #[test]
+    fn test_include_ignores_dots_in_the_input() {
+        let (_temp_dir, root) = include_input();
+        let files = Lit::new(root.join("docs/../docs"), root.join("out"))
+            .tangle_to_map()
+            .unwrap();
+        assert_eq!(
+            files.get(Utf8Path::new("NOTICE")).map(String::as_str),
+            Some("MIT\n")
+        );
+    }
+
+    #[test]
+    fn test_include_looks_for_a_bare_chunk_in_its_own_document() {
+        let (_temp_dir, root) = include_input();
+        fs::write(
+            root.join("docs/sub/main.md"),
+            "```tangle:///a?include=#lints\n```\n",
+        )
+        .unwrap();
+        let error = Lit::new(root.join("docs"), root.join("out"))
+            .tangle()
+            .unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(
+            matches!(&located.error, BlockError::UnknownInclude(include) if include == "#lints")
+        );
+    }
+
+    #[test]
+    fn test_include_rejects_unknown_chunks_and_documents() {
+        let (_temp_dir, root) = include_input();
+        for include in [
+            "../license.md",
+            "../license.md#nope",
+            "../license.md#bad id",
+            "missing.md#lints",
+        ] {
+            let markdown = format!("```tangle:///a?include={include}\n```\n");
+            fs::write(root.join("docs/sub/main.md"), markdown).unwrap();
+            assert!(
+                Lit::new(root.join("docs"), root.join("out"))
+                    .tangle()
+                    .is_err(),
+                "{include}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_keeps_parents_it_cannot_resolve() {
+        assert_eq!(normalize(Utf8Path::new("a/./b/../../c")), "c");
+        assert_eq!(normalize(Utf8Path::new("docs/../../x.txt")), "../x.txt");
+        assert_eq!(normalize(Utf8Path::new("./../a/../../b")), "../../b");
+        assert_eq!(normalize(Utf8Path::new("/a/../../x")), "/x");
+    }
+
+    /// The files a `docs/doc.md` of `markdown` tangles to, beside a four-line `vendor/snippet.rs`
+    fn from_input(
+        markdown: &str,
+    ) -> (
+        tempfile::TempDir,
+        Utf8PathBuf,
+        Result<BTreeMap<Utf8PathBuf, String>>,
+    ) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("vendor")).unwrap();
+        fs::write(root.join("vendor/snippet.rs"), "one\ntwo\nthree\nfour\n").unwrap();
+        fs::write(root.join("docs/doc.md"), markdown).unwrap();
+        let files = Lit::new(root.join("docs"), root.join("out")).tangle_to_map();
+        (temp_dir, root, files)
+    }
+
+    /// The block error of a tangle that failed at a fence
+    fn block_error<T>(result: Result<T>) -> BlockError {
+        match result {
+            Err(LitError::Located(located)) => located.error,
+            Err(error) => panic!("expected a located error, got {error}"),
+            Ok(_) => panic!("expected a located error"),
+        }
+    }
+
+    #[test]
+    fn test_from_files_and_line_ranges() {
+        let (_temp_dir, _root, files) = from_input(
+            r#"```tangle:///all.rs?from=../vendor/snippet.rs
+```
+
+```tangle:///some.rs?from=../vendor/snippet.rs&lines=2-3
+// and more
+```
+
+```tangle:///tail.rs?from=../vendor/snippet.rs&lines=3-
+```
+
+```tangle:///one.rs?from=../vendor/snippet.rs&lines=4
+```
+"#,
+        );
+        let files = files.unwrap();
+        let file = |path: &str| files.get(Utf8Path::new(path)).map(String::as_str);
+        assert_eq!(file("all.rs"), Some("one\ntwo\nthree\nfour\n"));
+        assert_eq!(file("some.rs"), Some("two\nthree\n// and more\n"));
+        assert_eq!(file("tail.rs"), Some("three\nfour\n"));
+        assert_eq!(file("one.rs"), Some("four\n"));
+    }
+
+    #[test]
+    fn test_from_is_read_before_the_chunk_is_included() {
+        let markdown = "```tangle:///a.rs?id=snippet&from=../vendor/snippet.rs&lines=2-3\n```\n\n```tangle:///b.rs?include=#snippet\n// b\n```\n";
+        let (_temp_dir, _root, files) = from_input(markdown);
+        let files = files.unwrap();
+        assert_eq!(files[Utf8Path::new("a.rs")], "two\nthree\n");
+        assert_eq!(files[Utf8Path::new("b.rs")], "two\nthree\n// b\n");
+    }
+
+    #[test]
+    fn test_from_refuses_ranges_past_the_end() {
+        let (_temp_dir, root, files) =
+            from_input("```tangle:///a.rs?from=../vendor/snippet.rs&lines=2-9\n```\n");
+        assert_eq!(
+            block_error(files).to_string(),
+            format!(
+                "Line range 2-9 is past the end of {root}/vendor/snippet.rs, which has 4 lines"
+            )
+        );
+        let (_temp_dir, _root, files) =
+            from_input("```tangle:///a.rs?from=../vendor/snippet.rs&lines=5-\n```\n");
+        let error = block_error(files);
+        assert!(
+            matches!(&error, BlockError::LinesOutOfRange { lines, .. } if lines == "5-"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_from_refuses_missing_files() {
+        let (_temp_dir, _root, files) = from_input("```tangle:///a.rs?from=missing.rs\n```\n");
+        let error = block_error(files);
+        assert!(matches!(error, BlockError::UnreadableFile(_)), "{error}");
+    }
+
+    #[test]
+    fn test_from_refuses_invalid_line_ranges() {
+        for lines in ["0-2", "3-2", "x", "2-y", ""] {
+            let (_temp_dir, _root, files) = from_input(&format!(
+                "```tangle:///a.rs?from=../vendor/snippet.rs&lines={lines}\n```\n"
+            ));
+            assert!(
+                matches!(&block_error(files), BlockError::InvalidLines(value) if value == lines),
+                "{lines}"
+            );
+        }
+        let (_temp_dir, _root, files) = from_input("```tangle:///a.rs?lines=1\n```\n");
+        assert!(matches!(block_error(files), BlockError::InvalidLines(_)));
+    }
+
+    #[test]
+    fn test_from_needs_a_document_on_disk() {
+        let error = tangle_document("doc.md", "```tangle:///a.rs?from=b.rs\n```\n");
+        assert!(matches!(block_error(error), BlockError::UnreadableFile(_)));
+    }
+
+    #[test]
+    fn test_init_creates_a_project_that_tangles() {
+        let (_temp_dir, root) = temp_root();
+
+        let created = init(&root).unwrap();
+        assert_eq!(
+            created,
+            vec![
+                root.join("lit.toml"),
+                root.join("docs/hello.md"),
+                root.join(".gitignore"),
+            ]
+        );
+
+        let config = Config::load(&root.join("lit.toml")).unwrap();
+        let mut lit = Lit::new(
+            config.input.clone().unwrap(),
+            config.output.clone().unwrap(),
+        );
+        lit.config = config;
+        lit.tangle().unwrap();
+
+        let main = fs::read_to_string(root.join("out/src/main.rs")).unwrap();
+        assert!(main.starts_with("fn greeting()"), "{main}");
+        assert!(main.trim_end().ends_with('}'), "{main}");
+        assert!(main.contains("fn main()"), "{main}");
+    }
+
+    #[test]
+    fn test_init_keeps_existing_files() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+
+        let created = init(&root).unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(
+            fs::read_to_string(root.join(".gitignore")).unwrap(),
+            "target/\n"
+        );
+
+        assert!(init(&root).unwrap().is_empty());
+    }
+
+    const INSPECT_DOC: &str = "# Doc\n\n```tangle:///a.rs?id=a&after=b&last\nfn a() {\n{{}}\n}\n```\n\n```tangle:///a.rs?id=b&first\nb\n```\n\n```tangle:///a.rs?inside=a\nnested\n```\n\n```tangle:///c.rs?skip\n```\n";
+
+    /// What `lit inspect` reports for a `doc.md` of `markdown`, with headers on
+    fn inspect(markdown: &str) -> (tempfile::TempDir, Utf8PathBuf, Vec<BlockInfo>) {
+        let (temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), markdown).unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config.header = true;
+        let blocks = lit.inspect().unwrap();
+        (temp_dir, root, blocks)
+    }
+
+    #[test]
+    fn test_inspect_describes_each_block() {
+        let (_temp_dir, root, blocks) = inspect(INSPECT_DOC);
+        assert_eq!(
+            blocks[0],
+            BlockInfo {
+                file: root.join("doc.md"),
+                target: Utf8PathBuf::from("a.rs"),
+                id: Some("a".to_string()),
+                constraints: vec!["after=b".to_string(), "last".to_string()],
+                inside: None,
+                skip: false,
+                span: (7, 62),
+                lines: (3, 7),
+                output_lines: Some((5, 7)),
+            }
+        );
+        assert_eq!(INSPECT_DOC.get(7..62).unwrap().lines().count(), 5);
+        assert_eq!(blocks[1].constraints, ["first"]);
+        assert_eq!(blocks[2].inside.as_deref(), Some("a"));
+        assert!(blocks[3].skip);
+    }
+
+    #[test]
+    fn test_inspect_output_lines_count_the_header() {
+        let (_temp_dir, _root, blocks) = inspect(INSPECT_DOC);
+        assert_eq!(blocks[1].output_lines, Some((3, 3)));
+    }
+
+    #[test]
+    fn test_inspect_output_lines_skip_nested_and_skipped_blocks() {
+        let (_temp_dir, _root, blocks) = inspect(INSPECT_DOC);
+        assert_eq!(blocks[2].output_lines, None);
+        assert_eq!(blocks[3].output_lines, None);
+    }
+
+    #[test]
+    fn test_inspect_broken_constraints_leave_no_output_lines() {
+        let (_temp_dir, _root, blocks) = inspect("```tangle:///a.rs?after=missing\n```\n");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].output_lines, None);
+    }
+
+    #[test]
+    fn test_constraint_display_matches_the_url() {
+        assert_eq!(
+            Constraint::Before(vec![BlockId::new("x".to_string()).unwrap()]).to_string(),
+            "before=x"
+        );
+    }
+
+    /// A `Lit` for blocks of 8, 4, 5, and a skipped 16 bytes, with `[limits]` set to `toml`
+    fn limits_input(toml: &str) -> (tempfile::TempDir, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.txt\n12345678\n```\n\n```tangle:///a.txt\n1234\n```\n\n```tangle:///b.txt\n12345\n```\n\n```tangle:///c.txt?skip\n0123456789abcdef\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config =
+            Config::parse(Utf8Path::new("lit.toml"), &format!("[limits]\n{toml}\n")).unwrap();
+        (temp_dir, lit)
+    }
+
+    #[test]
+    fn test_limits_allow_sizes_up_to_the_limit() {
+        let (_temp_dir, lit) =
+            limits_input("max_block_bytes = 8\nmax_file_bytes = 15\nmax_output_bytes = 21");
+        assert_eq!(lit.tangle().unwrap().files.len(), 2);
+    }
+
+    #[test]
+    fn test_limits_refuse_a_large_block_at_its_fence() {
+        let (_temp_dir, lit) = limits_input("max_block_bytes = 7");
+        let error = lit.tangle().unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.line, 1);
+        assert_eq!(error.to_string(), "Block is 8 bytes, over the limit of 7");
+    }
+
+    #[test]
+    fn test_limits_refuse_a_large_file() {
+        let (_temp_dir, lit) = limits_input("max_file_bytes = 14");
+        assert_eq!(
+            lit.tangle().unwrap_err().to_string(),
+            "a.txt is 15 bytes, over the limit of 14"
+        );
+    }
+
+    #[test]
+    fn test_limits_refuse_a_large_output() {
+        let (_temp_dir, lit) = limits_input("max_output_bytes = 20");
+        assert_eq!(
+            lit.tangle_to_map().unwrap_err().to_string(),
+            "the tangled files are 21 bytes, over the limit of 20"
+        );
+    }
+
+    #[test]
+    fn test_limits_reject_unknown_keys() {
+        let error =
+            Config::parse(Utf8Path::new("lit.toml"), "[limits]\nmax_bytes = 1\n").unwrap_err();
+        assert!(
+            error.to_string().contains("unknown field `max_bytes`"),
+            "{error}"
+        );
+    }
+
+    /// Documents with a bare fence, untangled code, an unknown parameter, and a tiny target
+    fn lint_input(config: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "```\nplain\n```\n\n```rust\nfn example() {}\n```\n\n```tangle:///a.rs?id=a&colour=red\nfn a() {}\nfn b() {}\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            "```python\nx = 1\n```\n\n```tangle:///b.txt\nb\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("c.md"), "Only an example:\n\n```sh\nlit\n```\n").unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), config).unwrap();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_lint_reports_default_rules() {
+        let (_temp_dir, root, lit) = lint_input("");
+        let findings = lit.lint().unwrap();
+        assert_eq!(
+            findings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                format!("{root}/a.md:1: warning[missing-language] code fence has no language"),
+                format!("{root}/a.md:9: error[unknown-param] unknown query parameter `colour`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_rules_take_configured_severities() {
+        let (_temp_dir, _root, lit) = lint_input(
+            "[lint]\nmissing-language = \"off\"\nunknown-param = \"warning\"\nuntangled-code = \"warning\"\ntiny-target = \"error\"\n",
+        );
+        let findings = lit.lint().unwrap();
+        assert_eq!(
+            findings
+                .iter()
+                .map(|f| (f.rule, f.severity, f.line))
+                .collect::<Vec<_>>(),
+            [
+                ("untangled-code", Severity::Warning, 5),
+                ("unknown-param", Severity::Warning, 9),
+                ("untangled-code", Severity::Warning, 1),
+                ("tiny-target", Severity::Error, 5),
+            ]
+        );
+        assert_eq!(Severity::Off.to_string(), "off");
+        assert_eq!(lit.config.severity("no-such-rule"), Severity::Off);
+    }
+
+    #[test]
+    fn test_lint_config_rejects_unknown_rules() {
+        let error = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[lint]\ntiny-targets = \"error\"\n",
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("unknown lint rule `tiny-targets`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_lint_duplicate_content() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.rs\nfn a() {}\n```\n\n```tangle:///a.rs\n}\n```\n\n```tangle:///b.rs\nfn a() {}\n```\n\n```tangle:///a.rs\n\n```\n\n```tangle:///a.rs\n\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            "```tangle:///a.rs\nfn a() {}\n```\n\n```tangle:///a.rs?allow=duplicate-content\n}\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        let findings = lit.lint().unwrap();
+        assert_eq!(
+            findings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [format!(
+                "{root}/b.md:1: warning[duplicate-content] same content as the block at {root}/a.md:1, also for a.rs"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_single_tangle_block() {
+        let markdown = r#"# Test
+
+```tangle:///src/main.rs
+fn main() {
+    println!("Hello");
+}
+```
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("src/main.rs"));
+        assert_eq!(
+            blocks[0].content,
+            "fn main() {\n    println!(\"Hello\");\n}"
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_tangle_blocks() {
+        let markdown = r#"# Multiple Blocks
+
+```tangle:///file1.rs
+code 1
+```
+
+Some text here.
+
+```tangle:///file2.rs
+code 2
+```
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("file1.rs"));
+        assert_eq!(blocks[0].content, "code 1");
+        assert_eq!(blocks[1].path, Utf8PathBuf::from("file2.rs"));
+        assert_eq!(blocks[1].content, "code 2");
+    }
+
+    #[test]
+    fn test_parse_ignore_regular_code_blocks() {
+        let markdown = r#"# Test
+
+```rust
+// This is regular code
+let x = 42;
+```
+
+```tangle:///output.rs
+// This should be extracted
+let y = 10;
+```
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("output.rs"));
+        assert_eq!(
+            blocks[0].content,
+            "// This should be extracted\nlet y = 10;"
+        );
+    }
+
+    #[test]
+    fn test_parse_ignore_nested_in_blockquote() {
+        let markdown = r#"# Test
+
+```tangle:///top-level.txt
+Top level content
+```
+
+> Blockquote here
+>
+> ```tangle:///nested.txt
+> This should NOT be extracted
+> ```
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("top-level.txt"));
+        assert_eq!(blocks[0].content, "Top level content");
+    }
+
+    #[test]
+    fn test_parse_ignore_nested_in_list() {
+        let markdown = r#"# Test
+
+```tangle:///top-level.txt
+Top level content
+```
+
+- Item 1
+- Item 2
+
+  ```tangle:///nested.txt
+  This should NOT be extracted
+  ```
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("top-level.txt"));
+        assert_eq!(blocks[0].content, "Top level content");
+    }
+
+    #[test]
+    fn test_parse_empty_markdown() {
+        let markdown = "";
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_no_tangle_blocks() {
+        let markdown = r#"# Just a regular document
+
+Some text here.
+
+```rust
+Regular code block
+```
+
+More text.
+"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_subdirectory_path() {
+        let markdown = r#"```tangle:///src/modules/utils.rs
+pub fn helper() {}
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("src/modules/utils.rs"));
+        assert_eq!(blocks[0].content, "pub fn helper() {}");
+    }
+
+    #[test]
+    fn test_parse_empty_tangle_block() {
+        let markdown = r#"```tangle:///empty.txt
+```"#;
+
+        let blocks = Lit::parse_markdown(markdown).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].path, Utf8PathBuf::from("empty.txt"));
+        assert_eq!(blocks[0].content, "");
+    }
+
+    #[test]
+    fn test_tangle_end_to_end() -> Result<()> {
+        use std::env;
+
+        let temp_dir = Utf8PathBuf::from_path_buf(env::temp_dir()).unwrap();
+        let temp_input = temp_dir.join("lit-test-input");
+        let temp_output = temp_dir.join("lit-test-output");
+
+        // Clean up any leftover temp dirs from previous runs
+        let _ = fs::remove_dir_all(&temp_input);
+        let _ = fs::remove_dir_all(&temp_output);
+
+        fs::create_dir_all(&temp_input)?;
+        let markdown = r#"# Test
+
+```tangle:///test.txt
+Hello World
+```
+
+```tangle:///subdir/test2.txt
+Nested file
+```
+"#;
+        fs::write(temp_input.join("test.md"), markdown)?;
+
+        let lit = Lit::new(temp_input.clone(), temp_output.clone());
+        lit.tangle()?;
+
+        assert!(temp_output.join("test.txt").exists());
+        assert!(temp_output.join("subdir/test2.txt").exists());
+
+        let content1 = fs::read_to_string(temp_output.join("test.txt"))?;
+        assert_eq!(content1, "Hello World\n");
+
+        let content2 = fs::read_to_string(temp_output.join("subdir/test2.txt"))?;
+        assert_eq!(content2, "Nested file\n");
+
+        fs::remove_dir_all(&temp_input)?;
+        fs::remove_dir_all(&temp_output)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tangled_files_end_with_newline() -> Result<()> {
+        use std::env;
+
+        let temp_dir = Utf8PathBuf::from_path_buf(env::temp_dir()).unwrap();
+        let temp_input = temp_dir.join("lit-test-newline-input");
+        let temp_output = temp_dir.join("lit-test-newline-output");
+
+        // Clean up any leftover temp dirs from previous runs
+        let _ = fs::remove_dir_all(&temp_input);
+        let _ = fs::remove_dir_all(&temp_output);
+
+        fs::create_dir_all(&temp_input)?;
+        let markdown = r#"# Test
+
+```tangle:///test.txt
+Line 1
+```
+"#;
+        fs::write(temp_input.join("test.md"), markdown)?;
+
+        let lit = Lit::new(temp_input.clone(), temp_output.clone());
+        lit.tangle()?;
+
+        let content = fs::read_to_string(temp_output.join("test.txt"))?;
+        assert!(
+            content.ends_with('\n'),
+            "Tangled file should end with a newline"
+        );
+
+        fs::remove_dir_all(&temp_input)?;
+        fs::remove_dir_all(&temp_output)?;
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_blocks_rejects_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (_temp_dir, input) = temp_root();
+        let name = std::ffi::OsStr::from_bytes(b"bad-\xff.md");
+        fs::write(input.as_std_path().join(name), "# Doc\n").unwrap();
+
+        let error = Lit::new(input.clone(), input.join("out"))
+            .read_blocks()
+            .unwrap_err();
+        assert!(matches!(error, LitError::NonUtf8Path(_)));
+        assert!(error.to_string().contains("not valid UTF-8"));
+    }
+
+    /// A document tangling `a.txt` from two blocks and `b.txt` from one, and a `Lit` for it
+    fn report_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///a.txt?id=outer\n[{{}}]\n```\n\n```tangle:///a.txt?id=inner&inside=outer\nx\n```\n\n```tangle:///b.txt\nb\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(input, root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    /// The report of tangling `report_input` again after `b.txt` went stale
+    fn second_report() -> (tempfile::TempDir, Utf8PathBuf, TangleReport) {
+        let (temp_dir, root, lit) = report_input();
+        lit.tangle().unwrap();
+        fs::write(root.join("out/b.txt"), "stale\n").unwrap();
+        let report = lit.tangle().unwrap();
+        (temp_dir, root, report)
+    }
+
+    #[test]
+    fn test_tangle_reports_every_file_written() {
+        let (_temp_dir, _root, lit) = report_input();
+        let first = lit.tangle().unwrap();
+        assert_eq!(
+            first.files,
+            vec![
+                FileReport {
+                    path: Utf8PathBuf::from("a.txt"),
+                    bytes: 4,
+                    blocks: 2,
+                    status: WriteStatus::Written,
+                },
+                FileReport {
+                    path: Utf8PathBuf::from("b.txt"),
+                    bytes: 2,
+                    blocks: 1,
+                    status: WriteStatus::Written,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tangle_reports_unchanged_files() {
+        let (_temp_dir, root, second) = second_report();
+        assert_eq!(second.count(WriteStatus::Unchanged), 1);
+        assert_eq!(second.count(WriteStatus::Written), 1);
+        assert_eq!(second.summary(), "2 files: 1 written, 1 unchanged");
+        assert_eq!(fs::read_to_string(root.join("out/b.txt")).unwrap(), "b\n");
+    }
+
+    #[test]
+    fn test_tangle_report_table() {
+        let (_temp_dir, _root, second) = second_report();
+        let table = second.to_string();
+        assert!(table.starts_with("status"), "{table}");
+        assert!(
+            table.contains("unchanged         4       2  a.txt"),
+            "{table}"
+        );
+        assert!(
+            table.contains("written           2       1  b.txt"),
+            "{table}"
+        );
+        assert!(
+            table.ends_with(&format!(
+                "2 files: 1 written, 1 unchanged\n{}\n",
+                second.timing()
+            )),
+            "{table}"
+        );
+        assert!(
+            TangleReport::default()
+                .to_string()
+                .ends_with("0 files: 0 written, 0 unchanged\n")
+        );
+    }
+
+    #[test]
+    fn test_tangle_report_times_every_phase_and_document() {
+        let (_temp_dir, _root, second) = second_report();
+        let phases: Vec<_> = second.timings.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(
+            phases,
+            ["walk", "parse", "merge", "render", "write", "hooks"]
+        );
+        assert_eq!(second.documents.len(), 1);
+    }
+
+    #[test]
+    fn test_tangle_report_timing() {
+        let report = TangleReport {
+            timings: vec![
+                ("parse", Duration::from_millis(120)),
+                ("write", Duration::from_micros(40_900)),
+            ],
+            ..TangleReport::default()
+        };
+        assert_eq!(report.timing(), "parse: 120ms, write: 40ms");
+        let report = TangleReport {
+            peak_memory: Some(12 << 20),
+            ..report
+        };
+        assert_eq!(
+            report.timing(),
+            "parse: 120ms, write: 40ms, peak memory: 12 MiB"
+        );
+    }
+
+    #[test]
+    fn test_tangle_leaves_no_temp_files() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///a.txt\na\n```\n\n```tangle:///sub/b.txt\nb\n```\n",
+        )
+        .unwrap();
+
+        Lit::new(input, root.join("out")).tangle().unwrap();
+
+        let mut names = WalkDir::new(root.join("out"))
+            .into_iter()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![MANIFEST_NAME, "a.txt", "b.txt", "out", "sub"]);
+    }
+
+    #[test]
+    fn test_tangle_updates_all_files_or_none() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        let output = root.join("out");
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///a.txt\nnew\n```\n\n```tangle:///blocked/b.txt\nb\n```\n",
+        )
+        .unwrap();
+        fs::write(output.join("a.txt"), "old\n").unwrap();
+        // A file where a directory is needed makes staging b.txt fail.
+        fs::write(output.join("blocked"), "").unwrap();
+
+        let mut lit = Lit::new(input, output.clone());
+        lit.force = true;
+        assert!(lit.tangle().is_err());
+
+        assert_eq!(fs::read_to_string(output.join("a.txt")).unwrap(), "old\n");
+        assert_eq!(
+            fs::read_dir(&output).unwrap().count(),
+            2,
+            "temp files were left behind"
+        );
+    }
+
+    #[test]
+    fn test_keep_temp_leaves_the_scratch_directory() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        let output = root.join("out");
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///a.txt\nnew\n```\n\n```tangle:///blocked/b.txt\nb\n```\n",
+        )
+        .unwrap();
+        // A non-empty directory where b.txt goes makes its rename fail, after staging.
+        fs::create_dir_all(output.join("blocked/b.txt/x")).unwrap();
+
+        let mut lit = Lit::new(input, output.clone());
+        lit.keep_temp = true;
+        assert!(lit.tangle().is_err());
+
+        let scratch = lit.scratch_dir();
+        assert_eq!(scratch.parent(), Some(output.as_path()));
+        let staged = fs::read_dir(&scratch)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().ends_with("-a.txt"))
+            .unwrap();
+        assert_eq!(fs::read_to_string(staged).unwrap(), "new\n");
+        assert!(!output.join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_parse_errors_are_all_collected() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("a.md"), "```tangle:///a.txt?id=\nx\n```\n").unwrap();
+        fs::write(
+            root.join("b.md"),
+            "```tangle:///b.txt?after=nope\nx\n```\n\n```tangle:///c.txt?id=\nx\n```\n\n```tangle:///d.txt?id=b@d\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        // Every parse error, in reading order; the unknown ID waits for them.
+        let errors = lit.tangle().unwrap_err().into_errors();
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|error| match error {
+                LitError::Located(located) => located.origin.to_string(),
+                error => error.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                format!("{root}/a.md:1"),
+                format!("{root}/b.md:5"),
+                format!("{root}/b.md:9")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ordering_errors_are_all_collected() {
+        let (_temp_dir, root) = temp_root();
+        // Once everything parses, every destination that cannot be ordered.
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.txt?before=gone\nx\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("b.md"), "```tangle:///b.txt?after=nope\nx\n```\n").unwrap();
+        let error = Lit::new(root.clone(), root.join("out"))
+            .tangle()
+            .unwrap_err();
+        assert!(error.to_string().contains("found 2 problems"), "{error}");
+        assert_eq!(error.into_errors().len(), 2);
+    }
+
+    #[test]
+    fn test_a_single_error_is_returned_as_it_is() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "```tangle:///a.txt?before=gone\nx\n```\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            Lit::new(root.clone(), root.join("out")).tangle(),
+            Err(LitError::Located(_))
+        ));
+    }
+
+    #[test]
+    fn test_diff_against_output() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), "```tangle:///a.txt\none\ntwo\n```\n").unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        assert_eq!(
+            lit.diff(Utf8Path::new("a.txt")).unwrap(),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -0,0 +1,2 @@\n+one\n+two\n"
+        );
+        lit.tangle().unwrap();
+        assert_eq!(lit.diff(Utf8Path::new("a.txt")).unwrap(), "");
+
+        fs::write(root.join("out/a.txt"), "one\n2\n").unwrap();
+        let diff = lit.diff(Utf8Path::new("a.txt")).unwrap();
+        assert!(
+            diff.ends_with("@@ -1,2 +1,2 @@\n one\n-2\n+two\n"),
+            "{diff}"
+        );
+        assert!(matches!(
+            lit.diff(Utf8Path::new("b.txt")),
+            Err(LitError::UnknownTarget { .. })
+        ));
+
+        fs::remove_file(root.join("out/a.txt")).unwrap();
+        fs::create_dir(root.join("out/a.txt")).unwrap();
+        assert!(matches!(
+            lit.diff(Utf8Path::new("a.txt")),
+            Err(LitError::Io(_))
+        ));
+    }
+
+    /// A `doc.md` holding `markdown`, and a `Lit` reading it with `strict`
+    fn strict_input(markdown: &str, strict: bool) -> (tempfile::TempDir, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), markdown).unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.strict = strict;
+        (temp_dir, lit)
+    }
+
+    #[test]
+    fn test_strict_allows_other_fences() {
+        let (_temp_dir, lit) = strict_input(
+            "```tangle:///a.txt\na\n```\n\n```rust\nfn main() {}\n```\n",
+            true,
+        );
+        assert_eq!(lit.blocks().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_look_alike_fences_are_skipped_unless_strict() {
+        let (_temp_dir, lit) = strict_input("# Doc\n\n```tangl:///a.txt\na\n```\n", false);
+        assert!(lit.blocks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_strict_fails_on_look_alike_fences() {
+        let (_temp_dir, lit) = strict_input("# Doc\n\n```tangl:///a.txt\na\n```\n", true);
+        let LitError::Located(error) = lit.blocks().unwrap_err() else {
+            panic!("expected a located error");
+        };
+        assert!(matches!(&error.error, BlockError::NotTangleUrl(word) if word == "tangl:///a.txt"));
+        assert_eq!(error.origin.line, 3);
+    }
+
+    #[test]
+    fn test_strict_fails_on_nested_fences() {
+        let (_temp_dir, lit) = strict_input(
+            "# Doc\n\n- item\n\n  > ```tangle:///a.txt\n  > a\n  > ```\n",
+            true,
+        );
+        let LitError::Located(error) = lit.blocks().unwrap_err() else {
+            panic!("expected a located error");
+        };
+        assert!(matches!(error.error, BlockError::NestedTangleBlock));
+        assert_eq!(error.origin.line, 5);
+    }
+
+    #[test]
+    fn test_tangle_document_in_memory() {
+        let files = tangle_document(
+            "doc.md",
+            "```tangle:///b.txt\nb\n```\n\n```tangle:///a.txt?id=two&last\n2\n```\n\n```tangle:///a.txt?id=one\n1\n```\n",
+        )
+        .unwrap();
+
+        let rendered: Vec<_> = files
+            .iter()
+            .map(|f| (f.path.as_str(), f.render()))
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![
+                ("a.txt", "1\n\n2\n".to_string()),
+                ("b.txt", "b\n".to_string())
+            ]
+        );
+        assert_eq!(files[0].origins[0].file, Utf8PathBuf::from("doc.md"));
+    }
+
+    #[test]
+    fn test_tangle_documents_isolates_errors_and_keeps_order() {
+        let documents = (0..20).map(|i| {
+            let markdown = if i == 7 {
+                "```tangle:///out.txt?id=Broken\nx\n```\n".to_string()
+            } else {
+                format!("```tangle:///doc{i}.txt\n{i}\n```\n")
+            };
+            (format!("doc{i}.md"), markdown)
+        });
+
+        let results = tangle_documents(documents);
+        assert_eq!(results.len(), 20);
+        for (i, (name, result)) in results.iter().enumerate() {
+            assert_eq!(name, &format!("doc{i}.md"));
+            if i == 7 {
+                let error = result.as_ref().unwrap_err();
+                let LitError::Located(located) = error else {
+                    panic!("expected a located error, got {error:?}");
+                };
+                assert_eq!(located.origin.file, Utf8PathBuf::from("doc7.md"));
+            } else {
+                let files = result.as_ref().unwrap();
+                assert_eq!(files[0].render(), format!("{i}\n"));
+            }
+        }
+
+        assert!(tangle_documents(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_skipped_blocks_are_listed_but_not_tangled() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.txt?id=main\na\n```\n\n```tangle:///a.txt?id=main&skip\nold\n```\n\n```tangle:///b.txt no-tangle\nb\n```\n",
+        )
+        .unwrap();
+
+        let lit = Lit::new(root.clone(), root.join("out"));
+        let blocks = lit.blocks().unwrap();
+        assert_eq!(blocks.iter().filter(|b| b.skip).count(), 2);
+
+        // The skipped duplicate of `main` is not a conflict.
+        let files = lit.read_blocks().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].render(), "a\n");
+    }
+
+    #[test]
+    fn test_extract_tangles_one_target() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.txt?id=b\nb\n```\n\n```tangle:///a.txt?id=a&before=b\na\n```\n\n```tangle:///broken.txt?after=nope\nx\n```\n",
+        )
+        .unwrap();
+
+        let lit = Lit::new(root.clone(), root.join("out"));
+        assert_eq!(lit.extract(Utf8Path::new("a.txt")).unwrap(), "a\n\nb\n");
+        assert!(!root.join("out").exists());
+
+        let error = lit.extract(Utf8Path::new("missing.txt")).unwrap_err();
+        assert_eq!(error.to_string(), "no blocks are tangled into missing.txt");
+    }
+
+    #[test]
+    fn test_extract_to_writes_what_extract_returns() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.rs?id=b\nb\n```\n\n```tangle:///a.rs?before=b\na\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        for header in [false, true] {
+            lit.config.header = header;
+            let mut written = Vec::new();
+            lit.extract_to(Utf8Path::new("a.rs"), &mut written).unwrap();
+            assert_eq!(
+                String::from_utf8(written).unwrap(),
+                lit.extract(Utf8Path::new("a.rs")).unwrap()
+            );
+        }
+    }
+
+    /// A document tangling `b.txt` and `a/a.txt`, and a `Lit` for it
+    fn map_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///b.txt\nb\n```\n\n```tangle:///a/a.txt\na\n```\n",
+        )
+        .unwrap();
+
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_tangle_to_map_writes_nothing() {
+        let (_temp_dir, root, lit) = map_input();
+        let files = lit.tangle_to_map().unwrap();
+        assert_eq!(
+            files,
+            [
+                (Utf8PathBuf::from("a/a.txt"), "a\n".to_string()),
+                (Utf8PathBuf::from("b.txt"), "b\n".to_string()),
+            ]
+            .into()
+        );
+        assert!(!root.join("out").exists());
+    }
+
+    #[test]
+    fn test_tangle_to_map_checks_assertions() {
+        let (_temp_dir, _root, mut lit) = map_input();
+        // Assertions are checked just as they are before writing.
+        lit.config.assertions.insert(
+            "b.txt".to_string(),
+            Assertions {
+                contains: vec!["missing".to_string()],
+                ..Assertions::default()
+            },
+        );
+        assert!(lit.tangle_to_map().is_err());
+    }
+
+    #[test]
+    fn test_read_blocks_merges_multiple_inputs() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("book")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "```tangle:///out.txt?id=a&first\na\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("book/b.md"),
+            "```tangle:///out.txt?id=b&after=a\nb\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("extra.md"),
+            "```tangle:///out.txt?id=c&after=b\nc\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.inputs.push(root.join("book"));
+        lit.inputs.push(root.join("extra.md"));
+        // Reaching a file twice reads it once, instead of declaring `a` twice.
+        lit.inputs.push(root.join("docs/a.md"));
+
+        let files = lit.read_blocks().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].render(), "a\n\nb\n\nc\n");
+    }
+
+    #[test]
+    fn test_duplicate_across_inputs_names_both_fences() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("book")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "# A\n\n```tangle:///out.txt?id=main\na\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("book/b.md"),
+            "```tangle:///out.txt?id=main\nb\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.inputs.push(root.join("book"));
+
+        let error = lit.read_blocks().unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(located.origin.file, root.join("book/b.md"));
+
+        let related: Vec<_> = error.related().unwrap().collect();
+        assert_eq!(related.len(), 1);
+        assert_eq!(
+            related[0].to_string(),
+            format!("first declared here: {}:3", root.join("docs/a.md"))
+        );
+        assert_eq!(related[0].severity(), Some(miette::Severity::Advice));
+        let label = related[0].labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), 29);
+        assert!(related[0].source_code().is_some());
+    }
+
+    #[test]
+    fn test_case_only_collisions_are_rejected() {
+        let markdown = "```tangle:///src/lib.rs\na\n```\n\n```tangle:///Src/a.rs\nb\n```\n\n```tangle:///src/Lib.rs\nc\n```\n";
+        let error = tangle_document("doc.md", markdown).unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error:?}");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Destination src/lib.rs differs from src/Lib.rs only in case"
+        );
+        assert_eq!(located.origin.line, 1);
+        assert_eq!(located.related[0].origin.line, 9);
+
+        let markdown = "```tangle:///src/lib.rs\na\n```\n\n```tangle:///Src/a.rs\nb\n```\n";
+        assert_eq!(tangle_document("doc.md", markdown).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unreadable_sources_are_reported_or_skipped() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("a.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+        fs::write(root.join("b.md"), b"```tangle:///b.txt\n\xff\n```\n").unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.inputs.push(root.join("missing"));
+
+        let errors = lit.tangle().unwrap_err().into_errors();
+        let messages: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(
+            messages[0].starts_with(&format!("cannot read {root}/missing: ")),
+            "{messages:?}"
+        );
+        assert_eq!(
+            messages[1],
+            format!("cannot read {root}/b.md: not valid UTF-8 after byte 19")
+        );
+        assert!(!root.join("out").exists());
+
+        lit.skip_invalid = true;
+        let report = lit.tangle().unwrap();
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(fs::read_to_string(root.join("out/a.txt")).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn test_broken_front_matter_is_reported_in_path_order() {
+        let (_temp_dir, root) = temp_root();
+        for name in ["d", "b", "a", "c"] {
+            let markdown =
+                format!("---\nlit:\n  {name}: 1\n---\n\n```tangle:///{name}.txt\n{name}\n```\n");
+            fs::write(root.join(format!("{name}.md")), markdown).unwrap();
+        }
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        for _ in 0..8 {
+            let error = lit.tangle().unwrap_err();
+            assert!(error.to_string().contains("unknown field `a`"), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_tangled_file_renders_in_pieces() {
+        let markdown = "```tangle:///a.rs?id=p\nmod m {\n{{}}\n}\n```\n\n```tangle:///a.rs?inside=p\nfn f() {}\n```\n\n```tangle:///a.rs?inside=p\nfn g() {}\n```\n\n```tangle:///a.rs?sep=2\n// end\n```\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        let file = files.first().unwrap();
+        let rendered = file.render();
+        assert_eq!(rendered, "mod m {\nfn f() {}\n\nfn g() {}\n}\n\n\n// end\n");
+        assert_eq!(file.rendered_len(), rendered.len());
+
+        let mut written = Vec::new();
+        file.write_to(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), rendered);
+    }
+
+    #[test]
+    fn test_output_lock_refuses_a_second_run() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        let _lock = OutputLock::acquire(&root.join("out")).unwrap();
+        let error = lit.tangle().unwrap_err();
+        let pid = std::process::id().to_string();
+        assert!(
+            matches!(&error, LitError::Locked { pid: holder, .. } if *holder == pid),
+            "{error}"
+        );
+        assert!(!root.join("out/a.txt").exists());
+    }
+
+    #[test]
+    fn test_output_lock_is_released_when_dropped() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(root.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+
+        drop(OutputLock::acquire(&root.join("out")).unwrap());
+        lit.tangle().unwrap();
+        assert_eq!(fs::read_to_string(root.join("out/a.txt")).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn test_output_lock_removes_its_file() {
+        let (_temp_dir, root) = temp_root();
+        let lock = OutputLock::acquire(&root.join("out")).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("out").join(LOCK_NAME)).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(lock);
+        assert!(!root.join("out").join(LOCK_NAME).exists());
+    }
+
+    #[test]
+    fn test_output_lock_takes_over_a_leftover_file() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("out")).unwrap();
+        for leftover in ["", "not a pid", "4194304\n"] {
+            fs::write(root.join("out").join(LOCK_NAME), leftover).unwrap();
+            let _lock = OutputLock::acquire(&root.join("out")).unwrap();
+            assert_eq!(
+                fs::read_to_string(root.join("out").join(LOCK_NAME)).unwrap(),
+                format!("{}\n", std::process::id())
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_lock_is_not_a_deleted_file() {
+        let (_temp_dir, root) = temp_root();
+        let path = root.join("lock");
+        let file = fs::File::create(&path).unwrap();
+        assert!(is_lock_file(&file, &path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(!is_lock_file(&file, &path).unwrap());
+        fs::write(&path, "").unwrap();
+        assert!(!is_lock_file(&file, &path).unwrap());
+    }
+
+    /// A server initialized in a workspace that tangles to `gen`, and the URI of its `doc.md`
+    fn language_server() -> (tempfile::TempDir, Utf8PathBuf, LanguageServer, String) {
+        let (temp_dir, root) = temp_root();
+        fs::write(root.join("lit.toml"), "output = \"gen\"\n").unwrap();
+        let mut server = LanguageServer::new();
+        let reply = server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": {"rootUri": Url::from_file_path(&root).unwrap().as_str()},
+        }));
+        assert_eq!(reply[0]["result"]["capabilities"]["hoverProvider"], true);
+        let uri = Url::from_file_path(root.join("doc.md"))
+            .unwrap()
+            .to_string();
+        (temp_dir, root, server, uri)
+    }
+
+    /// The diagnostics published after `uri` is opened with `text`
+    fn did_open(server: &mut LanguageServer, uri: &str, text: &str) -> serde_json::Value {
+        let reply = server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "markdown", "version": 1, "text": text}},
+        }));
+        reply[0]["params"]["diagnostics"].clone()
+    }
+
+    /// The hover text for `line` of `uri`
+    fn hover(server: &mut LanguageServer, uri: &str, line: usize) -> serde_json::Value {
+        server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover",
+            "params": {"textDocument": {"uri": uri}, "position": {"line": line, "character": 3}},
+        }))[0]["result"]["contents"]["value"]
+            .clone()
+    }
+
+    /// The document links for `uri`
+    fn document_links(server: &mut LanguageServer, uri: &str) -> serde_json::Value {
+        server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "id": 3, "method": "textDocument/documentLink",
+            "params": {"textDocument": {"uri": uri}},
+        }))[0]["result"]
+            .clone()
+    }
+
+    const LANGUAGE_SERVER_DOC: &str = "# Doc\n\n```rust tangle:///a.rs?id=a&last\nfn a() {\n{{}}\n}\n```\n\n```tangle:///a.rs?first\nb\n```\n\n```tangle:///a.rs?inside=a\nx\n```\n";
+
+    #[test]
+    fn test_language_server_reads_the_output_from_the_workspace_config() {
+        let (_temp_dir, root, server, _uri) = language_server();
+        assert_eq!(server.output, root.join("gen"));
+    }
+
+    #[test]
+    fn test_language_server_reports_errors_in_the_document() {
+        let (_temp_dir, _root, mut server, uri) = language_server();
+        // A missing ID may be declared in another document, so only the duplicate is reported.
+        let text = "# Doc\n\n```tangle:///a.rs?id=a\nfn a() {}\n```\n\n```tangle:///a.rs?id=a\n```\n\n```tangle:///b.rs?after=elsewhere\n```\n";
+        let diagnostics = did_open(&mut server, &uri, text);
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1, "{diagnostics}");
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 6);
+        assert_eq!(diagnostics[0]["range"]["end"]["character"], 22);
+        assert!(
+            diagnostics[0]["message"]
+                .as_str()
+                .unwrap()
+                .contains("Duplicate")
+        );
+    }
+
+    #[test]
+    fn test_language_server_clears_errors_when_a_change_fixes_them() {
+        let (_temp_dir, _root, mut server, uri) = language_server();
+        did_open(
+            &mut server,
+            &uri,
+            "```tangle:///a.rs?id=a\n```\n\n```tangle:///a.rs?id=a\n```\n",
+        );
+        let reply = server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "method": "textDocument/didChange",
+            "params": {"textDocument": {"uri": uri, "version": 2}, "contentChanges": [{"text": LANGUAGE_SERVER_DOC}]},
+        }));
+        assert_eq!(reply[0]["params"]["diagnostics"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_language_server_hover_shows_where_a_fence_is_tangled() {
+        let (_temp_dir, _root, mut server, uri) = language_server();
+        did_open(&mut server, &uri, LANGUAGE_SERVER_DOC);
+        assert_eq!(
+            hover(&mut server, &uri, 2),
+            "Tangled to `a.rs`, block 2 of 2, lines 3–5"
+        );
+        assert_eq!(
+            hover(&mut server, &uri, 12),
+            "Tangled to `a.rs`, inside `a`"
+        );
+        assert_eq!(hover(&mut server, &uri, 3), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_language_server_links_each_fence_to_its_target() {
+        let (_temp_dir, root, mut server, uri) = language_server();
+        did_open(&mut server, &uri, LANGUAGE_SERVER_DOC);
+        let links = document_links(&mut server, &uri);
+        assert_eq!(links.as_array().unwrap().len(), 3);
+        assert_eq!(
+            links[0]["range"]["start"],
+            serde_json::json!({"line": 2, "character": 8})
+        );
+        assert_eq!(
+            links[0]["range"]["end"],
+            serde_json::json!({"line": 2, "character": 32})
+        );
+        assert_eq!(
+            links[0]["target"],
+            Url::from_file_path(root.join("gen/a.rs")).unwrap().as_str()
+        );
+    }
+
+    #[test]
+    fn test_language_server_hover_works_on_a_document_that_cannot_tangle_alone() {
+        let (_temp_dir, _root, mut server, uri) = language_server();
+        assert_eq!(
+            did_open(&mut server, &uri, "```tangle:///c.rs?after=x\n```\n"),
+            serde_json::json!([])
+        );
+        assert_eq!(hover(&mut server, &uri, 0), "Tangled to `c.rs`");
+    }
+
+    #[test]
+    fn test_language_server_forgets_a_closed_document() {
+        let (_temp_dir, _root, mut server, uri) = language_server();
+        did_open(&mut server, &uri, LANGUAGE_SERVER_DOC);
+        let reply = server.handle(&serde_json::json!({
+            "jsonrpc": "2.0", "method": "textDocument/didClose",
+            "params": {"textDocument": {"uri": uri}},
+        }));
+        assert_eq!(reply[0]["params"]["diagnostics"], serde_json::json!([]));
+        assert!(server.documents.is_empty());
+        assert_eq!(hover(&mut server, &uri, 2), serde_json::Value::Null);
+        assert_eq!(document_links(&mut server, &uri), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_language_server_answers_other_requests() {
+        let (_temp_dir, _root, mut server, _uri) = language_server();
+        let reply = server.handle(
+            &serde_json::json!({"jsonrpc": "2.0", "id": 5, "method": "textDocument/completion"}),
+        );
+        assert_eq!(reply[0]["error"]["code"], -32601);
+        assert!(
+            server
+                .handle(&serde_json::json!({"jsonrpc": "2.0", "method": "$/cancelRequest"}))
+                .is_empty()
+        );
+        let reply =
+            server.handle(&serde_json::json!({"jsonrpc": "2.0", "id": 6, "method": "shutdown"}));
+        assert_eq!(reply[0]["result"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_language_server_works_without_a_workspace() {
+        assert_eq!(document_name("untitled:1"), "untitled:1");
+        let mut server = LanguageServer::new();
+        server.handle(&serde_json::json!({"method": "initialize", "id": 1, "params": {}}));
+        assert_eq!(server.output, "out");
+        let diagnostics = did_open(&mut server, "untitled:1", "```tangle:///a.rs?id=\n```\n");
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 0);
+    }
+
+    #[test]
+    fn test_language_server_messages_round_trip() {
+        let mut written = Vec::new();
+        let message = serde_json::json!({"jsonrpc": "2.0", "method": "exit"});
+        write_message(&mut written, &message).unwrap();
+        assert!(written.starts_with(b"Content-Length: 33\r\n\r\n"));
+
+        let mut reader = std::io::Cursor::new([b"X-Other: 1\r\n".as_slice(), &written].concat());
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_language_server_messages_need_a_valid_length() {
+        let mut reader = std::io::Cursor::new(b"\r\n{}".as_slice());
+        assert!(matches!(
+            read_message(&mut reader),
+            Err(LitError::Protocol(_))
+        ));
+        let mut reader = std::io::Cursor::new(b"Content-Length: 2\r\n\r\n{".as_slice());
+        assert!(read_message(&mut reader).is_err());
+        let mut reader = std::io::Cursor::new(b"Content-Length: 1\r\n\r\n{".as_slice());
+        assert!(matches!(
+            read_message(&mut reader),
+            Err(LitError::Protocol(_))
+        ));
+        let mut reader =
+            std::io::Cursor::new(b"Content-Length: 18446744073709551615\r\n\r\n{}".as_slice());
+        assert!(
+            matches!(read_message(&mut reader), Err(LitError::Protocol(message)) if message.contains("over the limit"))
+        );
+    }
+
+    #[test]
+    fn test_manifest_format_versions() {
+        let (_temp_dir, root) = temp_root();
+        let path = root.join(MANIFEST_NAME);
+
+        // Version 0 had no version line.
+        fs::write(&path, "# Files generated by lit. Do not edit.\na.txt\n").unwrap();
+        let old = Manifest::load(&root).unwrap();
+        assert_eq!(old.format_version, 0);
+        assert_eq!(old.files, [Utf8PathBuf::from("a.txt")].into());
+        assert!(old.render().contains("# format_version: 1\n"));
+
+        fs::write(&path, "# format_version: 2\na.txt\n").unwrap();
+        let error = Manifest::load(&root).unwrap_err();
+        assert!(matches!(&error, LitError::UnsupportedFormat { found, .. } if found == "2"));
+
+        fs::write(&path, "# format_version: one\n").unwrap();
+        let error = Manifest::load(&root).unwrap_err();
+        assert!(matches!(&error, LitError::UnsupportedFormat { found, .. } if found == "one"));
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let (_temp_dir, root) = temp_root();
+        assert_eq!(Manifest::load(&root).unwrap(), Manifest::default());
+
+        let manifest = Manifest {
+            files: [Utf8PathBuf::from("src/b.rs"), Utf8PathBuf::from("a.txt")].into(),
+            ..Manifest::default()
+        };
+        let text = manifest.render();
+        assert_eq!(
+            text,
+            "# Files generated by lit. Do not edit.\n# format_version: 1\na.txt\nsrc/b.rs\n"
+        );
+        fs::write(root.join(MANIFEST_NAME), text).unwrap();
+        assert_eq!(Manifest::load(&root).unwrap(), manifest);
+    }
+
+    /// A document tangling `a.rs` and `b.rs` into an output that already has them, and a `Lit` for it
+    fn overwrite_input(a: &str, b: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let input = root.join("in");
+        let output = root.join("out");
+        fs::create_dir_all(&input).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///a.rs\na\n```\n\n```tangle:///b.rs\nb\n```\n",
+        )
+        .unwrap();
+        fs::write(output.join("a.rs"), a).unwrap();
+        fs::write(output.join("b.rs"), b).unwrap();
+        let lit = Lit::new(input, output.clone());
+        (temp_dir, output, lit)
+    }
+
+    #[test]
+    fn test_tangle_refuses_to_overwrite_hand_written_files() {
+        let (_temp_dir, output, lit) = overwrite_input("hand-written\n", "b\n");
+        let error = lit.tangle().unwrap_err();
+        assert!(matches!(&error, LitError::WouldOverwrite { path } if path == "a.rs"));
+        assert_eq!(
+            fs::read_to_string(output.join("a.rs")).unwrap(),
+            "hand-written\n"
+        );
+        assert!(!output.join(MANIFEST_NAME).exists());
+    }
+
+    #[test]
+    fn test_tangle_overwrites_files_with_a_header() {
+        let (_temp_dir, output, lit) =
+            overwrite_input("// Generated by lit 0.0.0 from x.md.\nold\n", "b\n");
+        lit.tangle().unwrap();
+        assert_eq!(fs::read_to_string(output.join("a.rs")).unwrap(), "a\n");
+        assert_eq!(Manifest::load(&output).unwrap().files.len(), 2);
+    }
+
+    #[test]
+    fn test_tangle_overwrites_files_in_the_manifest() {
+        let (_temp_dir, output, lit) =
+            overwrite_input("// Generated by lit 0.0.0 from x.md.\nold\n", "b\n");
+        lit.tangle().unwrap();
+        fs::write(output.join("b.rs"), "edited\n").unwrap();
+        lit.tangle().unwrap();
+        assert_eq!(fs::read_to_string(output.join("b.rs")).unwrap(), "b\n");
+    }
+
+    #[test]
+    fn test_tangle_overwrites_anything_when_forced() {
+        let (_temp_dir, output, mut lit) = overwrite_input("a\n", "edited\n");
+        assert!(matches!(lit.tangle(), Err(LitError::WouldOverwrite { path }) if path == "b.rs"));
+        lit.force = true;
+        lit.tangle().unwrap();
+        assert_eq!(fs::read_to_string(output.join("b.rs")).unwrap(), "b\n");
+    }
+
+    #[test]
+    fn test_lit_header_must_start_the_file() {
+        assert!(has_lit_header(b"// Generated by lit 0.0.0 from x.md.\n"));
+        assert!(!has_lit_header(
+            b"#!/bin/sh\necho\n// Generated by lit 0.0.0\n"
+        ));
+    }
+
+    #[test]
+    fn test_preprocess_book() {
+        let chapter = |content: &str, sub_items: serde_json::Value| serde_json::json!({"Chapter": {"name": "c", "content": content, "sub_items": sub_items}});
+        let fence = "```tangle:///src/main.rs\nfn main() {}\n```\n";
+        let book = serde_json::json!({
+            "sections": [
+                chapter(fence, serde_json::json!([chapter(fence, serde_json::json!([]))])),
+                "Separator",
+                {"PartTitle": "Part"},
+            ],
+            "__non_exhaustive": null,
+        });
+        let input = serde_json::json!([{"root": "/book", "renderer": "html"}, book]).to_string();
+
+        let output: serde_json::Value =
+            serde_json::from_str(&preprocess_book(&input).unwrap()).unwrap();
+        let rendered = "```rust\nfn main() {}\n```\n";
+        assert_eq!(output["sections"][0]["Chapter"]["content"], rendered);
+        assert_eq!(
+            output["sections"][0]["Chapter"]["sub_items"][0]["Chapter"]["content"],
+            rendered
+        );
+        assert_eq!(output["sections"][1], "Separator");
+        assert!(output["__non_exhaustive"].is_null());
+
+        assert!(matches!(preprocess_book("{}"), Err(LitError::Book(_))));
+    }
+
+    #[test]
+    fn test_prepare_book() {
+        let (_temp_dir, book) = temp_root();
+        fs::create_dir_all(book.join("chapters/parts")).unwrap();
+        fs::write(book.join("book.toml"), "[book]\nsrc = \"chapters\"\n").unwrap();
+        fs::write(
+            book.join("chapters/intro.md"),
+            "Prose first.\n\n# Introduction \n",
+        )
+        .unwrap();
+        fs::write(book.join("chapters/parts/untitled.md"), "No heading.\n").unwrap();
+        fs::write(book.join("chapters/logo.png"), "").unwrap();
+
+        let (src, summary) = prepare_book(&book).unwrap();
+        assert_eq!(src, book.join("chapters"));
+        assert_eq!(summary, Some(src.join("SUMMARY.md")));
+        let expected = "# Summary\n\n- [Introduction](intro.md)\n- [untitled](parts/untitled.md)\n";
+        assert_eq!(
+            fs::read_to_string(src.join("SUMMARY.md")).unwrap(),
+            expected
+        );
+
+        // An existing summary is left alone, and is not a chapter of its own.
+        assert_eq!(prepare_book(&book).unwrap(), (src.clone(), None));
+        assert_eq!(book_summary(&src).unwrap(), expected);
+
+        fs::remove_file(book.join("book.toml")).unwrap();
+        fs::create_dir_all(book.join("src")).unwrap();
+        assert_eq!(prepare_book(&book).unwrap().0, book.join("src"));
+        fs::write(book.join("book.toml"), "[book\n").unwrap();
+        assert!(matches!(prepare_book(&book), Err(LitError::Book(_))));
+    }
+
+    /// The permission bits of `path`
+    #[cfg(unix)]
+    fn file_permissions(path: &Utf8Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_chmod_sets_file_modes() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///run.sh?chmod=0755\n#!/bin/sh\n```\n\n```tangle:///run.sh?chmod=600\necho\n```\n",
+        )
+        .unwrap();
+        Lit::new(root.clone(), root.join("out")).tangle().unwrap();
+        assert_eq!(file_permissions(&root.join("out/run.sh")), 0o755);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_chmod_restores_the_mode_of_an_unchanged_file() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///run.sh?chmod=755\n#!/bin/sh\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        lit.tangle().unwrap();
+        fs::set_permissions(
+            root.join("out/run.sh"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        assert_eq!(
+            lit.tangle().unwrap().files[0].status,
+            WriteStatus::Unchanged
+        );
+        assert_eq!(file_permissions(&root.join("out/run.sh")), 0o755);
+    }
+
+    #[test]
+    fn test_chmod_rejects_invalid_modes() {
+        for value in ["abc", "9", "17777"] {
+            let markdown = format!("```tangle:///a.sh?chmod={value}\n```\n");
+            let error = tangle_document("doc.md", &markdown).unwrap_err();
+            let LitError::Located(located) = error else {
+                panic!("expected a located error, got {error}");
+            };
+            assert!(matches!(&located.error, BlockError::InvalidMode(mode) if mode == value));
+        }
+    }
+
+    const MOVE_DOC: &str = "# A\n\n```rust tangle:///src/util.rs?id=use&first #imports\nuse std::fmt;\n```\n\n~~~~tangle:///src/util.rs?after=use\nfn f() {}\n~~~~\n";
+
+    /// Two documents tangling `src/util.rs` and `src/main.rs`, already tangled into `out`
+    fn move_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a.md"), MOVE_DOC).unwrap();
+        fs::write(root.join("docs/b.md"), "```tangle:///src/util.rs\nfn g() {}\n```\n\n```tangle:///src/main.rs\nfn main() {}\n```\n").unwrap();
+        let lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.tangle().unwrap();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_move_target_rewrites_every_fence() {
+        let (_temp_dir, root, lit) = move_input();
+        let before = lit.extract(Utf8Path::new("src/util.rs")).unwrap();
+        let changed = lit
+            .move_target(
+                Utf8Path::new("src/util.rs"),
+                Utf8Path::new("src/util/my mod.rs"),
+                MovedOutput::Move,
+            )
+            .unwrap();
+        assert_eq!(changed, [root.join("docs/a.md"), root.join("docs/b.md")]);
+        assert_eq!(
+            fs::read_to_string(root.join("docs/a.md")).unwrap(),
+            MOVE_DOC.replace("tangle:///src/util.rs", "tangle:///src/util/my%20mod.rs")
+        );
+        assert_eq!(
+            lit.extract(Utf8Path::new("src/util/my mod.rs")).unwrap(),
+            before
+        );
+    }
+
+    #[test]
+    fn test_move_target_moves_the_output() {
+        let (_temp_dir, root, lit) = move_input();
+        lit.move_target(
+            Utf8Path::new("src/util.rs"),
+            Utf8Path::new("src/util/my mod.rs"),
+            MovedOutput::Move,
+        )
+        .unwrap();
+        assert!(!root.join("out/src/util.rs").exists());
+        assert_eq!(lit.tangle().unwrap().count(WriteStatus::Unchanged), 2);
+    }
+
+    #[test]
+    fn test_move_target_can_delete_the_output() {
+        let (_temp_dir, root, lit) = move_input();
+        lit.move_target(
+            Utf8Path::new("src/util.rs"),
+            Utf8Path::new("src/core.rs"),
+            MovedOutput::Delete,
+        )
+        .unwrap();
+        assert!(!root.join("out/src/util.rs").exists());
+        assert!(!root.join("out/src/core.rs").exists());
+    }
+
+    #[test]
+    fn test_move_target_refuses_a_target_already_tangled() {
+        let (_temp_dir, _root, lit) = move_input();
+        let error = lit
+            .move_target(
+                Utf8Path::new("src/util.rs"),
+                Utf8Path::new("src/main.rs"),
+                MovedOutput::Move,
+            )
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("src/main.rs is already tangled, from"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_move_target_refuses_unknown_and_unwritable_targets() {
+        let (_temp_dir, _root, lit) = move_input();
+        assert!(matches!(
+            lit.move_target(
+                Utf8Path::new("src/gone.rs"),
+                Utf8Path::new("src/new.rs"),
+                MovedOutput::Keep
+            ),
+            Err(LitError::UnknownTarget { .. })
+        ));
+        assert!(matches!(
+            lit.move_target(
+                Utf8Path::new("src/util.rs"),
+                Utf8Path::new("../util.rs"),
+                MovedOutput::Keep
+            ),
+            Err(LitError::CannotMove { .. })
+        ));
+    }
+
+    #[test]
+    fn test_move_target_refuses_paths_from_front_matter() {
+        let (_temp_dir, root, lit) = move_input();
+        fs::write(
+            root.join("docs/c.md"),
+            "---\nlit:\n  prefix: src\n---\n\n```tangle:///lib.rs\n```\n",
+        )
+        .unwrap();
+        let error = lit
+            .move_target(
+                Utf8Path::new("src/lib.rs"),
+                Utf8Path::new("src/core.rs"),
+                MovedOutput::Keep,
+            )
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("c.md:6 writes it as lib.rs; change that fence by hand"),
+            "{error}"
+        );
+    }
+
+    /// A document with a runnable `src/main.rs` and a library file, and a `Lit` reading it with `config`
+    fn playground_input(config: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "# Run\n\n```tangle:///src/main.rs\nuse std::fmt;\n```\n\n```tangle:///src/main.rs\nfn main() {}\n```\n\n```tangle:///src/lib.rs\npub fn f() {}\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), config).unwrap();
+        (temp_dir, root, lit)
+    }
+
+    const PLAYGROUND_URL: &str = "https://play.rust-lang.org/?version=stable&mode=debug&edition=2024&code=use%20std%3A%3Afmt%3B%0A%0Afn%20main%28%29%20%7B%7D%0A";
+
+    #[test]
+    fn test_playground_links_are_off_by_default() {
+        let (_temp_dir, _root, lit) = playground_input("");
+        assert!(lit.playground_links().unwrap().is_empty());
+        assert!(
+            !lit.weave(WeaveFormat::Typst)
+                .unwrap()
+                .contains("play.rust-lang.org")
+        );
+    }
+
+    #[test]
+    fn test_playground_links_only_files_with_a_main() {
+        let (_temp_dir, _root, lit) = playground_input("[playground]\n");
+        assert_eq!(
+            lit.playground_links().unwrap(),
+            HashMap::from([(Utf8PathBuf::from("src/main.rs"), PLAYGROUND_URL.to_string())])
+        );
+    }
+
+    #[test]
+    fn test_playground_links_follow_each_block_in_a_weave() {
+        let (_temp_dir, _root, lit) = playground_input("[playground]\n");
+        let typst = lit.weave(WeaveFormat::Typst).unwrap();
+        assert_eq!(
+            typst
+                .matches(&format!(
+                    "]\n\n#link(\"{PLAYGROUND_URL}\")[Run on playground]\n\n"
+                ))
+                .count(),
+            2,
+            "{typst}"
+        );
+        let latex = lit.weave(WeaveFormat::Latex).unwrap();
+        assert!(latex.contains("\\end{lstlisting}\n\n\\href{https://play.rust-lang.org/?version=stable&mode=debug&edition=2024&code=use\\%20std"), "{latex}");
+    }
+
+    #[test]
+    fn test_playground_links_follow_the_site_cross_reference() {
+        let (_temp_dir, root, lit) = playground_input("[playground]\n");
+        lit.copy_site(&root.join("site")).unwrap();
+        let page = fs::read_to_string(root.join("site/a.md")).unwrap();
+        assert!(page.contains(&format!("→ [src/main.rs](lit-targets/src/main.rs.md), line 3 · [Run on playground]({PLAYGROUND_URL})")), "{page}");
+        assert!(
+            page.contains("→ [src/lib.rs](lit-targets/src/lib.rs.md), line 1\n"),
+            "{page}"
+        );
+    }
+
+    #[test]
+    fn test_playground_url_uses_the_configured_edition_and_channel() {
+        let playground = Playground {
+            edition: "2021".to_string(),
+            channel: "nightly".to_string(),
+        };
+        assert_eq!(
+            playground.url("x"),
+            "https://play.rust-lang.org/?version=nightly&mode=debug&edition=2021&code=x"
+        );
+    }
+
+    /// A Markdown and an AsciiDoc document, with source, transform, and output plugins configured
+    fn plugins_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///a.txt?id=a\nhello from markdown\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("app.adoc"),
+            "= App\n\n[source]\n----\nhello from asciidoc\n----\n",
+        )
+        .unwrap();
+        let fences = r#"{"fences":[{"url":"tangle:///a.txt?after=a","line":3,"end_line":6,"content":"hello from asciidoc"}]}"#;
+        let toml = format!(
+            "[plugins.asciidoc]\ncommand = '''cat > /dev/null; printf '%s' '{fences}' '''\nsources = [\"adoc\"]\n\n[plugins.shout]\ncommand = \"sed s/hello/HELLO/g\"\ntransform = true\n\n[plugins.publish]\ncommand = \"cat > {root}/published.json\"\noutput = true\n"
+        );
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), &toml).unwrap();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_plugins_read_sources_and_transform_blocks() {
+        let (_temp_dir, root, lit) = plugins_input();
+        lit.tangle().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("out/a.txt")).unwrap(),
+            "HELLO from markdown\n\nHELLO from asciidoc\n"
+        );
+    }
+
+    #[test]
+    fn test_plugin_sources_keep_their_locations() {
+        let (_temp_dir, _root, lit) = plugins_input();
+        let blocks = lit.blocks().unwrap();
+        let origin = blocks[0].origin.as_ref().unwrap();
+        assert_eq!(
+            (origin.file.file_name(), origin.line, origin.end_line),
+            (Some("app.adoc"), 3, 6)
+        );
+    }
+
+    #[test]
+    fn test_output_plugins_see_the_written_files() {
+        let (_temp_dir, root, lit) = plugins_input();
+        lit.tangle().unwrap();
+        let published: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(root.join("published.json")).unwrap())
+                .unwrap();
+        assert_eq!(published["kind"], "output");
+        assert_eq!(published["files"][0]["path"], "a.txt");
+        assert_eq!(published["files"][0]["changed"], true);
+    }
+
+    #[test]
+    fn test_plugins_fail_on_bad_replies_and_exit_statuses() {
+        let (_temp_dir, _root, mut lit) = plugins_input();
+        lit.config.plugins.get_mut("shout").unwrap().command = "echo '{\"blocks\":[]}'".to_string();
+        let error = lit.tangle().unwrap_err();
+        assert!(
+            matches!(&error, LitError::PluginFailed { name, .. } if name == "shout"),
+            "{error}"
+        );
+        lit.config.plugins.get_mut("shout").unwrap().command = "exit 4".to_string();
+        assert!(
+            lit.tangle()
+                .unwrap_err()
+                .to_string()
+                .contains("exit status: 4")
+        );
+    }
+
+    #[test]
+    fn test_plugins_are_off_in_a_sandbox() {
+        let (_temp_dir, _root, mut lit) = plugins_input();
+        lit.config.sandbox();
+        assert!(lit.config.plugins.is_empty());
+    }
+
+    #[test]
+    fn test_plugins_config_rejects_invalid_plugins() {
+        for invalid in [
+            "[plugins.x]\nsources = [\"adoc\"]\n",
+            "[plugins.x]\ncommand = \"x\"\nsources = [\"md\"]\n",
+            "[plugins.x]\ncommand = \"x\"\nsources = [\"adoc\"]\n[plugins.y]\ncommand = \"y\"\nsources = [\"adoc\"]\n",
+        ] {
+            assert!(
+                Config::parse(Utf8Path::new("lit.toml"), invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_profile() {
+        let report = TangleReport {
+            timings: vec![
+                ("walk", Duration::from_micros(812)),
+                ("parse", Duration::from_millis(20)),
+            ],
+            documents: vec![(
+                Utf8PathBuf::from("docs/lit.md"),
+                Duration::from_micros(9120),
+            )],
+            ..TangleReport::default()
+        };
+        assert_eq!(
+            report.profile().to_string(),
+            r#"{"documents":[{"micros":9120,"path":"docs/lit.md"}],"peak_memory_bytes":null,"phases":[{"micros":812,"phase":"walk"},{"micros":20000,"phase":"parse"}]}"#
+        );
+
+        #[cfg(target_os = "linux")]
+        assert!(peak_memory().is_some_and(|bytes| bytes > 0));
+    }
+
+    /// A repository whose document breaks each rule, and a `Lit` for it
+    fn render_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "+++\ntitle = \"A\"\n+++\n\n```tangle:///src/a.rs?id=a\nfn a() {}\n```\n\n```tangle:///run?lang=bash\nlit\n```\n\n```tangle:///notes\nx\n```\n\n```mermaid tangle:///a.mmd\ngraph TD\n```\n\n```rust tangle:///src/b.rs\nfn b() {}\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.join("docs"), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    /// The `render-compat` findings for `lit`, as `line: message`
+    fn render_findings(lit: &Lit) -> Vec<String> {
+        lit.lint()
+            .unwrap()
+            .into_iter()
+            .filter(|finding| finding.rule == "render-compat")
+            .map(|finding| format!("{}: {}", finding.line, finding.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_render_compat_is_quiet_without_a_platform() {
+        let (_temp_dir, _root, lit) = render_input();
+        assert!(lit.platforms().is_empty());
+        assert!(render_findings(&lit).is_empty());
+    }
+
+    #[test]
+    fn test_render_compat_warns_for_github() {
+        let (_temp_dir, root, lit) = render_input();
+        fs::create_dir_all(root.join(".github")).unwrap();
+        assert_eq!(lit.platforms(), [Platform::GitHub]);
+        assert_eq!(
+            render_findings(&lit),
+            [
+                "1: `+++` front matter shows as text on GitHub; write it as `---` YAML",
+                "5: a tangle URL as the fence's language is not highlighted on GitHub; write `rust tangle:///src/a.rs?id=a`",
+                "9: a tangle URL as the fence's language is not highlighted on GitHub; write `bash tangle:///run?lang=bash`",
+                "13: a tangle URL as the fence's language is not highlighted on GitHub; put the block's language before it",
+                "17: a `mermaid` fence renders on GitHub as something other than the code it tangles",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_compat_detects_every_platform() {
+        let (_temp_dir, root, lit) = render_input();
+        fs::create_dir_all(root.join(".github")).unwrap();
+        fs::write(root.join(".gitlab-ci.yml"), "").unwrap();
+        assert_eq!(lit.platforms(), [Platform::GitHub, Platform::GitLab]);
+    }
+
+    #[test]
+    fn test_render_compat_platforms_can_be_configured() {
+        let (_temp_dir, root, mut lit) = render_input();
+        fs::create_dir_all(root.join(".github")).unwrap();
+        lit.config = Config::parse(Utf8Path::new("lit.toml"), "render = [\"gitlab\"]\n").unwrap();
+        assert_eq!(lit.platforms(), [Platform::GitLab]);
+        assert_eq!(render_findings(&lit).len(), 4);
+        assert!(Config::parse(Utf8Path::new("lit.toml"), "render = [\"bitbucket\"]\n").is_err());
+    }
+
+    /// A reStructuredText document and a Markdown one tangling `app.py` together, and a `Lit` for them
+    fn rst_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("app.rst"),
+            "App\n===\n\n.. code-block:: python\n   :tangle: tangle:///app.py?id=main\n   :linenos:\n\n   def main():\n       pass\n\n\nMore prose.\n\n.. note::\n\n   .. code::\n      :tangle: tangle:///app.py?before=main\n\n      import sys\n\n.. code-block:: python\n\n   untangled = True\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("readme.md"),
+            "```tangle:///app.py?after=main\nmain()\n```\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_rst_code_blocks_tangle_with_markdown() {
+        let (_temp_dir, root, lit) = rst_input();
+        lit.tangle().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("out/app.py")).unwrap(),
+            "import sys\n\ndef main():\n    pass\n\nmain()\n"
+        );
+    }
+
+    #[test]
+    fn test_rst_code_blocks_keep_their_lines_and_language() {
+        let (_temp_dir, _root, lit) = rst_input();
+        let blocks = lit.blocks().unwrap();
+        let origin = blocks[0].origin.as_ref().unwrap();
+        assert_eq!((origin.line, origin.end_line), (5, 9));
+        assert_eq!(blocks[0].lang.as_deref(), Some("python"));
+        assert_eq!(blocks[1].origin.as_ref().unwrap().line, 17);
+    }
+
+    #[test]
+    fn test_rst_sources_are_not_linted_or_formatted() {
+        let (_temp_dir, _root, lit) = rst_input();
+        assert!(lit.lint().unwrap().is_empty());
+        assert_eq!(lit.format(true).unwrap(), Vec::<Utf8PathBuf>::new());
+    }
+
+    #[test]
+    fn test_rst_errors_point_at_the_tangle_option() {
+        let error = Lit::parse_file(
+            Utf8Path::new("doc.rst"),
+            ".. code-block:: rust\n   :tangle: tangle:///\n\n   x\n",
+        )
+        .unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert_eq!(located.origin.line, 2);
+    }
+
+    #[test]
+    fn test_rst_code_blocks_may_be_empty() {
+        let empty = Lit::parse_file(
+            Utf8Path::new("doc.rst"),
+            ".. code-block:: rust\n   :tangle: tangle:///a.rs\n",
+        )
+        .unwrap();
+        assert_eq!(empty[0].content, "");
+    }
+
+    #[test]
+    fn test_origin_of_line() {
+        let markdown = "# Doc\n\n```tangle:///a.rs\nfn a() {}\nfn b() {}\n```\n\n```tangle:///a.rs\nfn c() {}\n```\n";
+        let files = tangle_document("doc.md", markdown).unwrap();
+        let file = &files[0];
+
+        let line = |n| file.origin_of_line(n).map(|origin| origin.line);
+        assert_eq!(line(1), Some(4));
+        assert_eq!(line(2), Some(5));
+        assert_eq!(line(3), None);
+        assert_eq!(line(4), Some(9));
+        assert_eq!(line(5), None);
+        assert_eq!(file.origin_of_line(1).unwrap().file, "doc.md");
+    }
+
+    #[test]
+    fn test_rustfmt_hook_points_at_markdown() {
+        let (_temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(
+            input.join("doc.md"),
+            "# Doc\n\n```tangle:///main.rs\nfn main() {}\n```\n\n```tangle:///main.rs\nfn broken( {}\n```\n\n```tangle:///notes.txt\nx\n```\n",
+        )
+        .unwrap();
+
+        let mut lit = Lit::new(input.clone(), root.join("out"));
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "header = true\n[rust]\nrustfmt = true\n",
+        )
+        .unwrap();
+        let error = lit.tangle().unwrap_err();
+        assert!(error.to_string().starts_with("rustfmt failed"), "{error}");
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("(from {input}/doc.md:8)")),
+            "{error}"
+        );
+
+        fs::write(
+            input.join("doc.md"),
+            "```tangle:///main.rs\nfn main() {}\n```\n",
+        )
+        .unwrap();
+        lit.tangle().unwrap();
+    }
+
+    #[test]
+    fn test_cargo_check_hook_reports_failure() {
+        let (_temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///main.rs\nfn main() {}\n```\n",
+        )
+        .unwrap();
+
+        // Without a Cargo.toml in reach, cargo check itself fails.
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.config.rust.check = true;
+        let error = lit.tangle().unwrap_err();
+        assert!(matches!(
+            error,
+            LitError::HookFailed {
+                tool: "cargo check",
+                ..
+            }
+        ));
+
+        // A run without Rust files skips the hooks.
+        fs::write(root.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+        lit.output = root.join("txt");
+        lit.tangle().unwrap();
+    }
+
+    const CARGO_MANIFEST_FENCE: &str = "```tangle:///app/Cargo.toml\n[package]\nname = \"app\"\nedition = \"2024\"\n\n[workspace]\n```\n\n";
+
+    /// A `Lit` with the cargo hook on, for an `in/doc.md` of `markdown`
+    fn cargo_input(markdown: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let input = root.join("in");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(input.join("doc.md"), markdown).unwrap();
+        let mut lit = Lit::new(input.clone(), root.join("out"));
+        lit.config.rust.cargo = true;
+        (temp_dir, input, lit)
+    }
+
+    #[test]
+    fn test_cargo_hook_needs_a_tangled_crate() {
+        let (_temp_dir, _input, lit) = cargo_input("```tangle:///main.rs\nfn main() {}\n```\n");
+        lit.tangle().unwrap();
+    }
+
+    #[test]
+    fn test_cargo_hook_checks_tangled_crates() {
+        let markdown = format!(
+            "{CARGO_MANIFEST_FENCE}```tangle:///app/src/main.rs\nfn main() {{\n    let _x: u32 = \"a\";\n}}\n```\n"
+        );
+        let (_temp_dir, input, lit) = cargo_input(&markdown);
+        let error = lit.tangle().unwrap_err();
+        assert!(
+            error.to_string().starts_with("cargo check failed"),
+            "{error}"
+        );
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("(from {input}/doc.md:11)")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_cargo_hook_checks_formatting() {
+        let markdown =
+            format!("{CARGO_MANIFEST_FENCE}```tangle:///app/src/main.rs\nfn main() {{  }}\n```\n");
+        let (_temp_dir, _input, lit) = cargo_input(&markdown);
+        let error = lit.tangle().unwrap_err();
+        assert!(error.to_string().starts_with("cargo fmt failed"), "{error}");
+    }
+
+    /// A `docs` input beside a secret, with `doc.md` holding `markdown`, and a sandboxed `Lit` for it
+    fn sandboxed(markdown: &str) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join("secret.txt"), "secret\n").unwrap();
+        fs::write(root.join("docs/data.txt"), "data\n").unwrap();
+        fs::write(root.join("docs/doc.md"), markdown).unwrap();
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[hooks]\npost_tangle = [\"touch ../hooked\"]\n[limits]\nmax_block_bytes = 4194304\nmax_file_bytes = 100\n",
+        )
+        .unwrap();
+        lit.config.sandbox();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_sandbox_drops_hooks_and_caps_limits() {
+        let (_temp_dir, _root, lit) = sandboxed("");
+        assert!(lit.config.sandbox);
+        assert!(lit.config.hooks.post_tangle.is_empty());
+        assert_eq!(
+            lit.config.limits,
+            Limits {
+                max_block_bytes: Some(1 << 20),
+                max_file_bytes: Some(100),
+                max_output_bytes: Some(64 << 20),
+            }
+        );
+    }
+
+    #[test]
+    fn test_sandbox_tangles_files_from_the_inputs() {
+        let (_temp_dir, root, lit) = sandboxed("```tangle:///a.txt?from=data.txt\n```\n");
+        lit.tangle().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("out/a.txt")).unwrap(),
+            "data\n"
+        );
+        assert!(!root.join("hooked").exists());
+    }
+
+    #[test]
+    fn test_sandbox_refuses_to_read_outside_the_inputs() {
+        let (_temp_dir, _root, lit) = sandboxed("```tangle:///a.txt?from=../secret.txt\n```\n");
+        let error = lit.tangle().unwrap_err();
+        let LitError::Located(located) = &error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(
+            matches!(&located.error, BlockError::UnreadableFile(message) if message.contains("outside the inputs"))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sandbox_refuses_to_read_through_a_symlink() {
+        let (_temp_dir, root, lit) = sandboxed("```tangle:///a.txt?from=link.txt\n```\n");
+        std::os::unix::fs::symlink(root.join("secret.txt"), root.join("docs/link.txt")).unwrap();
+        assert!(lit.tangle().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sandbox_refuses_to_write_through_a_symlink() {
+        let (_temp_dir, root, lit) = sandboxed("```tangle:///escape/x.txt\nx\n```\n");
+        std::os::unix::fs::symlink(&root, root.join("out/escape")).unwrap();
+        let error = lit.tangle().unwrap_err();
+        assert!(matches!(&error, LitError::Sandboxed { .. }), "{error}");
+        assert!(!root.join("x.txt").exists());
+    }
+
+    #[test]
+    fn test_sandbox_refuses_to_run_builds() {
+        let (_temp_dir, root, lit) = sandboxed("");
+        assert!(matches!(
+            lit.verify_build(&root),
+            Err(LitError::Sandboxed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sandbox_checks_paths_after_variables() {
+        let (_temp_dir, _root, mut lit) = sandboxed("```tangle:///{{up}}/x.txt\nx\n```\n");
+        lit.config
+            .variables
+            .insert("up".to_string(), "..".to_string());
+        assert!(lit.tangle().is_err());
+    }
+
+    #[test]
+    fn test_scan_fences_matches_the_tree() {
+        let summary = |document: &str, nodes: &[Node]| {
+            nodes
+                .iter()
+                .filter_map(|node| match node {
+                    Node::Code(code)
+                        if code.position.as_ref().is_some_and(|p| {
+                            document[p.start.offset..].starts_with(['`', '~'])
+                        }) =>
+                    {
+                        Some((
+                            code.lang.clone(),
+                            code.meta.clone(),
+                            code.value.clone(),
+                            code.position
+                                .as_ref()
+                                .map(|p| (p.start.line, p.start.offset, p.end.line, p.end.offset)),
+                        ))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let tree = |text: &str| {
+            let options = ParseOptions {
+                constructs: Constructs {
+                    frontmatter: true,
+                    ..Constructs::default()
+                },
+                ..ParseOptions::default()
+            };
+            match to_mdast(text, &options).unwrap() {
+                Node::Root(root) => root.children,
+                _ => unreachable!(),
+            }
+        };
+
+        let mut documents: Vec<String> = [
+            "```rust tangle:///a.rs?id=a\nfn a() {}\n\n```\n",
+            "# Title\n\nProse\n```\nno info\n```\n",
+            "````markdown\n```tangle:///inner.rs\n```\n````\n",
+            "~~~  tangle:///t.txt \t extra  words\nx\n~~~~~\nafter\n",
+            "- item\n\n  ```tangle:///nested.rs\n  x\n  ```\n\n```tangle:///top.rs\ny\n```\n",
+            "> ```tangle:///quoted.rs\n> x\n```tangle:///after.rs\n```\n",
+            "    ```tangle:///indented.rs\n    x\n",
+            "``` not `a fence`\n",
+            "``not a fence\n```\nunclosed\n",
+            "```tangle:///a.rs\n  ```   \nclosed above\n",
+            "<div>\n\n```tangle:///html.rs\n```\n\n</div>\n",
+            "  ```tangle:///a.rs\n  x\n  ```\n",
+            "---\ntitle: x\n---\n```tangle:///a.rs\n```\n",
+            "```tangle:///a&amp;b.rs\n```\n",
+            "```tangle:///a.rs\r\n```\r\n",
+            "```",
+            "```\nx\n\n",
+            "",
+        ]
+        .map(String::from)
+        .to_vec();
+        let lit = Lit::new(
+            Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("lit"),
+            Utf8PathBuf::new(),
+        );
+        for path in lit.markdown_files().unwrap() {
+            documents.push(fs::read_to_string(path).unwrap());
+        }
+
+        let mut scanned = 0;
+        for document in &documents {
+            if let Some(nodes) = scan_fences(document) {
+                assert_eq!(
+                    summary(document, &nodes),
+                    summary(document, &tree(document)),
+                    "{document}"
+                );
+                scanned += 1;
+            }
+        }
+        assert!(
+            scanned > documents.len() / 2,
+            "only {scanned} of {} scanned",
+            documents.len()
+        );
+    }
+
+    #[test]
+    fn test_scan_fences_declines_what_it_cannot_match() {
+        assert_eq!(scan_fences("<div>\n"), None);
+        assert_eq!(scan_fences("  ```tangle:///a.rs\n```\n"), None);
+        assert_eq!(scan_fences("---\n"), None);
+        assert_eq!(scan_fences("```tangle:///a\\b.rs\n```\n"), None);
+    }
+
+    /// The sections of a document with front matter, prose, and code, and of a short one
+    fn sections_input() -> (tempfile::TempDir, Utf8PathBuf, Vec<Section>) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "---\ntitle: A\n---\n\nOpening *words*.\n\n```tangle:///a.rs\nfn a() {}\n```\n\n# The `parser`\n\n- one\n- two\n\n<div>html</div>\n\n> quoted\n\n```sh\ncargo run\n```\n\n## Empty\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            "# B\n\n```tangle:///b.rs\nfn b() {}\n```\n",
+        )
+        .unwrap();
+        let sections = Lit::new(root.clone(), root.join("out")).sections().unwrap();
+        (temp_dir, root, sections)
+    }
+
+    #[test]
+    fn test_sections_split_documents_at_headings() {
+        let (_temp_dir, _root, sections) = sections_input();
+        let summary: Vec<_> = sections
+            .iter()
+            .map(|section| {
+                (
+                    section.file.file_name().unwrap(),
+                    section.title.as_deref(),
+                    section.line,
+                    section.prose.as_str(),
+                    section.code.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("a.md", None, 1, "Opening words.", "fn a() {}"),
+                (
+                    "a.md",
+                    Some("The parser"),
+                    11,
+                    "one\ntwo\nquoted",
+                    "cargo run"
+                ),
+                ("a.md", Some("Empty"), 24, "", ""),
+                ("b.md", Some("B"), 1, "", "fn b() {}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sections_list_their_fences() {
+        let (_temp_dir, _root, sections) = sections_input();
+        assert_eq!(sections[0].fences, [36]);
+    }
+
+    #[test]
+    fn test_search_index_lists_sections_of_rendered_pages() {
+        let (_temp_dir, root, sections) = sections_input();
+        let pages = HashMap::from([(root.join("b.md"), Utf8PathBuf::from("docs/b.md"))]);
+        let index: serde_json::Value =
+            serde_json::from_str(&search_index(sections, &pages).unwrap()).unwrap();
+        assert_eq!(
+            index,
+            serde_json::json!([{"file": "docs/b.md", "title": "B", "line": 1, "prose": "", "code": "fn b() {}"}])
+        );
+    }
+
+    #[test]
+    fn test_rewrite_fences() {
+        let markdown = r#"+++
+title = "Post"
++++
+
+```tangle:///src/main.rs?id=main
+fn main() {}
+```
+
+```rust tangle:///src/lib.rs linenos no-tangle
+pub fn f() {}
+```
+
+````tangle:///notes.unknown
+text
+````
+
+```tangle:///bin/run?lang=sh
+echo run
+```
+
+```tangle:///docker/Dockerfile
+FROM scratch
+```
+
+```tangle:///bin/serve
+#!/usr/bin/env -S node --no-warnings
+```
+
+```tangle:///bin/fetch
+#!/usr/bin/python3.12
+```
+
+```tangle:///bin/unknown
+#!/opt/tool
+```
+
+```python
+print("untouched")
+```
+
+> ```tangle:///quoted.rs
+> nested fences are not tangled, so they are left alone
+> ```
+"#;
+
+        let rewritten = rewrite_fences(markdown).unwrap();
+        assert_eq!(
+            rewritten,
+            markdown
+                .replace("```tangle:///src/main.rs?id=main", "```rust")
+                .replace(
+                    "```rust tangle:///src/lib.rs linenos no-tangle",
+                    "```rust linenos"
+                )
+                .replace("````tangle:///notes.unknown", "````")
+                .replace("```tangle:///bin/run?lang=sh", "```sh")
+                .replace("```tangle:///docker/Dockerfile", "```dockerfile")
+                .replace("```tangle:///bin/serve", "```javascript")
+                .replace("```tangle:///bin/fetch", "```python")
+                .replace("```tangle:///bin/unknown", "```")
+        );
+    }
+
+    /// A content directory with a post, an image, and a stale output, and a `Lit` tangling into it
+    fn site_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let content = root.join("content");
+        fs::create_dir_all(content.join("blog")).unwrap();
+        fs::write(
+            content.join("blog/post.md"),
+            "```tangle:///a.rs\nfn a() {}\n```\n",
+        )
+        .unwrap();
+        fs::write(content.join("logo.png"), [0x89, 0x50]).unwrap();
+        fs::create_dir_all(content.join("out")).unwrap();
+        fs::write(content.join("out/stale.md"), "").unwrap();
+        let lit = Lit::new(content.clone(), content.join("out"));
+        (temp_dir, content, lit)
+    }
+
+    #[test]
+    fn test_copy_site_copies_documents_and_other_files() {
+        let (_temp_dir, content, lit) = site_input();
+        let site = content.join("site");
+        assert_eq!(
+            lit.copy_site(&site).unwrap(),
+            vec![
+                site.join("blog/post.md"),
+                site.join("logo.png"),
+                site.join("lit-targets/a.rs.md"),
+                site.join("lit-search.json")
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(site.join("blog/post.md")).unwrap(),
+            "```rust\nfn a() {}\n```\n\n→ [a.rs](../lit-targets/a.rs.md), line 1\n"
+        );
+        assert_eq!(fs::read(site.join("logo.png")).unwrap(), vec![0x89, 0x50]);
+    }
+
+    #[test]
+    fn test_copy_site_skips_its_previous_copy() {
+        let (_temp_dir, content, lit) = site_input();
+        let site = content.join("site");
+        lit.copy_site(&site).unwrap();
+        assert_eq!(lit.copy_site(&site).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_copy_site_writes_a_search_index() {
+        let (_temp_dir, content, lit) = site_input();
+        let site = content.join("site");
+        lit.copy_site(&site).unwrap();
+        let search: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(site.join("lit-search.json")).unwrap())
+                .unwrap();
+        assert_eq!(search[0]["file"], "blog/post.md");
+        assert_eq!(search[0]["code"], "fn a() {}");
+    }
+
+    #[test]
+    fn test_copy_site_puts_single_files_at_the_top() {
+        let (_temp_dir, content, _lit) = site_input();
+        let root = content.parent().unwrap();
+        let mut single = Lit::new(content.join("blog/post.md"), root.join("out"));
+        single.inputs.push(content.join("logo.png"));
+        assert_eq!(
+            single.copy_site(&root.join("single")).unwrap(),
+            vec![
+                root.join("single/post.md"),
+                root.join("single/logo.png"),
+                root.join("single/lit-targets/a.rs.md"),
+                root.join("single/lit-search.json")
+            ]
+        );
+    }
+
+    /// A site copied from a nested block and its neighbours, with headers on, and the `Lit` that copied it
+    fn cross_reference_site() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        let content = root.join("content");
+        fs::create_dir_all(content.join("guide")).unwrap();
+        fs::write(
+            content.join("guide/intro.md"),
+            "# Intro\n\n```tangle:///src/lib.rs?id=outer&after=top\nmod a {\n{{}}\n}\n```\n\n```tangle:///src/lib.rs?inside=outer\nfn f() {}\n```\nAfter.\n",
+        )
+        .unwrap();
+        fs::write(
+            content.join("top.md"),
+            "```tangle:///src/lib.rs?id=top\n// top\n```",
+        )
+        .unwrap();
+        let mut lit = Lit::new(content, root.join("out"));
+        lit.config.header = true;
+        let site = root.join("site");
+        lit.copy_site(&site).unwrap();
+        (temp_dir, site, lit)
+    }
+
+    #[test]
+    fn test_site_cross_references_follow_each_block() {
+        let (_temp_dir, site, _lit) = cross_reference_site();
+        let intro = fs::read_to_string(site.join("guide/intro.md")).unwrap();
+        assert!(
+            intro.contains("```\n\n→ [src/lib.rs](../lit-targets/src/lib.rs.md), lines 5–7\n"),
+            "{intro}"
+        );
+        assert!(
+            intro.contains("```\n\n→ [src/lib.rs](../lit-targets/src/lib.rs.md), nested\nAfter.\n"),
+            "{intro}"
+        );
+        assert!(
+            fs::read_to_string(site.join("top.md"))
+                .unwrap()
+                .ends_with("```\n\n→ [src/lib.rs](lit-targets/src/lib.rs.md), line 3")
+        );
+    }
+
+    #[test]
+    fn test_site_target_pages_list_blocks_in_order() {
+        let (_temp_dir, site, lit) = cross_reference_site();
+        assert_eq!(
+            fs::read_to_string(site.join("lit-targets/src/lib.rs.md")).unwrap(),
+            "# src/lib.rs\n\nTangled from these blocks, in order:\n\n\
+             - line 3: [top.md, line 1](../../top.md)\n\
+             - lines 5–7: [guide/intro.md, line 3](../../guide/intro.md)\n\
+             - nested: [guide/intro.md, line 9](../../guide/intro.md)\n"
+        );
+        assert_eq!(lit.target_indexes().unwrap()[0].blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_site_target_pages_name_uncopied_documents_by_path() {
+        let index = TargetIndex {
+            path: Utf8PathBuf::from("a.rs"),
+            blocks: vec![(
+                Origin {
+                    file: Utf8PathBuf::from("out/x.md"),
+                    line: 2,
+                    offset: 0,
+                    end_line: 2,
+                    end_offset: 0,
+                },
+                None,
+            )],
+        };
+        assert!(
+            index
+                .page(&HashMap::new())
+                .ends_with("- nested: out/x.md:2\n")
+        );
+    }
+
+    /// The statistics of a document of prose and code, and one of unusual targets
+    fn stats_input() -> (tempfile::TempDir, Utf8PathBuf, Stats) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            "# A\n\nSome prose.\n\n```rust tangle:///src/main.rs\nfn main() {\n\n}\n```\n\n```sh\nlit\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            "```tangle:///notes.xyz\nx\n```\n\n```tangle:///LICENSE\nMIT\n```\n\n```tangle:///gone.rs?skip\ny\n```\n",
+        )
+        .unwrap();
+
+        let stats = Lit::new(root.clone(), root.join("out")).stats().unwrap();
+        (temp_dir, root, stats)
+    }
+
+    #[test]
+    fn test_stats_count_documents_blocks_and_lines() {
+        let (_temp_dir, _root, stats) = stats_input();
+        assert_eq!(stats.documents, 2);
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.code_lines, 5);
+        assert_eq!(stats.prose_lines, 5);
+        assert_eq!(
+            stats.languages,
+            [
+                ("(none)".to_string(), 1),
+                ("rust".to_string(), 3),
+                ("xyz".to_string(), 1)
+            ]
+            .into()
+        );
+        assert_eq!(
+            stats.targets.first().unwrap(),
+            &(Utf8PathBuf::from("src/main.rs"), 3)
+        );
+    }
+
+    #[test]
+    fn test_stats_display() {
+        let (_temp_dir, _root, stats) = stats_input();
+        let shown = stats.to_string();
+        assert!(
+            shown.starts_with("2 documents, 4 blocks (1 skipped), 3 targets\n"),
+            "{shown}"
+        );
+        assert!(
+            shown.contains("(1.00 prose lines per code line)"),
+            "{shown}"
+        );
+        assert!(shown.contains("\nrust                 3\n"), "{shown}");
+        assert!(
+            shown.ends_with("       3  src/main.rs\n       1  LICENSE\n       1  notes.xyz\n"),
+            "{shown}"
+        );
+    }
+
+    /// A document tangling two sources and a readme, and a `Lit` selecting `globs`
+    fn targets_input(globs: &[&str]) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///src/lib.rs\nlib\n```\n\n```tangle:///src/bin/main.rs\nmain\n```\n\n```tangle:///README.txt\nreadme\n```\n",
+        )
+        .unwrap();
+        let mut lit = Lit::new(root.clone(), root.join("out"));
+        lit.targets = globs
+            .iter()
+            .map(|glob| TargetGlob::new(glob).unwrap())
+            .collect();
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_target_globs_select_destinations() {
+        let (_temp_dir, root, lit) = targets_input(&["src/**/*.rs", "none"]);
+        let report = lit.tangle().unwrap();
+        let paths: Vec<_> = report.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["src/bin/main.rs", "src/lib.rs"]);
+        assert!(!root.join("out/README.txt").exists());
+    }
+
+    #[test]
+    fn test_target_globs_still_check_every_destination() {
+        let (_temp_dir, _root, mut lit) = targets_input(&["src/**/*.rs"]);
+        lit.config = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[assert.\"README.txt\"]\ncontains = [\"missing\"]\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            lit.tangle(),
+            Err(LitError::AssertionFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_target_globs_keep_unselected_files_in_the_manifest() {
+        let (_temp_dir, root, mut lit) = targets_input(&["src/**/*.rs"]);
+        lit.tangle().unwrap();
+        lit.targets = vec![TargetGlob::new("*.txt").unwrap()];
+        assert_eq!(lit.tangle().unwrap().files.len(), 1);
+        assert_eq!(Manifest::load(&root.join("out")).unwrap().files.len(), 3);
+    }
+
+    #[test]
+    fn test_target_globs_must_be_valid() {
+        let error = TargetGlob::new("[z-a]").unwrap_err();
+        assert!(
+            error.to_string().starts_with("invalid target glob `[z-a]`"),
+            "{error}"
+        );
+    }
+
+    /// A template prompting for a project name and a license with a default
+    fn template_input() -> (tempfile::TempDir, Utf8PathBuf, Utf8PathBuf) {
+        let (temp_dir, root) = temp_root();
+        let template = root.join("cli.md");
+        fs::write(
+            &template,
+            "---\nlit:\n  prompts:\n    - name: project_name\n      ask: Project name\n    - name: license\n      ask: License\n      default: MIT\n---\n\n```toml tangle:///Cargo.toml\n[package]\nname = \"{{project_name}}\"\nlicense = \"{{license}}\"\n```\n\n```rust tangle:///src/{{project_name}}.rs?id=main\nfn main() { {{}} }\n```\n\n```rust tangle:///src/{{project_name}}.rs?inside=main\nlet x = 1;\n```\n",
+        )
+        .unwrap();
+        (temp_dir, root, template)
+    }
+
+    /// Answers naming the project `demo` and leaving the license to its default
+    fn demo_answers() -> BTreeMap<String, String> {
+        BTreeMap::from([("project_name".to_string(), "demo".to_string())])
+    }
+
+    #[test]
+    fn test_template_prompts_come_from_front_matter() {
+        let (_temp_dir, _root, template) = template_input();
+        let mut default = Prompt::new("license", "License");
+        default.default = Some("MIT".to_string());
+        assert_eq!(
+            template_prompts(&template).unwrap(),
+            vec![Prompt::new("project_name", "Project name"), default]
+        );
+    }
+
+    #[test]
+    fn test_from_template_tangles_with_the_answers() {
+        let (_temp_dir, root, template) = template_input();
+        let created = from_template(&template, &demo_answers(), &root.join("demo")).unwrap();
+        assert_eq!(
+            created,
+            vec![root.join("demo/Cargo.toml"), root.join("demo/src/demo.rs")]
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("demo/Cargo.toml")).unwrap(),
+            "[package]\nname = \"demo\"\nlicense = \"MIT\"\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("demo/src/demo.rs")).unwrap(),
+            "fn main() { let x = 1; }\n"
+        );
+    }
+
+    #[test]
+    fn test_from_template_refuses_a_directory_that_is_not_empty() {
+        let (_temp_dir, root, template) = template_input();
+        from_template(&template, &demo_answers(), &root.join("demo")).unwrap();
+        let error = from_template(&template, &demo_answers(), &root.join("demo")).unwrap_err();
+        assert!(
+            matches!(&error, LitError::NotEmpty { path } if path.ends_with("demo")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_from_template_needs_every_answer_without_a_default() {
+        let (_temp_dir, root, template) = template_input();
+        let error = from_template(&template, &BTreeMap::new(), &root.join("other")).unwrap_err();
+        assert!(
+            matches!(&error, LitError::Unanswered { name } if name == "project_name"),
+            "{error}"
+        );
+        assert!(!root.join("other").exists());
+    }
+
+    /// A document and a Typst template, with `lit.toml` naming it and a LaTeX template not yet written
+    fn themes_input() -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(
+            root.join("docs/a.md"),
+            "# Setup\n\nSay {{index}}.\n\n```tangle:///main.rs\nfn main() {}\n```\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("book.typ"),
+            "#set page(paper: \"a5\")\n{{preamble}}{{title}}\n{{body}}#pagebreak()\n{{index}}",
+        )
+        .unwrap();
+        fs::write(
+            root.join("lit.toml"),
+            "[weave]\ntypst = \"book.typ\"\nlatex = \"plain.tex\"\n",
+        )
+        .unwrap();
+        let lit = Lit::new(root.join("docs"), root.join("out"));
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_weave_templates_are_relative_to_the_config() {
+        let (_temp_dir, root, _lit) = themes_input();
+        assert_eq!(
+            Config::load(&root.join("lit.toml")).unwrap().weave.typst,
+            Some(root.join("book.typ"))
+        );
+    }
 
     #[test]
-    fn test_solve_simple_constraint_ordering() {
-        let blocks = vec![
-            create_constrained_block(
-                "c",
-                vec![Constraint::After(vec![
-                    BlockId::new("b".to_string()).unwrap(),
-                ])],
-                "Third",
-            ),
-            create_constrained_block("a", vec![Constraint::First], "First"),
-            create_constrained_block(
-                "b",
-                vec![Constraint::After(vec![
-                    BlockId::new("a".to_string()).unwrap(),
-                ])],
-                "Second",
-            ),
-        ];
+    fn test_weave_templates_place_each_part() {
+        let (_temp_dir, root, mut lit) = themes_input();
+        let plain = lit.weave(WeaveFormat::Typst).unwrap();
+        lit.config = Config::load(&root.join("lit.toml")).unwrap();
+        let woven = lit.weave(WeaveFormat::Typst).unwrap();
+        assert_eq!(
+            woven,
+            format!(
+                "#set page(paper: \"a5\")\n{}{{{{title}}}}\n{}#pagebreak()\n= Index of Files\n\n/ #raw(\"main.rs\"): #link(<lit-section-1>)[Setup]\n\n",
+                WeaveFormat::Typst.preamble(),
+                plain
+                    .split("= Index")
+                    .next()
+                    .unwrap()
+                    .strip_prefix(WeaveFormat::Typst.preamble())
+                    .unwrap()
+                    .strip_prefix('\n')
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_weave_templates_leave_placeholders_in_documents_alone() {
+        let (_temp_dir, root, mut lit) = themes_input();
+        lit.config = Config::load(&root.join("lit.toml")).unwrap();
+        let woven = lit.weave(WeaveFormat::Typst).unwrap();
+        assert!(woven.contains("Say {{index}}."), "{woven}");
+    }
+
+    #[test]
+    fn test_weave_templates_need_a_body() {
+        let (_temp_dir, root, mut lit) = themes_input();
+        lit.config = Config::load(&root.join("lit.toml")).unwrap();
+        fs::write(root.join("plain.tex"), "\\documentclass{book}\n").unwrap();
+        let error = lit.weave(WeaveFormat::Latex).unwrap_err();
+        assert!(
+            matches!(&error, LitError::TemplateWithoutBody { path } if *path == root.join("plain.tex")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_weave_context_lists_sections_targets_and_blocks() {
+        let (_temp_dir, _root, lit) = themes_input();
+        let context = lit.weave_context().unwrap();
+        assert_eq!(context["sections"][0]["label"], "lit-section-1");
+        assert_eq!(context["sections"][0]["title"], "Setup");
+        assert_eq!(
+            context["targets"],
+            serde_json::json!([{"path": "main.rs", "sections": ["lit-section-1"]}])
+        );
+        assert_eq!(context["blocks"][0]["lines"], serde_json::json!([5, 7]));
+    }
+
+    /// A build script reading a data file, beside a stale output, and a `Lit` that verifies with `command`
+    fn verify_input(command: Option<&str>) -> (tempfile::TempDir, Utf8PathBuf, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("data/nested")).unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(
+            root.join("docs/doc.md"),
+            "```tangle:///build.sh\ncat data/nested/input.txt\n```\n",
+        )
+        .unwrap();
+        fs::write(root.join("data/nested/input.txt"), "input\n").unwrap();
+        fs::write(root.join("build.sh"), "exit 1\n").unwrap();
+        fs::write(root.join("out/stale.txt"), "stale\n").unwrap();
+        let mut lit = Lit::new(root.join("docs"), root.join("out"));
+        lit.config.verify = Verify {
+            copy: vec![
+                "data/**".to_string(),
+                "build.sh".to_string(),
+                "out/*".to_string(),
+            ],
+            command: command.map(str::to_string),
+        };
+        (temp_dir, root, lit)
+    }
+
+    #[test]
+    fn test_verify_build_needs_a_command() {
+        let (_temp_dir, root, lit) = verify_input(None);
+        assert!(matches!(
+            lit.verify_build(&root),
+            Err(LitError::NoVerifyCommand)
+        ));
+    }
+
+    #[test]
+    fn test_verify_build_runs_the_tangled_files_over_copies() {
+        let (_temp_dir, root, mut lit) = verify_input(None);
+        lit.config.verify.command = Some(format!(
+            "sh build.sh > {root}/built && test ! -e stale.txt && test ! -e docs"
+        ));
+        let report = lit.verify_build(&root).unwrap();
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(fs::read_to_string(root.join("built")).unwrap(), "input\n");
+        assert!(!root.join("out/build.sh").exists());
+    }
+
+    #[test]
+    fn test_verify_build_fails_with_the_command() {
+        let (_temp_dir, root, lit) = verify_input(Some("exit 2"));
+        let error = lit.verify_build(&root).unwrap_err();
+        assert!(
+            matches!(&error, LitError::VerifyFailed { command, .. } if command == "exit 2"),
+            "{error}"
+        );
+    }
+
+    /// Two documents with front matter, inline markup, lists, quotes, and a nested block
+    fn weave_input() -> (tempfile::TempDir, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("a.md"),
+            r#"---
+lit:
+  prefix: src
+---
+
+# Main *entry* point
+
+Calls `f` — see [the **docs**](https://example.com/a#b)
+and ![logo](logo.png).
+
+```tangle:///main.rs?id=main&after=f#entry
+fn main() {}
+```
+
+<div>html</div>
+"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("b.md"),
+            r#"---
+lit:
+  prefix: src
+---
+
+## 50% off_{x}
+
+```tangle:///main.rs?id=f
+fn f() {}
+```
+
+- one
+- two ```` ``` ````
+
+  ````text
+  a ``` b
+  ````
+
+1. first
+
+> quoted\
+> line
+
+---
+
+```tangle:///main.rs?inside=f
+```
+"#,
+        )
+        .unwrap();
+        let lit = Lit::new(root.clone(), root.join("out"));
+        (temp_dir, lit)
+    }
+
+    #[test]
+    fn test_weave_typst() {
+        let (_temp_dir, lit) = weave_input();
+        assert_eq!(
+            lit.weave(WeaveFormat::Typst).unwrap(),
+            r##"#show raw.where(block: true): block.with(fill: luma(245), inset: 8pt, width: 100%)
+
+= Main #emph[entry] point <lit-section-1>
+
+Calls #raw("f") — see #link("https://example.com/a#b")[the #strong[docs]]
+and #link("logo.png")[logo].
+
+#figure(kind: raw, caption: [#raw("src/main.rs"), line 3, entry])[
+```rust
+fn main() {}
+```
+]
+
+== 50% off\_{x} <lit-section-2>
+
+#figure(kind: raw, caption: [#raw("src/main.rs"), line 1])[
+```rust
+fn f() {}
+```
+]
+
+#list([one
+
+], [two #raw("```")
+
+````text
+a ``` b
+````
+
+], )
+
+#enum([first
+
+], )
+
+#quote(block: true)[quoted\
+line
+
+]
+
+#line(length: 100%)
+
+#figure(kind: raw, caption: [#raw("src/main.rs"), nested])[
+```rust
+
+```
+]
+
+= Index of Files
+
+/ #raw("src/main.rs"): #link(<lit-section-2>)[50% off\_{x}], #link(<lit-section-1>)[Main entry point]
+
+"##
+        );
+    }
+
+    #[test]
+    fn test_weave_latex() {
+        let (_temp_dir, lit) = weave_input();
+        let latex = lit.weave(WeaveFormat::Latex).unwrap();
+        assert!(latex.starts_with("\\documentclass{article}\n"), "{latex}");
+        assert!(latex.ends_with("\\end{document}\n"), "{latex}");
+        for woven in [
+            "\\section{Main \\emph{entry} point}\\label{lit-section-1}",
+            "\\section*{Index of Files}\n\n\\begin{description}\n\\item[\\texttt{src/main.rs}] \\hyperref[lit-section-2]{50\\% off\\_\\{x\\}}, \\hyperref[lit-section-1]{Main entry point}\n\\end{description}",
+            "Calls \\texttt{f} — see \\href{https://example.com/a\\#b}{the \\textbf{docs}}\nand \\href{logo.png}{logo}.",
+            "\\begin{lstlisting}[caption={\\texttt{src/main.rs}, line 3, entry}]\nfn main() {}\n\\end{lstlisting}",
+            "\\subsection{50\\% off\\_\\{x\\}}\\label{lit-section-2}",
+            "\\begin{itemize}\n\\item one\n\n\\item two \\texttt{```}\n\n\\begin{lstlisting}\na ``` b\n\\end{lstlisting}\n\n\\end{itemize}",
+            "\\begin{enumerate}\n\\item first\n\n\\end{enumerate}",
+            "\\begin{quote}\nquoted\\\\\nline\n\n\\end{quote}",
+            "\\noindent\\rule{\\linewidth}{0.4pt}",
+            "[caption={\\texttt{src/main.rs}, nested}]",
+        ] {
+            assert!(latex.contains(woven), "{woven}\n\nnot in\n\n{latex}");
+        }
+        assert!(!latex.contains("html"));
+    }
+
+    #[test]
+    fn test_weave_escapes_text() {
+        assert_eq!(
+            latex_text("\\~^–"),
+            "\\textbackslash{}\\textasciitilde{}\\textasciicircum{}--"
+        );
+        assert_eq!(typst_text("a-b /c"), "a\\-b \\/c");
+        assert_eq!(typst_string("\"\\\n"), "\\\"\\\\\\n");
+    }
+
+    #[test]
+    fn test_weave_latex_headings_below_subsections_are_paragraphs() {
+        for depth in 3..=5 {
+            let mut weaver = Weaver::new(WeaveFormat::Latex);
+            let heading = to_mdast(
+                &format!("{} H", "#".repeat(depth)),
+                &ParseOptions::default(),
+            )
+            .unwrap();
+            weaver.block(&heading.children().unwrap()[0], None);
+            assert!(weaver.out.ends_with("{H}\n\n"), "{}", weaver.out);
+        }
+    }
+
+    /// A `Lit` for a hidden, a collapsed, and an expanded block of one file
+    fn weave_modes_input() -> (tempfile::TempDir, Lit) {
+        let (temp_dir, root) = temp_root();
+        fs::write(
+            root.join("doc.md"),
+            "```tangle:///main.rs?weave=hidden\nuse std::io;\n```\n\n```tangle:///main.rs?weave=collapsed\nfn a() {}\nfn b() {}\n```\n\n```tangle:///main.rs?weave=expanded\nfn main() {}\n```\n",
+        )
+        .unwrap();
+        (temp_dir, Lit::new(root.clone(), root.join("out")))
+    }
+
+    #[test]
+    fn test_weave_modes_hide_and_fold_blocks() {
+        let (_temp_dir, lit) = weave_modes_input();
+        let typst = lit.weave(WeaveFormat::Typst).unwrap();
+        assert!(!typst.contains("std::io"), "{typst}");
+        assert!(
+            typst.contains("caption: [#raw(\"main.rs\"), lines 3–4])[\n```\n... 2 lines folded\n```\n]\n\n#figure"),
+            "{typst}"
+        );
+        assert!(
+            typst.contains("line 6])[\n```rust\nfn main() {}\n```"),
+            "{typst}"
+        );
+        let latex = lit.weave(WeaveFormat::Latex).unwrap();
+        assert!(
+            latex.contains("lines 3--4}]\n... 2 lines folded\n\\end{lstlisting}"),
+            "{latex}"
+        );
+    }
+
+    #[test]
+    fn test_weave_modes_do_not_change_the_tangle() {
+        let (_temp_dir, lit) = weave_modes_input();
+        assert_eq!(
+            lit.tangle_to_map().unwrap()[Utf8Path::new("main.rs")],
+            "use std::io;\n\nfn a() {}\nfn b() {}\n\nfn main() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_weave_modes_must_be_known() {
+        let error = tangle_document("doc.md", "```tangle:///a.rs?weave=folded\n```\n").unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(matches!(&located.error, BlockError::UnknownWeave(mode) if mode == "folded"));
+    }
+
+    #[test]
+    fn test_trim() {
+        let content = || "a  \r\n\tb\t\n\n  \n\nc\n\n \n".to_string();
+        assert_eq!(Trim::named("none").unwrap().apply(content()), content());
+        assert_eq!(
+            Trim::named("lines").unwrap().apply(content()),
+            "a\r\n\tb\n\n\n\nc\n\n\n"
+        );
+        assert_eq!(
+            Trim::named("blank").unwrap().apply(content()),
+            "a  \r\n\tb\t\n\nc"
+        );
+        assert_eq!(
+            Trim::named("lines,blank").unwrap().apply(content()),
+            "a\r\n\tb\n\nc"
+        );
+        assert!(
+            matches!(Trim::named("lines,tabs"), Err(BlockError::UnknownTrim(value)) if value == "lines,tabs")
+        );
+
+        let markdown = "```tangle:///a.mk?id=m\nall:  \n\techo {{}}\n\n\n```\n\n```tangle:///a.mk?inside=m&trim=none\nx \n```\n\n```tangle:///a.mk?raw=true\n{{}}  \n\n```\n\n```tangle:///b.mk?trim=lines\nb  \n```\n";
+        let blocks = Lit::parse_file(Utf8Path::new("doc.md"), markdown).unwrap();
+        assert!(blocks[2].raw && !blocks[2].expand);
+        let sources = HashMap::from([(Utf8PathBuf::from("doc.md"), markdown.to_string())]);
+        let config =
+            Config::parse(Utf8Path::new("lit.toml"), "[trim]\nmk = \"lines,blank\"\n").unwrap();
+        let files = assemble(blocks, &sources, &config).unwrap();
+        assert_eq!(files[0].render(), "all:\n\techo x \n\n{{}}  \n\n");
+        assert_eq!(files[1].render(), "b\n");
+
+        let error = Lit::parse_file(
+            Utf8Path::new("doc.md"),
+            "```tangle:///a.mk?raw=true&trim=lines\nx\n```\n",
+        )
+        .unwrap_err();
+        let LitError::Located(located) = error else {
+            panic!("expected a located error, got {error}");
+        };
+        assert!(matches!(located.error, BlockError::RawTrimmed));
+        assert!(Config::parse(Utf8Path::new("lit.toml"), "[trim]\nmk = \"tabs\"\n").is_err());
+    }
+
+    #[test]
+    fn test_workspace_members_load_their_own_configs() {
+        let (_temp_dir, root) = temp_root();
+        fs::create_dir_all(root.join("crates/a")).unwrap();
+        fs::create_dir_all(root.join("crates/b")).unwrap();
+        fs::write(
+            root.join("crates/a/lit.toml"),
+            "input = \"docs\"\nheader = true\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("lit.toml"),
+            "[workspace]\nmembers = [\"crates/a\", \"crates/b\"]\n",
+        )
+        .unwrap();
+
+        let members = Config::load(&root.join("lit.toml"))
+            .unwrap()
+            .members()
+            .unwrap();
+        assert_eq!(
+            members
+                .iter()
+                .map(|member| member.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(members[0].config.input, Some(root.join("crates/a/docs")));
+        assert!(members[0].config.header);
+        assert_eq!(members[1].config.input, Some(root.join("crates/b")));
+    }
+
+    #[test]
+    fn test_workspace_members_default_to_none() {
+        assert!(Config::default().members().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_workspace_member_names_must_be_unique() {
+        let error = Config::parse(
+            Utf8Path::new("lit.toml"),
+            "[workspace]\nmembers = [\"a/core\", \"b/core\"]\n",
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("two workspace members are named `core`"),
+            "{error}"
+        );
+    }
+}
+
+/// Name of the lock file a tangle holds in the output directory
+pub const LOCK_NAME: &str = ".lit-lock";
+
+/// A run's claim on its output directory, released when dropped
+#[derive(Debug)]
+struct OutputLock {
+    /// The lock file, deleted when the lock is released
+    path: Utf8PathBuf,
+    /// The lock file, locked for as long as it is open
+    _file: fs::File,
+}
+
+impl OutputLock {
+    /// Take the lock on `output`, or fail if another run holds it
+    fn acquire(output: &Utf8Path) -> Result<OutputLock> {
+        use std::io::Write as _;
+
+        fs::create_dir_all(output)?;
+        let path = output.join(LOCK_NAME);
+        let mut file = loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            match file.file().try_lock() {
+                Ok(()) => {}
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(&path)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string();
+                    let pid = if holder.is_empty() {
+                        "unknown".to_string()
+                    } else {
+                        holder
+                    };
+                    return Err(LitError::Locked { path, pid });
+                }
+                Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+            }
+            if is_lock_file(&file, &path)? {
+                break file;
+            }
+        };
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(OutputLock { path, _file: file })
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Deleted before the file is closed, so while it is still locked.
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove the lock file: {e}");
+        }
+    }
+}
+
+/// Whether `file` is still the one at `path`, rather than one a finished run deleted
+#[cfg(unix)]
+fn is_lock_file(file: &fs::File, path: &Utf8Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let locked = file.metadata()?;
+    Ok(match fs::metadata(path) {
+        Ok(current) => (current.dev(), current.ino()) == (locked.dev(), locked.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e.into()),
+    })
+}
+
+/// Whether `file` is still the one at `path`; Windows cannot recreate a file being deleted
+#[cfg(not(unix))]
+fn is_lock_file(_file: &fs::File, _path: &Utf8Path) -> Result<bool> {
+    Ok(true)
+}
+
+/// The largest message `read_message` accepts, in bytes: 64 MiB
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Read one language server message, or `None` at the end of the stream
+pub fn read_message(reader: &mut impl std::io::BufRead) -> Result<Option<serde_json::Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(LitError::Protocol(
+            "message without a Content-Length header".to_string(),
+        ));
+    };
+    if length > MAX_MESSAGE_BYTES {
+        return Err(LitError::Protocol(format!(
+            "message of {length} bytes, over the limit of {MAX_MESSAGE_BYTES}"
+        )));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| LitError::Protocol(e.to_string()))
+}
+
+/// Write one language server message
+pub fn write_message(writer: &mut impl std::io::Write, message: &serde_json::Value) -> Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// The state of a `lit lsp` session: the open documents, by URI
+#[derive(Debug, Default)]
+pub struct LanguageServer {
+    /// Open documents' text, by URI
+    pub documents: HashMap<String, String>,
+    /// Where tangled files are written, for document links
+    pub output: Utf8PathBuf,
+}
+
+impl LanguageServer {
+    pub fn new() -> Self {
+        LanguageServer::default()
+    }
+
+    /// Handle one message, returning the messages to send in reply
+    pub fn handle(&mut self, message: &serde_json::Value) -> Vec<serde_json::Value> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let result = match method {
+            "initialize" => self.initialize(params),
+            "shutdown" => serde_json::Value::Null,
+            "textDocument/hover" => self.hover(params),
+            "textDocument/documentLink" => self.document_links(params),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                return self.update(document, document["text"].as_str());
+            }
+            "textDocument/didChange" => {
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last());
+                return self.update(
+                    &params["textDocument"],
+                    text.and_then(|change| change["text"].as_str()),
+                );
+            }
+            "textDocument/didClose" => return self.update(&params["textDocument"], None),
+            _ if message.get("id").is_some() => {
+                return vec![serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": message["id"],
+                    "error": {"code": -32601, "message": format!("unsupported method {method}")},
+                })];
+            }
+            _ => return Vec::new(),
+        };
+        vec![serde_json::json!({"jsonrpc": "2.0", "id": message["id"], "result": result})]
+    }
+
+    fn initialize(&mut self, params: &serde_json::Value) -> serde_json::Value {
+        let root = params["rootUri"]
+            .as_str()
+            .and_then(uri_path)
+            .unwrap_or_default();
+        let config = Config::load(&root.join("lit.toml")).unwrap_or_default();
+        self.output = config
+            .output
+            .unwrap_or_else(|| config.input.unwrap_or(root).join("out"));
+        serde_json::json!({
+            "capabilities": {
+                "textDocumentSync": 1,
+                "hoverProvider": true,
+                "documentLinkProvider": {},
+            },
+            "serverInfo": {"name": "lit", "version": env!("CARGO_PKG_VERSION")},
+        })
+    }
+
+    /// Record a document's new text, or that it closed, and publish its diagnostics
+    fn update(
+        &mut self,
+        document: &serde_json::Value,
+        text: Option<&str>,
+    ) -> Vec<serde_json::Value> {
+        let uri = document["uri"].as_str().unwrap_or_default().to_string();
+        let diagnostics = match text {
+            Some(text) => {
+                self.documents.insert(uri.clone(), text.to_string());
+                diagnostics(&uri, text)
+            }
+            None => {
+                self.documents.remove(&uri);
+                Vec::new()
+            }
+        };
+        vec![serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        })]
+    }
+
+    /// The text and blocks of an open document
+    fn document(&self, params: &serde_json::Value) -> Option<(&str, Vec<Block>)> {
+        let uri = params.pointer("/textDocument/uri")?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let blocks = Lit::parse_file(&document_name(uri), text).ok()?;
+        Some((text, blocks))
+    }
+}
+
+/// The diagnostics for a document, tangled on its own
+fn diagnostics(uri: &str, text: &str) -> Vec<serde_json::Value> {
+    let Err(error) = tangle_document(document_name(uri).as_str(), text) else {
+        return Vec::new();
+    };
+    error
+        .into_errors()
+        .into_iter()
+        .filter_map(|error| {
+            let (line, block_error) = match &error {
+                LitError::Located(located) => {
+                    (located.origin.line.saturating_sub(1), Some(&located.error))
+                }
+                _ => (0, None),
+            };
+            if let Some(
+                BlockError::UnknownBlockId(_)
+                | BlockError::UnknownInclude(_)
+                | BlockError::NotExpanded(_),
+            ) = block_error
+            {
+                return None;
+            }
+            let message = block_error.map_or_else(|| error.to_string(), ToString::to_string);
+            Some(serde_json::json!({
+                "range": line_range(text, line),
+                "severity": 1,
+                "source": "lit",
+                "message": message,
+            }))
+        })
+        .collect()
+}
+
+/// The range of a whole line, in the protocol's UTF-16 columns
+fn line_range(text: &str, line: usize) -> serde_json::Value {
+    let width = text
+        .lines()
+        .nth(line)
+        .map_or(0, |text| text.encode_utf16().count());
+    serde_json::json!({
+        "start": {"line": line, "character": 0},
+        "end": {"line": line, "character": width},
+    })
+}
+
+impl LanguageServer {
+    fn hover(&self, params: &serde_json::Value) -> serde_json::Value {
+        let line = params
+            .pointer("/position/line")
+            .and_then(serde_json::Value::as_u64)
+            .and_then(|line| usize::try_from(line).ok());
+        let Some(((text, blocks), line)) = self.document(params).zip(line) else {
+            return serde_json::Value::Null;
+        };
+        let fence_line = |block: &Block| {
+            block
+                .origin
+                .as_ref()
+                .map(|origin| origin.line.saturating_sub(1))
+        };
+        let Some(block) = blocks.iter().find(|block| fence_line(block) == Some(line)) else {
+            return serde_json::Value::Null;
+        };
+
+        let uri = params
+            .pointer("/textDocument/uri")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+        let files = tangle_document(document_name(uri).as_str(), text).unwrap_or_default();
+        let mut hover = format!("Tangled to `{}`", block.path);
+        if let Some(parent) = &block.inside {
+            hover.push_str(&format!(", inside `{parent}`"));
+        }
+        for file in files.iter().filter(|file| file.path == block.path) {
+            let placed =
+                file.blocks
+                    .iter()
+                    .zip(file.line_ranges())
+                    .enumerate()
+                    .find(|(_, (placed, _))| {
+                        placed.origin.as_ref().map(|origin| origin.offset)
+                            == block.origin.as_ref().map(|origin| origin.offset)
+                    });
+            if let Some((index, (_, (start, end)))) = placed {
+                let count = file.blocks.len();
+                hover.push_str(&format!(
+                    ", block {} of {count}, {}",
+                    index.saturating_add(1),
+                    lines_label(Some((start, end)))
+                ));
+            }
+        }
+        serde_json::json!({"contents": {"kind": "markdown", "value": hover}})
+    }
+
+    fn document_links(&self, params: &serde_json::Value) -> serde_json::Value {
+        let Some((text, blocks)) = self.document(params) else {
+            return serde_json::Value::Null;
+        };
+        let lines: Vec<&str> = text.lines().collect();
+        let links: Vec<_> = blocks
+            .iter()
+            .filter_map(|block| {
+                let line = block.origin.as_ref()?.line.saturating_sub(1);
+                let fence = lines.get(line)?;
+                let start = fence.find("tangle:")?;
+                let url = fence.get(start..)?.split_whitespace().next()?;
+                let column = |bytes: &str| bytes.encode_utf16().count();
+                let before = column(fence.get(..start)?);
+                let target = Url::from_file_path(self.output.join(&block.path)).ok()?;
+                Some(serde_json::json!({
+                    "range": {
+                        "start": {"line": line, "character": before},
+                        "end": {"line": line, "character": before.saturating_add(column(url))},
+                    },
+                    "target": target.as_str(),
+                }))
+            })
+            .collect();
+        serde_json::json!(links)
+    }
+}
+
+/// The file a `file://` URI names
+fn uri_path(uri: &str) -> Option<Utf8PathBuf> {
+    let path = Url::parse(uri).ok()?.to_file_path().ok()?;
+    Utf8PathBuf::from_path_buf(path).ok()
+}
+
+/// The name a document is reported under: its path, or its URI if it has none
+fn document_name(uri: &str) -> Utf8PathBuf {
+    uri_path(uri).unwrap_or_else(|| Utf8PathBuf::from(uri))
+}
+
+/// Name of the manifest file, at the top of the output directory
+pub const MANIFEST_NAME: &str = ".lit-manifest";
+
+/// The manifest format this version of lit writes
+pub const MANIFEST_FORMAT_VERSION: u32 = 1;
+
+/// The files lit has written to an output directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The format version the manifest was read in
+    pub format_version: u32,
+    /// Paths relative to the output directory
+    pub files: BTreeSet<Utf8PathBuf>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            format_version: MANIFEST_FORMAT_VERSION,
+            files: BTreeSet::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Read the manifest in `output`, or an empty one if there is none yet
+    pub fn load(output: &Utf8Path) -> Result<Manifest> {
+        let path = output.join(MANIFEST_NAME);
+        if !path.is_file() {
+            return Ok(Manifest::default());
+        }
+        let text = fs::read_to_string(&path)?;
+
+        let mut format_version = 0;
+        let mut files = BTreeSet::new();
+        for line in text.lines() {
+            if let Some(version) = line.strip_prefix("# format_version:") {
+                format_version =
+                    version
+                        .trim()
+                        .parse()
+                        .map_err(|_| LitError::UnsupportedFormat {
+                            path: path.clone(),
+                            found: version.trim().to_string(),
+                        })?;
+            } else if !line.is_empty() && !line.starts_with('#') {
+                files.insert(Utf8PathBuf::from(line));
+            }
+        }
+
+        if format_version > MANIFEST_FORMAT_VERSION {
+            return Err(LitError::UnsupportedFormat {
+                path,
+                found: format_version.to_string(),
+            });
+        }
+        Ok(Manifest {
+            format_version,
+            files,
+        })
+    }
+
+    /// The manifest's file contents, in the current format
+    pub fn render(&self) -> String {
+        let mut text = format!(
+            "# Files generated by lit. Do not edit.\n# format_version: {MANIFEST_FORMAT_VERSION}\n"
+        );
+        for file in &self.files {
+            text.push_str(file.as_str());
+            text.push('\n');
+        }
+        text
+    }
+}
 
-        let sorted = solve_block_order(&blocks).unwrap();
-        assert_eq!(sorted.len(), 3);
-        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "a");
-        assert_eq!(sorted[1].id.as_ref().unwrap().as_str(), "b");
-        assert_eq!(sorted[2].id.as_ref().unwrap().as_str(), "c");
+/// Whether existing file contents start with a lit generated-file header
+fn has_lit_header(contents: &[u8]) -> bool {
+    let needle = b"Generated by lit ";
+    contents
+        .split(|&byte| byte == b'\n')
+        .take(2)
+        .any(|line| line.windows(needle.len()).any(|window| window == needle))
+}
+
+/// The file mode a fence declares with `?chmod=`, in octal
+fn parse_mode(
+    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+) -> std::result::Result<Option<u32>, BlockError> {
+    let Some(value) = params.get("chmod") else {
+        return Ok(None);
+    };
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .map(Some)
+        .ok_or_else(|| BlockError::InvalidMode(value.to_string()))
+}
+
+/// Set a file's permission bits, unless it already has them
+#[cfg(unix)]
+fn set_mode(path: &Utf8Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    if fs::metadata(path)?.permissions().mode() & 0o7777 != mode {
+        debug!("Setting mode {mode:o} on {path}");
+        fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_solve_circular_dependency() {
-        let blocks = vec![
-            create_constrained_block(
-                "a",
-                vec![Constraint::After(vec![
-                    BlockId::new("b".to_string()).unwrap(),
-                ])],
-                "A",
-            ),
-            create_constrained_block(
-                "b",
-                vec![Constraint::After(vec![
-                    BlockId::new("a".to_string()).unwrap(),
-                ])],
-                "B",
-            ),
-        ];
+/// Modes are Unix permissions, so elsewhere there is nothing to set
+#[cfg(not(unix))]
+fn set_mode(_path: &Utf8Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
 
-        let result = solve_block_order(&blocks);
-        assert!(result.is_err());
-        assert!(
-            result
-                .unwrap_err()
-                .to_string()
-                .contains("Constraints are unsatisfiable")
-        );
+/// Files other than documents brought from the inputs into the output (see `lit/assets.md`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Assets {
+    /// Globs for the files to bring, relative to the input directory each is found in
+    pub include: Vec<String>,
+    /// Hard-link each file into the output instead of copying it
+    pub link: bool,
+}
+
+/// A file from the inputs that goes to the output as it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Asset {
+    /// Where the file is in the inputs
+    source: Utf8PathBuf,
+    /// Where it goes, relative to the output directory
+    path: Utf8PathBuf,
+}
+
+impl Asset {
+    /// The asset's line in a run's report
+    fn report(&self, status: WriteStatus) -> Result<FileReport> {
+        Ok(FileReport {
+            path: self.path.clone(),
+            bytes: usize::try_from(fs::metadata(&self.source)?.len()).unwrap_or(usize::MAX),
+            blocks: 0,
+            status,
+        })
     }
+}
 
-    #[test]
-    fn test_solve_unknown_block_id() {
-        let blocks = vec![create_constrained_block(
-            "a",
-            vec![Constraint::After(vec![
-                BlockId::new("unknown".to_string()).unwrap(),
-            ])],
-            "A",
-        )];
+/// A file's permission bits, for an archive entry
+#[cfg(unix)]
+fn file_mode(path: &Utf8Path) -> Result<u32> {
+    use std::os::unix::fs::PermissionsExt as _;
 
-        let result = solve_block_order(&blocks);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Unknown block ID"));
+    Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+}
+
+/// Modes are Unix permissions, so elsewhere every file gets the usual one
+#[cfg(not(unix))]
+fn file_mode(_path: &Utf8Path) -> Result<u32> {
+    Ok(0o644)
+}
+
+/// What `lit mv` does with the old target's file in the output directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MovedOutput {
+    /// Leave it where it is
+    #[default]
+    Keep,
+    /// Rename it to the new target's path
+    Move,
+    /// Delete it
+    Delete,
+}
+
+/// Characters a path must have percent-encoded to sit in a tangle URL
+const TANGLE_PATH_ESCAPES: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'?')
+    .add(b'`');
+
+/// A fence's opening line with the path of its tangle URL replaced by `path`
+fn retarget_fence(line: &str, path: &Utf8Path) -> String {
+    let path = percent_encoding::utf8_percent_encode(path.as_str(), TANGLE_PATH_ESCAPES);
+    line.split(' ')
+        .map(|word| {
+            let Some(start) = word.find("tangle:") else {
+                return word.to_string();
+            };
+            let (before, url) = word.split_at(start);
+            if !Url::parse(url).is_ok_and(|url| url.scheme() == "tangle") {
+                return word.to_string();
+            }
+            let rest = url
+                .find(['?', '#'])
+                .and_then(|at| url.get(at..))
+                .unwrap_or_default();
+            format!("{before}tangle:///{path}{rest}")
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An external program lit runs with JSON requests (see `lit/plugins.md`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Plugin {
+    /// Shell command that runs the plugin
+    pub command: String,
+    /// Extensions of the documents it reads, as a source frontend
+    pub sources: Vec<String>,
+    /// Whether every block's content passes through it
+    pub transform: bool,
+    /// Whether it receives every tangled file after a tangle
+    pub output: bool,
+}
+
+impl Config {
+    /// The plugin that reads `path`, by its extension, with its name
+    pub fn frontend(&self, path: &Utf8Path) -> Option<(&str, &Plugin)> {
+        let extension = path.extension()?;
+        self.plugins
+            .iter()
+            .find(|(_, plugin)| plugin.sources.iter().any(|source| source == extension))
+            .map(|(name, plugin)| (name.as_str(), plugin))
     }
 
-    #[test]
-    fn test_solve_first_and_last() {
-        let blocks = vec![
-            create_constrained_block("middle", vec![], "Middle"),
-            create_constrained_block("first", vec![Constraint::First], "First"),
-            create_constrained_block("last", vec![Constraint::Last], "Last"),
-        ];
+    /// Why the `[plugins]` table cannot be used, if it cannot
+    fn invalid_plugins(&self) -> Option<String> {
+        let mut claimed = HashMap::new();
+        for (name, plugin) in &self.plugins {
+            if plugin.command.trim().is_empty() {
+                return Some(format!("plugin `{name}` has no command"));
+            }
+            for source in &plugin.sources {
+                if source == "md" || source == "rst" {
+                    return Some(format!(
+                        "plugin `{name}` cannot read `.{source}` files, which lit reads itself"
+                    ));
+                }
+                if let Some(other) = claimed.insert(source.as_str(), name) {
+                    return Some(format!(
+                        "plugins `{other}` and `{name}` both read `.{source}` files"
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
 
-        let sorted = solve_block_order(&blocks).unwrap();
-        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "first");
-        assert_eq!(sorted[2].id.as_ref().unwrap().as_str(), "last");
+/// Run plugin `name` with `request` on its stdin, returning its stdout, or with `reply` false letting it through
+fn run_plugin(
+    name: &str,
+    plugin: &Plugin,
+    request: &serde_json::Value,
+    reply: bool,
+) -> Result<Vec<u8>> {
+    debug!("Running plugin {name}: {}", plugin.command);
+    let stdout = if reply {
+        std::process::Stdio::piped()
+    } else {
+        std::process::Stdio::inherit()
+    };
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", &plugin.command])
+        .stdin(std::process::Stdio::piped())
+        .stdout(stdout)
+        .spawn()?;
+    let request = request.to_string();
+    let output = std::thread::scope(|scope| {
+        if let Some(mut stdin) = child.stdin.take() {
+            // A plugin may exit without reading its whole request; its exit status says whether that was fine.
+            scope.spawn(move || std::io::Write::write_all(&mut stdin, request.as_bytes()));
+        }
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        return Err(LitError::PluginFailed {
+            name: name.to_string(),
+            message: output.status.to_string(),
+        });
     }
+    Ok(output.stdout)
+}
 
-    #[test]
-    fn test_solve_duplicate_id() {
-        let blocks = vec![
-            create_constrained_block("dup", vec![], "First"),
-            create_constrained_block("dup", vec![], "Second"),
-        ];
+/// Plugin `name`'s reply, read as a `T`
+fn plugin_reply<T: serde::de::DeserializeOwned>(name: &str, stdout: &[u8]) -> Result<T> {
+    serde_json::from_slice(stdout).map_err(|e| LitError::PluginFailed {
+        name: name.to_string(),
+        message: format!("invalid reply: {e}"),
+    })
+}
 
-        let result = solve_block_order(&blocks);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Duplicate"));
+/// One tangle block in a source frontend's reply
+#[derive(Debug, Deserialize)]
+struct PluginFence {
+    url: String,
+    lang: Option<String>,
+    line: usize,
+    end_line: Option<usize>,
+    content: String,
+}
+
+/// A source frontend's reply
+#[derive(Debug, Deserialize)]
+struct SourceReply {
+    fences: Vec<PluginFence>,
+}
+
+/// The tangle blocks plugin `name` finds in the document `path`, as `Code` nodes
+fn plugin_fences(name: &str, plugin: &Plugin, path: &Utf8Path, text: &str) -> Result<Vec<Node>> {
+    let request = serde_json::json!({"kind": "source", "path": path, "text": text});
+    let reply: SourceReply = plugin_reply(name, &run_plugin(name, plugin, &request, true)?)?;
+
+    // Each line's byte offset and length, without its line ending
+    let mut offset: usize = 0;
+    let mut lines = Vec::new();
+    for line in text.split_inclusive('\n') {
+        lines.push((offset, line.trim_end_matches(['\n', '\r']).len()));
+        offset = offset.saturating_add(line.len());
+    }
+    let point = |line: usize, end: bool| {
+        let (offset, length) = lines.get(line.checked_sub(1)?).copied()?;
+        let column = if end { length } else { 0 };
+        Some(markdown::unist::Point::new(
+            line,
+            column.saturating_add(1),
+            offset.saturating_add(column),
+        ))
+    };
+
+    let mut nodes = Vec::new();
+    for fence in reply.fences {
+        let end_line = fence.end_line.unwrap_or(fence.line);
+        let (Some(start), Some(end)) = (
+            point(fence.line, false),
+            point(end_line, true).filter(|_| end_line >= fence.line),
+        ) else {
+            return Err(LitError::PluginFailed {
+                name: name.to_string(),
+                message: format!("lines {}-{end_line} are not in {path}", fence.line),
+            });
+        };
+        let (lang, meta) = match fence.lang {
+            Some(lang) => (lang, Some(fence.url)),
+            None => (fence.url, None),
+        };
+        nodes.push(Node::Code(Code {
+            value: fence.content,
+            position: Some(markdown::unist::Position { start, end }),
+            lang: Some(lang),
+            meta,
+        }));
     }
+    Ok(nodes)
+}
 
-    #[test]
-    fn test_solve_unknown_inside_block_id() {
-        let blocks = vec![Block {
-            path: Utf8PathBuf::from("test.txt"),
-            id: Some(BlockId::new("child".to_string()).unwrap()),
-            constraints: vec![],
-            inside: Some(BlockId::new("nonexistent".to_string()).unwrap()),
-            content: "content".to_string(),
-        }];
+/// A block in a transformer's reply
+#[derive(Debug, Deserialize)]
+struct TransformedBlock {
+    content: String,
+}
 
-        let result = solve_block_order(&blocks);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Unknown"));
+/// A block transformer's reply
+#[derive(Debug, Deserialize)]
+struct TransformReply {
+    blocks: Vec<TransformedBlock>,
+}
+
+/// `blocks`, with their contents passed through each transformer plugin in turn
+fn transform_blocks(mut blocks: Vec<Block>, config: &Config) -> Result<Vec<Block>> {
+    for (name, plugin) in config.plugins.iter().filter(|(_, plugin)| plugin.transform) {
+        let request: Vec<_> = blocks
+            .iter()
+            .map(|block| {
+                serde_json::json!({
+                    "path": block.path,
+                    "id": block.id.as_ref().map(ToString::to_string),
+                    "lang": block.lang,
+                    "file": block.origin.as_ref().map(|origin| &origin.file),
+                    "line": block.origin.as_ref().map(|origin| origin.line),
+                    "content": block.content,
+                })
+            })
+            .collect();
+        let request = serde_json::json!({"kind": "transform", "blocks": request});
+        let reply: TransformReply = plugin_reply(name, &run_plugin(name, plugin, &request, true)?)?;
+        if reply.blocks.len() != blocks.len() {
+            return Err(LitError::PluginFailed {
+                name: name.clone(),
+                message: format!(
+                    "replied with {} blocks for {}",
+                    reply.blocks.len(),
+                    blocks.len()
+                ),
+            });
+        }
+        for (block, transformed) in blocks.iter_mut().zip(reply.blocks) {
+            block.content = transformed.content;
+        }
     }
+    Ok(blocks)
+}
 
-    #[test]
-    fn test_solve_empty_input() {
-        let blocks: Vec<Block> = vec![];
-        let sorted = solve_block_order(&blocks).unwrap();
-        assert!(sorted.is_empty());
+/// The process's peak resident memory so far, in bytes, where the platform reports it
+pub fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kilobytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    kilobytes.checked_mul(1024)
+}
+
+impl TangleReport {
+    /// The run's timings and peak memory, as `--profile` prints them
+    pub fn profile(&self) -> serde_json::Value {
+        let micros = |duration: &Duration| u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let phases: Vec<_> = self
+            .timings
+            .iter()
+            .map(
+                |(phase, duration)| serde_json::json!({"phase": phase, "micros": micros(duration)}),
+            )
+            .collect();
+        let documents: Vec<_> = self
+            .documents
+            .iter()
+            .map(|(path, duration)| serde_json::json!({"path": path, "micros": micros(duration)}))
+            .collect();
+        serde_json::json!({
+            "phases": phases,
+            "documents": documents,
+            "peak_memory_bytes": self.peak_memory,
+        })
     }
+}
 
-    fn create_constrained_block(id: &str, constraints: Vec<Constraint>, content: &str) -> Block {
-        Block {
-            path: Utf8PathBuf::from("test.txt"),
-            id: Some(BlockId::new(id.to_string()).unwrap()),
-            constraints,
-            inside: None,
-            content: content.to_string(),
+/// A site that renders markdown, with its own ideas about fences
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    GitHub,
+    GitLab,
+}
+
+impl Platform {
+    /// Every platform lit knows the rules of
+    pub const ALL: &[Platform] = &[Platform::GitHub, Platform::GitLab];
+
+    /// The platform's name, as it writes it
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::GitHub => "GitHub",
+            Platform::GitLab => "GitLab",
         }
     }
 
-    #[test]
-    fn test_surround_constraint() {
-        let markdown = r##"
-```tangle:///output.txt?id=wrapper
-struct Foo;
+    /// Fence languages it renders as something other than code
+    pub fn diagrams(self) -> &'static [&'static str] {
+        match self {
+            Platform::GitHub => &["mermaid", "geojson", "topojson", "stl", "math"],
+            Platform::GitLab => &["mermaid", "plantuml", "math"],
+        }
+    }
+
+    /// Whether it understands `+++` TOML front matter
+    pub fn reads_toml_front_matter(self) -> bool {
+        self == Platform::GitLab
+    }
+
+    /// Files or directories whose presence in a repository means it is hosted here
+    fn markers(self) -> &'static [&'static str] {
+        match self {
+            Platform::GitHub => &[".github"],
+            Platform::GitLab => &[".gitlab-ci.yml", ".gitlab"],
+        }
+    }
+}
+
+/// `platforms` as a phrase: "GitHub", or "GitHub and GitLab"
+fn platform_names(platforms: &[Platform]) -> String {
+    platforms
+        .iter()
+        .map(|platform| platform.name())
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+/// What renders poorly about a top-level `node` on `platforms`
+fn render_problems(node: &Node, platforms: &[Platform]) -> Vec<String> {
+    let on = |applies: &dyn Fn(Platform) -> bool| -> Vec<Platform> {
+        platforms
+            .iter()
+            .copied()
+            .filter(|platform| applies(*platform))
+            .collect()
+    };
+    let mut problems = Vec::new();
+    match node {
+        Node::Toml(_) => {
+            let unread = on(&|platform| !platform.reads_toml_front_matter());
+            if !unread.is_empty() {
+                problems.push(format!(
+                    "`+++` front matter shows as text on {}; write it as `---` YAML",
+                    platform_names(&unread)
+                ));
+            }
+        }
+        Node::Code(code) => {
+            let Some(url) = tangle_url(code) else {
+                return problems;
+            };
+            let lang = code.lang.as_deref().unwrap_or_default();
+            if Url::parse(lang).is_ok_and(|url| url.scheme() == "tangle") && !platforms.is_empty() {
+                let params: HashMap<_, _> = query_params(&url).into_iter().collect();
+                let language = block_language(code, &params)
+                    .or_else(|| language_of(Utf8Path::new(url.path())).map(str::to_string))
+                    .or_else(|| shebang_language(&code.value).map(str::to_string));
+                let fix = match language {
+                    Some(language) => {
+                        let info = std::iter::once(lang)
+                            .chain(code.meta.as_deref().unwrap_or_default().split_whitespace());
+                        format!("write `{language} {}`", info.collect::<Vec<_>>().join(" "))
+                    }
+                    None => "put the block's language before it".to_string(),
+                };
+                problems.push(format!(
+                    "a tangle URL as the fence's language is not highlighted on {}; {fix}",
+                    platform_names(platforms)
+                ));
+            }
+            let diagram = on(&|platform| platform.diagrams().contains(&lang));
+            if !diagram.is_empty() {
+                problems.push(format!(
+                    "a `{lang}` fence renders on {} as something other than the code it tangles",
+                    platform_names(&diagram)
+                ));
+            }
+        }
+        _ => {}
+    }
+    problems
+}
+
+/// Tools to run over tangled `.rs` files
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RustHooks {
+    /// Run `rustfmt --check` on every tangled `.rs` file
+    pub rustfmt: bool,
+    /// Run `cargo check` in the output directory
+    pub check: bool,
+    /// Run `cargo fmt --check` and `cargo check` in the directory of each tangled `Cargo.toml`
+    pub cargo: bool,
+    /// The edition `rustfmt` parses with
+    pub edition: String,
+}
+
+impl Default for RustHooks {
+    fn default() -> Self {
+        RustHooks {
+            rustfmt: false,
+            check: false,
+            cargo: false,
+            edition: "2024".to_string(),
+        }
+    }
+}
+
+/// A `path.rs:LINE` position in compiler output
+static RUST_POSITION: LazyLock<Regex> = LazyLock::new(|| {
+    // The pattern is a compile-time literal, so compilation cannot fail.
+    #[allow(clippy::unwrap_used)]
+    let pattern = Regex::new(r"([^\s:]+\.rs):(\d+)").unwrap();
+    pattern
+});
+
+impl TangledFile {
+    /// The markdown line a line of the rendered file came from (both 1-based)
+    pub fn origin_of_line(&self, line: usize) -> Option<Origin> {
+        self.blame(line).map(|blame| blame.line)
+    }
+}
+
+/// The largest sizes `--sandbox` allows, whatever `[limits]` says
+pub const SANDBOX_LIMITS: Limits = Limits {
+    max_block_bytes: Some(1 << 20),
+    max_file_bytes: Some(8 << 20),
+    max_output_bytes: Some(64 << 20),
+};
+
+impl Limits {
+    /// These limits, lowered to `ceiling` where they are unset or higher
+    pub fn capped(&self, ceiling: &Limits) -> Limits {
+        let cap = |limit: Option<usize>, ceiling: Option<usize>| match (limit, ceiling) {
+            (Some(limit), Some(ceiling)) => Some(limit.min(ceiling)),
+            (limit, ceiling) => limit.or(ceiling),
+        };
+        Limits {
+            max_block_bytes: cap(self.max_block_bytes, ceiling.max_block_bytes),
+            max_file_bytes: cap(self.max_file_bytes, ceiling.max_file_bytes),
+            max_output_bytes: cap(self.max_output_bytes, ceiling.max_output_bytes),
+        }
+    }
+}
+
+impl Config {
+    /// Restrict these settings to what is safe for untrusted documents
+    pub fn sandbox(&mut self) {
+        if !self.hooks.post_tangle.is_empty()
+            || !self.plugins.is_empty()
+            || self.rust.rustfmt
+            || self.rust.check
+            || self.rust.cargo
+        {
+            warn!("Sandboxed: not running the configured hooks or plugins");
+        }
+        self.sandbox = true;
+        self.env = false;
+        self.hooks = Hooks::default();
+        self.plugins.clear();
+        self.rust.rustfmt = false;
+        self.rust.check = false;
+        self.rust.cargo = false;
+        self.limits = self.limits.capped(&SANDBOX_LIMITS);
+    }
+}
+
+/// The first symlink on the way from `output` down to `path` inside it
+fn symlink_under(output: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let mut current = output.to_path_buf();
+    for component in path.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.is_symlink() => return Some(current),
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// The top-level fences of a document, found without parsing the rest of it,
+/// or `None` when the document has anything the scan cannot be sure about
+fn scan_fences(text: &str) -> Option<Vec<Node>> {
+    if text.starts_with(['-', '+', '\u{feff}']) || text.contains(['\r', '\0']) {
+        return None;
+    }
+
+    let mut nodes = Vec::new();
+    let mut open: Option<(char, usize, Code)> = None;
+    let mut lines = Vec::new();
+    let mut point = markdown::unist::Point::new(1, 1, 0);
+    for line in text.split_inclusive('\n') {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let start = point.clone();
+        // The end of this line's content, and where the next line starts
+        let end = markdown::unist::Point::new(
+            start.line,
+            content.chars().count().saturating_add(1),
+            start.offset.saturating_add(content.len()),
+        );
+        point = if line.len() > content.len() {
+            markdown::unist::Point::new(
+                start.line.saturating_add(1),
+                1,
+                start.offset.saturating_add(line.len()),
+            )
+        } else {
+            end.clone()
+        };
+
+        if let Some((marker, len, code)) = &mut open {
+            let trimmed = content.trim_start_matches(' ');
+            if content.len().saturating_sub(trimmed.len()) < 4
+                && trimmed.starts_with(*marker)
+                && is_closing_fence(trimmed, *marker, *len)
+            {
+                code.value = lines.join("\n");
+                if let Some(position) = &mut code.position {
+                    position.end = end;
+                }
+                nodes.push(Node::Code(code.clone()));
+                lines.clear();
+                open = None;
+            } else {
+                lines.push(content);
+            }
+            continue;
+        }
+
+        let trimmed = content.trim_start_matches(' ');
+        let indent = content.len().saturating_sub(trimmed.len());
+        if indent >= 4 {
+            continue;
+        }
+        if trimmed.starts_with('<') {
+            return None;
+        }
+        let Some(marker) = trimmed.chars().next().filter(|c| *c == '`' || *c == '~') else {
+            continue;
+        };
+        let info = trimmed.trim_start_matches(marker);
+        let len = trimmed.len().saturating_sub(info.len());
+        if len < 3 {
+            continue;
+        }
+        let escaped = info.contains('\\') || (info.contains('&') && info.contains(';'));
+        if indent > 0 || escaped || (marker == '`' && info.contains('`')) {
+            return None;
+        }
+
+        let info = info.trim_matches([' ', '\t']);
+        let (lang, meta) = match info.split_once([' ', '\t']) {
+            Some((lang, meta)) => (lang, meta.trim_start_matches([' ', '\t'])),
+            None => (info, ""),
+        };
+        let code = Code {
+            value: String::new(),
+            position: Some(markdown::unist::Position {
+                start: start.clone(),
+                end: start,
+            }),
+            lang: Some(lang.to_string()).filter(|lang| !lang.is_empty()),
+            meta: Some(meta.to_string()).filter(|meta| !meta.is_empty()),
+        };
+        open = Some((marker, len, code));
+    }
+
+    // A fence left open runs to the end of the document.
+    if let Some((_, _, mut code)) = open {
+        code.value = lines.join("\n");
+        if let Some(position) = &mut code.position {
+            position.end = point;
+        }
+        nodes.push(Node::Code(code));
+    }
+    Some(nodes)
+}
+
+/// The names of the reStructuredText directives for code
+const RST_CODE_DIRECTIVES: &[&str] = &["code-block", "code", "sourcecode"];
+
+/// Whether a source file is reStructuredText rather than markdown
+fn is_rst(path: &Utf8Path) -> bool {
+    path.extension() == Some("rst")
+}
+
+/// Whether a source file is markdown, rather than reStructuredText or a plugin's (see `lit/plugins.md`)
+fn is_markdown(path: &Utf8Path) -> bool {
+    path.extension() == Some("md")
+}
+
+/// The code directives of a reStructuredText document, as `Code` nodes
+fn rst_code_blocks(text: &str) -> Vec<Node> {
+    // Each line with its byte offset
+    let mut offset: usize = 0;
+    let mut lines = Vec::new();
+    for line in text.split_inclusive('\n') {
+        lines.push((offset, line.trim_end_matches(['\n', '\r'])));
+        offset = offset.saturating_add(line.len());
+    }
+    let indent = |line: &str| line.len().saturating_sub(line.trim_start().len());
+    let point = |index: usize, column: usize| {
+        let (offset, _) = lines.get(index).copied().unwrap_or((text.len(), ""));
+        markdown::unist::Point::new(
+            index.saturating_add(1),
+            column.saturating_add(1),
+            offset.saturating_add(column),
+        )
+    };
+
+    let mut nodes = Vec::new();
+    let mut index = 0;
+    while let Some((_, line)) = lines.get(index).copied() {
+        index = index.saturating_add(1);
+        let depth = indent(line);
+        let Some((name, argument)) = line
+            .trim_start()
+            .strip_prefix("..")
+            .and_then(|rest| rest.split_once("::"))
+        else {
+            continue;
+        };
+        if !RST_CODE_DIRECTIVES.contains(&name.trim()) {
+            continue;
+        }
+
+        // Options run from the directive to the first blank line.
+        let mut url = None;
+        while let Some((_, option)) = lines
+            .get(index)
+            .copied()
+            .filter(|(_, line)| indent(line) > depth)
+        {
+            if let Some(value) = option.trim_start().strip_prefix(":tangle:") {
+                url = Some((index, indent(option), value.trim().to_string()));
+            }
+            index = index.saturating_add(1);
+        }
+        let body = index;
+        while lines
+            .get(index)
+            .is_some_and(|(_, line)| line.trim().is_empty() || indent(line) > depth)
+        {
+            index = index.saturating_add(1);
+        }
+        let Some((line, column, url)) = url else {
+            continue;
+        };
+
+        let content: Vec<&str> = lines
+            .get(body..index)
+            .unwrap_or_default()
+            .iter()
+            .map(|(_, line)| *line)
+            .collect();
+        let written = |line: &&str| !line.trim().is_empty();
+        let (first, last) = match (
+            content.iter().position(written),
+            content.iter().rposition(written),
+        ) {
+            (Some(first), Some(last)) => (first, last.saturating_add(1)),
+            _ => (0, 0),
+        };
+        let content = content.get(first..last).unwrap_or_default();
+        let common = content
+            .iter()
+            .filter(|line| written(line))
+            .map(|line| indent(line))
+            .min()
+            .unwrap_or(0);
+        let value = content
+            .iter()
+            .map(|line| line.get(common..).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let argument = argument.trim();
+        let (lang, meta) = if argument.is_empty() {
+            (url, None)
+        } else {
+            (argument.to_string(), Some(url))
+        };
+        let last = body.saturating_add(last).saturating_sub(1).max(line);
+        nodes.push(Node::Code(Code {
+            value,
+            position: Some(markdown::unist::Position {
+                start: point(line, column),
+                end: point(last, lines.get(last).map_or(0, |(_, line)| line.len())),
+            }),
+            lang: Some(lang),
+            meta,
+        }));
+    }
+    nodes
+}
+
+/// Fence languages for destinations known by their whole file name
+const FILE_NAME_LANGUAGES: &[(&str, &str)] = &[
+    ("CMakeLists.txt", "cmake"),
+    ("Dockerfile", "dockerfile"),
+    ("Makefile", "makefile"),
+    ("justfile", "just"),
+];
+
+/// Fence languages for common destination extensions
+const FENCE_LANGUAGES: &[(&str, &str)] = &[
+    ("bash", "bash"),
+    ("c", "c"),
+    ("cpp", "cpp"),
+    ("css", "css"),
+    ("go", "go"),
+    ("h", "c"),
+    ("html", "html"),
+    ("java", "java"),
+    ("js", "javascript"),
+    ("json", "json"),
+    ("jsx", "jsx"),
+    ("kt", "kotlin"),
+    ("lua", "lua"),
+    ("md", "markdown"),
+    ("py", "python"),
+    ("rb", "ruby"),
+    ("rs", "rust"),
+    ("sh", "sh"),
+    ("sql", "sql"),
+    ("swift", "swift"),
+    ("toml", "toml"),
+    ("ts", "typescript"),
+    ("tsx", "tsx"),
+    ("xml", "xml"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+];
+
+/// Fence languages for the interpreters scripts name on their `#!` line
+const INTERPRETER_LANGUAGES: &[(&str, &str)] = &[
+    ("bash", "bash"),
+    ("node", "javascript"),
+    ("perl", "perl"),
+    ("python", "python"),
+    ("ruby", "ruby"),
+    ("sh", "sh"),
+    ("zsh", "zsh"),
+];
+
+/// The fence language for a destination path, by its file name or extension
+fn language_of(path: &Utf8Path) -> Option<&'static str> {
+    let name = path.file_name()?;
+    let extension = path.extension();
+    FILE_NAME_LANGUAGES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .or_else(|| {
+            FENCE_LANGUAGES
+                .iter()
+                .find(|(ext, _)| Some(*ext) == extension)
+        })
+        .map(|(_, language)| *language)
+}
 
-{{}}
-```
+/// The fence language for a script, by the interpreter its `#!` line names
+fn shebang_language(code: &str) -> Option<&'static str> {
+    let mut words = code.lines().next()?.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    // `python3` and `python3.12` are both Python.
+    let interpreter = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    INTERPRETER_LANGUAGES
+        .iter()
+        .find(|(known, _)| *known == interpreter)
+        .map(|(_, language)| *language)
+}
 
-```tangle:///output.txt?id=impl1&inside=wrapper
-impl Foo {
-    fn bar(&self) {}
+/// The info string a tangle fence is rewritten to, or `None` for other fences
+fn rendered_info(code: &Code) -> Option<String> {
+    let url = tangle_url(code)?;
+    let params: HashMap<_, _> = query_params(&url).into_iter().collect();
+    let declared = block_language(code, &params);
+    let lang = declared
+        .as_deref()
+        .or_else(|| language_of(Utf8Path::new(url.path())))
+        .or_else(|| shebang_language(&code.value))
+        .unwrap_or_default();
+
+    let meta = code.meta.as_deref().unwrap_or_default();
+    let words = std::iter::once(lang)
+        .chain(meta.split_whitespace())
+        .filter(|word| !word.is_empty() && *word != "no-tangle")
+        .filter(|word| Url::parse(word).map_or(true, |url| url.scheme() != "tangle"));
+    Some(words.collect::<Vec<_>>().join(" "))
 }
-```
 
-```tangle:///output.txt?id=impl2&inside=wrapper
-impl Foo {
-    fn baz(&self) {}
+/// Rewrite tangle fences so a static-site generator can highlight them
+pub fn rewrite_fences(markdown: &str) -> Result<String> {
+    rewrite_with_captions(markdown, |_| None)
 }
-```
-"##;
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 3);
+/// `rewrite_fences`, with a caption after each fence `caption` has one for
+fn rewrite_with_captions(
+    markdown: &str,
+    caption: impl Fn(usize) -> Option<String>,
+) -> Result<String> {
+    let ast = to_mdast(markdown, &ParseOptions::default())
+        .map_err(|e| LitError::Markdown(e.to_string()))?;
+    let Node::Root(root) = ast else {
+        return Err(LitError::NotRoot); // cov-excl-line: unreachable — to_mdast always returns Root
+    };
+
+    let mut rewritten = String::new();
+    let mut copied = 0;
+    for node in &root.children {
+        let (Node::Code(code), Some(position)) = (node, node.position()) else {
+            continue;
+        };
+        let Some(info) = rendered_info(code) else {
+            continue;
+        };
 
-        let sorted = solve_block_order(&blocks).unwrap();
-        assert_eq!(sorted.len(), 1); // Surrounded blocks merged into wrapper
+        let fence = markdown.get(position.start.offset..).unwrap_or_default();
+        let line = fence.lines().next().unwrap_or_default();
+        let info_start = line
+            .find(|c: char| !matches!(c, ' ' | '`' | '~'))
+            .unwrap_or(line.len());
+        let start = position.start.offset.saturating_add(info_start);
+        let end = position.start.offset.saturating_add(line.len());
+
+        rewritten.push_str(markdown.get(copied..start).unwrap_or_default());
+        rewritten.push_str(&info);
+        copied = end;
+
+        if let Some(caption) = caption(position.start.offset) {
+            let fence_end = position.end.offset.max(copied);
+            rewritten.push_str(markdown.get(copied..fence_end).unwrap_or_default());
+            rewritten.push_str("\n\n");
+            rewritten.push_str(&caption);
+            copied = fence_end;
+        }
+    }
+    rewritten.push_str(markdown.get(copied..).unwrap_or_default());
 
-        let content = &sorted[0].content;
-        assert!(content.contains("struct Foo;"));
-        assert!(content.contains("fn bar(&self) {}"));
-        assert!(content.contains("fn baz(&self) {}"));
-        assert!(!content.contains("{{}}")); // Placeholder replaced
+    Ok(rewritten)
+}
+
+/// The source directory of the mdBook in `book`, with a `SUMMARY.md` written if it has none
+pub fn prepare_book(book: &Utf8Path) -> Result<(Utf8PathBuf, Option<Utf8PathBuf>)> {
+    let book_toml = book.join("book.toml");
+    let src = if book_toml.is_file() {
+        let table: toml::Table = toml::from_str(&fs::read_to_string(&book_toml)?)
+            .map_err(|e| LitError::Book(format!("{book_toml}: {}", e.message())))?;
+        table
+            .get("book")
+            .and_then(|book| book.get("src"))
+            .and_then(toml::Value::as_str)
+            .unwrap_or("src")
+            .to_string()
+    } else {
+        "src".to_string()
+    };
+    let src = book.join(src);
+
+    let summary = src.join("SUMMARY.md");
+    if summary.exists() {
+        return Ok((src, None));
     }
+    fs::write(&summary, book_summary(&src)?)?;
+    Ok((src, Some(summary)))
+}
 
-    #[test]
-    fn test_surround_preserves_order() {
-        let markdown = r##"
-```tangle:///output.txt?id=wrapper&first
-fn main() {
-    {{}}
+/// A `SUMMARY.md` listing every chapter under `src`
+pub fn book_summary(src: &Utf8Path) -> Result<String> {
+    let mut summary = "# Summary\n\n".to_string();
+    for entry in WalkDir::new(src)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let path = Utf8PathBuf::try_from(entry.into_path())
+            .map_err(|e| LitError::NonUtf8Path(e.into_path_buf()))?;
+        let Ok(chapter) = path.strip_prefix(src) else {
+            continue; // cov-excl-line: every entry is under `src`
+        };
+        if path.extension() != Some("md") || chapter == "SUMMARY.md" {
+            continue;
+        }
+        let content = fs::read_to_string(&path)?;
+        let title = content
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(str::trim)
+            .or(path.file_stem())
+            .unwrap_or_default();
+        summary.push_str(&format!("- [{title}]({chapter})\n"));
+    }
+    Ok(summary)
 }
-```
 
-```tangle:///output.txt?id=body1&inside=wrapper
-println!("Hello");
-```
+/// Rewrite the tangle fences in every chapter of an mdBook preprocessor's input
+pub fn preprocess_book(input: &str) -> Result<String> {
+    let (_context, mut book): (serde_json::Value, serde_json::Value) =
+        serde_json::from_str(input).map_err(|e| LitError::Book(e.to_string()))?;
+    rewrite_chapters(&mut book)?;
+    Ok(book.to_string())
+}
 
-```tangle:///output.txt?id=body2&inside=wrapper&after=body1
-println!("World");
-```
+/// Rewrite the content of every chapter anywhere in `value`
+fn rewrite_chapters(value: &mut serde_json::Value) -> Result<()> {
+    match value {
+        serde_json::Value::Object(object) => {
+            if let Some(serde_json::Value::String(content)) = object
+                .get_mut("Chapter")
+                .and_then(|chapter| chapter.get_mut("content"))
+            {
+                *content = rewrite_fences(content)?;
+            }
+            for child in object.values_mut() {
+                rewrite_chapters(child)?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                rewrite_chapters(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
 
-```tangle:///output.txt?id=after&after=wrapper&last
-// End
-```
-"##;
+/// Where index pages for tangled files go, within a site
+pub const TARGETS_DIR: &str = "lit-targets";
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        let sorted = solve_block_order(&blocks).unwrap();
+/// The fences behind one tangled file, in the order the file has them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetIndex {
+    /// The tangled file, relative to the output directory
+    pub path: Utf8PathBuf,
+    /// Each contributing fence, with the lines it became, or `None` when nested
+    pub blocks: Vec<(Origin, Option<(usize, usize)>)>,
+}
 
-        assert_eq!(sorted.len(), 2); // wrapper (with surrounded) and after
-        assert_eq!(sorted[0].id.as_ref().unwrap().as_str(), "wrapper");
-        assert_eq!(sorted[1].id.as_ref().unwrap().as_str(), "after");
+impl TargetIndex {
+    /// The index page's markdown, linking to each fence's page in `pages`
+    fn page(&self, pages: &HashMap<Utf8PathBuf, Utf8PathBuf>) -> String {
+        let up = "../".repeat(self.path.components().count());
+        let mut page = format!(
+            "# {}\n\nTangled from these blocks, in order:\n\n",
+            self.path
+        );
+        for (origin, lines) in &self.blocks {
+            let source = match pages.get(&origin.file) {
+                Some(relative) => format!("[{relative}, line {}]({up}{relative})", origin.line),
+                None => origin.to_string(),
+            };
+            page.push_str(&format!("- {}: {source}\n", lines_label(*lines)));
+        }
+        page
+    }
+}
 
-        let wrapper_content = &sorted[0].content;
-        assert!(wrapper_content.contains("println!(\"Hello\")"));
-        assert!(wrapper_content.contains("println!(\"World\")"));
+/// The caption under a fence on the page at `page`, for code that went to `target`
+fn target_caption(page: &Utf8Path, target: &Utf8Path, lines: Option<(usize, usize)>) -> String {
+    let up = "../".repeat(page.components().count().saturating_sub(1));
+    format!(
+        "→ [{target}]({up}{TARGETS_DIR}/{target}.md), {}",
+        lines_label(lines)
+    )
+}
 
-        // Check order of surrounded blocks
-        let hello_pos = wrapper_content.find("Hello").unwrap();
-        let world_pos = wrapper_content.find("World").unwrap();
-        assert!(hello_pos < world_pos);
+/// `lines 3–7`, `line 3`, or `nested`
+fn lines_label(lines: Option<(usize, usize)>) -> String {
+    match lines {
+        Some((start, end)) if start == end => format!("line {start}"),
+        Some((start, end)) => format!("lines {start}–{end}"),
+        None => "nested".to_string(),
     }
+}
 
-    #[test]
-    fn test_surround_block_without_children() {
-        // A block with an id but no blocks inside=it should pass through unchanged;
-        // exercises the else branch in apply_surrounds (id present, no children)
-        let blocks = vec![Block {
-            path: Utf8PathBuf::from("test.txt"),
-            id: Some(BlockId::new("only".to_string()).unwrap()),
-            constraints: vec![],
-            inside: None,
-            content: "only block".to_string(),
-        }];
+/// Links from Rust programs' blocks to the Rust Playground (see `lit/playground.md`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Playground {
+    /// The edition the Playground compiles with
+    pub edition: String,
+    /// The release channel: stable, beta, or nightly
+    pub channel: String,
+}
 
-        let result = apply_surrounds(blocks).unwrap();
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0].id.as_ref().unwrap().as_str(), "only");
-        assert_eq!(result[0].content, "only block");
+impl Default for Playground {
+    fn default() -> Self {
+        Playground {
+            edition: "2024".to_string(),
+            channel: "stable".to_string(),
+        }
     }
+}
 
-    #[test]
-    fn test_parse_single_tangle_block() {
-        let markdown = r#"# Test
+impl Playground {
+    /// A Playground URL that opens `code`
+    pub fn url(&self, code: &str) -> String {
+        let code = percent_encoding::utf8_percent_encode(code, percent_encoding::NON_ALPHANUMERIC);
+        format!(
+            "https://play.rust-lang.org/?version={}&mode=debug&edition={}&code={code}",
+            self.channel, self.edition
+        )
+    }
+}
 
-```tangle:///src/main.rs
-fn main() {
-    println!("Hello");
+/// A document's stretch under one heading, as the indexes for readers see it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Section {
+    /// The document it is in
+    pub file: Utf8PathBuf,
+    /// The heading's text, or `None` before a document's first heading
+    pub title: Option<String>,
+    /// The heading's line, or 1
+    pub line: usize,
+    /// The prose, as plain text, a line per block
+    pub prose: String,
+    /// The code of its top-level fences, one after another
+    pub code: String,
+    /// The offsets of those fences, to find a tangle block's section by its origin
+    #[serde(skip)]
+    pub fences: Vec<usize>,
 }
-```
-"#;
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("src/main.rs"));
-        assert_eq!(
-            blocks[0].content,
-            "fn main() {\n    println!(\"Hello\");\n}"
-        );
+impl Section {
+    fn new(file: &Utf8Path, title: Option<String>, line: usize) -> Self {
+        Section {
+            file: file.to_owned(),
+            title,
+            line,
+            prose: String::new(),
+            code: String::new(),
+            fences: Vec::new(),
+        }
     }
+}
 
-    #[test]
-    fn test_parse_multiple_tangle_blocks() {
-        let markdown = r#"# Multiple Blocks
+/// A block node's text, with a line between the blocks inside it
+fn plain_text(node: &Node) -> String {
+    match node {
+        Node::List(_) | Node::ListItem(_) | Node::Blockquote(_) => node
+            .children()
+            .into_iter()
+            .flatten()
+            .map(plain_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Node::Html(_) | Node::Yaml(_) | Node::Toml(_) | Node::Definition(_) => String::new(),
+        _ => node.to_string(),
+    }
+}
 
-```tangle:///file1.rs
-code 1
-```
+/// Where `lit site` writes its search index, within the site
+pub const SEARCH_INDEX: &str = "lit-search.json";
+
+/// The search index for the sections of copied pages, with each file replaced by its page in `pages`
+fn search_index(
+    sections: Vec<Section>,
+    pages: &HashMap<Utf8PathBuf, Utf8PathBuf>,
+) -> Result<String> {
+    let entries: Vec<Section> = sections
+        .into_iter()
+        .filter_map(|section| {
+            let file = pages.get(&section.file)?.clone();
+            Some(Section { file, ..section })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&entries).map_err(std::io::Error::from)?)
+}
 
-Some text here.
+/// Figures describing a literate project
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Markdown files read
+    pub documents: usize,
+    /// Tangle blocks, including skipped ones
+    pub blocks: usize,
+    /// Blocks disabled with `?skip` or `no-tangle`
+    pub skipped: usize,
+    /// Non-blank markdown lines outside tangle blocks and their fences
+    pub prose_lines: usize,
+    /// Non-blank lines inside tangle blocks
+    pub code_lines: usize,
+    /// Generated lines per language
+    pub languages: BTreeMap<String, usize>,
+    /// Every target with its generated line count, largest first
+    pub targets: Vec<(Utf8PathBuf, usize)>,
+}
 
-```tangle:///file2.rs
-code 2
-```
-"#;
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} documents, {} blocks ({} skipped), {} targets",
+            self.documents,
+            self.blocks,
+            self.skipped,
+            self.targets.len()
+        )?;
+        let ratio = self.prose_lines as f64 / self.code_lines.max(1) as f64;
+        writeln!(
+            f,
+            "{} prose lines, {} code lines ({ratio:.2} prose lines per code line)",
+            self.prose_lines, self.code_lines
+        )?;
+
+        writeln!(f, "\n{:<12}  {:>8}", "language", "lines")?;
+        for (language, lines) in &self.languages {
+            writeln!(f, "{language:<12}  {lines:>8}")?;
+        }
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 2);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("file1.rs"));
-        assert_eq!(blocks[0].content, "code 1");
-        assert_eq!(blocks[1].path, Utf8PathBuf::from("file2.rs"));
-        assert_eq!(blocks[1].content, "code 2");
+        writeln!(f, "\nlargest targets")?;
+        for (path, lines) in self.targets.iter().take(5) {
+            writeln!(f, "{lines:>8}  {path}")?;
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_parse_ignore_regular_code_blocks() {
-        let markdown = r#"# Test
+/// Blocks with the same, or nearly the same, content, which could be one chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicates {
+    /// Where each block is, the target it writes, and its ID, in document order
+    pub blocks: Vec<(Origin, Utf8PathBuf, Option<String>)>,
+    /// Non-blank lines in the first block
+    pub lines: usize,
+    /// Whether the blocks match once whitespace is normalized, rather than only being alike
+    pub identical: bool,
+    /// Non-blank lines in all but the first block, which including it would save
+    pub savings: usize,
+}
 
-```rust
-// This is regular code
-let x = 42;
-```
+/// A block's content as `lit duplicates` compares it: its lines trimmed, without blank ones
+fn normalized_content(content: &str) -> String {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-```tangle:///output.rs
-// This should be extracted
-let y = 10;
-```
-"#;
+/// `path` as a relative path from `directory`, as `?include=` resolves it
+fn relative_to(path: &Utf8Path, directory: &Utf8Path) -> Utf8PathBuf {
+    let (path, directory) = (normalize(path), normalize(directory));
+    let common = path
+        .components()
+        .zip(directory.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = Utf8PathBuf::new();
+    for _ in directory.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component);
+    }
+    relative
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("output.rs"));
-        assert_eq!(
-            blocks[0].content,
-            "// This should be extracted\nlet y = 10;"
-        );
+impl std::fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.identical {
+            "identical"
+        } else {
+            "similar"
+        };
+        writeln!(
+            f,
+            "{} {kind} blocks, {} lines each, {} lines to save",
+            self.blocks.len(),
+            self.lines,
+            self.savings
+        )?;
+        let Some((first, _, id)) = self.blocks.first() else {
+            return Ok(());
+        };
+        let id = id.as_deref().unwrap_or("ID");
+        for (index, (origin, target, _)) in self.blocks.iter().enumerate() {
+            let fence = if index == 0 {
+                format!("?id={id}")
+            } else {
+                let directory = origin.file.parent().unwrap_or(Utf8Path::new(""));
+                let document = if origin.file == first.file {
+                    Utf8PathBuf::new()
+                } else {
+                    relative_to(&first.file, directory)
+                };
+                format!("?include={document}#{id}")
+            };
+            writeln!(f, "  {origin}  {target}  {fence}")?;
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_parse_ignore_nested_in_blockquote() {
-        let markdown = r#"# Test
+/// A glob over destination paths, choosing which files `tangle` writes
+#[derive(Debug, Clone)]
+pub struct TargetGlob {
+    /// The glob as written
+    pub glob: String,
+    regex: Regex,
+}
 
-```tangle:///top-level.txt
-Top level content
-```
+impl TargetGlob {
+    pub fn new(glob: &str) -> Result<Self> {
+        let regex =
+            Regex::new(&format!("^{}$", glob_regex(glob))).map_err(|e| LitError::InvalidGlob {
+                glob: glob.to_string(),
+                message: e.to_string(),
+            })?;
+        Ok(TargetGlob {
+            glob: glob.to_string(),
+            regex,
+        })
+    }
 
-> Blockquote here
->
-> ```tangle:///nested.txt
-> This should NOT be extracted
-> ```
-"#;
+    /// Whether the destination `path` matches the glob
+    pub fn matches(&self, path: &Utf8Path) -> bool {
+        self.regex.is_match(path.as_str())
+    }
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("top-level.txt"));
-        assert_eq!(blocks[0].content, "Top level content");
+/// A question a template asks before it is tangled, from its front matter's `prompts`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Prompt {
+    /// The `{{name}}` its answer replaces
+    pub name: String,
+    /// The question, as shown to the user
+    pub ask: String,
+    /// The answer when none is given
+    pub default: Option<String>,
+}
+
+impl Prompt {
+    pub fn new(name: &str, ask: &str) -> Self {
+        Prompt {
+            name: name.to_string(),
+            ask: ask.to_string(),
+            default: None,
+        }
     }
+}
 
-    #[test]
-    fn test_parse_ignore_nested_in_list() {
-        let markdown = r#"# Test
+/// The questions a template asks, in order
+pub fn template_prompts(template: &Utf8Path) -> Result<Vec<Prompt>> {
+    let markdown = fs::read_to_string(template)?;
+    Ok(FrontMatter::parse(template, &markdown)?.prompts)
+}
 
-```tangle:///top-level.txt
-Top level content
-```
+/// Tangle a template into the new directory `directory`, with `answers` for its prompts
+pub fn from_template(
+    template: &Utf8Path,
+    answers: &BTreeMap<String, String>,
+    directory: &Utf8Path,
+) -> Result<Vec<Utf8PathBuf>> {
+    let markdown = fs::read_to_string(template)?;
+    let mut filled = markdown.clone();
+    for prompt in FrontMatter::parse(template, &markdown)?.prompts {
+        let answer = answers
+            .get(&prompt.name)
+            .or(prompt.default.as_ref())
+            .ok_or_else(|| LitError::Unanswered {
+                name: prompt.name.clone(),
+            })?;
+        filled = filled.replace(&format!("{{{{{}}}}}", prompt.name), answer);
+    }
+    if directory.exists() && fs::read_dir(directory)?.next().is_some() {
+        return Err(LitError::NotEmpty {
+            path: directory.to_owned(),
+        });
+    }
 
-- Item 1
-- Item 2
+    let blocks = Lit::parse_file(template, &filled)?;
+    let sources = HashMap::from([(template.to_owned(), filled)]);
+    let blocks = resolve_includes(blocks, &sources)?;
+    let mut created = Vec::new();
+    for file in assemble(blocks, &sources, &Config::default())? {
+        let path = directory.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, file.bytes(file.render()))?;
+        if let Some(mode) = file.mode {
+            set_mode(&path, mode)?;
+        }
+        created.push(path);
+    }
+    Ok(created)
+}
 
-  ```tangle:///nested.txt
-  This should NOT be extracted
-  ```
-"#;
+/// How `lit verify-build` builds the tangled project (see `lit/verify.md`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Verify {
+    /// Globs for the files to copy in beside the tangled ones
+    pub copy: Vec<String>,
+    /// Shell command that builds the project
+    pub command: Option<String>,
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("top-level.txt"));
-        assert_eq!(blocks[0].content, "Top level content");
+/// A typesetting language `lit weave` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeaveFormat {
+    /// Typst markup, for `typst compile`
+    Typst,
+    /// A LaTeX document, for `pdflatex`, `lualatex`, or `xelatex`
+    Latex,
+}
+
+/// How much of a block `lit weave` shows, from `?weave=`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WeaveMode {
+    /// The whole listing
+    #[default]
+    Expanded,
+    /// The caption, with the code folded away
+    Collapsed,
+    /// Nothing
+    Hidden,
+}
+
+impl WeaveMode {
+    /// The mode a fence's query parameters ask for
+    fn of(
+        params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
+    ) -> std::result::Result<Self, BlockError> {
+        match params.get("weave").map(|mode| mode.as_ref()) {
+            None | Some("expanded") => Ok(WeaveMode::Expanded),
+            Some("collapsed") => Ok(WeaveMode::Collapsed),
+            Some("hidden") => Ok(WeaveMode::Hidden),
+            Some(mode) => Err(BlockError::UnknownWeave(mode.to_string())),
+        }
     }
 
-    #[test]
-    fn test_parse_empty_markdown() {
-        let markdown = "";
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 0);
+    /// The mode a code fence is woven in; fences that are not tangled are expanded
+    fn of_code(code: &Code) -> Self {
+        let Some(url) = tangle_url(code) else {
+            return WeaveMode::Expanded;
+        };
+        // The mode was checked when the inputs were read.
+        WeaveMode::of(&query_params(&url).into_iter().collect()).unwrap_or_default()
     }
+}
 
-    #[test]
-    fn test_parse_no_tangle_blocks() {
-        let markdown = r#"# Just a regular document
+/// Templates `lit weave` writes into, by format (see `lit/themes.md`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeaveTemplates {
+    /// Typst template
+    pub typst: Option<Utf8PathBuf>,
+    /// LaTeX template
+    pub latex: Option<Utf8PathBuf>,
+}
 
-Some text here.
+/// `{{name}}` placeholders a weave template may hold
+static WEAVE_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    // The pattern is a compile-time literal, so compilation cannot fail.
+    #[allow(clippy::unwrap_used)]
+    Regex::new(r"\{\{(body|index|preamble)\}\}").unwrap()
+});
 
-```rust
-Regular code block
-```
+impl WeaveFormat {
+    /// The template a document is written into when `[weave]` has none
+    fn default_template(self) -> &'static str {
+        match self {
+            WeaveFormat::Typst => "{{preamble}}\n{{body}}{{index}}",
+            WeaveFormat::Latex => {
+                "\\documentclass{article}\n{{preamble}}\\begin{document}\n\n{{body}}{{index}}\\end{document}\n"
+            }
+        }
+    }
 
-More text.
-"#;
+    /// What `{{preamble}}` becomes: the styling lit's listings and links need
+    fn preamble(self) -> &'static str {
+        match self {
+            WeaveFormat::Typst => {
+                "#show raw.where(block: true): block.with(fill: luma(245), inset: 8pt, width: 100%)\n"
+            }
+            WeaveFormat::Latex => concat!(
+                "\\usepackage{listings}\n",
+                "\\usepackage{hyperref}\n",
+                "\\lstset{basicstyle=\\ttfamily\\small, breaklines=true, columns=fullflexible, frame=single}\n",
+            ),
+        }
+    }
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 0);
+    /// `template` with its placeholders filled, in one pass so a woven `{{index}}` stays as written
+    fn fill(self, template: &str, body: &str, index: &str) -> String {
+        WEAVE_PLACEHOLDER
+            .replace_all(template, |captures: &regex::Captures| {
+                match captures.get(1).map(|name| name.as_str()) {
+                    Some("body") => body.to_string(),
+                    Some("index") => index.to_string(),
+                    _ => self.preamble().to_string(),
+                }
+            })
+            .into_owned()
     }
+}
 
-    #[test]
-    fn test_parse_subdirectory_path() {
-        let markdown = r#"```tangle:///src/modules/utils.rs
-pub fn helper() {}
-```"#;
+/// Writes markdown nodes as one typeset document
+struct Weaver {
+    format: WeaveFormat,
+    out: String,
+    /// The label for the next heading written, if it starts a section
+    label: Option<String>,
+    /// Playground URLs for tangled Rust programs, linked under their listings (see `lit/playground.md`)
+    playground: HashMap<Utf8PathBuf, String>,
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("src/modules/utils.rs"));
-        assert_eq!(blocks[0].content, "pub fn helper() {}");
-    }
+/// The label of the heading that starts section `number`, counting from 0
+fn section_label(number: usize) -> String {
+    format!("lit-section-{}", number.saturating_add(1))
+}
 
-    #[test]
-    fn test_parse_empty_tangle_block() {
-        let markdown = r#"```tangle:///empty.txt
-```"#;
+/// Each tangled file with the numbered sections whose blocks it was tangled from, for the index of files
+fn sections_of_files<'a>(
+    indexes: &'a [TargetIndex],
+    sections: &'a [Section],
+) -> Vec<(&'a Utf8Path, Vec<(usize, &'a Section)>)> {
+    let mut fences = HashMap::new();
+    for (number, section) in sections.iter().enumerate() {
+        for offset in &section.fences {
+            fences.insert((section.file.clone(), *offset), number);
+        }
+    }
+    let mut files = Vec::new();
+    for index in indexes {
+        let mut defined = Vec::new();
+        for (origin, _) in &index.blocks {
+            let Some(number) = fences.get(&(origin.file.clone(), origin.offset)) else {
+                continue;
+            };
+            if !defined.iter().any(|(placed, _)| placed == number) {
+                defined.extend(sections.get(*number).map(|section| (*number, section)));
+            }
+        }
+        if !defined.is_empty() {
+            files.push((index.path.as_path(), defined));
+        }
+    }
+    files
+}
 
-        let blocks = Lit::parse_markdown(markdown).unwrap();
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].path, Utf8PathBuf::from("empty.txt"));
-        assert_eq!(blocks[0].content, "");
+impl Weaver {
+    fn new(format: WeaveFormat) -> Self {
+        Weaver {
+            format,
+            out: String::new(),
+            label: None,
+            playground: HashMap::new(),
+        }
     }
 
-    #[test]
-    fn test_tangle_end_to_end() -> Result<()> {
-        use std::env;
+    /// Write a block node, captioned with where its code went when it was tangled
+    fn block(&mut self, node: &Node, caption: Option<(&Utf8Path, Option<(usize, usize)>)>) {
+        let typst = self.format == WeaveFormat::Typst;
+        match node {
+            Node::Heading(heading) => {
+                if typst {
+                    self.out.push_str(&"=".repeat(usize::from(heading.depth)));
+                    self.out.push(' ');
+                    self.inlines(&heading.children);
+                    if let Some(label) = self.label.take() {
+                        self.out.push_str(&format!(" <{label}>"));
+                    }
+                } else {
+                    let command = match heading.depth {
+                        1 => "section",
+                        2 => "subsection",
+                        3 => "subsubsection",
+                        4 => "paragraph",
+                        _ => "subparagraph",
+                    };
+                    self.out.push_str(&format!("\\{command}{{"));
+                    self.inlines(&heading.children);
+                    self.out.push('}');
+                    if let Some(label) = self.label.take() {
+                        self.out.push_str(&format!("\\label{{{label}}}"));
+                    }
+                }
+            }
+            Node::Paragraph(paragraph) => self.inlines(&paragraph.children),
+            Node::Code(code) if WeaveMode::of_code(code) == WeaveMode::Hidden => return,
+            Node::Code(code) => self.code(code, caption),
+            Node::List(list) => {
+                let (typst_function, environment) = if list.ordered {
+                    ("enum", "enumerate")
+                } else {
+                    ("list", "itemize")
+                };
+                if typst {
+                    self.out.push_str(&format!("#{typst_function}("));
+                } else {
+                    self.out.push_str(&format!("\\begin{{{environment}}}\n"));
+                }
+                for item in &list.children {
+                    self.out.push_str(if typst { "[" } else { "\\item " });
+                    for child in item.children().into_iter().flatten() {
+                        self.block(child, None);
+                    }
+                    self.out.push_str(if typst { "], " } else { "" });
+                }
+                if typst {
+                    self.out.push(')');
+                } else {
+                    self.out.push_str(&format!("\\end{{{environment}}}"));
+                }
+            }
+            Node::Blockquote(quote) => {
+                self.out.push_str(if typst {
+                    "#quote(block: true)["
+                } else {
+                    "\\begin{quote}\n"
+                });
+                for child in &quote.children {
+                    self.block(child, None);
+                }
+                self.out.push_str(if typst { "]" } else { "\\end{quote}" });
+            }
+            Node::ThematicBreak(_) if typst => self.out.push_str("#line(length: 100%)"),
+            Node::ThematicBreak(_) => self.out.push_str("\\noindent\\rule{\\linewidth}{0.4pt}"),
+            _ => return,
+        }
+        self.out.push_str("\n\n");
+    }
 
-        let temp_dir = Utf8PathBuf::from_path_buf(env::temp_dir()).unwrap();
-        let temp_input = temp_dir.join("lit-test-input");
-        let temp_output = temp_dir.join("lit-test-output");
+    /// Write a code block as a listing, with a caption if it has one
+    fn code(&mut self, code: &Code, caption: Option<(&Utf8Path, Option<(usize, usize)>)>) {
+        let info = rendered_info(code)
+            .or_else(|| code.lang.clone())
+            .unwrap_or_default();
+        let mut lang = info.split_whitespace().next().unwrap_or_default();
+        let mut value = std::borrow::Cow::from(code.value.as_str());
+        if WeaveMode::of_code(code) == WeaveMode::Collapsed {
+            let count = code.value.lines().count();
+            value = format!(
+                "... {count} {} folded",
+                if count == 1 { "line" } else { "lines" }
+            )
+            .into();
+            lang = "";
+        }
+        let label = caption.map(|(target, lines)| {
+            let lines = lines_label(lines);
+            match tangle_url(code).as_ref().and_then(url_label) {
+                Some(label) => (target, format!("{lines}, {label}")),
+                None => (target, lines),
+            }
+        });
+        if self.format == WeaveFormat::Latex {
+            let options = match &label {
+                Some((target, lines)) => format!(
+                    "[caption={{\\texttt{{{}}}, {}}}]",
+                    latex_text(target.as_str()),
+                    latex_text(lines)
+                ),
+                None => String::new(),
+            };
+            self.out.push_str(&format!(
+                "\\begin{{lstlisting}}{options}\n{value}\n\\end{{lstlisting}}"
+            ));
+        } else {
+            let longest = value
+                .split(|c| c != '`')
+                .map(str::len)
+                .max()
+                .unwrap_or_default();
+            let fence = "`".repeat(longest.saturating_add(1).max(3));
+            let listing = format!("{fence}{lang}\n{value}\n{fence}");
+            match &label {
+                Some((target, lines)) => self.out.push_str(&format!(
+                    "#figure(kind: raw, caption: [#raw(\"{}\"), {}])[\n{listing}\n]",
+                    typst_string(target.as_str()),
+                    typst_text(lines),
+                )),
+                None => self.out.push_str(&listing),
+            }
+        }
+        if let Some(url) = label
+            .and_then(|(target, _)| self.playground.get(target))
+            .cloned()
+        {
+            self.out.push_str("\n\n");
+            self.link(&url, "Run on playground");
+        }
+    }
 
-        // Clean up any leftover temp dirs from previous runs
-        let _ = fs::remove_dir_all(&temp_input);
-        let _ = fs::remove_dir_all(&temp_output);
+    /// Write the index of tangled files, each with the numbered sections it was tangled from
+    fn index_of_files(&mut self, files: &[(&Utf8Path, Vec<(usize, &Section)>)]) {
+        if files.is_empty() {
+            return;
+        }
+        let typst = self.format == WeaveFormat::Typst;
+        let text = if typst { typst_text } else { latex_text };
+        self.out.push_str(if typst {
+            "= Index of Files\n\n"
+        } else {
+            "\\section*{Index of Files}\n\n\\begin{description}\n"
+        });
+        for (path, sections) in files {
+            let links: Vec<String> = sections
+                .iter()
+                .map(|(number, section)| match (&section.title, typst) {
+                    (Some(title), true) => {
+                        format!("#link(<{}>)[{}]", section_label(*number), text(title))
+                    }
+                    (Some(title), false) => {
+                        format!("\\hyperref[{}]{{{}}}", section_label(*number), text(title))
+                    }
+                    (None, true) => format!("#raw(\"{}\")", typst_string(section.file.as_str())),
+                    (None, false) => format!("\\texttt{{{}}}", text(section.file.as_str())),
+                })
+                .collect();
+            let links = links.join(", ");
+            if typst {
+                self.out.push_str(&format!(
+                    "/ #raw(\"{}\"): {links}\n",
+                    typst_string(path.as_str())
+                ));
+            } else {
+                self.out.push_str(&format!(
+                    "\\item[\\texttt{{{}}}] {links}\n",
+                    text(path.as_str())
+                ));
+            }
+        }
+        self.out.push_str(if typst {
+            "\n"
+        } else {
+            "\\end{description}\n\n"
+        });
+    }
 
-        fs::create_dir_all(&temp_input)?;
-        let markdown = r#"# Test
+    /// Write inline nodes, such as a paragraph's
+    fn inlines(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.inline(node);
+        }
+    }
 
-```tangle:///test.txt
-Hello World
-```
+    fn inline(&mut self, node: &Node) {
+        let typst = self.format == WeaveFormat::Typst;
+        let text = if typst { typst_text } else { latex_text };
+        let (open, close) = match node {
+            Node::Text(t) => return self.out.push_str(&text(&t.value)),
+            Node::InlineCode(code) if typst => {
+                return self
+                    .out
+                    .push_str(&format!("#raw(\"{}\")", typst_string(&code.value)));
+            }
+            Node::InlineCode(code) => {
+                return self
+                    .out
+                    .push_str(&format!("\\texttt{{{}}}", latex_text(&code.value)));
+            }
+            Node::Break(_) => return self.out.push_str(if typst { "\\\n" } else { "\\\\\n" }),
+            Node::Image(image) => return self.link(&image.url, &text(&image.alt)),
+            Node::Link(link) => {
+                let mut label = Weaver::new(self.format);
+                label.inlines(&link.children);
+                return self.link(&link.url, &label.out);
+            }
+            Node::Emphasis(_) if typst => ("#emph[", "]"),
+            Node::Emphasis(_) => ("\\emph{", "}"),
+            Node::Strong(_) if typst => ("#strong[", "]"),
+            Node::Strong(_) => ("\\textbf{", "}"),
+            Node::Html(_) => return,
+            _ => ("", ""),
+        };
+        self.out.push_str(open);
+        self.inlines(node.children().map(Vec::as_slice).unwrap_or_default());
+        self.out.push_str(close);
+    }
 
-```tangle:///subdir/test2.txt
-Nested file
-```
-"#;
-        fs::write(temp_input.join("test.md"), markdown)?;
+    /// Write a link to `url` showing `label`, already typeset
+    fn link(&mut self, url: &str, label: &str) {
+        if self.format == WeaveFormat::Typst {
+            self.out
+                .push_str(&format!("#link(\"{}\")[{label}]", typst_string(url)));
+        } else {
+            let url = url
+                .replace('\\', "/")
+                .replace('%', "\\%")
+                .replace('#', "\\#");
+            self.out.push_str(&format!("\\href{{{url}}}{{{label}}}"));
+        }
+    }
+}
 
-        let lit = Lit::new(temp_input.clone(), temp_output.clone());
-        lit.tangle()?;
+/// Text as Typst markup, with every markup character escaped
+fn typst_text(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\*_`#$@<>[]~=-+/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
 
-        assert!(temp_output.join("test.txt").exists());
-        assert!(temp_output.join("subdir/test2.txt").exists());
+/// Text as the body of a Typst string literal
+fn typst_string(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
 
-        let content1 = fs::read_to_string(temp_output.join("test.txt"))?;
-        assert_eq!(content1, "Hello World\n");
+/// Text as LaTeX, with its special characters spelled out
+fn latex_text(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '–' => escaped.push_str("--"),
+            '{' | '}' | '$' | '&' | '%' | '#' | '_' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
 
-        let content2 = fs::read_to_string(temp_output.join("subdir/test2.txt"))?;
-        assert_eq!(content2, "Nested file\n");
+/// Whitespace removed from a block's content, from `?trim=` or `[trim]`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Trim {
+    /// Spaces and tabs at the end of each line
+    pub lines: bool,
+    /// Repeated blank lines, and those at the end
+    pub blank: bool,
+}
 
-        fs::remove_dir_all(&temp_input)?;
-        fs::remove_dir_all(&temp_output)?;
+impl Trim {
+    /// The trimming a `?trim=` value names: `lines`, `blank`, both joined by a comma, or `none`
+    pub fn named(value: &str) -> std::result::Result<Trim, BlockError> {
+        let mut trim = Trim::default();
+        for name in value.split(',') {
+            match name.trim() {
+                "none" => {}
+                "lines" => trim.lines = true,
+                "blank" => trim.blank = true,
+                _ => return Err(BlockError::UnknownTrim(value.to_string())),
+            }
+        }
+        Ok(trim)
+    }
 
-        Ok(())
+    /// `content` with this trimming applied
+    pub fn apply(self, content: String) -> String {
+        if self == Trim::default() {
+            return content;
+        }
+        let is_blank = |line: &str| {
+            line.trim_end_matches('\r')
+                .trim_matches([' ', '\t'])
+                .is_empty()
+        };
+        let mut lines: Vec<String> = Vec::new();
+        for line in content.split('\n') {
+            let line = if self.lines {
+                let (text, ending) = line
+                    .strip_suffix('\r')
+                    .map_or((line, ""), |text| (text, "\r"));
+                format!("{}{ending}", text.trim_end_matches([' ', '\t']))
+            } else {
+                line.to_string()
+            };
+            if self.blank && is_blank(&line) && lines.last().is_some_and(|last| is_blank(last)) {
+                continue;
+            }
+            lines.push(line);
+        }
+        if self.blank {
+            while lines.len() > 1 && lines.last().is_some_and(|last| is_blank(last)) {
+                lines.pop();
+            }
+        }
+        lines.join("\n")
     }
+}
 
-    #[test]
-    fn test_tangled_files_end_with_newline() -> Result<()> {
-        use std::env;
+impl TryFrom<String> for Trim {
+    type Error = String;
 
-        let temp_dir = Utf8PathBuf::from_path_buf(env::temp_dir()).unwrap();
-        let temp_input = temp_dir.join("lit-test-newline-input");
-        let temp_output = temp_dir.join("lit-test-newline-output");
+    fn try_from(value: String) -> std::result::Result<Self, String> {
+        Trim::named(&value)
+            .map_err(|_| format!("unknown trim `{value}`, expected lines, blank, or none"))
+    }
+}
 
-        // Clean up any leftover temp dirs from previous runs
-        let _ = fs::remove_dir_all(&temp_input);
-        let _ = fs::remove_dir_all(&temp_output);
+impl Config {
+    /// The trimming a destination's blocks get by default, by its extension
+    pub fn trim(&self, path: &Utf8Path) -> Trim {
+        self.trim
+            .get(path.extension().unwrap_or_default())
+            .copied()
+            .unwrap_or_default()
+    }
+}
 
-        fs::create_dir_all(&temp_input)?;
-        let markdown = r#"# Test
+/// Trim each block's content by its `?trim=`, or else by `trim`, leaving raw blocks alone
+fn trim_blocks(blocks: Vec<Block>, trim: Trim) -> Vec<Block> {
+    blocks
+        .into_iter()
+        .map(|block| {
+            if block.raw {
+                return block;
+            }
+            Block {
+                content: block.trim.unwrap_or(trim).apply(block.content),
+                ..block
+            }
+        })
+        .collect()
+}
 
-```tangle:///test.txt
-Line 1
-```
-"#;
-        fs::write(temp_input.join("test.md"), markdown)?;
+/// Projects tangled together by `lit --workspace`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workspace {
+    /// Each member's directory
+    #[serde(default)]
+    pub members: Vec<Utf8PathBuf>,
+}
 
-        let lit = Lit::new(temp_input.clone(), temp_output.clone());
-        lit.tangle()?;
+impl Workspace {
+    /// The first name two members share, if any
+    fn duplicate_name(&self) -> Option<&str> {
+        let mut names = BTreeSet::new();
+        self.members
+            .iter()
+            .map(|member| Member::name_of(member))
+            .find(|name| !names.insert(*name))
+    }
+}
 
-        let content = fs::read_to_string(temp_output.join("test.txt"))?;
-        assert!(
-            content.ends_with('\n'),
-            "Tangled file should end with a newline"
-        );
+/// One project of a workspace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// The member's directory name, which `-p` selects it by
+    pub name: String,
+    /// The member's own settings
+    pub config: Config,
+}
 
-        fs::remove_dir_all(&temp_input)?;
-        fs::remove_dir_all(&temp_output)?;
+impl Member {
+    fn name_of(directory: &Utf8Path) -> &str {
+        directory.file_name().unwrap_or(directory.as_str())
+    }
+}
 
-        Ok(())
+impl Config {
+    /// Load the settings of each workspace member, in the order they are listed
+    pub fn members(&self) -> Result<Vec<Member>> {
+        self.workspace
+            .members
+            .iter()
+            .map(|directory| {
+                let path = directory.join("lit.toml");
+                let mut config = if path.is_file() {
+                    Config::load(&path)?
+                } else {
+                    Config::default()
+                };
+                config.input.get_or_insert_with(|| directory.clone());
+                Ok(Member {
+                    name: Member::name_of(directory).to_string(),
+                    config,
+                })
+            })
+            .collect()
     }
 }
 
@@ -801,104 +13550,93 @@ pub enum Constraint {
 
 /// Represents a single tangle block from markdown
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Block {
-    /// The file path to write this block to
-    pub path: Utf8PathBuf,
-    /// Optional unique identifier for this block
-    pub id: Option<BlockId>,
-    /// Ordering constraints for this block
-    pub constraints: Vec<Constraint>,
-    /// Optional: This block is inside another
-    pub inside: Option<BlockId>,
-    /// The content of the code block
-    pub content: String,
-}
-
-impl TryFrom<&Node> for Block {
-    type Error = BlockError;
-
-    fn try_from(node: &Node) -> std::result::Result<Self, Self::Error> {
-        let Node::Code(code) = node else {
-            return Err(BlockError::NotTangleBlock);
-        };
-
-        let lang = code.lang.as_ref().ok_or(BlockError::NotTangleBlock)?;
-
-        // Parse the tangle:/// URL (hostless format)
-        let parsed = Url::parse(lang).map_err(|_| BlockError::NotTangleBlock)?;
-
-        // Check if it's a tangle URL
-        if parsed.scheme() != "tangle" {
-            return Err(BlockError::NotTangleBlock);
-        }
-
-        // Ensure it's hostless (tangle:///path, not tangle://path)
-        if parsed.host_str().is_some() {
-            return Err(BlockError::InvalidTangleUrl);
-        }
-
-        // Get the path from hostless URL (tangle:///path/to/file)
-        let path = parsed.path();
-        if path.is_empty() || path == "/" {
-            return Err(BlockError::MissingPath);
-        }
-        if path.starts_with("//") {
-            return Err(BlockError::InvalidPath);
-        }
-        // A hostless URL path always begins with '/', so stripping it cannot fail.
-        #[allow(clippy::unwrap_used)]
-        let path_str = path.strip_prefix('/').unwrap().to_string();
-
-        // Parse constraint parameters
-        let query_params: HashMap<_, _> = parsed.query_pairs().collect();
-        let (id, constraints, inside) = parse_constraints(&query_params)?;
-
-        Ok(Block {
-            path: Utf8PathBuf::from(path_str),
-            id,
-            constraints,
-            inside,
-            content: code.value.clone(),
-        })
-    }
-}
-
-type ParsedConstraints = (Option<BlockId>, Vec<Constraint>, Option<BlockId>);
-
-fn parse_constraints(
-    params: &HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>,
-) -> std::result::Result<ParsedConstraints, BlockError> {
-    let mut id = None;
-    let mut constraints = Vec::new();
-    let mut inside = None;
-
-    for (key, value) in params {
-        match key.as_ref() {
-            "id" => id = Some(BlockId::new(value.to_string())?),
-            "after" => {
-                let ids = value
-                    .split(',')
-                    .map(|s| BlockId::new(s.trim().to_string()))
-                    .collect::<std::result::Result<Vec<_>, _>>()?;
-                constraints.push(Constraint::After(ids));
-            }
-            "before" => {
-                let ids = value
-                    .split(',')
-                    .map(|s| BlockId::new(s.trim().to_string()))
-                    .collect::<std::result::Result<Vec<_>, _>>()?;
-                constraints.push(Constraint::Before(ids));
-            }
-            "first" => constraints.push(Constraint::First),
-            "last" => constraints.push(Constraint::Last),
-            "inside" => {
-                inside = Some(BlockId::new(value.to_string())?);
-            }
-            _ => {} // Ignore unknown parameters
-        }
+pub struct Block {
+    /// The file path to write this block to
+    pub path: Utf8PathBuf,
+    /// Optional unique identifier for this block
+    pub id: Option<BlockId>,
+    /// Ordering constraints for this block
+    pub constraints: Vec<Constraint>,
+    /// Optional: This block is inside another
+    pub inside: Option<BlockId>,
+    /// The content of the code block
+    pub content: String,
+    /// Where the block was declared, when it came from a markdown file
+    pub origin: Option<Origin>,
+    /// Disabled with `?skip` or `no-tangle`: read, but not tangled
+    pub skip: bool,
+    /// Declared with `?dup=append`: may repeat an earlier block's ID, and follows it
+    pub append: bool,
+    /// The code's language, from `?lang=` or a fence language beside the URL
+    pub lang: Option<String>,
+    /// False with `?expand=false`: the content is kept verbatim, `{{}}` and all
+    pub expand: bool,
+    /// `?include=doc.md#id`: the chunk whose content comes before this block's
+    pub include: Option<String>,
+    /// `#label`: a description for listings and diagnostics, ignored when tangling
+    pub label: Option<String>,
+    /// `?from=file.rs`: a file, relative to the document, whose text comes before this block's
+    pub from: Option<Utf8PathBuf>,
+    /// `?lines=10-40`: the 1-based lines of the `from` file to take, inclusive
+    pub lines: Option<(usize, usize)>,
+    /// Declared with `?encoding=base64`: the content is decoded when written
+    pub base64: bool,
+    /// `?chmod=755`: the mode to give the destination file, in octal
+    pub mode: Option<u32>,
+    /// `?sep=N`: blank lines between this block and the one before it
+    pub sep: Option<usize>,
+    /// `?encoding=utf-16` and the like: the encoding to write the destination in
+    pub encoding: Option<TextEncoding>,
+    /// `?trim=lines,blank`: the whitespace to trim from the content, instead of `[trim]`'s
+    pub trim: Option<Trim>,
+    /// `?raw=true`: the content is written byte for byte, neither trimmed nor expanded
+    pub raw: bool,
+    /// `?assert=contains:fn%20main`: checks on the destination file once it is assembled
+    pub assertions: Vec<Assertion>,
+}
+
+/// The markdown fence a block was declared in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// The markdown file containing the fence
+    pub file: Utf8PathBuf,
+    /// 1-based line of the opening fence
+    pub line: usize,
+    /// Byte offset of the opening fence within the file
+    pub offset: usize,
+    /// 1-based line of the closing fence, or the last line of an unclosed one
+    pub end_line: usize,
+    /// Byte offset just past the closing fence
+    pub end_offset: usize,
+}
+
+impl Origin {
+    fn of(file: &Utf8Path, node: &Node) -> Option<Self> {
+        node.position().map(|position| Origin {
+            file: file.to_owned(),
+            line: position.start.line,
+            offset: position.start.offset,
+            end_line: position.end.line,
+            end_offset: position.end.offset,
+        })
     }
+}
 
-    Ok((id, constraints, inside))
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl Block {
+    /// Whether this block names `id` in its `inside`, `after`, or `before` parameters
+    fn references(&self, id: &BlockId) -> bool {
+        self.inside.as_ref() == Some(id)
+            || self.constraints.iter().any(|constraint| match constraint {
+                Constraint::After(ids) | Constraint::Before(ids) => ids.contains(id),
+                Constraint::First | Constraint::Last => false,
+            })
+    }
 }
 
 /// Errors that can occur when parsing a block from a markdown node
@@ -907,15 +13645,55 @@ pub enum BlockError {
     #[error("Not a tangle block")]
     #[diagnostic(code(lit::block::not_tangle))]
     NotTangleBlock,
+    #[error("`{0}` is not a tangle URL, so this block would not be tangled")]
+    #[diagnostic(
+        code(lit::block::not_tangle_url),
+        help(
+            "spell the destination as tangle:///path, or drop the word if the block is not meant to be tangled"
+        )
+    )]
+    NotTangleUrl(String),
+    #[error("Tangle blocks inside quotes or lists are not tangled")]
+    #[diagnostic(
+        code(lit::block::nested),
+        help("move the fence to the top level of the document")
+    )]
+    NestedTangleBlock,
     #[error("Tangle URL must be hostless (use tangle:///path, not tangle://path)")]
-    #[diagnostic(code(lit::block::invalid_url))]
+    #[diagnostic(
+        code(lit::block::invalid_url),
+        help("add a third slash so the destination is a path: tangle:///path")
+    )]
     InvalidTangleUrl,
+    #[error("Tangle URL must start with tangle:///")]
+    #[diagnostic(
+        code(lit::block::opaque_url),
+        help("write the destination after three slashes: tangle:///path")
+    )]
+    OpaqueTangleUrl,
     #[error("Tangle URL missing path")]
-    #[diagnostic(code(lit::block::missing_path))]
+    #[diagnostic(
+        code(lit::block::missing_path),
+        help("name the destination file after tangle:///, e.g. tangle:///src/main.rs")
+    )]
     MissingPath,
     #[error("Invalid tangle URL path")]
-    #[diagnostic(code(lit::block::invalid_path))]
+    #[diagnostic(
+        code(lit::block::invalid_path),
+        help(
+            "destination paths are relative to the output directory: no extra leading slash, no `..`, and percent-escapes that decode to UTF-8"
+        )
+    )]
     InvalidPath,
+    #[error("Tangle path component '{component}' is not valid on Windows: {reason}")]
+    #[diagnostic(
+        code(lit::block::non_portable_path),
+        help("rename the destination so the output can be checked out on every platform")
+    )]
+    NonPortablePath {
+        component: String,
+        reason: &'static str,
+    },
     #[error(transparent)]
     #[diagnostic(transparent)]
     BlockIdError(#[from] BlockIdError),
@@ -925,14 +13703,154 @@ pub enum BlockError {
         help("declare the referenced block with ?id=… or fix the constraint")
     )]
     UnknownBlockId(BlockId),
-    #[error("Duplicate block ID within file: {0}")]
+    #[error("Block '{0}' is declared with expand=false, so nothing can be nested inside it")]
+    #[diagnostic(
+        code(lit::block::not_expanded),
+        help(
+            "drop expand=false from the parent, and escape its literal placeholders with a backslash"
+        )
+    )]
+    NotExpanded(BlockId),
+    #[error("No block to include at {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_include),
+        help("name a document relative to this one, and a block ID in it: ?include=other.md#id")
+    )]
+    UnknownInclude(String),
+    #[error("Cannot read {0}")]
+    #[diagnostic(
+        code(lit::block::unreadable_file),
+        help("name a file relative to this document: ?from=../vendor/snippet.rs")
+    )]
+    UnreadableFile(String),
+    #[error("Invalid line range: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_lines),
+        help("give 1-based lines of a ?from= file as ?lines=10-40, ?lines=10- or ?lines=10")
+    )]
+    InvalidLines(String),
+    #[error("Line range {lines} is past the end of {file}, which has {count} lines")]
+    #[diagnostic(
+        code(lit::block::lines_out_of_range),
+        help("the file may have changed since the range was written")
+    )]
+    LinesOutOfRange {
+        lines: String,
+        file: Utf8PathBuf,
+        count: usize,
+    },
+    #[error("Unknown block encoding: {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_encoding),
+        help(
+            "lit decodes base64, and writes utf-8, utf-8-bom, utf-16, utf-16le, utf-16be or latin-1"
+        )
+    )]
+    UnknownEncoding(String),
+    #[error("Block content is not valid base64: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_base64),
+        help("paste the output of `base64` unchanged; line breaks and indentation are ignored")
+    )]
+    InvalidBase64(String),
+    #[error("Invalid file mode: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_mode),
+        help("give the permission bits in octal, as chmod takes them: ?chmod=755")
+    )]
+    InvalidMode(String),
+    #[error("Invalid block separator: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_separator),
+        help("give the number of blank lines to put before the block: ?sep=0")
+    )]
+    InvalidSeparator(String),
+    #[error("Unknown dup value: {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_dup),
+        help(
+            "?dup=append repeats an earlier block's ID; to repeat its content, allow it with ?allow=duplicate-content"
+        )
+    )]
+    UnknownDup(String),
+    #[error("Unknown weave mode: {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_weave),
+        help("a block is woven expanded, collapsed or hidden: ?weave=collapsed")
+    )]
+    UnknownWeave(String),
+    #[error("Unknown trim: {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_trim),
+        help("trim lines, blank, both, or none: ?trim=lines,blank")
+    )]
+    UnknownTrim(String),
+    #[error("Invalid assertion: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_assertion),
+        help("assert contains:TEXT, not-contains:TEXT, matches:REGEX, min-lines:N or max-lines:N")
+    )]
+    InvalidAssertion(String),
+    #[error("A raw block cannot be trimmed")]
+    #[diagnostic(
+        code(lit::block::raw_trimmed),
+        help("drop ?trim=, or ?raw=true to trim the block")
+    )]
+    RawTrimmed,
+    #[error("Duplicate block ID within file: {id}{}", declared_at(.declarations.as_deref()))]
     #[diagnostic(
         code(lit::block::duplicate_id),
         help("each block ID must be unique within a destination file")
     )]
-    DuplicateId(BlockId),
+    DuplicateId {
+        id: BlockId,
+        /// The fences that declared it first and again, when both are known
+        declarations: Option<Box<(Origin, Origin)>>,
+    },
+    #[error("Destination {path} differs from {other} only in case")]
+    #[diagnostic(
+        code(lit::block::case_collision),
+        help(
+            "case-insensitive filesystems, the default on macOS and Windows, would write both to one file; rename one"
+        )
+    )]
+    CaseCollision {
+        path: Utf8PathBuf,
+        other: Utf8PathBuf,
+    },
+    #[error("Block is {size} bytes, over the limit of {limit}")]
+    #[diagnostic(
+        code(lit::block::too_large),
+        help(
+            "move the data out of the markdown, or raise max_block_bytes under [limits] in lit.toml"
+        )
+    )]
+    BlockTooLarge { size: usize, limit: usize },
+    #[error("Unknown variable in tangle path: {0}")]
+    #[diagnostic(
+        code(lit::block::unknown_variable),
+        help("define it under [variables] in lit.toml, or with --define NAME=VALUE")
+    )]
+    UnknownVariable(String),
+    #[error("Environment variable is not set: {0}")]
+    #[diagnostic(
+        code(lit::block::unset_env),
+        help(
+            "set it in the environment lit runs in, or run without --env to keep the text as written"
+        )
+    )]
+    UnsetEnv(String),
+    #[error("Tangle URL query has escapes that are not UTF-8: {0}")]
+    #[diagnostic(
+        code(lit::block::invalid_query_value),
+        help("percent-encode the value's UTF-8 bytes, as in %C3%A9 for é")
+    )]
+    InvalidQueryValue(String),
     #[error("Constraints are unsatisfiable (circular dependency detected)")]
-    #[diagnostic(code(lit::block::unsatisfiable))]
+    #[diagnostic(
+        code(lit::block::unsatisfiable),
+        help("look for blocks that are each ordered after (or before) the other")
+    )]
     UnsatisfiableConstraints,
     #[error("Constraint solver timeout")]
     #[diagnostic(code(lit::block::solver_timeout))]
@@ -946,6 +13864,248 @@ pub enum LitError {
     #[diagnostic(transparent)]
     Block(#[from] BlockError),
 
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Located(#[from] Box<LocatedError>),
+
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("assertion failed: {path} {failure}")]
+    #[diagnostic(code(lit::assertion_failed))]
+    AssertionFailed {
+        path: Utf8PathBuf,
+        failure: String,
+        #[help]
+        help: String,
+    },
+
+    #[error("{path} is written by more than one document")]
+    #[diagnostic(code(lit::shared_target))]
+    SharedTarget {
+        path: Utf8PathBuf,
+        #[help]
+        help: String,
+    },
+
+    #[error("refusing to overwrite {path}, which lit did not generate")]
+    #[diagnostic(
+        code(lit::would_overwrite),
+        help("pass --force to overwrite it anyway, or tangle into another directory")
+    )]
+    WouldOverwrite { path: Utf8PathBuf },
+
+    #[error("{path} is held by another lit run (process {pid})")]
+    #[diagnostic(
+        code(lit::locked),
+        help("wait for the other run to finish; the lock is released when its process ends")
+    )]
+    Locked { path: Utf8PathBuf, pid: String },
+
+    #[error("{path} has format version {found}, which this version of lit cannot read")]
+    #[diagnostic(
+        code(lit::unsupported_format),
+        help("upgrade lit, or delete the file to start it afresh")
+    )]
+    UnsupportedFormat { path: Utf8PathBuf, found: String },
+
+    #[error("no blocks are tangled into {path}")]
+    #[diagnostic(
+        code(lit::unknown_target),
+        help("`lit list` shows every destination; paths are relative to the output directory")
+    )]
+    UnknownTarget { path: Utf8PathBuf },
+
+    #[error("line {line} of {path} was not written by any block")]
+    #[diagnostic(
+        code(lit::not_from_block),
+        help(
+            "the line is part of the generated-file header, a blank line between blocks, or past the end"
+        )
+    )]
+    NotFromBlock { path: Utf8PathBuf, line: usize },
+
+    #[error("{path} contains {character:?}, which {encoding} cannot encode")]
+    #[diagnostic(
+        code(lit::unencodable),
+        help(
+            "write the file in an encoding that has the character, such as utf-8, or leave it out"
+        )
+    )]
+    Unencodable {
+        path: Utf8PathBuf,
+        encoding: TextEncoding,
+        character: char,
+    },
+
+    #[error("invalid target glob `{glob}`: {message}")]
+    #[diagnostic(
+        code(lit::invalid_glob),
+        help(
+            "globs match destination paths: `*` within a directory, `**` across them, `[a-z]` a character"
+        )
+    )]
+    InvalidGlob { glob: String, message: String },
+
+    #[error("{path} is {size} bytes, over the limit of {limit}")]
+    #[diagnostic(
+        code(lit::file_too_large),
+        help(
+            "look for a block that should not be there, or raise max_file_bytes under [limits] in lit.toml"
+        )
+    )]
+    FileTooLarge {
+        path: Utf8PathBuf,
+        size: usize,
+        limit: usize,
+    },
+
+    #[error("the tangled files are {size} bytes, over the limit of {limit}")]
+    #[diagnostic(
+        code(lit::output_too_large),
+        help(
+            "`lit stats` lists the largest targets; or raise max_output_bytes under [limits] in lit.toml"
+        )
+    )]
+    OutputTooLarge { size: usize, limit: usize },
+
+    #[error("no answer for `{name}`")]
+    #[diagnostic(
+        code(lit::unanswered),
+        help(
+            "answer it with --define NAME=VALUE, or give it a default in the template's front matter"
+        )
+    )]
+    Unanswered { name: String },
+
+    #[error("{path} is not empty")]
+    #[diagnostic(
+        code(lit::not_empty),
+        help("a template makes a new project; name a directory that does not exist yet")
+    )]
+    NotEmpty { path: Utf8PathBuf },
+
+    #[error("cannot tell what kind of archive {path} is")]
+    #[diagnostic(
+        code(lit::unknown_archive),
+        help("name the archive `.tar`, `.tar.gz`, `.tgz`, or `.zip`")
+    )]
+    UnknownArchive { path: Utf8PathBuf },
+
+    #[error("failed to write the archive {path}: {message}")]
+    #[diagnostic(code(lit::archive))]
+    Archive { path: Utf8PathBuf, message: String },
+
+    #[error("{path} mixes base64 blocks with text blocks")]
+    #[diagnostic(
+        code(lit::mixed_encoding),
+        help(
+            "a binary file is made only of ?encoding=base64 blocks; give the text its own destination"
+        )
+    )]
+    MixedEncoding { path: Utf8PathBuf },
+
+    #[error("{tool} failed on the tangled output:\n{output}")]
+    #[diagnostic(
+        code(lit::hook_failed),
+        help("lines naming a tangled file are annotated with the markdown they came from")
+    )]
+    HookFailed { tool: &'static str, output: String },
+
+    #[error("post-tangle hook `{command}` failed: {status}")]
+    #[diagnostic(code(lit::post_tangle_failed))]
+    PostTangleFailed { command: String, status: String },
+
+    #[error("no build to verify")]
+    #[diagnostic(
+        code(lit::no_verify_command),
+        help("set the build command under [verify] in lit.toml: command = \"cargo test\"")
+    )]
+    NoVerifyCommand,
+
+    #[error("build `{command}` failed on the tangled sources: {status}")]
+    #[diagnostic(
+        code(lit::verify_failed),
+        help(
+            "a file the build needs is missing from the markdown, or from [verify] copy; --keep-temp keeps the directory to look at"
+        )
+    )]
+    VerifyFailed { command: String, status: String },
+
+    #[error("{what} is not allowed in the sandbox")]
+    #[diagnostic(
+        code(lit::sandboxed),
+        help("--sandbox is for documents you do not trust; run without it only on ones you do")
+    )]
+    Sandboxed { what: String },
+
+    #[error("the tangled files are not the expected ones: {differences}")]
+    #[diagnostic(
+        code(lit::unexpected_targets),
+        help(
+            "define the missing files in the markdown, and fix or remove the fences writing the others, or update the `expect` files in lit.toml"
+        )
+    )]
+    UnexpectedTargets {
+        differences: String,
+        missing: Vec<Utf8PathBuf>,
+        unexpected: Vec<Utf8PathBuf>,
+    },
+
+    #[error("cannot read expected targets from {path}: {message}")]
+    #[diagnostic(code(lit::invalid_expect))]
+    InvalidExpect { path: Utf8PathBuf, message: String },
+
+    #[error("{path} is both an asset and a tangled file")]
+    #[diagnostic(
+        code(lit::asset_conflict),
+        help("leave the file out of `[assets] include`, or tangle it to another path")
+    )]
+    AssetConflict { path: Utf8PathBuf },
+
+    #[error("{count} denied warnings; the first is {first}")]
+    #[diagnostic(
+        code(lit::denied_warnings),
+        help("fix what the warnings point at, or leave them out of --deny")
+    )]
+    DeniedWarnings { count: usize, first: String },
+
+    #[error("cannot move {path}: {reason}")]
+    #[diagnostic(code(lit::cannot_move))]
+    CannotMove { path: Utf8PathBuf, reason: String },
+
+    #[error("weave template {path} has no {{{{body}}}} placeholder")]
+    #[diagnostic(
+        code(lit::template_without_body),
+        help("write {{{{body}}}} where the woven documents go")
+    )]
+    TemplateWithoutBody { path: Utf8PathBuf },
+
+    #[error("plugin `{name}` failed: {message}")]
+    #[diagnostic(
+        code(lit::plugin_failed),
+        help("the plugin's protocol is described in lit/plugins.md")
+    )]
+    PluginFailed { name: String, message: String },
+
+    #[error("invalid mdBook input: {0}")]
+    #[diagnostic(code(lit::mdbook))]
+    Book(String),
+
+    #[error("cannot read {path}: {reason}")]
+    #[diagnostic(
+        code(lit::unreadable_source),
+        help(
+            "lit reads documents as UTF-8; fix or convert the file, list it in .litignore, or pass --skip-invalid to go on without it"
+        )
+    )]
+    UnreadableSource { path: Utf8PathBuf, reason: String },
+
+    #[error("path is not valid UTF-8: {}", .0.display())]
+    #[diagnostic(code(lit::non_utf8_path))]
+    NonUtf8Path(std::path::PathBuf),
+
     #[error("failed to parse markdown: {0}")]
     #[diagnostic(code(lit::markdown))]
     Markdown(String),
@@ -954,73 +14114,330 @@ pub enum LitError {
     #[diagnostic(code(lit::markdown::not_root))]
     NotRoot,
 
+    #[error("language server protocol error: {0}")]
+    #[diagnostic(code(lit::lsp::protocol))]
+    Protocol(String),
+
     #[error(transparent)]
     #[diagnostic(code(lit::io))]
     Io(#[from] std::io::Error),
+
+    #[error("found {} problems", .errors.len())]
+    #[diagnostic(code(lit::errors))]
+    Errors {
+        #[related]
+        errors: Vec<LitError>,
+    },
 }
 
 /// Result alias used throughout the library.
 pub type Result<T> = std::result::Result<T, LitError>;
 
-/// Solve block ordering constraints using a topological sort
-pub fn solve_block_order(blocks: &[Block]) -> Result<Vec<Block>> {
-    if blocks.is_empty() {
-        return Ok(Vec::new());
+impl LitError {
+    /// Fail with the problems a stage found, if it found any
+    fn all(mut errors: Vec<LitError>) -> Result<()> {
+        if errors.len() > 1 {
+            return Err(LitError::Errors { errors });
+        }
+        errors.pop().map_or(Ok(()), Err)
+    }
+
+    /// The problems this error stands for: its collected ones, or itself
+    pub fn into_errors(self) -> Vec<LitError> {
+        match self {
+            LitError::Errors { errors } => errors,
+            error => vec![error],
+        }
+    }
+}
+
+/// A block error pinned to the markdown fence that caused it
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct LocatedError {
+    /// The underlying error
+    pub error: BlockError,
+    /// The fence the error was found in
+    pub origin: Origin,
+    /// Other fences involved in the error
+    pub related: Vec<RelatedFence>,
+    /// The fence's `#label`, shown beside it
+    pub block_label: Option<String>,
+    src: NamedSource<String>,
+    span: SourceSpan,
+}
+
+impl LocatedError {
+    fn new(error: BlockError, origin: Origin, source: &str) -> Box<Self> {
+        let span = error_span(&error, origin.offset, source);
+        Box::new(LocatedError {
+            src: NamedSource::new(origin.file.as_str(), source.to_string()),
+            error,
+            block_label: label_at(source, origin.offset),
+            origin,
+            related: Vec::new(),
+            span,
+        })
+    }
+
+    /// Note another fence involved in this error, pointing at the same value
+    fn relate(&mut self, message: &'static str, origin: Origin, source: &str) {
+        let span = error_span(&self.error, origin.offset, source);
+        self.related.push(RelatedFence {
+            message,
+            src: NamedSource::new(origin.file.as_str(), source.to_string()),
+            origin,
+            span,
+        });
+    }
+
+    fn label(&self) -> &'static str {
+        match self.error {
+            BlockError::NotTangleUrl(_) => "not a tangle URL",
+            BlockError::NestedTangleBlock => "nested tangle fence",
+            BlockError::InvalidTangleUrl => "tangle URL has a host",
+            BlockError::OpaqueTangleUrl => "no tangle:/// prefix",
+            BlockError::MissingPath => "no destination path",
+            BlockError::InvalidPath => "invalid destination path",
+            BlockError::NonPortablePath { .. } => "not valid on Windows",
+            BlockError::BlockIdError(_) => "invalid block ID",
+            BlockError::UnknownBlockId(_) => "no block has this ID",
+            BlockError::UnknownInclude(_) => "no such chunk",
+            BlockError::UnreadableFile(_) => "cannot read this file",
+            BlockError::InvalidLines(_) => "not a line range",
+            BlockError::LinesOutOfRange { .. } => "past the end of the file",
+            BlockError::UnknownEncoding(_) => "unknown encoding",
+            BlockError::InvalidBase64(_) => "content is not base64",
+            BlockError::InvalidMode(_) => "not an octal mode",
+            BlockError::InvalidSeparator(_) => "not a number of blank lines",
+            BlockError::UnknownDup(_) => "unknown dup value",
+            BlockError::UnknownWeave(_) => "unknown weave mode",
+            BlockError::UnknownTrim(_) => "unknown trim",
+            BlockError::RawTrimmed => "raw block trimmed",
+            BlockError::InvalidAssertion(_) => "not an assertion",
+            BlockError::NotExpanded(_) => "nested into a verbatim block",
+            BlockError::DuplicateId { .. } => "ID declared again here",
+            BlockError::BlockTooLarge { .. } => "block too large",
+            BlockError::CaseCollision { .. } => "same file on a case-insensitive filesystem",
+            BlockError::UnknownVariable(_) => "no such variable",
+            BlockError::UnsetEnv(_) => "not set in the environment",
+            BlockError::InvalidQueryValue(_) => "escapes are not UTF-8",
+            _ => "in this block", // cov-excl-line: other errors are never located
+        }
+    }
+}
+
+impl Diagnostic for LocatedError {
+    fn code<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.error.code()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.error.help()
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        Some(&self.src)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        let label = match &self.block_label {
+            Some(block_label) => format!("{} (#{block_label})", self.label()),
+            None => self.label().to_string(),
+        };
+        let label = LabeledSpan::new_with_span(Some(label), self.span);
+        Some(Box::new(std::iter::once(label)))
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        if self.related.is_empty() {
+            return None;
+        }
+        Some(Box::new(self.related.iter().map(|r| r as &dyn Diagnostic)))
+    }
+}
+
+/// A fence involved in an error, shown alongside it
+#[derive(Debug, Error, Diagnostic)]
+#[error("{message}: {origin}")]
+#[diagnostic(severity(Advice))]
+pub struct RelatedFence {
+    message: &'static str,
+    /// The related fence
+    pub origin: Origin,
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("{message}")]
+    span: SourceSpan,
+}
+
+/// Where a duplicated ID was declared, as its error puts it: " (declared at a.md:1 and again at b.md:5)"
+fn declared_at(declarations: Option<&(Origin, Origin)>) -> String {
+    match declarations {
+        Some((first, again)) => format!(" (declared at {first} and again at {again})"),
+        None => String::new(),
+    }
+}
+
+/// The value an error complains about, if it names one
+fn error_needle(error: &BlockError) -> Option<&str> {
+    match error {
+        BlockError::BlockIdError(BlockIdError::InvalidCharacters(value)) => Some(value),
+        BlockError::UnknownBlockId(id)
+        | BlockError::DuplicateId { id, .. }
+        | BlockError::NotExpanded(id) => Some(id.as_str()),
+        BlockError::NonPortablePath { component, .. } => Some(component),
+        BlockError::UnknownVariable(placeholder) | BlockError::UnsetEnv(placeholder) => {
+            Some(placeholder)
+        }
+        BlockError::UnknownEncoding(value)
+        | BlockError::InvalidMode(value)
+        | BlockError::InvalidSeparator(value)
+        | BlockError::UnknownDup(value)
+        | BlockError::UnknownWeave(value)
+        | BlockError::UnknownTrim(value)
+        | BlockError::InvalidAssertion(value)
+        | BlockError::InvalidQueryValue(value) => Some(value),
+        _ => None,
     }
+}
+
+/// Span of the offending part of the fence opening at `offset`
+fn error_span(error: &BlockError, offset: usize, source: &str) -> SourceSpan {
+    let line = source
+        .get(offset..)
+        .and_then(|rest| rest.lines().next())
+        .unwrap_or_default();
+    let info_start = line
+        .find(|c: char| !matches!(c, ' ' | '`' | '~'))
+        .unwrap_or(line.len());
+    let info = line.get(info_start..).unwrap_or_default().trim_end();
+
+    let (start, len) = error_needle(error)
+        .and_then(|needle| {
+            info.match_indices(needle)
+                .map(|(i, _)| i)
+                .find(|&i| {
+                    i.checked_sub(1)
+                        .and_then(|before| info.get(before..i))
+                        .is_some_and(|c| c == "=" || c == "," || c == "/")
+                })
+                .map(|i| (i, needle.len()))
+        })
+        .unwrap_or((0, info.len()));
+
+    let start = offset.saturating_add(info_start).saturating_add(start);
+    SourceSpan::new(start.into(), len)
+}
+
+/// Solve block ordering constraints using a topological sort
+pub fn solve_block_order(blocks: Vec<Block>) -> Result<Vec<Block>> {
+    let order = block_order(&blocks)?;
+    Ok(apply_surrounds(arrange(blocks, &order)))
+}
 
-    // Collect blocks with IDs (for constraint solving)
-    let with_ids: Vec<_> = blocks.iter().filter(|b| b.id.is_some()).collect();
+/// `blocks` moved into `order`, a permutation of their indices
+fn arrange(blocks: Vec<Block>, order: &[usize]) -> Vec<Block> {
+    let mut slots: Vec<_> = blocks.into_iter().map(Some).collect();
+    order
+        .iter()
+        .filter_map(|&i| slots.get_mut(i)?.take())
+        .collect()
+}
+
+/// The order of `blocks` their constraints give, as indices into them
+fn block_order(blocks: &[Block]) -> Result<Vec<usize>> {
+    // Collect blocks that take part in ordering: named ones, and anonymous
+    // ones that constrain their own position
+    let is_ordered = |b: &Block| b.id.is_some() || !b.constraints.is_empty() || b.inside.is_some();
+
+    // The rest are placed at the default position, at the end
+    let unordered = blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| !is_ordered(b))
+        .map(|(i, _)| i);
 
-    // Collect blocks without IDs (will be placed at default position)
-    let without_ids: Vec<_> = blocks.iter().filter(|b| b.id.is_none()).cloned().collect();
+    if !blocks.iter().any(is_ordered) {
+        // No constraints, keep them as they are
+        return Ok((0..blocks.len()).collect());
+    }
 
-    if with_ids.is_empty() {
-        // No constraints, return as-is
-        return Ok(blocks.to_vec());
+    // Set aside `dup=append` blocks that repeat an earlier block's ID; they
+    // follow that block wherever it ends up
+    let mut appended: HashMap<BlockId, Vec<usize>> = HashMap::new();
+    let mut heads: Vec<(usize, &Block)> = Vec::new();
+    for (i, block) in blocks.iter().enumerate().filter(|(_, b)| is_ordered(b)) {
+        match &block.id {
+            Some(id) if block.append && heads.iter().any(|(_, b)| b.id.as_ref() == Some(id)) => {
+                appended.entry(id.clone()).or_default().push(i);
+            }
+            _ => heads.push((i, block)),
+        }
     }
+    let ordered: Vec<&Block> = heads.iter().map(|&(_, block)| block).collect();
+    let appended_to = |block: &Block| {
+        block
+            .id
+            .as_ref()
+            .and_then(|id| appended.get(id))
+            .into_iter()
+            .flatten()
+            .copied()
+    };
 
     // Check for duplicate IDs
-    let mut seen = HashSet::new();
-    for block in &with_ids {
-        if let Some(id) = &block.id
-            && !seen.insert(id.as_str())
-        {
-            return Err(BlockError::DuplicateId(id.clone()).into());
+    let mut seen = HashMap::new();
+    for block in &ordered {
+        let Some(id) = &block.id else {
+            continue;
+        };
+        if let Some(first) = seen.insert(id.as_str(), *block) {
+            let declarations = first.origin.clone().zip(block.origin.clone()).map(Box::new);
+            return Err(BlockError::DuplicateId {
+                id: id.clone(),
+                declarations,
+            }
+            .into());
         }
     }
 
-    // Build ID to index map. `with_ids` is filtered to blocks whose id is Some,
-    // so unwrapping the id here cannot fail.
-    #[allow(clippy::unwrap_used)]
-    let id_to_idx: HashMap<_, _> = with_ids
+    // Build ID to index map
+    let id_to_idx: HashMap<_, _> = ordered
         .iter()
         .enumerate()
-        .map(|(i, b)| (b.id.as_ref().unwrap().clone(), i))
+        .filter_map(|(i, b)| Some((b.id.clone()?, i)))
         .collect();
 
     // Validate inside relationships
-    for block in &with_ids {
-        if let Some(surrounding_id) = &block.inside
-            && !id_to_idx.contains_key(surrounding_id)
-        {
-            return Err(BlockError::UnknownBlockId(surrounding_id.clone()).into());
+    for block in &ordered {
+        if let Some(surrounding_id) = &block.inside {
+            let Some(&parent) = id_to_idx.get(surrounding_id) else {
+                return Err(BlockError::UnknownBlockId(surrounding_id.clone()).into());
+            };
+            if ordered.get(parent).is_some_and(|parent| !parent.expand) {
+                return Err(BlockError::NotExpanded(surrounding_id.clone()).into());
+            }
         }
     }
 
     // Build a dependency graph: an edge a -> b means "a must come before b".
-    // Nodes are added in input order, so node index == index into `with_ids`.
+    // Nodes are added in input order, so node index == index into `ordered`.
     let mut graph = DiGraph::<usize, ()>::new();
-    let nodes: Vec<NodeIndex> = (0..with_ids.len()).map(|i| graph.add_node(i)).collect();
+    let nodes: Vec<NodeIndex> = (0..ordered.len()).map(|i| graph.add_node(i)).collect();
 
     // Every index used to address `nodes` is in range by construction.
     #[allow(clippy::indexing_slicing)]
-    for (i, block) in with_ids.iter().enumerate() {
-        for constraint in &block.constraints {
+    for (i, block) in ordered.iter().enumerate() {
+        let appended_constraints = appended_to(block)
+            .filter_map(|j| blocks.get(j))
+            .flat_map(|b| &b.constraints);
+        for constraint in block.constraints.iter().chain(appended_constraints) {
             match constraint {
                 Constraint::First => {
                     // Edge to every other block, so this is the only node with
                     // in-degree zero and the sort emits it first (absolute pos 0).
-                    for j in 0..with_ids.len() {
+                    for j in 0..ordered.len() {
                         if j != i {
                             graph.add_edge(nodes[i], nodes[j], ());
                         }
@@ -1029,7 +14446,7 @@ pub fn solve_block_order(blocks: &[Block]) -> Result<Vec<Block>> {
                 Constraint::Last => {
                     // Edge from every other block, so this node is reached only
                     // after all of them and the sort emits it last.
-                    for j in 0..with_ids.len() {
+                    for j in 0..ordered.len() {
                         if j != i {
                             graph.add_edge(nodes[j], nodes[i], ());
                         }
@@ -1072,7 +14489,7 @@ pub fn solve_block_order(blocks: &[Block]) -> Result<Vec<Block>> {
     // Indices come from the graph's own node set, so addressing `nodes` and
     // `in_degree` cannot go out of bounds; the in-degree never underflows
     // because each edge is only decremented once.
-    let mut order = Vec::with_capacity(with_ids.len());
+    let mut order = Vec::with_capacity(ordered.len());
     #[allow(clippy::indexing_slicing)]
     while let Some(Reverse(i)) = ready.pop() {
         order.push(i);
@@ -1087,25 +14504,22 @@ pub fn solve_block_order(blocks: &[Block]) -> Result<Vec<Block>> {
 
     // If not every node was emitted, some nodes never reached in-degree zero,
     // which means the graph contains a cycle (contradictory constraints).
-    if order.len() != with_ids.len() {
+    if order.len() != ordered.len() {
         return Err(BlockError::UnsatisfiableConstraints.into());
     }
 
-    // `order` is a permutation of `0..with_ids.len()`, so every index is valid.
-    #[allow(clippy::indexing_slicing)]
-    let sorted: Vec<Block> = order.iter().map(|&i| with_ids[i].clone()).collect();
-
-    // Apply surround relationships
-    let mut sorted_blocks = apply_surrounds(sorted)?;
-
-    // Add blocks without IDs at the end
-    sorted_blocks.extend(without_ids);
-
-    Ok(sorted_blocks)
+    // Each head is followed by the blocks appended to it, and the unordered
+    // blocks come last
+    Ok(order
+        .iter()
+        .filter_map(|&i| heads.get(i))
+        .flat_map(|&(i, block)| std::iter::once(i).chain(appended_to(block)))
+        .chain(unordered)
+        .collect())
 }
 
 /// Apply surround relationships to blocks
-fn apply_surrounds(blocks: Vec<Block>) -> Result<Vec<Block>> {
+fn apply_surrounds(blocks: Vec<Block>) -> Vec<Block> {
     // Build map of surrounded blocks
     let mut surrounded: HashMap<BlockId, Vec<Block>> = HashMap::new();
     let mut non_surrounded = Vec::new();
@@ -1125,50 +14539,126 @@ fn apply_surrounds(blocks: Vec<Block>) -> Result<Vec<Block>> {
         match block.id.as_ref().and_then(|id| surrounded.get(id)) {
             Some(children) => {
                 // This block has children, replace {{}} placeholder
-                let children_content = children
-                    .iter()
-                    .map(|b| b.content.as_str())
-                    .collect::<Vec<_>>()
-                    .join("\n\n");
+                let mut children_content = String::new();
+                for (i, child) in children.iter().enumerate() {
+                    if i > 0 {
+                        children_content.push_str("\n\n");
+                    }
+                    children_content.push_str(&child.content);
+                }
 
-                // Replace {{}} with children content
-                let content = block.content.replace("{{}}", &children_content);
+                // Replace {{}} with children content, keeping escaped \{{}} literal,
+                // straight into one string
+                let mut content = String::with_capacity(
+                    block.content.len().saturating_add(children_content.len()),
+                );
+                for (i, part) in block.content.split("\\{{}}").enumerate() {
+                    if i > 0 {
+                        content.push_str("{{}}");
+                    }
+                    for (j, piece) in part.split("{{}}").enumerate() {
+                        if j > 0 {
+                            content.push_str(&children_content);
+                        }
+                        content.push_str(piece);
+                    }
+                }
 
-                result.push(Block {
-                    path: block.path.clone(),
-                    id: block.id.clone(),
-                    constraints: block.constraints.clone(),
-                    inside: block.inside.clone(),
-                    content,
-                });
+                result.push(Block { content, ..block });
             }
             None => result.push(block),
         }
     }
 
-    Ok(result)
+    result
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TangledFile {
     pub path: Utf8PathBuf,
     pub blocks: Vec<Block>,
+    /// Number of tangle blocks read for this file, before nesting
+    pub block_count: usize,
+    /// Where the contributing blocks were declared, in reading order
+    pub origins: Vec<Origin>,
+    /// The mode the file is given, from the first block declaring one
+    pub mode: Option<u32>,
+    /// Blank lines between blocks, unless a block sets its own with `?sep=`
+    pub separator: usize,
+    /// The encoding the file is written in, unless it is binary
+    pub encoding: TextEncoding,
 }
 
 impl TangledFile {
     pub fn new(path: Utf8PathBuf, blocks: Vec<Block>) -> Self {
         // Blocks are assumed to be pre-sorted by solve_block_order
-        TangledFile { path, blocks }
+        let block_count = blocks.len();
+        TangledFile {
+            path,
+            blocks,
+            block_count,
+            origins: Vec::new(),
+            mode: None,
+            separator: 1,
+            encoding: TextEncoding::Utf8,
+        }
     }
 
+    /// The file's contents: its blocks joined by their separators, with a final newline
     pub fn render(&self) -> String {
-        let content = self
-            .blocks
+        let mut content = String::with_capacity(self.rendered_len());
+        for piece in self.pieces() {
+            content.push_str(piece);
+        }
+        content
+    }
+
+    /// Write the contents `render` returns to `out` a piece at a time, without building them whole
+    pub fn write_to(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        for piece in self.pieces() {
+            out.write_all(piece.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The length in bytes of the rendered contents
+    pub fn rendered_len(&self) -> usize {
+        self.pieces().map(str::len).sum()
+    }
+
+    /// The rendered contents in order: each block, the newlines before it, and the final newline
+    fn pieces(&self) -> impl Iterator<Item = &str> {
+        self.blocks
             .iter()
-            .map(|b| b.content.as_str())
-            .collect::<Vec<_>>()
-            .join("\n\n");
+            .enumerate()
+            .flat_map(|(i, block)| {
+                let newlines = if i == 0 {
+                    0
+                } else {
+                    self.gap_before(block).saturating_add(1)
+                };
+                std::iter::repeat_n("\n", newlines).chain(std::iter::once(block.content.as_str()))
+            })
+            .chain(std::iter::once("\n"))
+    }
 
-        format!("{content}\n")
+    /// Blank lines between `block` and the block before it
+    fn gap_before(&self, block: &Block) -> usize {
+        block.sep.unwrap_or(self.separator)
+    }
+
+    /// The 1-based first and last line of each block in the rendered file
+    pub fn line_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = 1usize;
+        for (i, block) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                start = start.saturating_add(self.gap_before(block));
+            }
+            let end = start.saturating_add(block.content.lines().count().max(1).saturating_sub(1));
+            ranges.push((start, end));
+            start = end.saturating_add(1);
+        }
+        ranges
     }
 }
@@ -1,6 +1,20 @@
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
+use clap::ArgAction;
+use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
+use lit::Config;
+use lit::Diagnostics;
+use lit::Level;
 use lit::Lit;
+use lit::LitError;
+use lit::Message;
+use lit::MovedOutput;
+use lit::TargetGlob;
+use lit::WriteStatus;
+use std::io::IsTerminal;
+use std::sync::LazyLock;
 use tracing::info;
 use tracing_subscriber::EnvFilter;
 
@@ -8,34 +22,1141 @@ use tracing_subscriber::EnvFilter;
 #[command(name = "lit")]
 #[command(about = "A literate programming tool", long_about = None)]
 struct Args {
-    /// Input directory to process
-    #[arg(value_name = "INPUT")]
-    directory: Utf8PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Output directory for tangled files (defaults to INPUT/out)
-    #[arg(value_name = "OUTPUT")]
+    /// Input directories or files, then the output directory unless -o is given
+    #[arg(value_name = "INPUT [OUTPUT]")]
+    paths: Vec<Utf8PathBuf>,
+
+    /// Output directory for tangled files (defaults to `output` in lit.toml, then INPUT/out)
+    #[arg(short, long, value_name = "OUTPUT")]
     output: Option<Utf8PathBuf>,
+
+    /// Tangle every member of the lit.toml workspace
+    #[arg(long, conflicts_with_all = ["paths", "output"])]
+    workspace: bool,
+
+    /// Tangle only this workspace member (repeatable); implies --workspace
+    #[arg(short, long = "package", value_name = "MEMBER", conflicts_with_all = ["paths", "output"])]
+    packages: Vec<String>,
+
+    /// Print phase timings, per-document parse times, and peak memory as JSON on stdout
+    #[arg(long, conflicts_with_all = ["workspace", "packages"])]
+    profile: bool,
+
+    /// Write only destinations matching this glob (repeatable), still checking the rest
+    #[arg(long = "target", value_name = "GLOB", value_parser = parse_target)]
+    targets: Vec<TargetGlob>,
+
+    /// Tangle into this .tar, .tar.gz, .tgz, or .zip file instead of a directory
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["output", "workspace", "packages"])]
+    output_archive: Option<Utf8PathBuf>,
+
+    /// Write the files (write), only report the ones that would change (dry), or print them to stdout as one bundle
+    #[arg(long, value_enum, default_value = "write", conflicts_with_all = ["output_archive", "workspace", "packages"])]
+    output_mode: OutputMode,
+
+    /// Report each file written (-v), or trace everything (-vv)
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet", global = true)]
+    verbose: u8,
+
+    /// Print nothing unless the command fails
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Config file (defaults to lit.toml in the current directory, if present)
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<Utf8PathBuf>,
+
+    /// Set a tangle path variable, overriding lit.toml
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = parse_define, global = true)]
+    defines: Vec<(String, String)>,
+
+    /// Keep the run's temp files instead of cleaning them up, for debugging
+    #[arg(long, global = true)]
+    keep_temp: bool,
+
+    /// Overwrite existing files that lit did not generate
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Fail on fences that look like tangle blocks but would be skipped
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Warn about documents that cannot be read, and tangle the rest, instead of failing
+    #[arg(long, global = true)]
+    skip_invalid: bool,
+
+    /// Report every problem found, not just the first
+    #[arg(long, global = true)]
+    all_errors: bool,
+
+    /// Run a shell command after tangling, after lit.toml's post_tangle hooks
+    #[arg(
+        long = "exec",
+        value_name = "CMD",
+        conflicts_with = "sandbox",
+        global = true
+    )]
+    exec: Vec<String>,
+
+    /// Run nothing, read and write only inside the project, and limit sizes, for untrusted documents
+    #[arg(long, global = true)]
+    sandbox: bool,
+
+    /// Run cargo fmt --check and cargo check in each crate the tangle writes a Cargo.toml for
+    #[arg(long, conflicts_with = "sandbox", global = true)]
+    cargo: bool,
+
+    /// Expand $NAME in tangle paths and {{env.NAME}} in paths and content from the environment
+    #[arg(long, conflicts_with = "sandbox", global = true)]
+    env: bool,
+
+    /// Fail on every warning (warnings), or on the warnings with this code (repeatable)
+    #[arg(long, value_name = "WARNINGS|CODE", global = true)]
+    deny: Vec<String>,
+
+    /// Report warnings, notes, and errors as text, or as JSON lines on stderr
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    message_format: MessageFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a starter literate project
+    Init {
+        /// Directory to create the project in
+        #[arg(value_name = "DIR", default_value = ".")]
+        directory: Utf8PathBuf,
+    },
+    /// Create a project from a literate template, asking its questions
+    New {
+        /// The template document
+        #[arg(long = "from", value_name = "TEMPLATE")]
+        template: Utf8PathBuf,
+        /// Directory to create the project in
+        #[arg(value_name = "DIR")]
+        directory: Utf8PathBuf,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        /// The shell to complete for
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+    /// Tangle a static site's content and copy it with fences rewritten for rendering
+    Site {
+        /// The site's content directory
+        #[arg(value_name = "CONTENT")]
+        content: Utf8PathBuf,
+        /// Where to write the rewritten copy of the content
+        #[arg(value_name = "SITE")]
+        site: Utf8PathBuf,
+        /// Output directory for tangled files (defaults to `output` in lit.toml, then CONTENT/../src)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<Utf8PathBuf>,
+    },
+    /// Print one tangled file to stdout without writing anything
+    Extract {
+        /// Destination path, relative to the output directory
+        #[arg(value_name = "TARGET")]
+        target: Utf8PathBuf,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Rewrite every fence that tangles to one target to tangle to another
+    Mv {
+        /// Destination path to move, relative to the output directory
+        #[arg(value_name = "OLD")]
+        old: Utf8PathBuf,
+        /// Destination path to move it to
+        #[arg(value_name = "NEW")]
+        new: Utf8PathBuf,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+        /// Output directory holding the old file (defaults to `output` in lit.toml, then INPUT/out)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<Utf8PathBuf>,
+        /// Rename the old file in the output directory to the new path
+        #[arg(long, conflicts_with = "delete_output")]
+        move_output: bool,
+        /// Delete the old file from the output directory
+        #[arg(long)]
+        delete_output: bool,
+    },
+    /// Show the markdown line a line of a tangled file came from
+    Blame {
+        /// Destination path, relative to the output directory, and a 1-based line
+        #[arg(value_name = "TARGET:LINE", value_parser = parse_position)]
+        position: (Utf8PathBuf, usize),
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Show what tangling would change in one file of the output directory
+    Diff {
+        /// Destination path, relative to the output directory
+        #[arg(value_name = "TARGET")]
+        target: Utf8PathBuf,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+        /// Output directory to compare against (defaults to `output` in lit.toml, then INPUT/out)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<Utf8PathBuf>,
+    },
+    /// Tangle an mdBook's chapters, or preprocess its fences for rendering
+    #[command(args_conflicts_with_subcommands = true)]
+    Mdbook {
+        #[command(subcommand)]
+        command: Option<MdbookCommand>,
+        /// The book's directory, containing book.toml
+        #[arg(value_name = "BOOK", default_value = ".")]
+        book: Utf8PathBuf,
+        /// Output directory for tangled files (defaults to `output` in lit.toml, then BOOK/out)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<Utf8PathBuf>,
+    },
+    /// List every tangle block, marking skipped ones
+    List {
+        /// List each destination's blocks in the order they are written
+        #[arg(long)]
+        order: bool,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Print every tangle block with its target, constraints, and position
+    Inspect {
+        /// Print a JSON array, for editor plugins
+        #[arg(long)]
+        json: bool,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Run a language server for editors on stdin and stdout
+    Lsp,
+    /// Keep parsed documents in memory and answer tangle requests on a Unix socket
+    Daemon {
+        /// The socket to listen on
+        #[arg(long, value_name = "PATH", default_value = ".lit.sock")]
+        socket: Utf8PathBuf,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+        /// Output directory for tangled files (defaults to `output` in lit.toml, then INPUT/out)
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<Utf8PathBuf>,
+    },
+    /// Normalize tangle fences in the markdown inputs
+    Fmt {
+        /// Report files that need formatting instead of rewriting them, and fail if any do
+        #[arg(long)]
+        check: bool,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Check tangle fences and targets against the lint rules
+    Lint {
+        /// How to print findings
+        #[arg(long, value_enum, default_value = "text")]
+        format: LintFormat,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Typeset the markdown inputs as one Typst or LaTeX document
+    Weave {
+        /// The typesetting language to write
+        #[arg(long, value_enum, default_value = "typst")]
+        format: WeaveFormat,
+        /// Template to write the document into, instead of [weave] in lit.toml
+        #[arg(long, value_name = "FILE")]
+        template: Option<Utf8PathBuf>,
+        /// File to write the document to (defaults to stdout)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<Utf8PathBuf>,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Find blocks repeated across the project that could be one shared chunk
+    Duplicates {
+        /// Ignore blocks with fewer non-blank lines than this
+        #[arg(long, value_name = "N", default_value_t = 3)]
+        min_lines: usize,
+        /// How alike two blocks must be to count as near-identical, from 0 to 1
+        #[arg(long, value_name = "RATIO", default_value_t = 0.9)]
+        similarity: f32,
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Report documents, blocks, prose and generated lines, and the largest targets
+    Stats {
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+    /// Tangle into a temp directory and run the [verify] build there
+    VerifyBuild {
+        /// Input directories or files (defaults to `input` in lit.toml)
+        #[arg(value_name = "INPUT")]
+        inputs: Vec<Utf8PathBuf>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputMode {
+    /// Write the tangled files into the output directory
+    Write,
+    /// Write nothing; print the files writing would change
+    Dry,
+    /// Write nothing; print every file to stdout as a lit-bundle
+    StdoutBundle,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageFormat {
+    /// Log each message as it is reported
+    Text,
+    /// One JSON object per message, and the error last
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum LintFormat {
+    /// One `file:line: severity[rule] message` line per finding
+    Text,
+    /// A JSON array of findings
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum WeaveFormat {
+    /// Typst markup, for `typst compile`
+    Typst,
+    /// A LaTeX document
+    Latex,
+    /// The sections, targets, and blocks, for an outside template engine
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum MdbookCommand {
+    /// Run as an mdBook preprocessor, rewriting tangle fences in the book on stdin
+    Preprocess {
+        #[command(subcommand)]
+        check: Option<PreprocessCheck>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PreprocessCheck {
+    /// Tell mdBook whether a renderer is supported; every one is
+    Supports {
+        #[arg(value_name = "RENDERER")]
+        renderer: String,
+    },
+}
+
+fn load_config(args: &Args) -> miette::Result<Config> {
+    let default = Utf8Path::new("lit.toml");
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None if default.is_file() => Config::load(default)?,
+        None => Config::default(),
+    };
+    override_config(args, &mut config);
+    Ok(config)
+}
+
+fn override_config(args: &Args, config: &mut Config) {
+    config.variables.extend(args.defines.iter().cloned());
+    config.hooks.post_tangle.extend(args.exec.iter().cloned());
+    config.rust.cargo |= args.cargo;
+    config.env |= args.env;
+    if args.sandbox {
+        config.sandbox();
+    }
+}
+
+fn parse_define(define: &str) -> Result<(String, String), String> {
+    define
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got {define:?}"))
+}
+
+fn parse_target(glob: &str) -> Result<TargetGlob, String> {
+    TargetGlob::new(glob).map_err(|e| e.to_string())
+}
+
+fn log_level(args: &Args) -> &'static str {
+    let quiet = args.quiet || args.message_format == MessageFormat::Json;
+    match (quiet, args.verbose) {
+        (true, _) => "error",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    }
+}
+
+fn split_paths(args: &Args) -> miette::Result<(Vec<Utf8PathBuf>, Option<Utf8PathBuf>)> {
+    match (args.paths.as_slice(), &args.output) {
+        (inputs, Some(output)) => Ok((inputs.to_vec(), Some(output.clone()))),
+        ([], None) => Ok((Vec::new(), None)),
+        ([input], None) => Ok((vec![input.clone()], None)),
+        ([input, output], None) => Ok((vec![input.clone()], Some(output.clone()))),
+        (_, None) => miette::bail!(
+            help = "pass the output directory with -o to tangle several inputs",
+            "too many paths: expected INPUT [OUTPUT]"
+        ),
+    }
+}
+
+fn project(
+    mut inputs: Vec<Utf8PathBuf>,
+    output: Option<Utf8PathBuf>,
+    config: Config,
+) -> miette::Result<Lit> {
+    if inputs.is_empty() {
+        inputs.extend(config.input.clone());
+    }
+    let Some(first) = inputs.first() else {
+        miette::bail!("no input directory: pass INPUT or set `input` in lit.toml");
+    };
+    let output = output
+        .or_else(|| config.output.clone())
+        .unwrap_or_else(|| first.join("out"));
+
+    let mut lit = Lit::new(first.clone(), output);
+    lit.inputs = inputs;
+    lit.config = config;
+    lit.diagnostics = DIAGNOSTICS.clone();
+    Ok(lit)
+}
+
+fn tangle(args: &Args) -> miette::Result<()> {
+    if args.workspace || !args.packages.is_empty() {
+        return tangle_workspace(args);
+    }
+    let (inputs, output) = match &args.output_archive {
+        Some(_) => (args.paths.clone(), None),
+        None => split_paths(args)?,
+    };
+    let mut lit = project(inputs, output, load_config(args)?)?;
+    lit.keep_temp = args.keep_temp;
+    lit.force = args.force;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    lit.targets = args.targets.clone();
+
+    for input in &lit.inputs {
+        info!("Reading markdown files from: {input}");
+    }
+    if let Some(archive) = &args.output_archive {
+        info!("Writing tangled files to: {archive}");
+        let report = lit.tangle_to_archive(archive)?;
+        info!("Tangling complete! {}", report.summary());
+        if args.profile {
+            println!("{}", report.profile());
+        }
+        return Ok(());
+    }
+    match args.output_mode {
+        OutputMode::Dry if args.profile => {
+            miette::bail!(
+                "--profile prints to stdout, which --output-mode dry is listing the changed files on"
+            )
+        }
+        OutputMode::Dry => {
+            let report = lit.tangle_dry_run()?;
+            for file in report
+                .files
+                .iter()
+                .filter(|file| file.status == WriteStatus::Written)
+            {
+                println!("{}", file.path);
+            }
+            info!("Dry run complete! {}", report.summary());
+            return Ok(());
+        }
+        OutputMode::StdoutBundle if args.profile => {
+            miette::bail!(
+                "--profile prints to stdout, which --output-mode stdout-bundle is writing the bundle to"
+            )
+        }
+        OutputMode::StdoutBundle => {
+            let report = lit.tangle_to_bundle(&mut std::io::stdout().lock())?;
+            info!("Bundled {} files to stdout", report.files.len());
+            return Ok(());
+        }
+        OutputMode::Write => {}
+    }
+    info!("Writing tangled files to: {}", lit.output);
+
+    let report = lit.tangle();
+    if args.keep_temp {
+        info!("Temp files kept in: {}", lit.scratch_dir());
+    }
+    let report = report?;
+
+    if args.verbose > 0 {
+        eprint!("{report}");
+    }
+    info!("Tangling complete! {}", report.summary());
+    if args.profile {
+        println!("{}", report.profile());
+    }
+
+    Ok(())
+}
+
+fn tangle_workspace(args: &Args) -> miette::Result<()> {
+    let members = load_config(args)?.members()?;
+    if members.is_empty() {
+        miette::bail!("no workspace members: list them under [workspace] in lit.toml");
+    }
+    if let Some(name) = args
+        .packages
+        .iter()
+        .find(|name| !members.iter().any(|member| member.name == **name))
+    {
+        let names: Vec<_> = members.iter().map(|member| member.name.as_str()).collect();
+        miette::bail!(
+            help = format!("the members are: {}", names.join(", ")),
+            "no workspace member named `{name}`"
+        );
+    }
+
+    for member in members {
+        if !args.packages.is_empty() && !args.packages.contains(&member.name) {
+            continue;
+        }
+        let mut config = member.config;
+        override_config(args, &mut config);
+        let mut lit = project(Vec::new(), None, config)?;
+        lit.keep_temp = args.keep_temp;
+        lit.force = args.force;
+        lit.strict = args.strict;
+        lit.skip_invalid = args.skip_invalid;
+        lit.targets = args.targets.clone();
+
+        let report = lit.tangle()?;
+        if args.verbose > 0 {
+            eprint!("{report}");
+        }
+        info!(
+            "Tangled {} into {}: {}",
+            member.name,
+            lit.output,
+            report.summary()
+        );
+    }
+    Ok(())
+}
+
+fn list(args: &Args, order: bool, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    if order {
+        for index in lit.target_indexes()? {
+            println!("{}", index.path);
+            for (position, (origin, lines)) in index.blocks.iter().enumerate() {
+                match lines {
+                    Some((start, end)) => println!(
+                        "  {}. {origin}  (lines {start}-{end})",
+                        position.saturating_add(1)
+                    ),
+                    None => println!("  -  {origin}  (nested)"),
+                }
+            }
+        }
+        return Ok(());
+    }
+    let blocks = lit.blocks()?;
+
+    for block in &blocks {
+        let origin = block
+            .origin
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let id = block
+            .id
+            .as_ref()
+            .map(|id| format!("?id={id}"))
+            .unwrap_or_default();
+        let label = block
+            .label
+            .as_ref()
+            .map(|label| format!("#{label}"))
+            .unwrap_or_default();
+        let skipped = if block.skip { "  (skipped)" } else { "" };
+        println!("{origin}  {}{id}{label}{skipped}", block.path);
+    }
+
+    let skipped = blocks.iter().filter(|block| block.skip).count();
+    info!("{} blocks, {skipped} skipped", blocks.len());
+    Ok(())
+}
+
+fn stats(args: &Args, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    print!("{}", lit.stats()?);
+    Ok(())
+}
+
+fn duplicates(
+    args: &Args,
+    min_lines: usize,
+    similarity: f32,
+    inputs: &[Utf8PathBuf],
+) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    let groups = lit.duplicates(min_lines, similarity)?;
+    for (index, group) in groups.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        print!("{group}");
+    }
+    let savings: usize = groups.iter().map(|group| group.savings).sum();
+    info!(
+        "{} groups of duplicated blocks, {savings} lines to save",
+        groups.len()
+    );
+    Ok(())
+}
+
+fn verify_build(args: &Args, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.keep_temp = args.keep_temp;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    let root = args
+        .config
+        .as_deref()
+        .and_then(Utf8Path::parent)
+        .filter(|parent| !parent.as_str().is_empty())
+        .unwrap_or(Utf8Path::new("."));
+    let report = lit.verify_build(root)?;
+    info!("Build passed on {} tangled files", report.files.len());
+    Ok(())
+}
+
+fn inspect(args: &Args, json: bool, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    let blocks = lit.inspect()?;
+    if json {
+        let json = serde_json::to_string(&blocks).map_err(|e| miette::miette!("{e}"))?;
+        println!("{json}");
+        return Ok(());
+    }
+    for block in &blocks {
+        let id = block
+            .id
+            .as_ref()
+            .map(|id| format!("?id={id}"))
+            .unwrap_or_default();
+        let output = match block.output_lines {
+            Some((start, end)) => format!("  (lines {start}-{end})"),
+            None => String::new(),
+        };
+        let (start, end) = block.lines;
+        println!("{}:{start}-{end}  {}{id}{output}", block.file, block.target);
+    }
+    Ok(())
+}
+
+fn lsp() -> miette::Result<()> {
+    let mut server = lit::LanguageServer::new();
+    let (mut stdin, mut stdout) = (std::io::stdin().lock(), std::io::stdout().lock());
+    while let Some(message) = lit::read_message(&mut stdin)? {
+        if message.get("method").is_some_and(|method| method == "exit") {
+            break;
+        }
+        for reply in server.handle(&message) {
+            lit::write_message(&mut stdout, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn daemon(
+    args: &Args,
+    socket: &Utf8Path,
+    inputs: &[Utf8PathBuf],
+    output: Option<Utf8PathBuf>,
+) -> miette::Result<()> {
+    use std::os::unix::net::UnixListener;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    let mut lit = project(inputs.to_vec(), output, load_config(args)?)?;
+    lit.force = args.force;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    lit.targets = args.targets.clone();
+    let daemon = lit::Daemon::new(lit);
+
+    if UnixStream::connect(socket).is_ok() {
+        miette::bail!("a daemon is already listening on {socket}");
+    }
+    if socket.exists() {
+        fs_err::remove_file(socket).map_err(LitError::from)?;
+    }
+    let listener = UnixListener::bind(socket).map_err(LitError::from)?;
+    info!("Listening on {socket}");
+
+    let stopping = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let (daemon, stopping) = (&daemon, &stopping);
+            scope.spawn(move || {
+                let served = stream
+                    .try_clone()
+                    .map_err(LitError::from)
+                    .and_then(|reader| {
+                        daemon.serve(&mut std::io::BufReader::new(reader), &mut stream)
+                    });
+                match served {
+                    Ok(true) => {
+                        stopping.store(true, Ordering::SeqCst);
+                        // Wake the loop, which is waiting for the next connection.
+                        let _ = UnixStream::connect(socket);
+                    }
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!("Dropping a client: {e}"),
+                }
+            });
+        }
+    });
+    fs_err::remove_file(socket).map_err(LitError::from)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemon(
+    _args: &Args,
+    _socket: &Utf8Path,
+    _inputs: &[Utf8PathBuf],
+    _output: Option<Utf8PathBuf>,
+) -> miette::Result<()> {
+    miette::bail!("lit daemon listens on a Unix socket, which this platform does not have");
+}
+
+fn lint(args: &Args, format: LintFormat, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    let findings = lit.lint()?;
+    match format {
+        LintFormat::Text => {
+            for finding in &findings {
+                println!("{finding}");
+            }
+        }
+        LintFormat::Json => {
+            let json = serde_json::to_string(&findings).map_err(|e| miette::miette!("{e}"))?;
+            println!("{json}");
+        }
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == lit::Severity::Error)
+        .count();
+    info!("{} findings, {errors} errors", findings.len());
+    if errors > 0 {
+        miette::bail!("{errors} lint errors");
+    }
+    Ok(())
+}
+
+fn fmt(args: &Args, check: bool, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    let changed = lit.format(check)?;
+    for path in &changed {
+        info!("{} {path}", if check { "Unformatted" } else { "Formatted" });
+    }
+    if check && !changed.is_empty() {
+        miette::bail!("{} files need formatting; run `lit fmt`", changed.len());
+    }
+    Ok(())
+}
+
+fn extract(args: &Args, target: &Utf8Path, inputs: &[Utf8PathBuf]) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    lit.extract_to(target, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+fn mv(
+    args: &Args,
+    (old, new): (&Utf8Path, &Utf8Path),
+    inputs: &[Utf8PathBuf],
+    output: Option<Utf8PathBuf>,
+    moved: MovedOutput,
+) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), output, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    lit.force = args.force;
+    for path in lit.move_target(old, new, moved)? {
+        info!("Rewrote {path}");
+    }
+    Ok(())
+}
+
+fn mdbook(args: &Args, book: &Utf8Path, output: Option<Utf8PathBuf>) -> miette::Result<()> {
+    let (src, summary) = lit::prepare_book(book)?;
+    if let Some(summary) = summary {
+        info!("Wrote {summary}");
+    }
+    let config = load_config(args)?;
+    let output = output
+        .or_else(|| config.output.clone())
+        .unwrap_or_else(|| book.join("out"));
+    let mut lit = project(vec![src], Some(output), config)?;
+    lit.keep_temp = args.keep_temp;
+    lit.force = args.force;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+
+    let report = lit.tangle()?;
+    info!("Tangled into {}: {}", lit.output, report.summary());
+    Ok(())
+}
+
+fn mdbook_preprocess(supports: bool) -> miette::Result<()> {
+    if supports {
+        return Ok(());
+    }
+    let input = std::io::read_to_string(std::io::stdin()).map_err(lit::LitError::from)?;
+    print!("{}", lit::preprocess_book(&input)?);
+    Ok(())
+}
+
+fn blame(
+    args: &Args,
+    target: &Utf8Path,
+    line: usize,
+    inputs: &[Utf8PathBuf],
+) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), None, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    println!("{}", lit.blame(target, line)?);
+    Ok(())
+}
+
+fn parse_position(position: &str) -> Result<(Utf8PathBuf, usize), String> {
+    position
+        .rsplit_once(':')
+        .and_then(|(path, line)| Some((Utf8PathBuf::from(path), line.parse().ok()?)))
+        .ok_or_else(|| format!("expected TARGET:LINE, got {position:?}"))
+}
+
+fn diff(
+    args: &Args,
+    target: &Utf8Path,
+    inputs: &[Utf8PathBuf],
+    output: Option<Utf8PathBuf>,
+) -> miette::Result<()> {
+    let mut lit = project(inputs.to_vec(), output, load_config(args)?)?;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+    let diff = lit.diff(target)?;
+    if diff.is_empty() {
+        info!("{target} is up to date");
+        return Ok(());
+    }
+
+    let color = std::io::stdout().is_terminal();
+    for line in diff.lines() {
+        let code = match line.as_bytes().first() {
+            _ if !color => None,
+            Some(b'+') if !line.starts_with("+++") => Some("32"),
+            Some(b'-') if !line.starts_with("---") => Some("31"),
+            Some(b'@') => Some("36"),
+            Some(b'+' | b'-') => Some("1"),
+            _ => None,
+        };
+        match code {
+            Some(code) => println!("\x1b[{code}m{line}\x1b[0m"),
+            None => println!("{line}"),
+        }
+    }
+    Ok(())
+}
+
+fn site(
+    args: &Args,
+    content: &Utf8Path,
+    site: &Utf8Path,
+    output: Option<&Utf8Path>,
+) -> miette::Result<()> {
+    let config = load_config(args)?;
+    let output = output
+        .map(Utf8Path::to_owned)
+        .or_else(|| config.output.clone())
+        .unwrap_or_else(|| content.parent().unwrap_or(Utf8Path::new("")).join("src"));
+    let mut lit = project(vec![content.to_owned()], Some(output), config)?;
+    lit.keep_temp = args.keep_temp;
+    lit.force = args.force;
+    lit.strict = args.strict;
+    lit.skip_invalid = args.skip_invalid;
+
+    let report = lit.tangle()?;
+    info!("Tangled into {}: {}", lit.output, report.summary());
+    let copied = lit.copy_site(site)?;
+    info!("Copied {} files to {site}", copied.len());
+    Ok(())
+}
+
+fn weave(
+    args: &Args,
+    format: WeaveFormat,
+    template: Option<&Utf8Path>,
+    output: Option<&Utf8Path>,
+    inputs: &[Utf8PathBuf],
+) -> miette::Result<()> {
+    let mut config = load_config(args)?;
+    let template = template.map(Utf8Path::to_path_buf);
+    match format {
+        WeaveFormat::Typst if template.is_some() => config.weave.typst = template,
+        WeaveFormat::Latex if template.is_some() => config.weave.latex = template,
+        WeaveFormat::Json if template.is_some() => {
+            miette::bail!("--template is for --format typst or latex")
+        }
+        _ => {}
+    }
+    let lit = project(inputs.to_vec(), None, config)?;
+    let woven = match format {
+        WeaveFormat::Typst => lit.weave(lit::WeaveFormat::Typst)?,
+        WeaveFormat::Latex => lit.weave(lit::WeaveFormat::Latex)?,
+        WeaveFormat::Json => {
+            let context = lit.weave_context()?;
+            let json =
+                serde_json::to_string_pretty(&context).map_err(|e| miette::miette!("{e}"))?;
+            format!("{json}\n")
+        }
+    };
+    match output {
+        Some(path) => {
+            fs_err::write(path, woven).map_err(LitError::Io)?;
+            info!("Wove {path}");
+        }
+        None => print!("{woven}"),
+    }
+    Ok(())
+}
+
+/// The first of several collected problems, or the error as it is
+fn first_error(report: miette::Report) -> miette::Report {
+    match report.downcast::<LitError>() {
+        Ok(LitError::Errors { mut errors }) if !errors.is_empty() => errors.swap_remove(0).into(),
+        Ok(error) => error.into(),
+        Err(report) => report,
+    }
+}
+
+/// The warnings and notes from every project this run opens
+static DIAGNOSTICS: LazyLock<Diagnostics> = LazyLock::new(Diagnostics::default);
+
+/// Print the run's messages and its error, if any, as JSON lines on stderr
+fn report_json(result: miette::Result<()>) -> miette::Result<()> {
+    let mut messages = DIAGNOSTICS.messages();
+    if let Err(report) = &result {
+        let code = report
+            .code()
+            .map_or_else(|| "lit::error".to_string(), |code| code.to_string());
+        messages.push(Message::new(Level::Error, &code, report.to_string()));
+    }
+    for message in &messages {
+        eprintln!(
+            "{}",
+            serde_json::to_string(message).map_err(|e| miette::miette!("{e}"))?
+        );
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn init(directory: &Utf8Path) -> miette::Result<()> {
+    for path in lit::init(directory)? {
+        info!("Created {path}");
+    }
+    info!("Run `lit` in {directory} to tangle the example");
+    Ok(())
+}
+
+fn new(args: &Args, template: &Utf8Path, directory: &Utf8Path) -> miette::Result<()> {
+    let mut answers: std::collections::BTreeMap<_, _> = args.defines.iter().cloned().collect();
+    let mut stdin = std::io::stdin().lock();
+    for prompt in lit::template_prompts(template)? {
+        if answers.contains_key(&prompt.name) {
+            continue;
+        }
+        loop {
+            match &prompt.default {
+                Some(default) => eprint!("{} [{default}]: ", prompt.ask),
+                None => eprint!("{}: ", prompt.ask),
+            }
+            let mut line = String::new();
+            if std::io::BufRead::read_line(&mut stdin, &mut line).map_err(LitError::Io)? == 0 {
+                eprintln!();
+                break;
+            }
+            let line = line.trim();
+            if !line.is_empty() {
+                answers.insert(prompt.name.clone(), line.to_string());
+                break;
+            }
+            if prompt.default.is_some() {
+                break;
+            }
+        }
+    }
+    for path in lit::from_template(template, &answers, directory)? {
+        info!("Created {path}");
+    }
+    Ok(())
+}
+
+fn completions(shell: clap_complete::Shell) {
+    clap_complete::generate(shell, &mut Args::command(), "lit", &mut std::io::stdout());
 }
 
 fn main() -> miette::Result<()> {
     miette::set_panic_hook();
+    let args = Args::parse();
+
     tracing_subscriber::fmt()
         .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level(&args))),
         )
+        .with_writer(std::io::stderr)
         .init();
+    for code in &args.deny {
+        DIAGNOSTICS.deny(code);
+    }
+    if args.message_format == MessageFormat::Json {
+        DIAGNOSTICS.quiet();
+    }
 
-    let args = Args::parse();
-    let output = args.output.unwrap_or_else(|| args.directory.join("out"));
-
-    let input = &args.directory;
-    info!("Reading markdown files from: {input}");
-    info!("Writing tangled files to: {output}");
-
-    let lit = Lit::new(args.directory, output);
-    lit.tangle()?;
-
-    info!("Tangling complete!");
-
-    Ok(())
+    let result = match &args.command {
+        Some(Command::Init { directory }) => init(directory),
+        Some(Command::New {
+            template,
+            directory,
+        }) => new(&args, template, directory),
+        Some(Command::Completions { shell }) => {
+            completions(*shell);
+            Ok(())
+        }
+        Some(Command::List { order, inputs }) => list(&args, *order, inputs),
+        Some(Command::Stats { inputs }) => stats(&args, inputs),
+        Some(Command::Duplicates {
+            min_lines,
+            similarity,
+            inputs,
+        }) => duplicates(&args, *min_lines, *similarity, inputs),
+        Some(Command::VerifyBuild { inputs }) => verify_build(&args, inputs),
+        Some(Command::Weave {
+            format,
+            template,
+            output,
+            inputs,
+        }) => weave(
+            &args,
+            *format,
+            template.as_deref(),
+            output.as_deref(),
+            inputs,
+        ),
+        Some(Command::Lint { format, inputs }) => lint(&args, *format, inputs),
+        Some(Command::Inspect { json, inputs }) => inspect(&args, *json, inputs),
+        Some(Command::Lsp) => lsp(),
+        Some(Command::Daemon {
+            socket,
+            inputs,
+            output,
+        }) => daemon(&args, socket, inputs, output.clone()),
+        Some(Command::Fmt { check, inputs }) => fmt(&args, *check, inputs),
+        Some(Command::Extract { target, inputs }) => extract(&args, target, inputs),
+        Some(Command::Mv {
+            old,
+            new,
+            inputs,
+            output,
+            move_output,
+            delete_output,
+        }) => {
+            let moved = match (move_output, delete_output) {
+                (true, _) => MovedOutput::Move,
+                (_, true) => MovedOutput::Delete,
+                _ => MovedOutput::Keep,
+            };
+            mv(&args, (old, new), inputs, output.clone(), moved)
+        }
+        Some(Command::Blame {
+            position: (target, line),
+            inputs,
+        }) => blame(&args, target, *line, inputs),
+        Some(Command::Diff {
+            target,
+            inputs,
+            output,
+        }) => diff(&args, target, inputs, output.clone()),
+        Some(Command::Mdbook {
+            command: Some(MdbookCommand::Preprocess { check }),
+            ..
+        }) => mdbook_preprocess(check.is_some()),
+        Some(Command::Mdbook {
+            command: None,
+            book,
+            output,
+        }) => mdbook(&args, book, output.clone()),
+        Some(Command::Site {
+            content,
+            site: site_dir,
+            output,
+        }) => site(&args, content, site_dir, output.as_deref()),
+        None => tangle(&args),
+    };
+    let result = if args.all_errors {
+        result
+    } else {
+        result.map_err(first_error)
+    };
+    match args.message_format {
+        MessageFormat::Text => result,
+        MessageFormat::Json => report_json(result),
+    }
 }
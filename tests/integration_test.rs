@@ -81,3 +81,980 @@ pub fn greet() {
         "// Header comment\n\n// Main content\npub fn greet() {\n    println!(\"Hello!\");\n}\n\n// Footer comment\n"
     );
 }
+
+#[test]
+fn test_quiet_prints_nothing_on_success() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("--quiet")
+        .arg(&input_dir)
+        .arg(temp_dir.path().join("output"))
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+    assert!(temp_dir.path().join("output/a.txt").exists());
+}
+
+#[test]
+fn test_verbose_prints_summary_table() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("-v")
+        .arg(&input_dir)
+        .arg(temp_dir.path().join("output"))
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("Failed to execute lit");
+
+    // stderr is human-readable log text here, so lossy decoding is fine.
+    #[allow(clippy::disallowed_methods)]
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "lit command failed: {stderr}");
+    assert!(stderr.contains("written"), "{stderr}");
+    assert!(
+        stderr.contains("1 files: 1 written, 0 unchanged"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_multiple_inputs_with_output_flag() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::create_dir_all(root.join("book")).unwrap();
+    fs::write(root.join("docs/a.md"), "```tangle:///a.txt?id=a\na\n```\n").unwrap();
+    fs::write(
+        root.join("book/b.md"),
+        "```tangle:///a.txt?after=a\nb\n```\n",
+    )
+    .unwrap();
+    fs::write(root.join("extra.md"), "```tangle:///c.txt\nc\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(root.join("docs"))
+        .arg(root.join("book"))
+        .arg(root.join("extra.md"))
+        .arg("-o")
+        .arg(root.join("out"))
+        .output()
+        .expect("Failed to execute lit");
+
+    // stderr is human-readable diagnostic text here, so lossy decoding is fine.
+    #[allow(clippy::disallowed_methods)]
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "lit command failed: {stderr}");
+    assert_eq!(
+        fs::read_to_string(root.join("out/a.txt")).unwrap(),
+        "a\n\nb\n"
+    );
+    assert_eq!(fs::read_to_string(root.join("out/c.txt")).unwrap(), "c\n");
+
+    // Without -o, a third positional is ambiguous.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(root.join("docs"))
+        .arg(root.join("book"))
+        .arg(root.join("out"))
+        .output()
+        .expect("Failed to execute lit");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_list_marks_skipped_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt?id=a\na\n```\n\n```tangle:///b.txt?skip\nb\n```\n",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("list")
+        .arg(&input_dir)
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert!(
+        lines.next().unwrap().ends_with("doc.md:1  a.txt?id=a"),
+        "{stdout}"
+    );
+    assert!(
+        lines
+            .next()
+            .unwrap()
+            .ends_with("doc.md:5  b.txt  (skipped)"),
+        "{stdout}"
+    );
+    assert!(lines.next().is_none(), "{stdout}");
+
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt\na\n```\n\n```tangle:///a.txt?id=b&first\nb\n{{}}\n```\n\n```tangle:///a.txt?inside=b\nc\n```\n",
+    )
+    .unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["list", "--order"])
+        .arg(&input_dir)
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("a.txt"), "{stdout}");
+    for (number, ending) in [
+        ("  1. ", "doc.md:5  (lines 1-2)"),
+        ("  2. ", "doc.md:1  (lines 4-4)"),
+        ("  -  ", "doc.md:10  (nested)"),
+    ] {
+        let line = lines.next().unwrap();
+        assert!(
+            line.starts_with(number) && line.ends_with(ending),
+            "{stdout}"
+        );
+    }
+}
+
+#[test]
+fn test_site_tangles_and_copies_content() {
+    let temp_dir = TempDir::new().unwrap();
+    let content = temp_dir.path().join("content");
+    fs::create_dir_all(&content).unwrap();
+    fs::write(
+        content.join("index.md"),
+        "# Home\n\n```tangle:///main.rs\nfn main() {}\n```\n",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("site")
+        .arg(&content)
+        .arg(temp_dir.path().join("public"))
+        .output()
+        .expect("Failed to execute lit");
+
+    // stderr is human-readable diagnostic text here, so lossy decoding is fine.
+    #[allow(clippy::disallowed_methods)]
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "lit command failed: {stderr}");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("src/main.rs")).unwrap(),
+        "fn main() {}\n"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("public/index.md")).unwrap(),
+        "# Home\n\n```rust\nfn main() {}\n```\n\n→ [main.rs](lit-targets/main.rs.md), line 1\n"
+    );
+    assert!(
+        temp_dir
+            .path()
+            .join("public/lit-targets/main.rs.md")
+            .is_file()
+    );
+}
+
+#[test]
+fn test_extract_prints_one_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt\na\n```\n\n```tangle:///b.txt\nb\n```\n",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("extract")
+        .arg("b.txt")
+        .arg(&input_dir)
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b\n");
+    assert!(!input_dir.join("out").exists());
+}
+
+#[test]
+fn test_blame_prints_markdown_line() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "# Doc\n\n```tangle:///a.rs?id=a\nfn a() {}\nfn b() {}\n```\n",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["blame", "a.rs:2"])
+        .arg(&input_dir)
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    let doc = input_dir.join("doc.md");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{}:5 in the block at {}:3 (?id=a)\n",
+            doc.display(),
+            doc.display()
+        )
+    );
+}
+
+#[test]
+fn test_diff_prints_pending_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    let output_dir = temp_dir.path().join("output");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::create_dir_all(&output_dir).unwrap();
+    fs::write(input_dir.join("doc.md"), "```tangle:///a.txt\nnew\n```\n").unwrap();
+    fs::write(output_dir.join("a.txt"), "old\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["diff", "a.txt"])
+        .arg(&input_dir)
+        .arg("-o")
+        .arg(&output_dir)
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-old\n+new\n"
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.join("a.txt")).unwrap(),
+        "old\n"
+    );
+
+    fs::write(output_dir.join("a.txt"), "new\n").unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["diff", "a.txt"])
+        .arg(&input_dir)
+        .arg("-o")
+        .arg(&output_dir)
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_exec_runs_after_tangle() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&input_dir)
+        .arg("--exec")
+        .arg("cp \"$LIT_OUTPUT/$LIT_CHANGED_FILES\" copied.txt")
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("copied.txt")).unwrap(),
+        "a\n"
+    );
+}
+
+#[test]
+fn test_all_errors_reports_every_problem() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("a.md"), "```tangle:///a.txt?id=\nx\n```\n").unwrap();
+    fs::write(input_dir.join("b.md"), "```tangle:///b.txt?id=\nx\n```\n").unwrap();
+
+    let run = |all_errors: bool| {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_lit"));
+        command.arg(&input_dir).current_dir(temp_dir.path());
+        if all_errors {
+            command.arg("--all-errors");
+        }
+        let output = command.output().expect("Failed to execute lit");
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    assert_eq!(run(false).matches("Block ID cannot be empty").count(), 1);
+    let stderr = run(true);
+    assert!(stderr.contains("found 2 problems"), "{stderr}");
+    assert_eq!(stderr.matches("Block ID cannot be empty").count(), 2);
+}
+
+#[test]
+fn test_stats_prints_totals() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "Prose.\n\n```tangle:///a.rs\nfn a() {}\n```\n",
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("stats")
+        .arg(&input_dir)
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("1 documents, 1 blocks (0 skipped), 1 targets\n"),
+        "{stdout}"
+    );
+    assert!(!input_dir.join("out").exists());
+}
+
+#[test]
+fn test_fmt_check_fails_on_unformatted_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt?last&id=a\na\n```\n",
+    )
+    .unwrap();
+
+    let run = |check: bool| {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_lit"));
+        command.arg("fmt").arg(&input_dir);
+        if check {
+            command.arg("--check");
+        }
+        command.output().expect("Failed to execute lit")
+    };
+
+    assert!(!run(true).status.success());
+    assert!(run(false).status.success());
+    assert!(run(true).status.success());
+    assert_eq!(
+        fs::read_to_string(input_dir.join("doc.md")).unwrap(),
+        "```tangle:///a.txt?id=a&last\na\n```\n"
+    );
+}
+
+#[test]
+fn test_mdbook_preprocessor() {
+    let lit = env!("CARGO_BIN_EXE_lit");
+    let supports = std::process::Command::new(lit)
+        .args(["mdbook", "preprocess", "supports", "html"])
+        .status()
+        .expect("Failed to execute lit");
+    assert!(supports.success());
+
+    let mut child = std::process::Command::new(lit)
+        .args(["mdbook", "preprocess"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to execute lit");
+    let input = r#"[{"root": "."}, {"sections": [{"Chapter": {"content": "```tangle:///a.rs\nx\n```\n", "sub_items": []}}]}]"#;
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        r#"{"sections":[{"Chapter":{"content":"```rust\nx\n```\n","sub_items":[]}}]}"#
+    );
+}
+
+#[test]
+fn test_workspace_tangles_members() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    for member in ["a", "b"] {
+        fs::create_dir_all(root.join(member)).unwrap();
+        fs::write(
+            root.join(member).join("doc.md"),
+            format!("```tangle:///{member}.txt\n{member}\n```\n"),
+        )
+        .unwrap();
+    }
+    fs::write(root.join("b/lit.toml"), "output = \"gen\"\n").unwrap();
+    fs::write(
+        root.join("lit.toml"),
+        "[workspace]\nmembers = [\"a\", \"b\"]\n",
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .args(args)
+            .current_dir(root)
+            .output()
+            .expect("Failed to execute lit")
+    };
+
+    assert!(run(&["-p", "b"]).status.success());
+    assert_eq!(fs::read_to_string(root.join("b/gen/b.txt")).unwrap(), "b\n");
+    assert!(!root.join("a/out").exists());
+
+    assert!(run(&["--workspace"]).status.success());
+    assert_eq!(fs::read_to_string(root.join("a/out/a.txt")).unwrap(), "a\n");
+
+    let output = run(&["-p", "c"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("no workspace member named `c`"), "{stderr}");
+}
+
+#[test]
+fn test_lint_reports_findings() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(input_dir.join("doc.md"), "```\nplain\n```\n").unwrap();
+
+    let run = |format: &str| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .args(["lint", "--format", format])
+            .arg(&input_dir)
+            .output()
+            .expect("Failed to execute lit")
+    };
+
+    let output = run("text");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{}:1: warning[missing-language] code fence has no language\n",
+            input_dir.join("doc.md").display()
+        )
+    );
+
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt?colour=red\na\n```\n",
+    )
+    .unwrap();
+    let output = run("json");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with(r#"[{"rule":"unknown-param","severity":"error","#),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn test_completions_lists_subcommands() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["completions", "bash"])
+        .output()
+        .expect("Failed to execute lit");
+
+    assert!(output.status.success());
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.contains("complete -F _lit"), "{script}");
+    assert!(script.contains("--all-errors"), "{script}");
+}
+
+#[test]
+fn test_inspect_prints_block_positions() {
+    let temp_dir = TempDir::new().unwrap();
+    let doc = temp_dir.path().join("doc.md");
+    fs::write(&doc, "# Doc\n\n```tangle:///a.rs?id=a\nfn a() {}\n```\n").unwrap();
+
+    let run = |json: bool| {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_lit"));
+        command.arg("inspect").arg(&doc);
+        if json {
+            command.arg("--json");
+        }
+        let output = command.output().expect("Failed to execute lit");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        run(false),
+        format!("{}:3-5  a.rs?id=a  (lines 1-1)\n", doc.display())
+    );
+    assert!(
+        run(true).contains(r#""target":"a.rs","id":"a","constraints":[],"inside":null,"skip":false,"span":[7,43],"lines":[3,5],"output_lines":[1,1]"#)
+    );
+}
+
+#[test]
+fn test_lsp_answers_over_stdio() {
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("lsp")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to execute lit");
+    let mut input = String::new();
+    for message in [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ] {
+        input.push_str(&format!(
+            "Content-Length: {}\r\n\r\n{message}",
+            message.len()
+        ));
+    }
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Content-Length: "), "{stdout}");
+    assert!(stdout.contains(r#""hoverProvider":true"#), "{stdout}");
+    assert!(
+        stdout.ends_with(r#"{"id":2,"jsonrpc":"2.0","result":null}"#),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn test_weave_writes_typst_and_latex() {
+    let temp_dir = TempDir::new().unwrap();
+    let doc = temp_dir.path().join("doc.md");
+    fs::write(&doc, "# Doc\n\n```tangle:///a.rs\nfn a() {}\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg("weave")
+        .arg(&doc)
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    let typst = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        typst.contains(
+            "= Doc <lit-section-1>\n\n#figure(kind: raw, caption: [#raw(\"a.rs\"), line 1])["
+        ),
+        "{typst}"
+    );
+
+    let book = temp_dir.path().join("book.tex");
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["weave", "--format", "latex", "-o"])
+        .arg(&book)
+        .arg(&doc)
+        .status()
+        .expect("Failed to execute lit");
+    assert!(status.success());
+    assert!(
+        fs::read_to_string(&book)
+            .unwrap()
+            .contains("\\section{Doc}")
+    );
+}
+
+#[test]
+fn test_output_archive_leaves_the_directory_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.rs\nfn a() {}\n```\n").unwrap();
+    let archive = temp_dir.path().join("gen.tar.gz");
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&docs)
+        .arg("--output-archive")
+        .arg(&archive)
+        .status()
+        .expect("Failed to execute lit");
+    assert!(status.success());
+    assert!(archive.is_file());
+    assert!(!docs.join("out").exists());
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&docs)
+        .arg("--output-archive")
+        .arg(temp_dir.path().join("gen.7z"))
+        .output()
+        .expect("Failed to execute lit");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("cannot tell what kind of archive")
+    );
+}
+
+#[test]
+fn test_output_modes_leave_the_directory_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.rs\nfn a() {}\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&docs)
+        .args(["--output-mode", "stdout-bundle"])
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "lit-bundle 1\nlit-file 644 10 a.rs\nfn a() {}\n\n"
+    );
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&docs)
+        .args(["--output-mode", "dry"])
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.rs\n");
+    assert!(!docs.join("out").exists());
+}
+
+#[test]
+fn test_env_reads_the_environment_only_when_asked() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(
+        docs.join("doc.md"),
+        "```tangle:///$OUT_NAME/main.rs\n// {{env.OUT_NAME}}\n```\n",
+    )
+    .unwrap();
+    let lit = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .arg(&docs)
+            .args(args)
+            .env("OUT_NAME", "release")
+            .output()
+            .expect("Failed to execute lit")
+    };
+
+    assert!(lit(&[]).status.success());
+    assert_eq!(
+        fs::read_to_string(docs.join("out/$OUT_NAME/main.rs")).unwrap(),
+        "// {{env.OUT_NAME}}\n"
+    );
+    assert!(lit(&["--env"]).status.success());
+    assert_eq!(
+        fs::read_to_string(docs.join("out/release/main.rs")).unwrap(),
+        "// release\n"
+    );
+    assert!(!lit(&["--env", "--sandbox"]).status.success());
+}
+
+#[test]
+fn test_new_asks_a_template_its_questions() {
+    use std::io::Write as _;
+
+    let temp_dir = TempDir::new().unwrap();
+    let template = temp_dir.path().join("template.md");
+    fs::write(
+        &template,
+        "---\nlit:\n  prompts:\n    - name: name\n      ask: Project name\n    - name: license\n      ask: License\n      default: MIT\n    - name: year\n      ask: Year\n---\n\n```tangle:///{{name}}.txt\n{{name}} {{license}} {{year}}\n```\n",
+    )
+    .unwrap();
+    let directory = temp_dir.path().join("project");
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["new", "-D", "year=2024", "--from"])
+        .arg(&template)
+        .arg(&directory)
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to execute lit");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"\ndemo\n\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Project name: Project name: License [MIT]: "),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("Year"), "{stderr}");
+    assert_eq!(
+        fs::read_to_string(directory.join("demo.txt")).unwrap(),
+        "demo MIT 2024\n"
+    );
+}
+
+#[test]
+fn test_verify_build_runs_the_build_on_tangled_sources() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(
+        docs.join("doc.md"),
+        "```tangle:///check.sh\ntest -f input.txt\n```\n",
+    )
+    .unwrap();
+    fs::write(temp_dir.path().join("input.txt"), "input\n").unwrap();
+    let verify = |config: &str| {
+        fs::write(temp_dir.path().join("lit.toml"), config).unwrap();
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .args(["verify-build", "docs"])
+            .current_dir(temp_dir.path())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .expect("Failed to execute lit")
+    };
+
+    assert!(verify("[verify]\ncopy = [\"input.txt\"]\ncommand = \"sh check.sh\"\n").success());
+    assert!(!verify("[verify]\ncommand = \"sh check.sh\"\n").success());
+    assert!(!docs.join("out").exists());
+}
+
+#[test]
+fn test_sandbox_skips_hooks_and_refuses_exec() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+    fs::write(
+        temp_dir.path().join("lit.toml"),
+        "[hooks]\npost_tangle = [\"touch hooked\"]\n",
+    )
+    .unwrap();
+    let lit = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .args(args)
+            .current_dir(temp_dir.path())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .expect("Failed to execute lit")
+    };
+
+    assert!(lit(&["--sandbox", "docs", "out"]).success());
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("out/a.txt")).unwrap(),
+        "a\n"
+    );
+    assert!(!temp_dir.path().join("hooked").exists());
+    assert!(!lit(&["--sandbox", "--exec", "touch hooked", "docs", "out"]).success());
+    assert!(!temp_dir.path().join("hooked").exists());
+}
+
+#[test]
+fn test_profile_prints_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["--profile", "docs", "out"])
+        .current_dir(temp_dir.path())
+        .output()
+        .expect("Failed to execute lit");
+    assert!(output.status.success());
+    let profile: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let phases: Vec<_> = profile
+        .get("phases")
+        .and_then(|phases| phases.as_array())
+        .unwrap()
+        .iter()
+        .filter_map(|phase| phase.get("phase")?.as_str())
+        .collect();
+    assert_eq!(
+        phases,
+        ["walk", "parse", "merge", "render", "write", "hooks"]
+    );
+    assert_eq!(
+        profile
+            .pointer("/documents/0/path")
+            .and_then(|path| path.as_str()),
+        Some("docs/doc.md")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_tangles_over_a_socket() {
+    use std::io::Read;
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.txt\na\n```\n").unwrap();
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["daemon", "--socket", "lit.sock", "docs", "-o", "out"])
+        .current_dir(temp_dir.path())
+        .spawn()
+        .expect("Failed to execute lit");
+    let socket = temp_dir.path().join("lit.sock");
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(connected) = std::os::unix::net::UnixStream::connect(&socket) {
+            stream = Some(connected);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let mut stream = stream.expect("the daemon never listened");
+
+    for message in [
+        r#"{"jsonrpc":"2.0","id":1,"method":"tangle"}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+    ] {
+        write!(stream, "Content-Length: {}\r\n\r\n{message}", message.len()).unwrap();
+    }
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    drop(stream);
+
+    assert!(child.wait().unwrap().success());
+    assert!(replies.contains(r#""path":"a.txt""#), "{replies}");
+    assert!(
+        replies.ends_with(r#"{"id":2,"jsonrpc":"2.0","result":null}"#),
+        "{replies}"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("out/a.txt")).unwrap(),
+        "a\n"
+    );
+    assert!(!socket.exists());
+}
+
+#[test]
+fn test_deny_warnings_reports_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.rs\nfn a() {}\n```\n").unwrap();
+    let lit = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .arg(&docs)
+            .args(["--target", "*.py"])
+            .args(args)
+            .output()
+            .expect("Failed to execute lit")
+    };
+
+    assert!(lit(&[]).status.success());
+    let output = lit(&["--deny", "warnings", "--message-format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        concat!(
+            r#"{"level":"warning","code":"unmatched-target","message":"No destination matches --target *.py","file":null,"line":null}"#,
+            "\n",
+            r#"{"level":"error","code":"lit::denied_warnings","message":"1 denied warnings; the first is warning[unmatched-target] No destination matches --target *.py","file":null,"line":null}"#,
+            "\n",
+        )
+    );
+}
+
+#[test]
+fn test_include_finds_chunks_under_a_dotted_input() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(
+        docs.join("license.md"),
+        "```tangle:///LICENSE?id=license\nMIT\n```\n",
+    )
+    .unwrap();
+    fs::write(
+        docs.join("notice.md"),
+        "```tangle:///NOTICE?include=license.md#license\n```\n",
+    )
+    .unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .args(["./docs", "out"])
+        .current_dir(temp_dir.path())
+        .status()
+        .expect("Failed to execute lit");
+    assert!(status.success());
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("out/NOTICE")).unwrap(),
+        "MIT\n"
+    );
+}
+
+#[test]
+fn test_profile_is_an_error_with_a_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("doc.md"), "```tangle:///a.rs\nfn a() {}\n```\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+        .arg(&docs)
+        .args(["--output-mode", "dry", "--profile"])
+        .output()
+        .expect("Failed to execute lit");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("--profile prints to stdout")
+    );
+}
+
+#[test]
+fn test_global_flags_may_come_before_a_subcommand() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_dir = temp_dir.path().join("input");
+    fs::create_dir_all(&input_dir).unwrap();
+    fs::write(
+        input_dir.join("doc.md"),
+        "```tangle:///a.txt?last&id=a\na\n```\n",
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_lit"))
+            .args(args)
+            .arg(&input_dir)
+            .output()
+            .expect("Failed to execute lit")
+    };
+
+    let list = run(&["-v", "list"]);
+    let stdout = String::from_utf8(list.stdout).unwrap();
+    assert!(list.status.success());
+    assert!(stdout.contains("a.txt"), "{stdout}");
+
+    let fmt = run(&["-q", "fmt"]);
+    assert!(fmt.status.success());
+    assert!(fmt.stdout.is_empty());
+    assert_eq!(
+        fs::read_to_string(input_dir.join("doc.md")).unwrap(),
+        "```tangle:///a.txt?id=a&last\na\n```\n"
+    );
+    assert!(!input_dir.join("out").exists());
+}